@@ -1,63 +1,269 @@
-use std::collections::HashMap;
-use std::os::unix::net::UnixStream;
-use std::io::Write;
-use std::process::Command;
-use std::time::Duration;
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+use std::fs::{self, File};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use std::thread;
 
-use serde::{Serialize, Deserialize};
-
-/// Messages sent from guest to host about window state
-#[derive(Debug, Serialize, Deserialize)]
-pub enum WindowMessage {
-    // Window lifecycle
-    WindowCreated { 
-        id: u32, 
-        title: String,
-        width: u32,
-        height: u32,
-        x: i32,
-        y: i32,
-        app_name: String,
-    },
-    WindowDestroyed { 
-        id: u32 
-    },
-    WindowMoved { 
-        id: u32, 
-        x: i32, 
-        y: i32 
-    },
-    WindowResized { 
-        id: u32, 
-        width: u32, 
-        height: u32 
-    },
-    WindowTitleChanged { 
-        id: u32, 
-        title: String 
-    },
-    WindowFocusChanged { 
-        id: u32, 
-        focused: bool 
-    },
-    
-    // Application lifecycle
-    ApplicationStarted { 
-        app_name: String,
-        pid: u32,
-    },
-    ApplicationStopped { 
-        app_name: String,
-        pid: u32,
-    },
-}
+// Shared with the host, which uses parts of the protocol the agent doesn't
+#[allow(dead_code)]
+mod protocol;
+#[allow(dead_code)]
+mod tls;
+
+use protocol::{clipboard_changed, clipboard_contents, pick_clipboard_type, AppEntry, ClipboardContents, ClipboardMessage, LogLevel, PointerAction, WindowMessage, HEARTBEAT_TIMEOUT, MAX_FILE_TRANSFER_SIZE, PING_INTERVAL, PROTOCOL_VERSION};
+
+/// Local socket on which `guest-agent open-url <url>` hands links to the running agent
+const OPEN_URL_SOCKET: &str = "/tmp/guest-agent-open-url.sock";
+
+/// Local socket on which `guest-agent notify` hands notifications to the running agent
+const NOTIFY_SOCKET: &str = "/tmp/guest-agent-notify.sock";
+
+/// Longest notification summary/body forwarded to the host, in characters
+const MAX_NOTIFICATION_TEXT: usize = 1024;
+
+/// Default minimum time between geometry updates for a window being dragged/resized
+const DEFAULT_DEBOUNCE_MS: u64 = 1000;
+
+/// How long a window may take to close (e.g. while asking to save) before the host is told
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// First wait before reconnecting to the host after the connection drops
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest wait between reconnection attempts
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
 
 /// Tracks application windows in the VM
 pub struct GuestAgent {
-    host_socket: UnixStream,
+    endpoint: String,
+    tls: Option<Arc<tls::Identity>>,  // Authenticates TCP connections to the host
+    host_socket: HostStream,
+    host_link: HostLink,  // Shared with the threads that outlive a connection
     windows: HashMap<u32, WindowInfo>,
+    geometry: GeometryDebouncer,
+    log_level: LogLevel,
+    tools: WindowTools,
+    stacking: Vec<u32>,
+    xwininfo_failed: bool,
+    bad_window_ids: HashSet<String>,  // Already reported, so each is logged once
     next_window_id: u32,
+    clipboard: Option<GuestClipboard>,  // Set when the host syncs the clipboard
+}
+
+/// Window listing tools installed in the guest
+#[derive(Debug, Clone, Copy)]
+struct WindowTools {
+    xwininfo: bool,
+    wmctrl: bool,
+    xprop: bool,
+}
+
+impl WindowTools {
+    fn detect() -> Self {
+        Self {
+            xwininfo: find_in_path("xwininfo"),
+            wmctrl: find_in_path("wmctrl"),
+            xprop: find_in_path("xprop"),
+        }
+    }
+}
+
+/// Parse an X11 window ID as printed by xwininfo/wmctrl ("0x1c00001", or zero-padded
+/// like "0x01c00001"). XIDs are 29-bit by protocol, so `u32` always holds a valid
+/// one and anything wider is rejected instead of truncated.
+fn parse_window_id(id: &str) -> Result<u32, String> {
+    let hex = id.strip_prefix("0x")
+        .or_else(|| id.strip_prefix("0X"))
+        .ok_or_else(|| format!("'{}' is not a hex window ID", id))?;
+    if hex.is_empty() {
+        return Err(format!("'{}' is not a hex window ID", id));
+    }
+    
+    let significant = hex.trim_start_matches('0');
+    if significant.len() > 8 {
+        return Err(format!("window ID {} does not fit in 32 bits", id));
+    }
+    if significant.is_empty() {
+        return if hex.chars().all(|c| c == '0') { Ok(0) } else { Err(format!("'{}' is not a hex window ID", id)) };
+    }
+    u32::from_str_radix(significant, 16).map_err(|_| format!("'{}' is not a hex window ID", id))
+}
+
+/// A launchable application and the command that starts it
+#[derive(Debug, Clone)]
+struct InstalledApp {
+    entry: AppEntry,
+    exec: String,
+}
+
+/// Directories holding desktop entries, later ones overriding earlier ones
+fn application_dirs() -> Vec<PathBuf> {
+    let mut dirs = vec![
+        PathBuf::from("/usr/share/applications"),
+        PathBuf::from("/var/lib/flatpak/exports/share/applications"),
+    ];
+    if let Ok(home) = std::env::var("HOME") {
+        dirs.push(PathBuf::from(&home).join(".local/share/flatpak/exports/share/applications"));
+        dirs.push(PathBuf::from(&home).join(".local/share/applications"));
+    }
+    dirs
+}
+
+/// Installed applications from desktop entries and `flatpak list`, sorted by name
+fn discover_applications() -> Vec<InstalledApp> {
+    let mut apps: HashMap<String, InstalledApp> = HashMap::new();
+    
+    for dir in application_dirs() {
+        let Ok(entries) = fs::read_dir(&dir) else { continue };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Some(id) = path.file_name().and_then(|n| n.to_str()).and_then(|n| n.strip_suffix(".desktop")) else {
+                continue;
+            };
+            if let Some(app) = fs::read_to_string(&path).ok().and_then(|content| parse_desktop_entry(id, &content)) {
+                apps.insert(app.entry.id.clone(), app);
+            }
+        }
+    }
+    
+    // Flatpaks whose desktop files aren't exported yet
+    if let Ok(output) = Command::new("flatpak").args(["list", "--app", "--columns=application,name"]).output() {
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            let mut columns = line.split('\t');
+            if let (Some(id), Some(name)) = (columns.next(), columns.next()) {
+                apps.entry(id.to_string()).or_insert_with(|| InstalledApp {
+                    entry: AppEntry { id: id.to_string(), name: name.to_string(), icon: Some(id.to_string()) },
+                    exec: format!("flatpak run {}", id),
+                });
+            }
+        }
+    }
+    
+    let mut apps: Vec<InstalledApp> = apps.into_values().collect();
+    apps.sort_by_key(|app| app.entry.name.to_lowercase());
+    apps
+}
+
+/// Parse the `[Desktop Entry]` group of a .desktop file, skipping hidden and non-application entries
+fn parse_desktop_entry(id: &str, content: &str) -> Option<InstalledApp> {
+    let mut in_entry = false;
+    let (mut name, mut exec, mut icon) = (None, None, None);
+    
+    for line in content.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_entry = line == "[Desktop Entry]";
+            continue;
+        }
+        if !in_entry {
+            continue;
+        }
+        
+        let Some((key, value)) = line.split_once('=') else { continue };
+        match (key.trim(), value.trim()) {
+            ("Type", kind) if kind != "Application" => return None,
+            ("NoDisplay" | "Hidden", "true") => return None,
+            ("Name", value) => name = Some(value.to_string()),
+            ("Exec", value) => exec = Some(value.to_string()),
+            ("Icon", value) => icon = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    
+    Some(InstalledApp {
+        entry: AppEntry { id: id.to_string(), name: name?, icon },
+        exec: exec?,
+    })
+}
+
+/// Look an application up by desktop file ID, falling back to a case-insensitive name match
+fn find_application<'a>(apps: &'a [InstalledApp], query: &str) -> Option<&'a InstalledApp> {
+    apps.iter()
+        .find(|app| app.entry.id == query)
+        .or_else(|| apps.iter().find(|app| app.entry.name.eq_ignore_ascii_case(query)))
+}
+
+/// Split a desktop entry `Exec` line into arguments, dropping `%f`-style field codes
+fn exec_argv(exec: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut has_arg = false;
+    let mut chars = exec.chars();
+    
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                has_arg = true;
+            }
+            '\\' if in_quotes => {
+                if let Some(escaped) = chars.next() {
+                    current.push(escaped);
+                }
+            }
+            c if c.is_whitespace() && !in_quotes => {
+                if has_arg {
+                    args.push(std::mem::take(&mut current));
+                    has_arg = false;
+                }
+            }
+            c => {
+                current.push(c);
+                has_arg = true;
+            }
+        }
+    }
+    if has_arg {
+        args.push(current);
+    }
+    
+    args.into_iter()
+        .filter(|arg| !(arg.len() == 2 && arg.starts_with('%')))
+        .map(|arg| arg.replace("%%", "%"))
+        .collect()
+}
+
+/// Window IDs from `xprop -root _NET_CLIENT_LIST_STACKING`, bottom to top.
+///
+/// Format: "_NET_CLIENT_LIST_STACKING(WINDOW): window id # 0x1c00001, 0x2000003"
+fn parse_client_list_stacking(output: &str) -> Vec<u32> {
+    output.split_once('#')
+        .map(|(_, ids)| ids)
+        .unwrap_or("")
+        .split(',')
+        .filter_map(|id| parse_window_id(id.trim()).ok())
+        .collect()
+}
+
+/// App name from a window's `WM_CLASS` (instance, class): the lowercased class,
+/// which stays the same across title changes ("Navigator" "firefox" → "firefox"),
+/// falling back to the instance
+fn app_name_from_wm_class(names: &[&str]) -> String {
+    names.iter()
+        .rev()
+        .map(|name| name.trim())
+        .find(|name| !name.is_empty())
+        .map(|name| name.to_lowercase())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// PID from `xprop -id <window> _NET_WM_PID`.
+///
+/// Format: "_NET_WM_PID(CARDINAL) = 1234"
+fn parse_net_wm_pid(output: &str) -> Option<u32> {
+    output.split_once('=')?.1.trim().parse().ok().filter(|pid| *pid != 0)
+}
+
+fn find_in_path(tool: &str) -> bool {
+    std::env::var_os("PATH")
+        .map(|path| std::env::split_paths(&path).any(|dir| dir.join(tool).is_file()))
+        .unwrap_or(false)
 }
 
 #[derive(Debug, Clone)]
@@ -73,105 +279,371 @@ struct WindowInfo {
 }
 
 impl GuestAgent {
-    pub fn new(socket_path: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let host_socket = UnixStream::connect(socket_path)?;
+    pub fn new(socket_path: &str, tls: Option<tls::Identity>, debounce: Duration, log_level: LogLevel, share_clipboard: bool) -> Result<Self, Box<dyn std::error::Error>> {
+        let tls = tls.map(Arc::new);
+        let host_socket = HostStream::connect(socket_path, tls.as_deref())?;
+        let host_link = HostLink::new(host_socket.try_clone()?);
         
         Ok(Self {
+            endpoint: socket_path.to_string(),
+            tls,
             host_socket,
+            host_link,
             windows: HashMap::new(),
+            geometry: GeometryDebouncer::new(debounce),
+            log_level,
+            tools: WindowTools::detect(),
+            stacking: Vec::new(),
+            xwininfo_failed: false,
+            bad_window_ids: HashSet::new(),
             next_window_id: 1,
+            clipboard: share_clipboard.then(GuestClipboard::new),
         })
     }
     
+    /// Serve the host until the agent is killed. When the connection drops (the host
+    /// proxy restarted, say), reconnect with backoff and re-announce everything.
     pub fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         println!("🪟 Guest Agent started - monitoring application windows");
         
         // Start monitoring processes
-        let socket_clone = self.host_socket.try_clone()?;
+        let link = self.host_link.clone();
+        thread::spawn(move || {
+            Self::monitor_processes(link);
+        });
+        
+        // Send what's copied here to the host
+        if let Some(clipboard) = self.clipboard.clone() {
+            let link = self.host_link.clone();
+            let log_level = self.log_level;
+            thread::spawn(move || {
+                clipboard.monitor(link, log_level);
+            });
+        }
+        
+        // Forward links from the xdg-open handler to the host browser
+        let listener = {
+            let _ = fs::remove_file(OPEN_URL_SOCKET);
+            UnixListener::bind(OPEN_URL_SOCKET)
+        };
+        match listener {
+            Ok(listener) => {
+                let link = self.host_link.clone();
+                thread::spawn(move || {
+                    Self::forward_open_url_requests(listener, link);
+                });
+            }
+            Err(e) => self.log(LogLevel::Warn, format!("Open-URL bridge unavailable ({}): {}", OPEN_URL_SOCKET, e))?,
+        }
+        
+        // Forward notifications handed over by dunst's script hook
+        let listener = {
+            let _ = fs::remove_file(NOTIFY_SOCKET);
+            UnixListener::bind(NOTIFY_SOCKET)
+        };
+        match listener {
+            Ok(listener) => {
+                let link = self.host_link.clone();
+                thread::spawn(move || {
+                    Self::forward_notifications(listener, link);
+                });
+            }
+            Err(e) => self.log(LogLevel::Warn, format!("Notification bridge unavailable ({}): {}", NOTIFY_SOCKET, e))?,
+        }
+        
+        loop {
+            match self.serve_host() {
+                Ok(never) => match never {},
+                Err(e) => eprintln!("🔌 Lost connection to host: {}", e),
+            }
+            self.reconnect();
+        }
+    }
+    
+    /// Announce the agent on the current connection, then report windows until
+    /// sending fails or the host hangs up
+    fn serve_host(&mut self) -> Result<std::convert::Infallible, Box<dyn std::error::Error>> {
+        // Announce our protocol version so the host can reject mismatches
+        Self::send_message(&mut self.host_socket, &WindowMessage::ProtocolHello { version: PROTOCOL_VERSION })?;
+        
+        // Tell the host what it can launch here
+        let apps: Vec<AppEntry> = discover_applications().into_iter().map(|app| app.entry).collect();
+        self.log(LogLevel::Debug, format!("Reporting {} installed applications", apps.len()))?;
+        Self::send_message(&mut self.host_socket, &WindowMessage::ApplicationList { apps })?;
+        
+        // Start out with the host's clipboard; later changes are pushed as they happen
+        if self.clipboard.is_some() {
+            Self::send_message(&mut self.host_socket, &WindowMessage::Clipboard(ClipboardMessage::GetClipboard))?;
+        }
+        
+        // Without either tool the agent can't see any windows, so say so loudly
+        match (self.tools.xwininfo, self.tools.wmctrl) {
+            (true, _) => self.log(LogLevel::Info, "Scanning windows with xwininfo")?,
+            (false, true) => self.log(LogLevel::Warn, "xwininfo not found, scanning windows with wmctrl")?,
+            (false, false) => self.log(LogLevel::Error, 
+                "Neither xwininfo nor wmctrl is installed; no windows will be reported. Install them with: sudo dnf install xwininfo wmctrl")?,
+        }
+        
+        // Handle messages sent by the host: files, app launches, input, clipboard and password changes
+        let reader = self.host_socket.try_clone()?;
+        let writer = self.host_socket.try_clone()?;
+        let log_level = self.log_level;
+        let closed = Arc::new(AtomicBool::new(false));
+        let host_closed = closed.clone();
+        let last_pong = Arc::new(Mutex::new(Instant::now()));
+        let pong = last_pong.clone();
+        let clipboard = self.clipboard.clone();
         thread::spawn(move || {
-            Self::monitor_processes(socket_clone);
+            Self::handle_host_messages(reader, writer, log_level, pong, clipboard);
+            host_closed.store(true, Ordering::Relaxed);
         });
         
+        // After a reconnect the host starts from nothing, so rebuild its view
+        let mut windows: Vec<WindowInfo> = self.windows.values().cloned().collect();
+        windows.sort_by_key(|window| window.id);
+        for window in &windows {
+            self.send_window_created(window)?;
+        }
+        self.stacking.clear();
+        
         // Main loop - monitor X11 windows (applications run in Xwayland)
+        let mut heartbeat = Heartbeat::new(Instant::now());
         loop {
-            self.scan_windows()?;
+            if closed.load(Ordering::Relaxed) {
+                return Err("host closed the connection".into());
+            }
+            if let Some(seq) = heartbeat.due(Instant::now()) {
+                if *last_pong.lock().unwrap() + HEARTBEAT_TIMEOUT < Instant::now() {
+                    return Err(format!("no heartbeat answer from the host for {}s", HEARTBEAT_TIMEOUT.as_secs()).into());
+                }
+                Self::send_message(&mut self.host_socket, &WindowMessage::Ping { seq })?;
+            }
+            if let Err(e) = self.scan_windows() {
+                // Report and keep going; if even the report fails the host is gone
+                self.log(LogLevel::Error, format!("Window scan failed: {}", e))?;
+            }
+            if let Err(e) = self.update_stacking() {
+                self.log(LogLevel::Debug, format!("Stacking order unavailable: {}", e))?;
+            }
             thread::sleep(Duration::from_millis(500));
         }
     }
     
+    /// Connect to the host again, waiting longer after each failed attempt
+    fn reconnect(&mut self) {
+        // Make sure the old connection's reader thread stops
+        self.host_socket.shutdown();
+        
+        let mut delay = RECONNECT_MIN_DELAY;
+        loop {
+            thread::sleep(delay);
+            match HostStream::connect(&self.endpoint, self.tls.as_deref()).and_then(|stream| Ok((stream.try_clone()?, stream))) {
+                Ok((shared, stream)) => {
+                    println!("🔌 Reconnected to host at {}", self.endpoint);
+                    self.host_socket = stream;
+                    self.host_link.replace(shared);
+                    return;
+                }
+                Err(e) => {
+                    delay = next_reconnect_delay(delay);
+                    eprintln!("Reconnecting to {} failed: {} (next attempt in {}s)", self.endpoint, e, delay.as_secs());
+                }
+            }
+        }
+    }
+    
     fn scan_windows(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.tools.xwininfo {
+            return self.scan_windows_wmctrl();
+        }
+        
         // Use xwininfo to get window list
         let output = Command::new("xwininfo")
             .args(&["-root", "-tree"])
             .output();
             
         let window_list = match output {
-            Ok(out) if out.status.success() => String::from_utf8_lossy(&out.stdout).to_string(),
-            _ => {
+            Ok(out) if out.status.success() => {
+                if self.xwininfo_failed {
+                    self.xwininfo_failed = false;
+                    self.log(LogLevel::Info, "xwininfo is working again")?;
+                }
+                String::from_utf8_lossy(&out.stdout).to_string()
+            }
+            failed => {
+                // Only report the transition, not every scan
+                if !self.xwininfo_failed {
+                    self.xwininfo_failed = true;
+                    let reason = match failed {
+                        Ok(out) => String::from_utf8_lossy(&out.stderr).trim().to_string(),
+                        Err(e) => e.to_string(),
+                    };
+                    let fallback = if self.tools.wmctrl { "falling back to wmctrl" } else { "and wmctrl is not installed" };
+                    self.log(LogLevel::Warn, format!("xwininfo failed ({}), {}", reason, fallback))?;
+                }
+                
                 // Fallback: try wmctrl if available
                 return self.scan_windows_wmctrl();
             }
         };
         
-        let current_windows = self.parse_xwininfo_output(&window_list)?;
+        let (current_windows, bad_ids) = Self::parse_xwininfo_output(&window_list);
+        self.report_bad_window_ids(bad_ids)?;
+        self.apply_scan(&current_windows)
+    }
+    
+    /// Tell the host which windows appeared, closed or changed since the last scan
+    fn apply_scan(&mut self, current_windows: &[WindowInfo]) -> Result<(), Box<dyn std::error::Error>> {
+        let (new_windows, closed_windows) = window_changes(&self.windows, current_windows);
         
-        // Detect new windows
-        for window in &current_windows {
-            if !self.windows.contains_key(&window.id) {
-                println!("📱 New window detected: {} ({})", window.title, window.app_name);
-                self.send_window_created(&window)?;
-                self.windows.insert(window.id, window.clone());
-            }
+        for mut window in new_windows {
+            self.resolve_owner(&mut window);
+            println!("📱 New window detected: {} ({}, pid {})", window.title, window.app_name, window.pid);
+            self.send_window_created(&window)?;
+            self.geometry.track(window.id, Geometry::of(&window));
+            self.windows.insert(window.id, window);
         }
         
-        // Detect closed windows
-        let current_ids: Vec<u32> = current_windows.iter().map(|w| w.id).collect();
-        let closed_windows: Vec<u32> = self.windows.keys()
-            .filter(|id| !current_ids.contains(id))
-            .cloned()
-            .collect();
-            
         for window_id in closed_windows {
             println!("🗑️  Window closed: {}", window_id);
             self.send_window_destroyed(window_id)?;
+            self.geometry.forget(window_id);
             self.windows.remove(&window_id);
         }
         
         // Detect window changes (title, size, position)
-        for current_window in &current_windows {
+        let now = Instant::now();
+        for current_window in current_windows {
             let needs_update = if let Some(old_window) = self.windows.get(&current_window.id) {
                 let title_changed = old_window.title != current_window.title;
-                let size_changed = old_window.width != current_window.width || old_window.height != current_window.height;
-                let pos_changed = old_window.x != current_window.x || old_window.y != current_window.y;
+                let geometry_changed = Geometry::of(old_window) != Geometry::of(current_window);
                 
                 // Send change notifications
                 if title_changed {
                     self.send_window_title_changed(current_window.id, &current_window.title)?;
                 }
-                if size_changed {
-                    self.send_window_resized(current_window.id, current_window.width, current_window.height)?;
-                }
-                if pos_changed {
-                    self.send_window_moved(current_window.id, current_window.x, current_window.y)?;
+                
+                // Geometry updates are coalesced while a window is being dragged/resized
+                let update = self.geometry.update(current_window.id, Geometry::of(current_window), geometry_changed, now);
+                if let Some((previous, next)) = update {
+                    if previous.width != next.width || previous.height != next.height {
+                        self.send_window_resized(current_window.id, next.width, next.height)?;
+                    }
+                    if previous.x != next.x || previous.y != next.y {
+                        self.send_window_moved(current_window.id, next.x, next.y)?;
+                    }
                 }
                 
-                title_changed || size_changed || pos_changed
+                title_changed || geometry_changed
             } else {
                 false
             };
             
-            // Update stored window info if there were changes
+            // Update stored window info if there were changes, keeping the owner resolved on creation
             if needs_update {
-                self.windows.insert(current_window.id, current_window.clone());
+                if let Some(window) = self.windows.get_mut(&current_window.id) {
+                    let (pid, app_name) = (window.pid, std::mem::take(&mut window.app_name));
+                    *window = WindowInfo { pid, app_name, ..current_window.clone() };
+                }
+            }
+        }
+        
+        Ok(())
+    }
+    
+    fn forward_open_url_requests(listener: UnixListener, link: HostLink) {
+        for stream in listener.incoming().flatten() {
+            let mut url = String::new();
+            if (&stream).take(8192).read_to_string(&mut url).is_err() {
+                continue;
+            }
+            
+            // The host validates again; this just avoids forwarding obvious junk
+            let url = url.trim().to_string();
+            if !(url.starts_with("http://") || url.starts_with("https://")) {
+                eprintln!("Refusing to forward non-http(s) URL: {}", url);
+                continue;
+            }
+            
+            if let Err(e) = link.send(&WindowMessage::OpenUrl { url }) {
+                eprintln!("Failed to forward URL to host: {}", e);
+            }
+        }
+    }
+    
+    fn forward_notifications(listener: UnixListener, link: HostLink) {
+        for stream in listener.incoming().flatten() {
+            let mut data = Vec::new();
+            if (&stream).take(64 * 1024).read_to_end(&mut data).is_err() {
+                continue;
             }
+            
+            let Some(msg) = parse_notification(&String::from_utf8_lossy(&data)) else {
+                eprintln!("Ignoring malformed notification");
+                continue;
+            };
+            if let Err(e) = link.send(&msg) {
+                eprintln!("Failed to forward notification to host: {}", e);
+            }
+        }
+    }
+    
+    /// Fill in the process owning a new window from its `_NET_WM_PID`, naming the
+    /// window after that process when it has no `WM_CLASS`
+    fn resolve_owner(&self, window: &mut WindowInfo) {
+        if !self.tools.xprop {
+            return;
+        }
+        
+        let output = Command::new("xprop")
+            .args(["-id", &format!("0x{:x}", window.id), "_NET_WM_PID"])
+            .output();
+        let Some(pid) = output.ok()
+            .filter(|out| out.status.success())
+            .and_then(|out| parse_net_wm_pid(&String::from_utf8_lossy(&out.stdout)))
+        else {
+            return;
+        };
+        
+        window.pid = pid;
+        if window.app_name == "unknown" {
+            window.app_name = Self::process_name(pid);
+        }
+    }
+    
+    /// Send the z-order of tracked windows whenever it changes
+    fn update_stacking(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.tools.xprop {
+            return Ok(());
+        }
+        
+        let output = Command::new("xprop")
+            .args(["-root", "_NET_CLIENT_LIST_STACKING"])
+            .output()?;
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).trim().to_string().into());
+        }
+        
+        let ids: Vec<u32> = parse_client_list_stacking(&String::from_utf8_lossy(&output.stdout))
+            .into_iter()
+            .filter(|id| self.windows.contains_key(id))
+            .collect();
+        
+        if ids != self.stacking {
+            self.stacking = ids.clone();
+            Self::send_message(&mut self.host_socket, &WindowMessage::StackingOrder { ids })?;
         }
         
         Ok(())
     }
     
     fn scan_windows_wmctrl(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.tools.wmctrl {
+            return Ok(()); // Already reported at startup
+        }
+        
         let output = Command::new("wmctrl")
-            .args(&["-l", "-G"])
+            .args(&["-l", "-G", "-x"])
             .output()?;
             
         if !output.status.success() {
@@ -179,16 +651,15 @@ impl GuestAgent {
         }
         
         let window_list = String::from_utf8_lossy(&output.stdout);
-        let _current_windows = self.parse_wmctrl_output(&window_list)?;
-        
-        // Same logic as scan_windows for detecting changes
-        // ... (implement similar logic)
-        
-        Ok(())
+        let (current_windows, bad_ids) = Self::parse_wmctrl_output(&window_list);
+        self.report_bad_window_ids(bad_ids)?;
+        self.apply_scan(&current_windows)
     }
     
-    fn parse_xwininfo_output(&self, output: &str) -> Result<Vec<WindowInfo>, Box<dyn std::error::Error>> {
+    /// Parse `xwininfo -root -tree`, returning the named windows and any window IDs that didn't parse
+    fn parse_xwininfo_output(output: &str) -> (Vec<WindowInfo>, Vec<String>) {
         let mut windows = Vec::new();
+        let mut bad_ids = Vec::new();
         
         for line in output.lines() {
             if line.contains("children:") || line.trim().is_empty() {
@@ -197,81 +668,103 @@ impl GuestAgent {
             
             // Parse xwininfo tree output
             // Format: "     0x1c00001 \"LibreWolf\": (\"librewolf\" \"LibreWolf\")  800x600+100+50  +100+50"
-            if let Some(window_info) = self.parse_xwininfo_line(line) {
-                windows.push(window_info);
+            match Self::parse_xwininfo_line(line) {
+                Some(Ok(window_info)) => windows.push(window_info),
+                Some(Err(id)) => bad_ids.push(id),
+                None => {}
             }
         }
         
-        Ok(windows)
+        (windows, bad_ids)
     }
     
-    fn parse_xwininfo_line(&self, line: &str) -> Option<WindowInfo> {
+    /// `None` for lines that aren't named windows with a geometry, `Err` with the
+    /// raw ID for windows whose ID can't be parsed (so they aren't dropped silently)
+    fn parse_xwininfo_line(line: &str) -> Option<Result<WindowInfo, String>> {
         // Extract window ID, title, dimensions and position from xwininfo format
-        if let Some(id_start) = line.find("0x") {
-            if let Some(id_end) = line[id_start..].find(' ') {
-                let id_str = &line[id_start..id_start + id_end];
-                if let Ok(id) = u32::from_str_radix(&id_str[2..], 16) {
-                    
-                    // Extract title
-                    if let Some(title_start) = line.find('"') {
-                        if let Some(title_end) = line[title_start + 1..].find('"') {
-                            let title = line[title_start + 1..title_start + 1 + title_end].to_string();
-                            
-                            // Extract dimensions and position
-                            if let Some(geom_match) = self.extract_geometry(line) {
-                                return Some(WindowInfo {
-                                    id,
-                                    title: title.clone(),
-                                    width: geom_match.width,
-                                    height: geom_match.height,
-                                    x: geom_match.x,
-                                    y: geom_match.y,
-                                    app_name: self.get_app_name_from_title(&title),
-                                    pid: 0, // Will be filled later if needed
-                                });
-                            }
-                        }
-                    }
-                }
-            }
-        }
-        None
+        let id_start = line.find("0x")?;
+        let id_str = line[id_start..].split_whitespace().next()?;
+        
+        // Extract title; unnamed windows print "(has no name)" without quotes
+        let title_start = line.find('"')?;
+        let title_end = line[title_start + 1..].find('"')?;
+        let title = line[title_start + 1..title_start + 1 + title_end].to_string();
+        
+        // WM_CLASS follows the title: ("instance" "Class")
+        let wm_class = line[title_start + title_end + 2..]
+            .split_once('(')
+            .and_then(|(_, rest)| rest.split_once(')'))
+            .map(|(class, _)| class)
+            .unwrap_or("");
+        let class_names: Vec<&str> = wm_class.split('"').skip(1).step_by(2).collect();
+        
+        // Extract dimensions and position
+        let geom_match = Self::extract_geometry(line)?;
+        
+        let Ok(id) = parse_window_id(id_str) else {
+            return Some(Err(id_str.to_string()));
+        };
+        Some(Ok(WindowInfo {
+            id,
+            title: title.clone(),
+            width: geom_match.width,
+            height: geom_match.height,
+            x: geom_match.x,
+            y: geom_match.y,
+            app_name: app_name_from_wm_class(&class_names),
+            pid: 0, // Will be filled later if needed
+        }))
     }
     
-    fn parse_wmctrl_output(&self, output: &str) -> Result<Vec<WindowInfo>, Box<dyn std::error::Error>> {
+    /// Parse `wmctrl -l -G -x`, returning the windows and any window IDs that didn't parse
+    fn parse_wmctrl_output(output: &str) -> (Vec<WindowInfo>, Vec<String>) {
         let mut windows = Vec::new();
+        let mut bad_ids = Vec::new();
         
         for line in output.lines() {
-            // wmctrl format: "0x01c00001  0 100 50 800 600 hostname LibreWolf"
+            // wmctrl format: "0x01c00001  0 100 50 800 600 Navigator.librewolf hostname LibreWolf"
             let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() >= 7 {
-                if let Ok(id) = u32::from_str_radix(&parts[0][2..], 16) {
-                    if let (Ok(x), Ok(y), Ok(width), Ok(height)) = (
-                        parts[2].parse::<i32>(),
-                        parts[3].parse::<i32>(),
-                        parts[4].parse::<u32>(),
-                        parts[5].parse::<u32>(),
-                    ) {
-                        let title = parts[7..].join(" ");
-                        windows.push(WindowInfo {
-                            id,
-                            title: title.clone(),
-                            width,
-                            height,
-                            x,
-                            y,
-                            app_name: self.get_app_name_from_title(&title),
-                            pid: 0,
-                        });
-                    }
+            if parts.len() >= 8 {
+                let Ok(id) = parse_window_id(parts[0]) else {
+                    bad_ids.push(parts[0].to_string());
+                    continue;
+                };
+                if let (Ok(x), Ok(y), Ok(width), Ok(height)) = (
+                    parts[2].parse::<i32>(),
+                    parts[3].parse::<i32>(),
+                    parts[4].parse::<u32>(),
+                    parts[5].parse::<u32>(),
+                ) {
+                    let title = parts[8..].join(" ");
+                    let class_names: Vec<&str> = parts[6].splitn(2, '.').collect();
+                    windows.push(WindowInfo {
+                        id,
+                        title,
+                        width,
+                        height,
+                        x,
+                        y,
+                        app_name: app_name_from_wm_class(&class_names),
+                        pid: 0,
+                    });
                 }
             }
         }
         
-        Ok(windows)
+        (windows, bad_ids)
+    }
+    
+    /// Warn (once per ID) about windows skipped because their ID didn't parse
+    fn report_bad_window_ids(&mut self, ids: Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
+        for id in ids {
+            if self.bad_window_ids.insert(id.clone()) {
+                self.log(LogLevel::Warn, format!("Skipping window with unparseable ID {}", id))?;
+            }
+        }
+        Ok(())
     }
     
-    fn extract_geometry(&self, line: &str) -> Option<Geometry> {
+    fn extract_geometry(line: &str) -> Option<Geometry> {
         // Look for pattern like "800x600+100+50" or "800x600-100-50"
         use regex::Regex;
         let re = Regex::new(r"(\d+)x(\d+)([\+\-]\d+)([\+\-]\d+)").ok()?;
@@ -288,35 +781,43 @@ impl GuestAgent {
         None
     }
     
-    fn get_app_name_from_title(&self, title: &str) -> String {
-        // Extract application name from window title
-        match title {
-            t if t.contains("LibreWolf") => "librewolf".to_string(),
-            t if t.contains("Firefox") => "firefox".to_string(),
-            t if t.contains("Chromium") => "chromium".to_string(),
-            t if t.contains("LibreOffice") => "libreoffice".to_string(),
-            t if t.contains("Visual Studio Code") => "code".to_string(),
-            _ => "unknown".to_string(),
-        }
-    }
-    
-    fn monitor_processes(mut socket: UnixStream) {
+    fn monitor_processes(link: HostLink) {
+        // PIDs seen on the previous scan, with their resolved app names
+        let mut running: HashMap<u32, String> = HashMap::new();
+        
         loop {
             // Monitor process starts/stops
             let output = Command::new("pgrep")
-                .args(&["-f", "librewolf|firefox|chromium|libreoffice|code"])
+                .args(["-f", "librewolf|firefox|chromium|libreoffice|code"])
                 .output();
                 
             if let Ok(out) = output {
-                let pids = String::from_utf8_lossy(&out.stdout);
-                for pid_str in pids.lines() {
-                    if let Ok(pid) = pid_str.parse::<u32>() {
-                        // Send application started message
+                let pids: Vec<u32> = String::from_utf8_lossy(&out.stdout)
+                    .lines()
+                    .filter_map(|line| line.trim().parse().ok())
+                    .collect();
+                
+                for &pid in &pids {
+                    if let Entry::Vacant(entry) = running.entry(pid) {
+                        let app_name = Self::process_name(pid);
                         let msg = WindowMessage::ApplicationStarted {
-                            app_name: "detected".to_string(),
+                            app_name: app_name.clone(),
                             pid,
                         };
-                        let _ = Self::send_message(&mut socket, &msg);
+                        let _ = link.send(&msg);
+                        entry.insert(app_name);
+                    }
+                }
+                
+                // Anything we saw before that is no longer running has exited
+                let stopped: Vec<u32> = running.keys()
+                    .filter(|pid| !pids.contains(pid))
+                    .cloned()
+                    .collect();
+                for pid in stopped {
+                    if let Some(app_name) = running.remove(&pid) {
+                        let msg = WindowMessage::ApplicationStopped { app_name, pid };
+                        let _ = link.send(&msg);
                     }
                 }
             }
@@ -325,6 +826,214 @@ impl GuestAgent {
         }
     }
     
+    /// Resolve a process's name from /proc (comm, falling back to argv[0])
+    fn process_name(pid: u32) -> String {
+        if let Ok(comm) = fs::read_to_string(format!("/proc/{}/comm", pid)) {
+            let comm = comm.trim();
+            if !comm.is_empty() {
+                return comm.to_string();
+            }
+        }
+        
+        fs::read(format!("/proc/{}/cmdline", pid))
+            .ok()
+            .and_then(|cmdline| {
+                let argv0 = cmdline.split(|b| *b == 0).next()?.to_vec();
+                let argv0 = String::from_utf8(argv0).ok()?;
+                Path::new(&argv0).file_name()?.to_str().map(|s| s.to_string())
+            })
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| "unknown".to_string())
+    }
+    
+    fn handle_host_messages(mut socket: HostStream, mut writer: HostStream, log_level: LogLevel, last_pong: Arc<Mutex<Instant>>, clipboard: Option<GuestClipboard>) {
+        let mut receiver = FileReceiver::new();
+        let input = match writer.try_clone() {
+            Ok(log) => Self::spawn_input_injector(log, log_level),
+            Err(e) => {
+                eprintln!("Failed to clone host connection: {}", e);
+                return;
+            }
+        };
+        
+        loop {
+            let Ok(data) = protocol::read_frame(&mut socket) else {
+                println!("🔌 Host connection closed");
+                return;
+            };
+            
+            let result = match bincode::deserialize::<WindowMessage>(&data) {
+                Ok(WindowMessage::FileTransferStart { transfer_id, file_name, size, sha256, destination }) => {
+                    receiver.start(transfer_id, &file_name, destination.as_deref(), size, sha256)
+                        .map_err(|e| format!("Rejected file '{}': {}", file_name, e))
+                        .map(|_| None)
+                }
+                Ok(WindowMessage::FileTransferChunk { transfer_id, data }) => {
+                    receiver.chunk(transfer_id, &data)
+                        .map_err(|e| format!("File transfer {} failed: {}", transfer_id, e))
+                        .map(|_| None)
+                }
+                Ok(WindowMessage::FileTransferEnd { transfer_id }) => {
+                    receiver.finish(transfer_id)
+                        .map_err(|e| format!("File transfer {} failed: {}", transfer_id, e))
+                        .map(|path| Some(format!("Received file: {}", path.display())))
+                }
+                Ok(WindowMessage::OpenApp { app_id }) => {
+                    Self::launch_application(&app_id, &mut writer)
+                        .map_err(|e| format!("Failed to launch '{}': {}", app_id, e))
+                        .map(Some)
+                }
+                Ok(WindowMessage::ChangePassword { new }) => {
+                    let result = change_password(&new).map_err(|e| e.to_string());
+                    let _ = Self::send_message(&mut writer, &WindowMessage::PasswordChanged { error: result.clone().err() });
+                    result
+                        .map_err(|e| format!("Failed to change password: {}", e))
+                        .map(|_| Some("Password changed".to_string()))
+                }
+                Ok(WindowMessage::Pong { .. }) => {
+                    *last_pong.lock().unwrap() = Instant::now();
+                    Ok(None)
+                }
+                Ok(WindowMessage::CloseRequested { id }) => {
+                    Self::close_window(id, &writer, log_level)
+                        .map_err(|e| format!("Failed to close window 0x{:08x}: {}", id, e))
+                        .map(Some)
+                }
+                Ok(msg @ (WindowMessage::KeyEvent { .. } | WindowMessage::PointerEvent { .. })) => {
+                    let _ = input.send(msg);
+                    Ok(None)
+                }
+                Ok(WindowMessage::Clipboard(msg)) => match (&clipboard, msg) {
+                    (Some(clipboard), ClipboardMessage::ClipboardContent(text)) => clipboard.set(ClipboardContents::Text(text)),
+                    (Some(clipboard), ClipboardMessage::ClipboardData { mime_type, data }) => {
+                        clipboard.set(ClipboardContents::Data { mime_type, data })
+                    }
+                    (Some(_), _) => Ok(None),
+                    (None, _) => Err("Clipboard sync is not enabled in this guest (GUEST_AGENT_CLIPBOARD)".to_string()),
+                },
+                Ok(other) => Ok(Some(format!("Ignoring unexpected host message: {:?}", other))),
+                Err(e) => Err(format!("Failed to decode host message: {}", e)),
+            };
+            
+            let _ = match result {
+                Ok(Some(info)) => Self::send_log(&mut writer, log_level, LogLevel::Info, info),
+                Ok(None) => Ok(()),
+                Err(error) => Self::send_log(&mut writer, log_level, LogLevel::Error, error),
+            };
+        }
+    }
+    
+    /// Launch a known application and report it to the host. Only entries from
+    /// the discovered application list can be started, never arbitrary commands.
+    fn launch_application(app_id: &str, writer: &mut HostStream) -> Result<String, Box<dyn std::error::Error>> {
+        let apps = discover_applications();
+        let app = find_application(&apps, app_id).ok_or("not an installed application")?;
+        
+        let argv = exec_argv(&app.exec);
+        let (program, args) = argv.split_first().ok_or("desktop entry has an empty Exec line")?;
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let pid = child.id();
+        
+        // Reap the process when it exits so it doesn't linger as a zombie
+        thread::spawn(move || {
+            let _ = child.wait();
+        });
+        
+        Self::send_message(writer, &WindowMessage::ApplicationStarted {
+            app_name: app.entry.name.clone(),
+            pid,
+        })?;
+        Ok(format!("Launched {} (pid {})", app.entry.name, pid))
+    }
+    
+    /// Inject host input with xdotool (XTEST) on a thread of its own, so a slow
+    /// xdotool doesn't hold up other host messages. Input that queues up while
+    /// xdotool runs goes out together in one chained invocation.
+    fn spawn_input_injector(mut writer: HostStream, log_level: LogLevel) -> mpsc::Sender<WindowMessage> {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let installed = find_in_path("xdotool");
+            let mut warned = false;
+            let mut active = None;
+            while let Ok(first) = rx.recv() {
+                let events: Vec<WindowMessage> = std::iter::once(first).chain(rx.try_iter()).collect();
+                if !installed {
+                    if !warned {
+                        warned = true;
+                        let _ = Self::send_log(&mut writer, log_level, LogLevel::Warn,
+                            "xdotool is not installed; keyboard and mouse input from the host is ignored. \
+                             Install it with: sudo dnf install xdotool".to_string());
+                    }
+                    continue;
+                }
+                
+                let args = xdotool_args(&events, &mut active);
+                if args.is_empty() {
+                    continue;
+                }
+                let error = match Command::new("xdotool").args(&args).stdin(Stdio::null()).stdout(Stdio::null()).output() {
+                    Ok(output) if output.status.success() => continue,
+                    Ok(output) => String::from_utf8_lossy(&output.stderr).trim().to_string(),
+                    Err(e) => e.to_string(),
+                };
+                // The window may be gone or have lost focus; activate again on the next input
+                active = None;
+                let _ = Self::send_log(&mut writer, log_level, LogLevel::Warn, format!("xdotool failed: {}", error));
+            }
+        });
+        tx
+    }
+    
+    /// Close a window the way its close button would (`_NET_CLOSE_WINDOW` via
+    /// `wmctrl -c`), so the app can ask to save first. Never kills the app; if the
+    /// window is still there after `CLOSE_TIMEOUT` the host gets a warning instead.
+    fn close_window(id: u32, writer: &HostStream, log_level: LogLevel) -> Result<String, Box<dyn std::error::Error>> {
+        let window = format!("0x{:08x}", id);
+        let output = Command::new("wmctrl").args(["-i", "-c", &window]).output()?;
+        if !output.status.success() {
+            return Err(format!("wmctrl: {}", String::from_utf8_lossy(&output.stderr).trim()).into());
+        }
+        
+        let mut writer = writer.try_clone()?;
+        thread::spawn(move || {
+            let deadline = Instant::now() + CLOSE_TIMEOUT;
+            while Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(500));
+                if !window_listed(id) {
+                    return;
+                }
+            }
+            let _ = Self::send_log(&mut writer, log_level, LogLevel::Warn, format!(
+                "Window 0x{:08x} is still open {}s after a close request; it may be waiting for an answer (unsaved changes?)",
+                id, CLOSE_TIMEOUT.as_secs()
+            ));
+        });
+        
+        Ok(format!("Asked window {} to close", window))
+    }
+    
+    /// Log locally and forward to the host if `level` passes the verbosity threshold
+    fn log(&mut self, level: LogLevel, message: impl Into<String>) -> Result<(), Box<dyn std::error::Error>> {
+        Self::send_log(&mut self.host_socket, self.log_level, level, message.into())
+    }
+    
+    fn send_log(socket: &mut HostStream, threshold: LogLevel, level: LogLevel, message: String) -> Result<(), Box<dyn std::error::Error>> {
+        match level {
+            LogLevel::Error | LogLevel::Warn => eprintln!("{:?}: {}", level, message),
+            LogLevel::Info | LogLevel::Debug => println!("{:?}: {}", level, message),
+        }
+        
+        if level > threshold {
+            return Ok(());
+        }
+        Self::send_message(socket, &WindowMessage::GuestLog { level, message })
+    }
+    
     // Message sending methods
     fn send_window_created(&mut self, window: &WindowInfo) -> Result<(), Box<dyn std::error::Error>> {
         let msg = WindowMessage::WindowCreated {
@@ -362,19 +1071,523 @@ impl GuestAgent {
         Self::send_message(&mut self.host_socket, &msg)
     }
     
-    fn send_message(socket: &mut UnixStream, msg: &WindowMessage) -> Result<(), Box<dyn std::error::Error>> {
-        let data = bincode::serialize(msg)?;
-        let len = data.len() as u32;
-        
-        // Send length prefix followed by data
-        socket.write_all(&len.to_le_bytes())?;
-        socket.write_all(&data)?;
+    fn send_message(socket: &mut HostStream, msg: &WindowMessage) -> Result<(), Box<dyn std::error::Error>> {
+        protocol::send_message(socket, msg)
+    }
+}
+
+/// Connection to the host window proxy: `vsock:CID:PORT`, `host:port` over TCP,
+/// or a Unix socket path. Vsock and TLS connections are carried in a `UnixStream`,
+/// which only needs plain read/write on the socket.
+enum HostStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl HostStream {
+    fn connect(endpoint: &str, tls: Option<&tls::Identity>) -> std::io::Result<Self> {
+        if let Some((cid, port)) = parse_vsock_endpoint(endpoint) {
+            vsock_connect(cid, port).map(Self::Unix)
+        } else if is_tcp_endpoint(endpoint) {
+            let stream = TcpStream::connect(endpoint)?;
+            match tls {
+                Some(identity) => tls::connect(stream, identity).map(Self::Unix),
+                None => Ok(Self::Tcp(stream)),
+            }
+        } else {
+            UnixStream::connect(endpoint).map(Self::Unix)
+        }
+    }
+    
+    fn try_clone(&self) -> std::io::Result<Self> {
+        match self {
+            Self::Tcp(stream) => stream.try_clone().map(Self::Tcp),
+            Self::Unix(stream) => stream.try_clone().map(Self::Unix),
+        }
+    }
+    
+    /// Close both directions for every clone of the stream
+    fn shutdown(&self) {
+        let _ = match self {
+            Self::Tcp(stream) => stream.shutdown(std::net::Shutdown::Both),
+            Self::Unix(stream) => stream.shutdown(std::net::Shutdown::Both),
+        };
+    }
+}
+
+/// The current host connection for threads that outlive any one connection. Each
+/// message is sent under the lock, so it goes out whole on whichever connection
+/// is current.
+#[derive(Clone)]
+struct HostLink(Arc<Mutex<HostStream>>);
+
+impl HostLink {
+    fn new(stream: HostStream) -> Self {
+        Self(Arc::new(Mutex::new(stream)))
+    }
+    
+    fn send(&self, msg: &WindowMessage) -> Result<(), Box<dyn std::error::Error>> {
+        GuestAgent::send_message(&mut self.0.lock().unwrap(), msg)
+    }
+    
+    fn replace(&self, stream: HostStream) {
+        *self.0.lock().unwrap() = stream;
+    }
+}
+
+/// Paces the agent's pings: one every `PING_INTERVAL`, numbered from 1
+struct Heartbeat {
+    next_at: Instant,
+    seq: u64,
+}
+
+impl Heartbeat {
+    fn new(now: Instant) -> Self {
+        Self { next_at: now, seq: 0 }
+    }
+    
+    /// Sequence number of the ping to send now, if one is due
+    fn due(&mut self, now: Instant) -> Option<u64> {
+        if now < self.next_at {
+            return None;
+        }
+        self.next_at = now + PING_INTERVAL;
+        self.seq += 1;
+        Some(self.seq)
+    }
+}
+
+/// Doubles the wait between reconnection attempts, up to `RECONNECT_MAX_DELAY`
+fn next_reconnect_delay(delay: Duration) -> Duration {
+    (delay * 2).min(RECONNECT_MAX_DELAY)
+}
+
+impl Read for HostStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.read(buf),
+            Self::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for HostStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.write(buf),
+            Self::Unix(stream) => stream.write(buf),
+        }
+    }
+    
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.flush(),
+            Self::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Endpoints are `host:port` for TCP; anything else is a Unix socket path
+fn is_tcp_endpoint(endpoint: &str) -> bool {
+    !endpoint.contains('/') && endpoint.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok())
+}
+
+/// The guest side of clipboard sync (`clipboard_transport = "Custom"`). Apps run
+/// under X11 or Xwayland, so this syncs the X11 clipboard selection with xclip.
+#[derive(Clone)]
+struct GuestClipboard(Arc<Mutex<ClipboardContents>>);
+
+impl GuestClipboard {
+    fn new() -> Self {
+        Self(Arc::new(Mutex::new(ClipboardContents::Text(String::new()))))
+    }
+    
+    /// Send what's copied in the guest to the host, checking once a second
+    fn monitor(self, link: HostLink, log_level: LogLevel) {
+        if !find_in_path("xclip") {
+            let message = "xclip is not installed; the clipboard is not shared with the host. \
+                           Install it with: sudo dnf install xclip".to_string();
+            let _ = GuestAgent::send_log(&mut link.0.lock().unwrap(), log_level, LogLevel::Warn, message);
+            return;
+        }
         
+        loop {
+            thread::sleep(Duration::from_secs(1));
+            // Read and compare under the lock, so a paste from the host is never echoed back
+            let mut known = self.0.lock().unwrap();
+            let Some(contents) = read_clipboard() else {
+                continue;
+            };
+            if let Some(contents) = clipboard_changed(&mut *known, contents) {
+                if let Err(e) = link.send(&WindowMessage::Clipboard(contents.into_message(false))) {
+                    eprintln!("Failed to send the clipboard to the host: {}", e);
+                }
+            }
+        }
+    }
+    
+    /// Take over the host's clipboard
+    fn set(&self, contents: ClipboardContents) -> Result<Option<String>, String> {
+        let mut known = self.0.lock().unwrap();
+        write_clipboard(&contents).map_err(|e| format!("Failed to set the clipboard: {}", e))?;
+        *known = contents;
+        Ok(None)
+    }
+}
+
+/// The clipboard in the representation worth syncing, or `None` when it's empty,
+/// offers nothing usable or is too big to send
+fn read_clipboard() -> Option<ClipboardContents> {
+    let xclip = |target: &str| {
+        Command::new("xclip")
+            .args(["-selection", "clipboard", "-o", "-t", target])
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| output.stdout)
+    };
+    let targets = xclip("TARGETS")?;
+    let target = pick_clipboard_type(String::from_utf8_lossy(&targets).lines())?.to_string();
+    clipboard_contents(&target, xclip(&target)?)
+}
+
+fn write_clipboard(contents: &ClipboardContents) -> Result<(), Box<dyn std::error::Error>> {
+    let (target, bytes) = match contents {
+        ClipboardContents::Text(text) => ("UTF8_STRING", text.as_bytes()),
+        ClipboardContents::Data { mime_type, data } => (mime_type.as_str(), data.as_slice()),
+    };
+    // xclip forks a background process to serve the selection; the one waited for exits once stdin is read
+    let mut child = Command::new("xclip")
+        .args(["-selection", "clipboard", "-i", "-t", target])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    child.stdin.take().ok_or("xclip has no stdin")?.write_all(bytes)?;
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(format!("xclip exited with {}", status).into());
+    }
+    Ok(())
+}
+
+/// Set the user's password with `chpasswd`. The agent runs as the user, so
+/// root comes from the passwordless sudo the kickstart grants it.
+fn change_password(new: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if new.is_empty() || new.chars().any(char::is_control) {
+        return Err("password is empty or contains control characters".into());
+    }
+    
+    let mut child = Command::new("sudo")
+        .args(["-n", "chpasswd"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(chpasswd_input(new).as_bytes())?;
+    }
+    
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string().into());
+    }
+    Ok(())
+}
+
+/// What `chpasswd` reads on stdin: the one `user:password` line
+fn chpasswd_input(new: &str) -> String {
+    format!("user:{}\n", new)
+}
+
+/// One chained xdotool command line for a batch of host input. A window is
+/// activated before it first gets input, since XTEST events go to the focused
+/// window; `active` remembers which one that is. Of consecutive pointer
+/// motions over a window only the last is kept.
+fn xdotool_args(events: &[WindowMessage], active: &mut Option<u32>) -> Vec<String> {
+    let mut args = Vec::new();
+    for (i, event) in events.iter().enumerate() {
+        let (id, command) = match *event {
+            WindowMessage::KeyEvent { id, key, pressed } => {
+                (id, vec![if pressed { "keydown" } else { "keyup" }.to_string(), x11_key(key)])
+            }
+            WindowMessage::PointerEvent { id, action: PointerAction::Motion { x, y } } => {
+                let superseded = matches!(events.get(i + 1),
+                    Some(WindowMessage::PointerEvent { id: next, action: PointerAction::Motion { .. } }) if *next == id);
+                if superseded {
+                    continue;
+                }
+                let window = format!("0x{:08x}", id);
+                (id, vec!["mousemove".to_string(), "--window".to_string(), window, x.to_string(), y.to_string()])
+            }
+            WindowMessage::PointerEvent { id, action: PointerAction::Button { button, pressed } } => {
+                let Some(button) = x11_button(button) else {
+                    continue;
+                };
+                (id, vec![if pressed { "mousedown" } else { "mouseup" }.to_string(), button.to_string()])
+            }
+            WindowMessage::PointerEvent { id, action: PointerAction::Scroll { dx, dy } } => {
+                // Wheel steps are clicks of buttons 4/5 (up/down) and 6/7 (left/right)
+                let mut clicks = Vec::new();
+                for (steps, back, forward) in [(dy, 4, 5), (dx, 6, 7)] {
+                    if steps != 0 {
+                        let button = if steps < 0 { back } else { forward };
+                        clicks.extend(["click", "--repeat", &steps.unsigned_abs().to_string(), "--delay", "0", &button.to_string()]
+                            .map(String::from));
+                    }
+                }
+                (id, clicks)
+            }
+            _ => continue,
+        };
+        if *active != Some(id) {
+            args.extend(["windowactivate".to_string(), "--sync".to_string(), format!("0x{:08x}", id)]);
+            *active = Some(id);
+        }
+        args.extend(command);
+    }
+    args
+}
+
+/// An evdev key code as xdotool takes it: the X key code, which is 8 higher
+fn x11_key(key: u32) -> String {
+    match key {
+        // X key code 9 would be read as the keysym for the digit 9
+        1 => "Escape".to_string(),
+        _ => (key + 8).to_string(),
+    }
+}
+
+/// The X button number for an evdev button code
+fn x11_button(button: u32) -> Option<u32> {
+    match button {
+        0x110 => Some(1),  // BTN_LEFT
+        0x111 => Some(3),  // BTN_RIGHT
+        0x112 => Some(2),  // BTN_MIDDLE
+        0x113 => Some(8),  // BTN_SIDE (back)
+        0x114 => Some(9),  // BTN_EXTRA (forward)
+        _ => None,
+    }
+}
+
+/// Windows in `current` that aren't known yet, and known windows no longer in `current`
+fn window_changes(known: &HashMap<u32, WindowInfo>, current: &[WindowInfo]) -> (Vec<WindowInfo>, Vec<u32>) {
+    let new_windows = current.iter()
+        .filter(|window| !known.contains_key(&window.id))
+        .cloned()
+        .collect();
+    let closed_windows = known.keys()
+        .filter(|id| !current.iter().any(|window| window.id == **id))
+        .copied()
+        .collect();
+    (new_windows, closed_windows)
+}
+
+/// Whether the window manager still lists the window (`wmctrl -l`)
+fn window_listed(id: u32) -> bool {
+    Command::new("wmctrl")
+        .arg("-l")
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.split_whitespace().next())
+            .any(|window| parse_window_id(window) == Ok(id)))
+        .unwrap_or(false)
+}
+
+/// `vsock:CID:PORT`, where the host is CID 2
+fn parse_vsock_endpoint(endpoint: &str) -> Option<(u32, u32)> {
+    let (cid, port) = endpoint.strip_prefix("vsock:")?.split_once(':')?;
+    Some((cid.parse().ok()?, port.parse().ok()?))
+}
+
+fn vsock_connect(cid: u32, port: u32) -> std::io::Result<UnixStream> {
+    use std::os::fd::{FromRawFd, OwnedFd};
+    
+    let fd = unsafe { libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    
+    let mut addr: libc::sockaddr_vm = unsafe { std::mem::zeroed() };
+    addr.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+    addr.svm_cid = cid;
+    addr.svm_port = port;
+    let ret = unsafe {
+        libc::connect(
+            std::os::fd::AsRawFd::as_raw_fd(&fd),
+            &addr as *const libc::sockaddr_vm as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    
+    Ok(UnixStream::from(fd))
+}
+
+/// Assembles files pushed from the host into ~/Downloads
+struct FileReceiver {
+    transfers: HashMap<u32, IncomingFile>,
+    home: PathBuf,
+    inbox: PathBuf,
+}
+
+struct IncomingFile {
+    file_name: String,
+    target: Option<PathBuf>,  // Replaced if it exists; None: a new file in the inbox
+    size: u64,
+    sha256: String,
+    part_path: PathBuf,
+    file: File,
+    received: u64,
+}
+
+impl FileReceiver {
+    fn new() -> Self {
+        let home = std::env::var("HOME").unwrap_or_else(|_| "/home/user".to_string());
+        Self {
+            transfers: HashMap::new(),
+            home: PathBuf::from(&home),
+            inbox: Path::new(&home).join("Downloads"),
+        }
+    }
+    
+    fn start(
+        &mut self,
+        transfer_id: u32,
+        file_name: &str,
+        destination: Option<&str>,
+        size: u64,
+        sha256: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if size > MAX_FILE_TRANSFER_SIZE {
+            return Err(format!("file is {} bytes, limit is {}", size, MAX_FILE_TRANSFER_SIZE).into());
+        }
+        
+        // Only keep the final path component so the host can't write outside the inbox
+        let file_name = Path::new(file_name)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .filter(|n| !n.starts_with('.'))
+            .ok_or("invalid file name")?
+            .to_string();
+        
+        let target = destination.map(|destination| self.injection_target(destination)).transpose()?;
+        let dir = target.as_deref().and_then(Path::parent).unwrap_or(&self.inbox);
+        
+        fs::create_dir_all(dir)?;
+        let part_path = dir.join(format!(".{}.part", file_name));
+        let file = File::create(&part_path)?;
+        
+        self.transfers.insert(transfer_id, IncomingFile {
+            file_name,
+            target,
+            size,
+            sha256,
+            part_path,
+            file,
+            received: 0,
+        });
+        Ok(())
+    }
+    
+    fn chunk(&mut self, transfer_id: u32, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let incoming = self.transfers.get_mut(&transfer_id).ok_or("unknown transfer")?;
+        
+        if incoming.received + data.len() as u64 > incoming.size {
+            let incoming = self.transfers.remove(&transfer_id).unwrap();
+            let _ = fs::remove_file(&incoming.part_path);
+            return Err("received more data than announced".into());
+        }
+        
+        incoming.file.write_all(data)?;
+        incoming.received += data.len() as u64;
         Ok(())
     }
+    
+    fn finish(&mut self, transfer_id: u32) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        let mut incoming = self.transfers.remove(&transfer_id).ok_or("unknown transfer")?;
+        incoming.file.flush()?;
+        
+        if incoming.received != incoming.size {
+            let _ = fs::remove_file(&incoming.part_path);
+            return Err(format!("expected {} bytes, received {}", incoming.size, incoming.received).into());
+        }
+        
+        let checksum = sha256_file(&incoming.part_path)?;
+        if checksum != incoming.sha256 {
+            let _ = fs::remove_file(&incoming.part_path);
+            return Err("checksum mismatch".into());
+        }
+        
+        // Injected files replace the previous start's copy and may hold secrets
+        if let Some(target) = incoming.target {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&incoming.part_path, fs::Permissions::from_mode(0o600))?;
+            fs::rename(&incoming.part_path, &target)?;
+            return Ok(target);
+        }
+        
+        // Don't clobber existing downloads
+        let mut target = self.inbox.join(&incoming.file_name);
+        let mut n = 1;
+        while target.exists() {
+            target = self.inbox.join(format!("{} ({})", incoming.file_name, n));
+            n += 1;
+        }
+        
+        fs::rename(&incoming.part_path, &target)?;
+        Ok(target)
+    }
 }
 
-#[derive(Debug)]
+impl FileReceiver {
+    /// Where an injected file goes, refusing anything that would end up outside
+    /// the home directory, including through symlinked directories
+    fn injection_target(&self, destination: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        let relative = home_relative_path(destination)?;
+        let target = self.home.join(relative);
+        let parent = target.parent().ok_or("invalid destination")?;
+        
+        fs::create_dir_all(parent)?;
+        if !parent.canonicalize()?.starts_with(self.home.canonicalize()?) {
+            return Err(format!("{} is outside the home directory", destination).into());
+        }
+        Ok(target)
+    }
+}
+
+/// `destination` as a path below the home directory: relative, optionally
+/// starting with `~/`, and without `..` or other special components
+fn home_relative_path(destination: &str) -> Result<PathBuf, String> {
+    use std::path::Component;
+    
+    let path = Path::new(destination.strip_prefix("~/").unwrap_or(destination));
+    if path.as_os_str().is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(format!("destination {} must be a path inside the home directory", destination));
+    }
+    Ok(path.to_path_buf())
+}
+
+fn sha256_file(path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+    let output = Command::new("sha256sum").arg(path).output()?;
+    if !output.status.success() {
+        return Err("sha256sum failed".into());
+    }
+    
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()
+        .map(|s| s.to_string())
+        .ok_or_else(|| "unexpected sha256sum output".into())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 struct Geometry {
     width: u32,
     height: u32,
@@ -382,12 +1595,454 @@ struct Geometry {
     y: i32,
 }
 
+impl Geometry {
+    fn of(window: &WindowInfo) -> Self {
+        Self {
+            width: window.width,
+            height: window.height,
+            x: window.x,
+            y: window.y,
+        }
+    }
+}
+
+/// Coalesces geometry updates while windows are being dragged or resized.
+///
+/// The first change is reported immediately, further changes at most once per
+/// `interval`, and the final geometry once the window stops changing.
+struct GeometryDebouncer {
+    interval: Duration,
+    windows: HashMap<u32, DebounceState>,
+}
+
+struct DebounceState {
+    sent: Geometry,
+    last_sent: Option<Instant>,
+    changing: bool,
+}
+
+impl GeometryDebouncer {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            windows: HashMap::new(),
+        }
+    }
+    
+    fn track(&mut self, id: u32, geometry: Geometry) {
+        self.windows.insert(id, DebounceState {
+            sent: geometry,
+            last_sent: None,
+            changing: false,
+        });
+    }
+    
+    fn forget(&mut self, id: u32) {
+        self.windows.remove(&id);
+    }
+    
+    /// Returns `(previously sent, to send)` when an update should go to the host
+    fn update(&mut self, id: u32, current: Geometry, changed: bool, now: Instant) -> Option<(Geometry, Geometry)> {
+        let state = self.windows.get_mut(&id)?;
+        
+        let send = if changed {
+            let due = state.last_sent.is_none_or(|t| now.duration_since(t) >= self.interval);
+            let first = !state.changing;
+            state.changing = true;
+            first || due
+        } else {
+            // Settled: flush the final geometry if the last one sent was intermediate
+            let settled = state.changing;
+            state.changing = false;
+            settled && state.sent != current
+        };
+        
+        if !send {
+            return None;
+        }
+        
+        let previous = state.sent;
+        state.sent = current;
+        state.last_sent = Some(now);
+        Some((previous, current))
+    }
+}
+
+/// Build a `Notification` from NUL-separated app, summary, body and icon fields
+fn parse_notification(data: &str) -> Option<WindowMessage> {
+    let mut fields = data.split('\0');
+    let app = fields.next()?.trim().to_string();
+    let summary = fields.next()?.chars().take(MAX_NOTIFICATION_TEXT).collect();
+    let body = fields.next().unwrap_or("").chars().take(MAX_NOTIFICATION_TEXT).collect();
+    let icon = fields.next().map(str::trim).filter(|icon| !icon.is_empty()).map(|icon| icon.to_string());
+    
+    Some(WindowMessage::Notification { app, summary, body, icon })
+}
+
 // Main function for guest agent binary
+/// `guest-agent open-url <url>`: the guest's xdg-open handler for http(s) links
+fn open_url(url: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut stream = UnixStream::connect(OPEN_URL_SOCKET)
+        .map_err(|e| format!("Guest agent is not running ({}): {}", OPEN_URL_SOCKET, e))?;
+    stream.write_all(url.as_bytes())?;
+    Ok(())
+}
+
+/// `guest-agent notify <app> <summary> [body] [icon]`: called by dunst for every notification
+fn notify(fields: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    if fields.len() < 2 {
+        return Err("Usage: guest-agent notify <app> <summary> [body] [icon]".into());
+    }
+    
+    let mut stream = UnixStream::connect(NOTIFY_SOCKET)
+        .map_err(|e| format!("Guest agent is not running ({}): {}", NOTIFY_SOCKET, e))?;
+    stream.write_all(fields.iter().take(4).map(|f| f.replace('\0', "")).collect::<Vec<_>>().join("\0").as_bytes())?;
+    Ok(())
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let socket_path = std::env::args()
-        .nth(1)
-        .unwrap_or_else(|| "/tmp/vm-window-proxy.sock".to_string());
+    let mut args = std::env::args().skip(1);
+    let first = args.next();
+    
+    if first.as_deref() == Some("open-url") {
+        let url = args.next().ok_or("Usage: guest-agent open-url <url>")?;
+        return open_url(&url);
+    }
+    
+    if first.as_deref() == Some("notify") {
+        return notify(&args.collect::<Vec<_>>());
+    }
+    
+    // Host proxy endpoint, as the kickstart passes it: "vsock:CID:PORT", "host:port"
+    // or a Unix socket path
+    let socket_path = first.unwrap_or_else(|| "/tmp/vm-window-proxy.sock".to_string());
+    
+    // Debounce interval for window drag/resize updates
+    let debounce_ms = std::env::var("GUEST_AGENT_DEBOUNCE_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_DEBOUNCE_MS);
+    
+    // Which guest log messages are forwarded to the host (error, warn, info, debug)
+    let log_level = match std::env::var("GUEST_AGENT_LOG_LEVEL").as_deref() {
+        Ok("error") => LogLevel::Error,
+        Ok("info") => LogLevel::Info,
+        Ok("debug") => LogLevel::Debug,
+        _ => LogLevel::Warn,
+    };
     
-    let mut agent = GuestAgent::new(&socket_path)?;
+    // The VM's TLS identity, shared with the host, when the proxy requires TLS
+    let tls = match std::env::var("GUEST_AGENT_TLS") {
+        Ok(path) if !path.is_empty() => Some(tls::Identity::load(&path)?),
+        _ => None,
+    };
+    
+    // Set by the kickstart with `clipboard_transport = "Custom"`
+    let share_clipboard = std::env::var("GUEST_AGENT_CLIPBOARD").is_ok_and(|v| v == "1");
+    
+    let mut agent = GuestAgent::new(&socket_path, tls, Duration::from_millis(debounce_ms), log_level, share_clipboard)?;
     agent.run()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn desktop_entries_parse_name_exec_and_icon() {
+        let content = "[Desktop Entry]\nType=Application\nName=LibreWolf\nName[de]=LibreWolf DE\nExec=librewolf %u\nIcon=librewolf\n\n[Desktop Action new-window]\nName=New Window\nExec=librewolf --new-window\n";
+        let app = parse_desktop_entry("io.gitlab.librewolf-community", content).unwrap();
+        assert_eq!(app.entry, AppEntry {
+            id: "io.gitlab.librewolf-community".to_string(),
+            name: "LibreWolf".to_string(),
+            icon: Some("librewolf".to_string()),
+        });
+        assert_eq!(app.exec, "librewolf %u");
+        
+        assert!(parse_desktop_entry("hidden", "[Desktop Entry]\nName=X\nExec=x\nNoDisplay=true\n").is_none());
+        assert!(parse_desktop_entry("link", "[Desktop Entry]\nType=Link\nName=X\nURL=https://example.com\n").is_none());
+        assert!(parse_desktop_entry("noexec", "[Desktop Entry]\nName=X\n").is_none());
+    }
+    
+    #[test]
+    fn exec_lines_split_into_arguments_without_field_codes() {
+        assert_eq!(exec_argv("librewolf %u"), vec!["librewolf"]);
+        assert_eq!(
+            exec_argv("/usr/bin/flatpak run --branch=stable --command=mpv io.mpv.Mpv --player-operation-mode=pseudo-gui -- @@ %F @@"),
+            vec!["/usr/bin/flatpak", "run", "--branch=stable", "--command=mpv", "io.mpv.Mpv", "--player-operation-mode=pseudo-gui", "--", "@@", "@@"]
+        );
+        assert_eq!(exec_argv("\"/opt/My App/app\" --title \"100%%\""), vec!["/opt/My App/app", "--title", "100%"]);
+    }
+    
+    #[test]
+    fn applications_are_found_by_id_or_name() {
+        let app = |id: &str, name: &str| InstalledApp {
+            entry: AppEntry { id: id.to_string(), name: name.to_string(), icon: None },
+            exec: id.to_string(),
+        };
+        let apps = vec![app("io.gitlab.librewolf-community", "LibreWolf"), app("mpv", "mpv Media Player")];
+        
+        assert_eq!(find_application(&apps, "io.gitlab.librewolf-community").unwrap().entry.name, "LibreWolf");
+        assert_eq!(find_application(&apps, "librewolf").unwrap().entry.id, "io.gitlab.librewolf-community");
+        assert!(find_application(&apps, "sh -c 'rm -rf ~'").is_none());
+    }
+    
+    #[test]
+    fn notifications_parse_from_dunst_fields() {
+        match parse_notification("Firefox\0Download complete\0report.pdf\0firefox") {
+            Some(WindowMessage::Notification { app, summary, body, icon }) => {
+                assert_eq!(app, "Firefox");
+                assert_eq!(summary, "Download complete");
+                assert_eq!(body, "report.pdf");
+                assert_eq!(icon.as_deref(), Some("firefox"));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        
+        match parse_notification(&format!("app\0{}\0\0", "x".repeat(5000))) {
+            Some(WindowMessage::Notification { summary, body, icon, .. }) => {
+                assert_eq!(summary.len(), MAX_NOTIFICATION_TEXT);
+                assert_eq!(body, "");
+                assert_eq!(icon, None);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        
+        assert!(parse_notification("only-an-app").is_none());
+    }
+    
+    #[test]
+    fn injected_files_stay_in_the_home_directory() {
+        assert_eq!(home_relative_path("~/.config/app/token").unwrap(), PathBuf::from(".config/app/token"));
+        assert_eq!(home_relative_path("work/settings.json").unwrap(), PathBuf::from("work/settings.json"));
+        assert!(home_relative_path("/etc/passwd").is_err());
+        assert!(home_relative_path("~/../other/.bashrc").is_err());
+        assert!(home_relative_path("a/../../b").is_err());
+        assert!(home_relative_path("~/").is_err());
+        assert!(home_relative_path("~user/file").is_ok());  // A directory literally named "~user"
+        
+        let home = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), home.path().join("escape")).unwrap();
+        let receiver = FileReceiver { transfers: HashMap::new(), home: home.path().to_path_buf(), inbox: home.path().join("Downloads") };
+        assert_eq!(receiver.injection_target("~/.config/token").unwrap(), home.path().join(".config/token"));
+        assert!(receiver.injection_target("escape/token").is_err());
+    }
+    
+    #[test]
+    fn host_endpoints_select_tcp_or_unix() {
+        assert!(is_tcp_endpoint("_gateway:9999"));
+        assert!(is_tcp_endpoint("192.168.122.1:10123"));
+        assert!(!is_tcp_endpoint("/tmp/vm-window-proxy.sock"));
+        assert!(!is_tcp_endpoint("/run/proxy:9999"));
+        assert!(!is_tcp_endpoint("_gateway:port"));
+        
+        assert_eq!(parse_vsock_endpoint("vsock:2:9999"), Some((2, 9999)));
+        assert_eq!(parse_vsock_endpoint("vsock:2"), None);
+        assert_eq!(parse_vsock_endpoint("vsock:host:9999"), None);
+        assert_eq!(parse_vsock_endpoint("_gateway:9999"), None);
+    }
+    
+    #[test]
+    fn host_link_follows_the_reconnected_stream() {
+        let (old, mut old_host) = UnixStream::pair().unwrap();
+        let (new, mut new_host) = UnixStream::pair().unwrap();
+        let link = HostLink::new(HostStream::Unix(old));
+        let helper = link.clone();
+        
+        let read_msg = |host: &mut UnixStream| {
+            bincode::deserialize::<WindowMessage>(&protocol::read_frame(host).unwrap()).unwrap()
+        };
+        
+        helper.send(&WindowMessage::WindowDestroyed { id: 1 }).unwrap();
+        assert!(matches!(read_msg(&mut old_host), WindowMessage::WindowDestroyed { id: 1 }));
+        
+        link.replace(HostStream::Unix(new));
+        helper.send(&WindowMessage::WindowDestroyed { id: 2 }).unwrap();
+        assert!(matches!(read_msg(&mut new_host), WindowMessage::WindowDestroyed { id: 2 }));
+    }
+    
+    #[test]
+    fn heartbeat_pings_once_per_interval() {
+        let start = Instant::now();
+        let mut heartbeat = Heartbeat::new(start);
+        assert_eq!(heartbeat.due(start), Some(1));
+        assert_eq!(heartbeat.due(start + Duration::from_millis(500)), None);
+        assert_eq!(heartbeat.due(start + PING_INTERVAL), Some(2));
+        assert_eq!(heartbeat.due(start + PING_INTERVAL + Duration::from_secs(1)), None);
+    }
+    
+    #[test]
+    fn reconnect_delay_doubles_up_to_the_cap() {
+        assert_eq!(next_reconnect_delay(RECONNECT_MIN_DELAY), Duration::from_secs(2));
+        assert_eq!(next_reconnect_delay(Duration::from_secs(20)), RECONNECT_MAX_DELAY);
+        assert_eq!(next_reconnect_delay(RECONNECT_MAX_DELAY), RECONNECT_MAX_DELAY);
+    }
+    
+    #[test]
+    fn window_ids_parse_at_any_padding() {
+        assert_eq!(parse_window_id("0x1c00001"), Ok(0x1c00001));
+        assert_eq!(parse_window_id("0x01c00001"), Ok(0x1c00001));
+        assert_eq!(parse_window_id("0x00000000001c00001"), Ok(0x1c00001));
+        assert_eq!(parse_window_id("0X1FFFFFFF"), Ok(0x1fffffff));
+        assert_eq!(parse_window_id("0xffffffff"), Ok(u32::MAX));
+        assert!(parse_window_id("0x100000000").unwrap_err().contains("32 bits"));
+        assert!(parse_window_id("0x").is_err());
+        assert!(parse_window_id("0xzz").is_err());
+        assert!(parse_window_id("1c00001").is_err());
+    }
+    
+    #[test]
+    fn xwininfo_tree_lines_parse_or_report_bad_ids() {
+        let output = "\
+xwininfo: Window id: 0x4ac (the root window) (has no name)
+
+  Root window id: 0x4ac (the root window) (has no name)
+  Parent window id: 0x0 (none)
+     3 children:
+     0x1c00001 \"LibreWolf\": (\"librewolf\" \"LibreWolf\")  800x600+100+50  +100+50
+     0x0200003 (has no name): ()  1x1+-1+-1  +-1+-1
+     0x1c00001ffff \"Broken\": (\"broken\" \"Broken\")  10x10+0+0  +0+0
+";
+        let (windows, bad_ids) = GuestAgent::parse_xwininfo_output(output);
+        assert_eq!(windows.len(), 1);
+        assert_eq!(windows[0].id, 0x1c00001);
+        assert_eq!(windows[0].title, "LibreWolf");
+        assert_eq!(windows[0].app_name, "librewolf");
+        assert_eq!((windows[0].width, windows[0].height, windows[0].x, windows[0].y), (800, 600, 100, 50));
+        assert_eq!(bad_ids, vec!["0x1c00001ffff"]);
+    }
+    
+    #[test]
+    fn wmctrl_lines_parse_or_report_bad_ids() {
+        let output = "0x01c00001  0 100 50 800 600 Navigator.firefox vm Mozilla Firefox\nx  0 0 0 1 1 a.b vm Short\n";
+        let (windows, bad_ids) = GuestAgent::parse_wmctrl_output(output);
+        assert_eq!(windows.len(), 1);
+        assert_eq!(windows[0].id, 0x1c00001);
+        assert_eq!(windows[0].title, "Mozilla Firefox");
+        assert_eq!(windows[0].app_name, "firefox");
+        assert_eq!(bad_ids, vec!["x"]);
+    }
+    
+    #[test]
+    fn wmctrl_scans_report_new_and_closed_windows() {
+        // Sticky windows are on desktop -1; windows without WM_CLASS show N/A
+        let first = "0x01c00001  0 100 50 800 600 Navigator.firefox vm Mozilla Firefox\n\
+                     0x02000003 -1 0 0 300 200 N/A vm Sticky note\n";
+        let (windows, bad_ids) = GuestAgent::parse_wmctrl_output(first);
+        assert!(bad_ids.is_empty());
+        assert_eq!(windows[1].title, "Sticky note");
+        
+        let (new_windows, closed) = window_changes(&HashMap::new(), &windows);
+        assert_eq!(new_windows.iter().map(|w| w.id).collect::<Vec<_>>(), [0x1c00001, 0x2000003]);
+        assert!(closed.is_empty());
+        
+        let known: HashMap<u32, WindowInfo> = windows.into_iter().map(|w| (w.id, w)).collect();
+        let second = "0x01c00001  0 100 50 800 600 Navigator.firefox vm Mozilla Firefox\n\
+                      0x02400001  0 10 10 640 480 kitty.kitty vm Terminal\n";
+        let (windows, _) = GuestAgent::parse_wmctrl_output(second);
+        let (new_windows, closed) = window_changes(&known, &windows);
+        assert_eq!(new_windows.iter().map(|w| w.id).collect::<Vec<_>>(), [0x2400001]);
+        assert_eq!(closed, [0x2000003]);
+    }
+    
+    #[test]
+    fn app_name_comes_from_wm_class() {
+        let line = "     0x2400007 \"Untitled - GIMP (a) 2.10\": (\"gimp\" \"Gimp\")  1024x768+0+0  +0+0";
+        let window = GuestAgent::parse_xwininfo_line(line).unwrap().unwrap();
+        assert_eq!(window.title, "Untitled - GIMP (a) 2.10");
+        assert_eq!(window.app_name, "gimp");
+        
+        let line = "     0x2400009 \"my-tool\": ()  300x200+0+0  +0+0";
+        assert_eq!(GuestAgent::parse_xwininfo_line(line).unwrap().unwrap().app_name, "unknown");
+        assert_eq!(app_name_from_wm_class(&["org.inkscape.Inkscape", ""]), "org.inkscape.inkscape");
+    }
+    
+    #[test]
+    fn net_wm_pid_parses_xprop_output() {
+        assert_eq!(parse_net_wm_pid("_NET_WM_PID(CARDINAL) = 1234\n"), Some(1234));
+        assert_eq!(parse_net_wm_pid("_NET_WM_PID:  not found.\n"), None);
+        assert_eq!(parse_net_wm_pid("_NET_WM_PID(CARDINAL) = 0\n"), None);
+    }
+    
+    #[test]
+    fn stacking_order_parses_xprop_output() {
+        let output = "_NET_CLIENT_LIST_STACKING(WINDOW): window id # 0x1c00001, 0x2000003\n";
+        assert_eq!(parse_client_list_stacking(output), vec![0x1c00001, 0x2000003]);
+        assert!(parse_client_list_stacking("_NET_CLIENT_LIST_STACKING:  not found.\n").is_empty());
+    }
+    
+    fn geometry(x: i32) -> Geometry {
+        Geometry { width: 800, height: 600, x, y: 0 }
+    }
+    
+    #[test]
+    fn debouncer_sends_first_change_immediately() {
+        let mut debouncer = GeometryDebouncer::new(Duration::from_secs(1));
+        debouncer.track(1, geometry(0));
+        
+        let update = debouncer.update(1, geometry(10), true, Instant::now());
+        assert_eq!(update, Some((geometry(0), geometry(10))));
+    }
+    
+    #[test]
+    fn debouncer_suppresses_intermediate_changes_and_flushes_final() {
+        let mut debouncer = GeometryDebouncer::new(Duration::from_secs(1));
+        debouncer.track(1, geometry(0));
+        let start = Instant::now();
+        
+        assert!(debouncer.update(1, geometry(10), true, start).is_some());
+        assert!(debouncer.update(1, geometry(20), true, start + Duration::from_millis(500)).is_none());
+        
+        // No change since the last scan: report where the window ended up
+        let update = debouncer.update(1, geometry(20), false, start + Duration::from_millis(1000));
+        assert_eq!(update, Some((geometry(10), geometry(20))));
+        
+        // Nothing more to report once settled
+        assert!(debouncer.update(1, geometry(20), false, start + Duration::from_millis(1500)).is_none());
+    }
+    
+    #[test]
+    fn debouncer_rate_limits_long_drags() {
+        let mut debouncer = GeometryDebouncer::new(Duration::from_secs(1));
+        debouncer.track(1, geometry(0));
+        let start = Instant::now();
+        
+        assert!(debouncer.update(1, geometry(10), true, start).is_some());
+        assert!(debouncer.update(1, geometry(20), true, start + Duration::from_millis(500)).is_none());
+        assert!(debouncer.update(1, geometry(30), true, start + Duration::from_millis(1000)).is_some());
+    }
+    
+    #[test]
+    fn host_input_becomes_one_xdotool_command_line() {
+        let key = |key, pressed| WindowMessage::KeyEvent { id: 0x1c00001, key, pressed };
+        let pointer = |id, action| WindowMessage::PointerEvent { id, action };
+        let events = [
+            key(30, true),
+            key(30, false),
+            pointer(0x1c00001, PointerAction::Motion { x: 5, y: 5 }),
+            pointer(0x1c00001, PointerAction::Motion { x: 10, y: 20 }),
+            pointer(0x1c00001, PointerAction::Button { button: 0x111, pressed: true }),
+            pointer(0x1c00001, PointerAction::Button { button: 0x200, pressed: true }),
+            pointer(0x1c00001, PointerAction::Scroll { dx: 0, dy: -2 }),
+            pointer(0x2000003, PointerAction::Motion { x: 1, y: 2 }),
+        ];
+        let mut active = None;
+        let args = xdotool_args(&events, &mut active);
+        assert_eq!(args.join(" "), "windowactivate --sync 0x01c00001 keydown 38 keyup 38 \
+                                    mousemove --window 0x01c00001 10 20 mousedown 3 \
+                                    click --repeat 2 --delay 0 4 \
+                                    windowactivate --sync 0x02000003 mousemove --window 0x02000003 1 2");
+        assert_eq!(active, Some(0x2000003));
+        
+        // The window stays active for the next batch
+        assert_eq!(xdotool_args(&[pointer(0x2000003, PointerAction::Button { button: 0x110, pressed: false })], &mut active),
+                   ["mouseup", "1"]);
+        assert_eq!(x11_key(1), "Escape");
+    }
+    
+    #[test]
+    fn chpasswd_gets_only_the_new_password_line() {
+        assert_eq!(chpasswd_input("n3w:s3cret"), "user:n3w:s3cret\n");
+    }
+}
@@ -0,0 +1,114 @@
+use std::process::Command;
+use std::sync::OnceLock;
+
+/// System libvirt, managed with sudo
+pub const SYSTEM_URI: &str = "qemu:///system";
+
+/// Connection selected with `--connect`
+static URI: OnceLock<String> = OnceLock::new();
+
+/// Select the libvirt connection every libvirt tool is run against
+pub fn init(uri: &str) {
+    let _ = URI.set(uri.to_string());
+}
+
+pub fn uri() -> &'static str {
+    URI.get().map_or(SYSTEM_URI, String::as_str)
+}
+
+/// Whether the connection is a per-user session daemon, which needs no root
+/// (`qemu:///session`, also over ssh or with a socket path)
+pub fn is_session() -> bool {
+    is_session_uri(uri())
+}
+
+fn is_session_uri(uri: &str) -> bool {
+    uri.split(['?', '#']).next().is_some_and(|path| path.ends_with("/session"))
+}
+
+/// A `virsh` command line for the user to run against the selected connection,
+/// e.g. `virsh -c qemu:///session console vm`; system libvirt needs sudo
+pub fn virsh_hint(args: &str) -> String {
+    virsh_hint_for(uri(), args)
+}
+
+fn virsh_hint_for(uri: &str, args: &str) -> String {
+    let sudo = if is_session_uri(uri) { "" } else { "sudo " };
+    format!("{}virsh -c {} {}", sudo, uri, args)
+}
+
+/// `virsh` on the selected connection
+pub fn virsh() -> Command {
+    let mut command = Command::new("virsh");
+    command.args(["--connect", uri()]);
+    command
+}
+
+/// A libvirt tool that takes `--connect` (virt-install, virt-clone, virt-xml, ...)
+pub fn tool(program: &str) -> Command {
+    let mut command = Command::new(program);
+    command.args(["--connect", uri()]);
+    command
+}
+
+/// `program` with root for system libvirt, whose images and logs are root-owned;
+/// as the invoking user for a session connection
+pub fn privileged(program: &str) -> Command {
+    if is_session() {
+        Command::new(program)
+    } else {
+        let mut command = Command::new("sudo");
+        command.arg(program);
+        command
+    }
+}
+
+/// `privileged` libvirt tool on the selected connection
+pub fn privileged_tool(program: &str) -> Command {
+    let mut command = privileged(program);
+    command.args(["--connect", uri()]);
+    command
+}
+
+/// Where the connection's daemon keeps QEMU logs, e.g. serial console logs
+pub fn log_dir() -> String {
+    match std::env::var("HOME") {
+        Ok(home) if is_session() => format!("{}/.cache/libvirt/qemu/log", home),
+        _ => "/var/log/libvirt/qemu".to_string(),
+    }
+}
+
+/// Where the connection's daemon keeps `virsh managedsave` images
+pub fn save_dir() -> String {
+    match std::env::var("HOME") {
+        Ok(home) if is_session() => format!("{}/.config/libvirt/qemu/save", home),
+        _ => "/var/lib/libvirt/qemu/save".to_string(),
+    }
+}
+
+/// Default `vm_dir` for the connection: libvirt's own pool directory for it
+pub fn default_images_dir() -> String {
+    match std::env::var("HOME") {
+        Ok(home) if is_session() => format!("{}/.local/share/libvirt/images", home),
+        _ => "/var/lib/libvirt/images".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn session_uris_are_recognised() {
+        assert!(is_session_uri("qemu:///session"));
+        assert!(is_session_uri("qemu+ssh://host/session?keyfile=/k"));
+        assert!(!is_session_uri("qemu:///system"));
+        assert!(!is_session_uri("qemu+ssh://host/system"));
+    }
+    
+    #[test]
+    fn virsh_hints_match_the_connection() {
+        assert_eq!(virsh_hint_for("qemu:///session", "console vm"), "virsh -c qemu:///session console vm");
+        assert_eq!(virsh_hint_for("qemu:///system", "console vm"), "sudo virsh -c qemu:///system console vm");
+    }
+}
@@ -1,18 +1,169 @@
+#[macro_use]
+mod output;
 mod config;
+mod libvirt;
 mod provisioner;
 mod window_proxy;
-mod guest_agent;
+mod state;
+mod protocol;
+// Shared with the guest agent, whose client side the host doesn't use
+#[allow(dead_code)]
+mod tls;
 
-use std::path::Path;
-use std::collections::HashMap;
-use clap::{Parser, Subcommand};
-use dialoguer::Confirm;
+use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use dialoguer::{Confirm, Input};
 use tokio;
 use serde::{Serialize, Deserialize};
 
-use config::AppVMConfig;
+use config::{AppVMConfig, ClipboardTransport, ProxyTransport};
 use provisioner::AppVMProvisioner;
-use window_proxy::VMIntegrationHost;
+use state::{VMPhase, VMState};
+use protocol::WindowMessage;
+use window_proxy::{Transport, VMIntegrationHost};
+
+/// Write vm-passwords.toml as plaintext instead of encrypting it (`--no-encrypt`)
+static NO_ENCRYPT: AtomicBool = AtomicBool::new(false);
+
+/// Passphrase for vm-passwords.toml, asked for at most once per run
+static PASSPHRASE: OnceLock<String> = OnceLock::new();
+
+/// First line of an encrypted vm-passwords.toml. It's a TOML comment, so plaintext
+/// files never start with it; the version says how the rest is encoded.
+const PASSWORDS_HEADER_PREFIX: &str = "# vm-provisioner-passwords v";
+
+/// How vm-passwords.toml is stored on disk
+#[derive(Debug, PartialEq)]
+enum PasswordsFormat<'a> {
+    Plaintext(&'a str),
+    /// v2: `EncryptedPasswords` as TOML, keyed with the Argon2id parameters from the header
+    AesGcmV2(KdfParams, &'a str),
+}
+
+impl<'a> PasswordsFormat<'a> {
+    fn detect(content: &'a str) -> Result<Self, Box<dyn std::error::Error>> {
+        let Some(rest) = content.strip_prefix(PASSWORDS_HEADER_PREFIX) else {
+            return Ok(Self::Plaintext(content));
+        };
+        let (header, body) = rest.split_once('\n').unwrap_or((rest, ""));
+        match header.trim().strip_prefix("2 aes-256-gcm argon2id ") {
+            Some(kdf) => Ok(Self::AesGcmV2(kdf.parse()?, body)),
+            None => Err(format!("vm-passwords.toml uses format v{}, which this vm-provisioner doesn't know; \
+                                  upgrade vm-provisioner to read it", header.trim()).into()),
+        }
+    }
+}
+
+/// Argon2id cost, written into the store's header as `m=<KiB>,t=<passes>,p=<lanes>`
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct KdfParams {
+    memory_kib: u32,
+    passes: u32,
+    lanes: u32,
+}
+
+/// Argon2id cost for new password stores, RFC 9106's second recommended option
+const PASSWORDS_KDF: KdfParams = KdfParams { memory_kib: 64 * 1024, passes: 3, lanes: 4 };
+
+impl std::fmt::Display for KdfParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "m={},t={},p={}", self.memory_kib, self.passes, self.lanes)
+    }
+}
+
+impl std::str::FromStr for KdfParams {
+    type Err = Box<dyn std::error::Error>;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut params = (None, None, None);
+        for field in s.split(',') {
+            let (key, value) = field.split_once('=').ok_or_else(|| format!("invalid Argon2id parameter {:?}", field))?;
+            let value = value.parse::<u32>().map_err(|_| format!("invalid Argon2id parameter {:?}", field))?;
+            match key {
+                "m" => params.0 = Some(value),
+                "t" => params.1 = Some(value),
+                "p" => params.2 = Some(value),
+                _ => return Err(format!("unknown Argon2id parameter {:?}", key).into()),
+            }
+        }
+        match params {
+            (Some(memory_kib), Some(passes), Some(lanes)) => Ok(Self { memory_kib, passes, lanes }),
+            _ => Err(format!("incomplete Argon2id parameters {:?}", s).into()),
+        }
+    }
+}
+
+/// The v2 password store: the TOML sealed with AES-256-GCM under a key derived from
+/// the passphrase with Argon2id. Binary fields are hex encoded.
+#[derive(Debug, Serialize, Deserialize)]
+struct EncryptedPasswords {
+    salt: String,
+    nonce: String,
+    ciphertext: String,  // Includes the GCM tag, so tampering or a wrong passphrase is detected
+}
+
+impl EncryptedPasswords {
+    fn seal(plaintext: &[u8], passphrase: &str, kdf: KdfParams) -> Result<Self, Box<dyn std::error::Error>> {
+        use ring::aead::{Aad, Nonce, NONCE_LEN};
+        
+        let mut salt = [0u8; 16];
+        let mut nonce = [0u8; NONCE_LEN];
+        getrandom::fill(&mut salt).and_then(|_| getrandom::fill(&mut nonce))
+            .map_err(|e| format!("the OS random number generator failed: {}", e))?;
+        
+        let mut ciphertext = plaintext.to_vec();
+        passwords_key(passphrase, &salt, kdf)?
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut ciphertext)
+            .map_err(|_| "encryption failed")?;
+        Ok(Self {
+            salt: hex_encode(&salt),
+            nonce: hex_encode(&nonce),
+            ciphertext: hex_encode(&ciphertext),
+        })
+    }
+    
+    fn open(&self, passphrase: &str, kdf: KdfParams) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        use ring::aead::{Aad, Nonce};
+        
+        let nonce = Nonce::try_assume_unique_for_key(&hex_decode(&self.nonce)?).map_err(|_| "bad nonce length")?;
+        let mut data = hex_decode(&self.ciphertext)?;
+        let plaintext = passwords_key(passphrase, &hex_decode(&self.salt)?, kdf)?
+            .open_in_place(nonce, Aad::empty(), &mut data)
+            .map_err(|_| "wrong passphrase, or the file was modified")?;
+        Ok(plaintext.to_vec())
+    }
+}
+
+/// The AES-256-GCM key for `passphrase`
+fn passwords_key(passphrase: &str, salt: &[u8], kdf: KdfParams) -> Result<ring::aead::LessSafeKey, Box<dyn std::error::Error>> {
+    use argon2::{Algorithm, Argon2, Params, Version};
+    use ring::aead::{LessSafeKey, UnboundKey, AES_256_GCM};
+    
+    let mut key = [0u8; 32];
+    let params = Params::new(kdf.memory_kib, kdf.passes, kdf.lanes, Some(key.len()))
+        .map_err(|e| format!("invalid Argon2id parameters {}: {}", kdf, e))?;
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| format!("key derivation failed: {}", e))?;
+    Ok(LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &key).map_err(|_| "bad key length")?))
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hex_decode(hex: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if !hex.len().is_multiple_of(2) {
+        return Err("odd number of hex digits".into());
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2).ok_or("not hex")?, 16).map_err(Into::into))
+        .collect()
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 struct VMPasswords {
@@ -31,25 +182,86 @@ impl VMPasswords {
         
         if Path::new(&password_file).exists() {
             let content = std::fs::read_to_string(&password_file)?;
-            Ok(toml::from_str(&content).unwrap_or_else(|_| Self::new()))
+            let content = match PasswordsFormat::detect(&content)? {
+                PasswordsFormat::Plaintext(toml) => toml.to_string(),
+                PasswordsFormat::AesGcmV2(kdf, body) => {
+                    let sealed: EncryptedPasswords = toml::from_str(body)
+                        .map_err(|e| format!("{} is damaged: {}", password_file, e))?;
+                    let plaintext = sealed.open(passphrase(false)?, kdf)
+                        .map_err(|e| format!("Failed to decrypt {}: {}", password_file, e))?;
+                    String::from_utf8(plaintext)?
+                }
+            };
+            // A store that doesn't parse is reported rather than replaced, which would lose every password
+            toml::from_str(&content).map_err(|e| format!("{} is not a valid password store: {}", password_file, e).into())
         } else {
             Ok(Self::new())
         }
     }
     
     fn save(&self, config_dir: &str) -> Result<(), Box<dyn std::error::Error>> {
+        use std::io::Write;
+        use std::os::unix::fs::OpenOptionsExt;
+        
         // Ensure directory exists
         std::fs::create_dir_all(config_dir)?;
         
         let password_file = format!("{}/vm-passwords.toml", config_dir);
-        std::fs::write(&password_file, toml::to_string_pretty(self)?)?;
-        println!("💾 Passwords saved to: {}", password_file);
+        let toml = toml::to_string_pretty(self)?;
+        let content = if NO_ENCRYPT.load(Ordering::Relaxed) {
+            toml
+        } else {
+            // A new passphrase is confirmed; an existing encrypted store's was already asked for
+            let first_use = PASSPHRASE.get().is_none();
+            let sealed = EncryptedPasswords::seal(toml.as_bytes(), passphrase(first_use)?, PASSWORDS_KDF)?;
+            format!("{}2 aes-256-gcm argon2id {}\n{}", PASSWORDS_HEADER_PREFIX, PASSWORDS_KDF, toml::to_string(&sealed)?)
+        };
+        
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&password_file)?;
+        file.write_all(content.as_bytes())?;
+        outln!("💾 Passwords saved to: {}", password_file);
         Ok(())
     }
     
     fn add_vm(&mut self, vm_name: &str, password: &str) {
         self.vms.insert(vm_name.to_string(), password.to_string());
     }
+    
+    fn remove_vm(&mut self, vm_name: &str) -> bool {
+        self.vms.remove(vm_name).is_some()
+    }
+}
+
+/// Refuse `--no-encrypt` when the store on disk is encrypted, which saving would
+/// quietly turn into plaintext. Checked up front, before a command changes anything.
+fn check_store_unencrypted(config_dir: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let password_file = format!("{}/vm-passwords.toml", config_dir);
+    match std::fs::read_to_string(&password_file) {
+        Ok(content) if content.starts_with(PASSWORDS_HEADER_PREFIX) => Err(format!(
+            "{} is encrypted and --no-encrypt would rewrite it as plaintext. \
+             Run without --no-encrypt, or move the store aside to start a plaintext one", password_file
+        ).into()),
+        _ => Ok(()),
+    }
+}
+
+/// The password store's passphrase, prompting for it (twice when `confirm`) the first time
+fn passphrase(confirm: bool) -> Result<&'static str, Box<dyn std::error::Error>> {
+    if let Some(passphrase) = PASSPHRASE.get() {
+        return Ok(passphrase);
+    }
+    
+    let mut prompt = dialoguer::Password::new().with_prompt("Passphrase for the VM password store");
+    if confirm {
+        prompt = prompt.with_confirmation("Repeat passphrase", "Passphrases don't match");
+    }
+    let passphrase = prompt.interact()?;
+    Ok(PASSPHRASE.get_or_init(|| passphrase))
 }
 
 #[derive(Parser)]
@@ -58,43 +270,354 @@ impl VMPasswords {
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    
+    /// Plain output without emoji or colors (also enabled by setting NO_COLOR)
+    #[arg(long, global = true, visible_alias = "no-emoji")]
+    plain: bool,
+    
+    /// Store VM passwords as plaintext instead of encrypting them with a passphrase
+    #[arg(long, global = true)]
+    no_encrypt: bool,
+    
+    /// libvirt connection URI; qemu:///session runs VMs as your user without sudo
+    #[arg(long, global = true, env = "LIBVIRT_DEFAULT_URI", default_value = libvirt::SYSTEM_URI)]
+    connect: String,
 }
 
-#[derive(Subcommand)]
-enum Commands {
-    /// Create a new application VM
-    Create {
-        /// VM name
-        #[arg(short, long)]
-        name: Option<String>,
+#[derive(Args)]
+struct CreateArgs {
+    /// VM name
+    #[arg(short, long)]
+    name: Option<String>,
+    
+    /// System packages to install (can be used multiple times)
+    #[arg(long, action = clap::ArgAction::Append)]
+    system: Vec<String>,
+    
+    /// Flatpak packages to install (can be used multiple times)
+    #[arg(long, action = clap::ArgAction::Append)]
+    flatpak: Vec<String>,
+    
+    /// Package profile to include, e.g. dev, media, office (can be used multiple times)
+    #[arg(long = "profile", action = clap::ArgAction::Append)]
+    profiles: Vec<String>,
+    
+    /// Skip interactive configuration
+    #[arg(short = 'y', long)]
+    yes: bool,
+    
+    /// Configuration file path
+    #[arg(short, long)]
+    config: Option<String>,
+    
+    /// Memory in MB (default: 4096)
+    #[arg(long)]
+    memory: Option<u64>,
+    
+    /// Number of CPUs (default: 2)
+    #[arg(long)]
+    vcpus: Option<u32>,
+    
+    /// Disk size in GB (default: 20)
+    #[arg(long)]
+    disk: Option<u64>,
+    
+    /// Fedora release to install (default: 41)
+    #[arg(long)]
+    fedora_version: Option<u32>,
+    
+    /// Don't verify the downloaded ISO against the mirror's CHECKSUM file (air-gapped mirrors)
+    #[arg(long)]
+    skip_checksum: bool,
+    
+    /// Install from a local ISO instead of downloading Fedora
+    #[arg(long)]
+    iso: Option<PathBuf>,
+    
+    /// Provision from a cloud image with cloud-init instead of the Fedora installer
+    #[arg(long, conflicts_with = "iso")]
+    cloud_image: Option<PathBuf>,
+    
+    /// Expected SHA-256 of the local ISO
+    #[arg(long, requires = "iso")]
+    iso_sha256: Option<String>,
+    
+    /// Install tree URL or local directory (e.g. a local mirror) passed to virt-install --location
+    #[arg(long)]
+    location: Option<String>,
+    
+    /// Hostname inside the guest (default: VM name)
+    #[arg(long)]
+    hostname: Option<String>,
+    
+    /// New random MAC address and hostname on every start, for privacy VMs
+    #[arg(long, visible_alias = "randomize-identity")]
+    randomize_mac: bool,
+    
+    /// Extra argument passed verbatim to virt-install (can be used multiple times)
+    #[arg(long = "virt-arg", action = clap::ArgAction::Append, allow_hyphen_values = true)]
+    virt_args: Vec<String>,
+    
+    /// DNS server for the guest, overriding DHCP (can be used multiple times)
+    #[arg(long = "dns")]
+    dns_servers: Vec<String>,
+    
+    /// DNS search domain for the guest (can be used multiple times)
+    #[arg(long = "dns-search")]
+    dns_search: Vec<String>,
+    
+    /// Share a host folder through remote-viewer (SPICE webdav)
+    #[arg(long)]
+    enable_spice_folder_sharing: bool,
+    
+    /// Address the SPICE display listens on (default 127.0.0.1); anything else needs a password
+    #[arg(long, value_name = "ADDR")]
+    spice_listen: Option<String>,
+    
+    /// Password for the SPICE display (generated when --spice-listen isn't loopback)
+    #[arg(long)]
+    spice_password: Option<String>,
+    
+    /// CPU weight relative to other VMs (libvirt default: 1024)
+    #[arg(long)]
+    cpu_shares: Option<u32>,
+    
+    /// Pin each vCPU to a host CPU, in vCPU order (e.g. 2,3,6,7)
+    #[arg(long, value_delimiter = ',')]
+    cpu_pinning: Vec<u32>,
+    
+    /// Pass a host USB device through, as VENDOR:PRODUCT (can be used multiple times; list them with `vm-provisioner usb`)
+    #[arg(long = "usb", value_parser = config::parse_usb_id, action = clap::ArgAction::Append)]
+    usb_devices: Vec<String>,
+    
+    /// Share a host directory over virtiofs as /host/path:tag, mounted at /mnt/<tag> in the guest (can be used multiple times)
+    #[arg(long = "share", value_parser = config::parse_shared_folder, action = clap::ArgAction::Append)]
+    shared_folders: Vec<(PathBuf, String)>,
+    
+    /// vCPU topology as SOCKETSxCORESxTHREADS (e.g. 1x2x2); must multiply out to --vcpus
+    #[arg(long, value_parser = config::parse_cpu_topology)]
+    cpu_topology: Option<(u32, u32, u32)>,
+    
+    /// Directory for the VM disk and downloaded ISO (default: /var/lib/libvirt/images)
+    #[arg(long)]
+    vm_dir: Option<String>,
+    
+    /// Memory in MB for the installer only (default: same as --memory)
+    #[arg(long)]
+    install_memory: Option<u64>,
+    
+    /// HiDPI scale factor for the guest desktop, 0.5 to 3.0 (e.g. 2 on a 4K laptop)
+    #[arg(long, visible_alias = "gui-scale")]
+    scale: Option<f32>,
+    
+    /// Launch the installation in the background and return immediately
+    #[arg(long)]
+    no_wait: bool,
+    
+    /// Print the kickstart and the virt-install command instead of creating the VM
+    #[arg(long)]
+    dry_run: bool,
+    
+    /// Download and verify the install ISO into the cache, then stop
+    #[arg(long, conflicts_with = "dry_run")]
+    download_only: bool,
+    
+    /// Stop the VM this long after its first start, e.g. 90m, 8h or 2d
+    #[arg(long, value_parser = config::parse_duration)]
+    expire_after: Option<std::time::Duration>,
+    
+    /// Destroy the VM without asking once it expires, instead of just stopping it
+    #[arg(long, requires = "expire_after")]
+    auto_destroy: bool,
+    
+    /// TCP port for the host window proxy, baked into the guest agent (default: 9999)
+    #[arg(long)]
+    proxy_port: Option<u16>,
+    
+    /// Use the TCP window proxy transport with mutual TLS (a per-VM certificate both ends pin)
+    #[arg(long)]
+    proxy_tls: bool,
+}
+
+const DEFAULT_MEMORY_MB: u64 = 4096;
+const DEFAULT_VCPUS: u32 = 2;
+const DEFAULT_DISK_GB: u64 = 20;
+const DEFAULT_SPICE_PASSWORD_LENGTH: usize = 16;
+
+impl CreateArgs {
+    /// Resolve the VM configuration. Precedence, lowest first: built-in defaults,
+    /// the `--config` file, then flags given on the command line. Scalar flags
+    /// replace the file's values; package and virt-install flags add to them.
+    fn into_config(self) -> Result<AppVMConfig, Box<dyn std::error::Error>> {
+        let mut config = match &self.config {
+            Some(path) => load_template(Path::new(path))?,
+            None => AppVMConfig::new(self.generated_name(), DEFAULT_MEMORY_MB, DEFAULT_VCPUS, DEFAULT_DISK_GB, 
+                                     Vec::new(), Vec::new()),
+        };
         
-        /// System packages to install (can be used multiple times)
-        #[arg(long, action = clap::ArgAction::Append)]
-        system: Vec<String>,
+        if let Some(name) = self.name {
+            config.name = name;
+        }
+        if let Some(memory) = self.memory {
+            config.memory_mb = memory;
+        }
+        if let Some(vcpus) = self.vcpus {
+            config.vcpus = vcpus;
+        }
+        if let Some(disk) = self.disk {
+            config.disk_size_gb = disk;
+        }
+        if let Some(vm_dir) = self.vm_dir {
+            config.vm_dir = vm_dir.trim_end_matches('/').to_string();
+        }
+        // Profiles from the config file may not have been expanded yet; re-applying is harmless
+        let profiles: Vec<String> = config.profiles.iter().cloned().chain(self.profiles).collect();
+        config.apply_profiles(&profiles)?;
+        config.add_packages(self.system, self.flatpak);
         
-        /// Flatpak packages to install (can be used multiple times)
-        #[arg(long, action = clap::ArgAction::Append)]
-        flatpak: Vec<String>,
+        if self.hostname.is_some() {
+            config.hostname = self.hostname;
+        }
+        if self.randomize_mac {
+            config.identity_mode = config::IdentityMode::RandomizePerBoot;
+        }
+        if let Some(version) = self.fedora_version {
+            config.distro = config::Distro::Fedora { version };
+        }
+        if self.skip_checksum {
+            config.skip_iso_checksum = true;
+        }
+        if self.iso.is_some() {
+            config.iso_path = self.iso;
+            config.iso_sha256 = self.iso_sha256;
+        }
+        if self.location.is_some() {
+            config.install_location = self.location;
+        }
+        if self.cloud_image.is_some() {
+            config.provisioning = config::ProvisioningMethod::CloudInit;
+            config.cloud_image = self.cloud_image;
+        }
+        config.extra_virt_install_args.extend(self.virt_args);
+        if self.cpu_shares.is_some() {
+            config.cpu_shares = self.cpu_shares;
+        }
+        if !self.cpu_pinning.is_empty() {
+            config.cpu_pinning = Some(self.cpu_pinning);
+        }
+        if self.cpu_topology.is_some() {
+            config.cpu_topology = self.cpu_topology;
+        }
+        for id in self.usb_devices {
+            if !config.usb_devices.contains(&id) {
+                config.usb_devices.push(id);
+            }
+        }
+        for folder in self.shared_folders {
+            if !config.shared_folders.contains(&folder) {
+                config.shared_folders.push(folder);
+            }
+        }
+        if self.install_memory.is_some() {
+            config.install_memory_mb = self.install_memory;
+        }
+        if self.enable_spice_folder_sharing {
+            config.enable_spice_folder_sharing = true;
+        }
+        if self.spice_listen.is_some() {
+            config.spice_listen = self.spice_listen;
+        }
+        if self.spice_password.is_some() {
+            config.spice_password = self.spice_password;
+        }
+        if config.spice_needs_password() && config.spice_password.is_none() {
+            config.spice_password = Some(config::generate_password(DEFAULT_SPICE_PASSWORD_LENGTH, false));
+        }
+        if !self.dns_servers.is_empty() {
+            config.dns_servers = self.dns_servers;
+        }
+        if !self.dns_search.is_empty() {
+            config.dns_search = self.dns_search;
+        }
+        if self.expire_after.is_some() {
+            config.max_lifetime = self.expire_after;
+            config.auto_destroy_on_expiry = self.auto_destroy;
+        }
+        if self.scale.is_some() {
+            config.display_scale = self.scale;
+        }
+        if let Some(port) = self.proxy_port {
+            config.proxy_port = port;
+        }
+        if self.proxy_tls {
+            config.proxy_transport = ProxyTransport::Tcp;
+            config.proxy_tls = true;
+        }
         
-        /// Skip interactive configuration
-        #[arg(short = 'y', long)]
-        yes: bool,
+        Ok(config)
+    }
+    
+    /// VM name when neither --name nor a config file provides one
+    fn generated_name(&self) -> String {
+        if !self.flatpak.is_empty() {
+            format!("{}-vm", self.flatpak[0].replace(".", "-"))
+        } else if !self.system.is_empty() {
+            format!("{}-vm", self.system[0])
+        } else if !self.profiles.is_empty() {
+            format!("{}-vm", self.profiles[0])
+        } else {
+            format!("app-vm-{}", std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs())
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Print the effective configuration of a VM, with defaults filled in
+    Show {
+        /// VM name
+        name: String,
         
-        /// Configuration file path
-        #[arg(short, long)]
-        config: Option<String>,
+        /// Include the VM and SPICE passwords instead of redacting them
+        #[arg(long)]
+        show_password: bool,
         
-        /// Memory in MB (default: 4096)
-        #[arg(long, default_value = "4096")]
-        memory: u64,
+        /// Output format
+        #[arg(long, value_enum, default_value = "toml")]
+        format: ConfigFormat,
+    },
+    
+    /// Edit a VM's configuration in $EDITOR; it is only saved if it still validates
+    Edit {
+        /// VM name
+        name: String,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ConfigFormat {
+    Toml,
+    Json,
+    Yaml,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Create a new application VM
+    Create(Box<CreateArgs>),
+    
+    /// Create a VM from every *.toml config in a directory
+    CreateAll {
+        /// Directory of VM configs, in the same format as `create --config` takes
+        dir: PathBuf,
         
-        /// Number of CPUs (default: 2)
-        #[arg(long, default_value = "2")]
-        vcpus: u32,
+        /// How many VMs to install at the same time
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+        parallel: u32,
         
-        /// Disk size in GB (default: 20)
-        #[arg(long, default_value = "20")]
-        disk: u64,
+        /// Skip confirmation
+        #[arg(short = 'y', long)]
+        yes: bool,
     },
     
     /// Start an existing VM
@@ -102,9 +625,18 @@ enum Commands {
         /// VM name
         name: String,
         
-        /// Enable seamless window mode
-        #[arg(short, long, default_value = "true")]
+        /// Run the window proxy for seamless windows (`--seamless false` only boots the VM)
+        #[arg(short, long, default_value_t = true, action = clap::ArgAction::Set)]
         seamless: bool,
+        
+        /// Window proxy port for this start only (default: the VM's proxy_port)
+        #[arg(long)]
+        proxy_port: Option<u16>,
+        
+        /// Copy FILE to GUESTPATH (relative to the guest user's home) once the guest
+        /// agent connects (can be used multiple times)
+        #[arg(long = "inject", value_name = "FILE:GUESTPATH", value_parser = parse_injection)]
+        inject: Vec<(PathBuf, String)>,
     },
     
     /// Stop a running VM
@@ -113,9 +645,65 @@ enum Commands {
         name: String,
     },
     
+    /// Suspend a running VM's execution without shutting it down
+    Pause {
+        /// VM name
+        name: String,
+    },
+    
+    /// Continue a paused VM and reopen its viewer
+    Resume {
+        /// VM name
+        name: String,
+    },
+    
+    /// Change a VM's memory and vCPUs, live if it's running, and save them to its config
+    SetResources {
+        /// VM name
+        name: String,
+        
+        /// Memory in MB
+        #[arg(long)]
+        memory: Option<u64>,
+        
+        /// Number of CPUs
+        #[arg(long)]
+        vcpus: Option<u32>,
+    },
+    
+    /// Grow a shut-off VM's disk
+    ResizeDisk {
+        /// VM name
+        name: String,
+        
+        /// New disk size in GB; disks can't shrink
+        #[arg(long)]
+        size: u64,
+        
+        /// Also boot the VM and grow its root partition and filesystem (needs qemu-guest-agent in the guest)
+        #[arg(long)]
+        grow_fs: bool,
+    },
+    
     /// List all VMs
     List,
     
+    /// List host USB devices that can be passed through with `create --usb`
+    Usb,
+    
+    /// Live CPU and memory usage of running VMs
+    Top {
+        /// Seconds between refreshes
+        #[arg(long, default_value_t = 2)]
+        interval: u64,
+    },
+    
+    /// Show the state of a VM, including background installations
+    Status {
+        /// VM name
+        name: String,
+    },
+    
     /// Show passwords for all VMs
     Passwords,
     
@@ -127,99 +715,435 @@ enum Commands {
         /// Skip confirmation
         #[arg(short = 'y', long)]
         yes: bool,
+        
+        /// Save a recovery snapshot before destroying (see `recover`)
+        #[arg(long)]
+        snapshot_first: bool,
+        
+        /// Only list what would be removed
+        #[arg(long, visible_alias = "dry-run")]
+        plan: bool,
+    },
+    
+    /// Open the graphical display of a running VM, or of every running VM
+    Connect {
+        /// VM name
+        #[arg(required_unless_present = "all")]
+        name: Option<String>,
+        
+        /// Open viewers for all running VMs
+        #[arg(long, conflicts_with = "name")]
+        all: bool,
     },
     
     /// Connect to VM console
     Console {
         /// VM name
         name: String,
+        
+        /// Show the kickstart post-install log instead of attaching to the console
+        #[arg(long)]
+        log: bool,
     },
     
-}
-
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let cli = Cli::parse();
-    
-    match cli.command {
-        Commands::Create { name, system, flatpak, yes, config, memory, vcpus, disk } => {
-            create_vm(name, system, flatpak, yes, config, memory, vcpus, disk).await?;
-        }
-        
-        Commands::Start { name, seamless } => {
-            start_vm(name, seamless).await?;
-        }
-        
-        Commands::Stop { name } => {
-            stop_vm(name).await?;
-        }
+    /// Show the guest agent's journal interleaved with the host's window-proxy log
+    Logs {
+        /// VM name
+        name: String,
         
-        Commands::List => {
-            list_vms()?;
-        }
+        /// Keep streaming new lines
+        #[arg(long, short)]
+        follow: bool,
+    },
+    
+    /// Copy a file into a running VM's ~/Downloads
+    Push {
+        /// VM name
+        name: String,
         
-        Commands::Passwords => {
-            show_passwords()?;
-        }
+        /// File to send
+        file: String,
+    },
+    
+    /// Launch an application in a VM, starting the VM first if needed
+    Run {
+        /// VM name
+        name: String,
         
-        Commands::Destroy { name, yes } => {
-            destroy_vm(name, yes).await?;
-        }
+        /// Application ID or name, as listed by `status`
+        app: String,
         
-        Commands::Console { name } => {
-            connect_console(name)?;
-        }
+        /// Shut the VM down again once the application closes
+        #[arg(long)]
+        ephemeral: bool,
         
-    }
-    
-    Ok(())
-}
-
-async fn create_vm(
-    name: Option<String>,
-    system_packages: Vec<String>,
-    flatpak_packages: Vec<String>,
-    skip_confirm: bool,
-    config_path: Option<String>,
-    memory: u64,
-    vcpus: u32,
-    disk: u64,
-) -> Result<(), Box<dyn std::error::Error>> {
-    println!("🚀 VM Provisioner - Dynamic Package Installer");
-    println!("==============================================");
+        /// Window proxy port for this run only (default: the VM's proxy_port)
+        #[arg(long)]
+        proxy_port: Option<u16>,
+    },
     
-    let config = if let Some(path) = config_path {
-        // Load from file
-        let content = std::fs::read_to_string(path)?;
-        toml::from_str::<AppVMConfig>(&content)?
-    } else {
-        // Generate VM name if not provided
-        let vm_name = if let Some(name) = name {
-            name
-        } else if !flatpak_packages.is_empty() {
-            format!("{}-vm", flatpak_packages[0].replace(".", "-"))
-        } else if !system_packages.is_empty() {
-            format!("{}-vm", system_packages[0])
-        } else {
-            format!("app-vm-{}", std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs())
-        };
+    /// Launch an application inside a running VM
+    Open {
+        /// VM name
+        name: String,
         
-        // Create config with dynamic packages
-        AppVMConfig::new(vm_name, memory, vcpus, disk, system_packages, flatpak_packages)
-    };
+        /// Application ID or name, as listed by `status`
+        app: String,
+    },
     
-    // Display configuration
-    println!("\n📋 VM Configuration:");
-    println!("   Name: {}", config.name);
-    println!("   System Packages: {:?}", config.system_packages);
-    println!("   Flatpak Packages: {:?}", config.flatpak_packages);
-    println!("   Memory: {} MB", config.memory_mb);
-    println!("   vCPUs: {}", config.vcpus);
-    println!("   Disk: {} GB", config.disk_size_gb);
-    println!("   Graphics: {:?}", config.graphics_backend);
-    println!("   Network: {:?}", config.network_mode);
-    println!("   Clipboard: {}", if config.enable_clipboard { "✓" } else { "✗" });
-    println!("   Audio: {}", if config.enable_audio { "✓" } else { "✗" });
+    /// Install packages added to a running VM's config and refresh its auto-start apps
+    Rebuild {
+        /// VM name
+        name: String,
+    },
+    
+    /// Rebuild a VM from its saved configuration (keeps name, packages and password)
+    Reprovision {
+        /// VM name
+        name: String,
+        
+        /// Skip confirmation
+        #[arg(short = 'y', long)]
+        yes: bool,
+    },
+    
+    /// Inspect stored VM configuration
+    Config {
+        #[command(subcommand)]
+        action: ConfigCommand,
+    },
+    
+    /// Checkpoint a VM, e.g. before installing something risky
+    Snapshot {
+        /// VM name
+        name: String,
+        
+        /// Name for the snapshot
+        snapshot_name: String,
+    },
+    
+    /// List a VM's snapshots
+    SnapshotList {
+        /// VM name
+        name: String,
+    },
+    
+    /// Roll a VM back to one of its snapshots
+    SnapshotRevert {
+        /// VM name
+        name: String,
+        
+        /// Snapshot to revert to
+        snapshot_name: String,
+        
+        /// Revert even though the VM is running (its current state is lost)
+        #[arg(long)]
+        force: bool,
+    },
+    
+    /// Copy a VM into a new one with the same disk, packages and password
+    Clone {
+        /// VM to copy
+        source: String,
+        
+        /// Name for the new VM
+        target: String,
+        
+        /// Clone while the source keeps running (crash-consistent copy)
+        #[arg(long, visible_alias = "from-running")]
+        live: bool,
+        
+        /// Freeze guest filesystems during a live clone (needs qemu-guest-agent in the guest)
+        #[arg(long, requires = "live")]
+        quiesce: bool,
+    },
+    
+    /// Write a VM's definition to a file to share, without its password
+    Export {
+        /// VM name
+        name: String,
+        
+        /// File to write
+        out: PathBuf,
+        
+        /// Bundle the disk image too, as a tar archive (the VM must be shut off)
+        #[arg(long)]
+        with_disk: bool,
+    },
+    
+    /// Create a VM from a file written by `export`, with a new password
+    Import {
+        /// Exported config or bundle
+        file: PathBuf,
+        
+        /// Import under another name, e.g. when a VM with the exported name exists
+        #[arg(long)]
+        name: Option<String>,
+    },
+    
+    /// Change the password of a VM's user account
+    Passwd {
+        /// VM name
+        name: String,
+        
+        /// Type the new password instead of generating one
+        #[arg(long)]
+        prompt: bool,
+    },
+    
+    /// Restore a destroyed VM from its most recent recovery snapshot
+    Recover {
+        /// VM name
+        name: String,
+        
+        /// Skip confirmation
+        #[arg(short = 'y', long)]
+        yes: bool,
+    },
+    
+    /// Print a shell completion script, e.g. `vm-provisioner completions bash > ~/.local/share/bash-completion/completions/vm-provisioner`
+    Completions {
+        /// Shell to generate the script for
+        #[arg(required_unless_present = "list_names")]
+        shell: Option<Shell>,
+        
+        /// Print the configured VM names instead; the scripts call this to complete them
+        #[arg(long, hide = true, conflicts_with = "shell")]
+        list_names: bool,
+    },
+    
+    /// Stop (or destroy) a VM whose lifetime has run out; run by the timer `start` schedules
+    #[command(hide = true)]
+    Expire {
+        /// VM name
+        name: String,
+    },
+    
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    output::init(cli.plain);
+    libvirt::init(&cli.connect);
+    NO_ENCRYPT.store(cli.no_encrypt, Ordering::Relaxed);
+    if cli.no_encrypt {
+        check_store_unencrypted(&format!("{}/.config/vm-provisioner", std::env::var("HOME")?))?;
+    }
+    
+    match cli.command {
+        Commands::Create(args) => {
+            create_vm(*args).await?;
+        }
+        
+        Commands::CreateAll { dir, parallel, yes } => {
+            create_all(&dir, parallel as usize, yes).await?;
+        }
+        
+        Commands::Start { name, seamless, proxy_port, inject } => {
+            start_vm(name, seamless, proxy_port, inject).await?;
+        }
+        
+        Commands::Stop { name } => {
+            stop_vm(name).await?;
+        }
+        
+        Commands::Pause { name } => {
+            pause_vm(&name)?;
+        }
+        
+        Commands::Resume { name } => {
+            resume_vm(&name)?;
+        }
+        
+        Commands::SetResources { name, memory, vcpus } => {
+            set_resources(&name, memory, vcpus)?;
+        }
+        
+        Commands::ResizeDisk { name, size, grow_fs } => {
+            resize_disk(&name, size, grow_fs)?;
+        }
+        
+        Commands::List => {
+            list_vms()?;
+        }
+        
+        Commands::Usb => {
+            list_usb_devices()?;
+        }
+        
+        Commands::Top { interval } => {
+            top(interval).await?;
+        }
+        
+        Commands::Passwords => {
+            show_passwords()?;
+        }
+        
+        Commands::Destroy { name, yes, snapshot_first, plan } => {
+            destroy_vm(name, yes, snapshot_first, plan).await?;
+        }
+        
+        Commands::Connect { name, all } => {
+            let names = if all { provisioner::list_running_domains()? } else { name.into_iter().collect() };
+            connect_displays(&names)?;
+        }
+        
+        Commands::Console { name, log: false } => {
+            connect_console(name)?;
+        }
+        
+        Commands::Console { name, log: true } => {
+            show_post_install_log(&name)?;
+        }
+        
+        Commands::Logs { name, follow } => {
+            show_logs(&name, follow)?;
+        }
+        
+        Commands::Push { name, file } => {
+            push_file(&name, Path::new(&file), None)?;
+        }
+        
+        Commands::Run { name, app, ephemeral, proxy_port } => {
+            run_app(name, app, ephemeral, proxy_port)?;
+        }
+        
+        Commands::Open { name, app } => {
+            open_app(&name, &app)?;
+        }
+        
+        Commands::Rebuild { name } => {
+            rebuild_vm(&name)?;
+        }
+        
+        Commands::Reprovision { name, yes } => {
+            reprovision_vm(name, yes).await?;
+        }
+        
+        Commands::Status { name } => {
+            show_status(&name)?;
+        }
+        
+        Commands::Config { action: ConfigCommand::Show { name, show_password, format } } => {
+            show_config(&name, show_password, format)?;
+        }
+        
+        Commands::Config { action: ConfigCommand::Edit { name } } => {
+            edit_config(&name)?;
+        }
+        
+        Commands::Snapshot { name, snapshot_name } => {
+            AppVMProvisioner::new(load_vm_config(&name)?).create_snapshot(&snapshot_name)?;
+        }
+        
+        Commands::SnapshotList { name } => {
+            outln!("{}", AppVMProvisioner::new(load_vm_config(&name)?).list_snapshots()?);
+        }
+        
+        Commands::SnapshotRevert { name, snapshot_name, force } => {
+            revert_snapshot(&name, &snapshot_name, force)?;
+        }
+        
+        Commands::Clone { source, target, live, quiesce } => {
+            clone_vm(source, target, live, quiesce)?;
+        }
+        
+        Commands::Export { name, out, with_disk } => {
+            export_vm(&name, &out, with_disk)?;
+        }
+        
+        Commands::Import { file, name } => {
+            import_vm(&file, name)?;
+        }
+        
+        Commands::Passwd { name, prompt } => {
+            change_password(name, prompt)?;
+        }
+        
+        Commands::Recover { name, yes } => {
+            recover_vm(name, yes)?;
+        }
+        
+        Commands::Completions { shell, list_names } => {
+            if list_names {
+                for name in configured_vm_names()? {
+                    println!("{}", name);
+                }
+            } else if let Some(shell) = shell {
+                print!("{}", completion_script(shell));
+            }
+        }
+        
+        Commands::Expire { name } => {
+            expire_vm(name).await?;
+        }
+        
+    }
+    
+    Ok(())
+}
+
+async fn create_vm(args: CreateArgs) -> Result<(), Box<dyn std::error::Error>> {
+    outln!("🚀 VM Provisioner - Dynamic Package Installer");
+    outln!("==============================================");
+    
+    let skip_confirm = args.yes;
+    let no_wait = args.no_wait;
+    
+    let dry_run = args.dry_run;
+    let download_only = args.download_only;
+    
+    let mut config = args.into_config()?;
+    config.validate()?;
+    if let Some(warning) = config.adjust_graphics_for_arch(std::env::consts::ARCH)? {
+        outln!("⚠️  {}", warning);
+    }
+    let claimed = claimed_proxy_ports(&config.name)?;
+    assign_proxy_port(&mut config, &claimed)?;
+    
+    if dry_run {
+        return AppVMProvisioner::new(config).wait_for_install(!no_wait).dry_run();
+    }
+    if download_only {
+        return AppVMProvisioner::new(config).download_only();
+    }
+    
+    // A half-destroyed domain with the same name would make virt-install fail mid-provision
+    if provisioner::list_domains()?.contains(&config.name) {
+        outln!("⚠️  A libvirt domain named '{}' already exists", config.name);
+        
+        let cleanup = !skip_confirm && Confirm::new()
+            .with_prompt("Remove the leftover domain and its storage before continuing?")
+            .default(false)
+            .interact()?;
+            
+        if !cleanup {
+            return Err(format!(
+                "Domain '{0}' already exists. Remove it with: vm-provisioner destroy {0} (or: virsh undefine {0} --remove-all-storage --nvram)",
+                config.name
+            ).into());
+        }
+        
+        AppVMProvisioner::new(config.clone()).destroy_vm()?;
+    }
+    
+    // Display configuration
+    outln!("\n📋 VM Configuration:");
+    outln!("   Name: {}", config.name);
+    outln!("   Hostname: {}", config.guest_hostname());
+    outln!("   System Packages: {:?}", config.system_packages);
+    outln!("   Flatpak Packages: {:?}", config.flatpak_packages);
+    outln!("   Memory: {} MB", config.memory_mb);
+    if let Some(install_memory) = config.install_memory_mb {
+        outln!("   Install Memory: {} MB", install_memory);
+    }
+    outln!("   vCPUs: {}", config.vcpus);
+    outln!("   Disk: {} GB", config.disk_size_gb);
+    outln!("   Graphics: {:?}", config.graphics_backend);
+    outln!("   Network: {:?}", config.network_mode);
+    outln!("   Clipboard: {:?}", config.effective_clipboard_transport());
+    outln!("   Audio: {}", if config.enable_audio { "✓" } else { "✗" });
     
     if !skip_confirm {
         let confirm = Confirm::new()
@@ -228,233 +1152,2215 @@ async fn create_vm(
             .interact()?;
             
         if !confirm {
-            println!("❌ VM creation cancelled");
+            outln!("❌ VM creation cancelled");
+            return Ok(());
+        }
+    }
+    
+    // Save configuration for future reference
+    let config_file = save_vm_config(&config)?;
+    outln!("💾 Configuration saved to: {}", config_file);
+    let config_dir = format!("{}/.config/vm-provisioner", std::env::var("HOME")?);
+    
+    // Save password to centralized password file
+    let mut passwords = VMPasswords::load_or_create(&config_dir)?;
+    passwords.add_vm(&config.name, &config.user_password);
+    passwords.save(&config_dir)?;
+    
+    // Create and provision VM
+    let provisioner = AppVMProvisioner::new(config.clone())
+        .wait_for_install(!no_wait)
+        .assume_yes(skip_confirm);
+    provisioner.provision_vm().await?;
+    
+    if no_wait {
+        outln!("\n⏳ VM creation started!");
+    } else {
+        outln!("\n✅ VM created successfully!");
+    }
+    outln!("   VM Name: {}", config.name);
+    outln!("   Username: user");
+    outln!("   Password: {}", config.user_password);
+    outln!("   Config: {}", config_file);
+    outln!("   Passwords: {}/.config/vm-provisioner/vm-passwords.toml", std::env::var("HOME")?);
+    outln!("   Start with: vm-provisioner start {}", config.name);
+    
+    Ok(())
+}
+
+/// Read a config that describes a new VM (`create --config`, `create-all`)
+fn load_template(path: &Path) -> Result<AppVMConfig, Box<dyn std::error::Error>> {
+    let content = std::fs::read_to_string(path)?;
+    // A template for a new VM rather than a saved config to migrate
+    let mut config = toml::from_str::<AppVMConfig>(&content)?;
+    config.version = config::CONFIG_VERSION;
+    Ok(config)
+}
+
+/// Provision every config in `dir`, `parallel` installs at a time. A VM that fails
+/// doesn't stop the others; the summary at the end lists what failed and why.
+async fn create_all(dir: &Path, parallel: usize, skip_confirm: bool) -> Result<(), Box<dyn std::error::Error>> {
+    outln!("🚀 Creating VMs from {}", dir.display());
+    
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)
+        .map_err(|e| format!("Can't read {}: {}", dir.display(), e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().and_then(|s| s.to_str()) == Some("toml") && !is_passwords_file(path))
+        .collect();
+    paths.sort();
+    if paths.is_empty() {
+        return Err(format!("No *.toml configs in {}", dir.display()).into());
+    }
+    let total = paths.len();
+    
+    // Check everything before creating anything, so a typo doesn't leave half a fleet
+    let domains = provisioner::list_domains()?;
+    let mut claimed = claimed_proxy_ports("")?;
+    let mut configs: Vec<AppVMConfig> = Vec::new();
+    let mut failed: Vec<(String, String)> = Vec::new();
+    for path in &paths {
+        let checked = load_template(path).and_then(|mut config| {
+            let profiles = config.profiles.clone();
+            config.apply_profiles(&profiles)?;
+            config.validate()?;
+            if let Some(warning) = config.adjust_graphics_for_arch(std::env::consts::ARCH)? {
+                outln!("⚠️  {}: {}", config.name, warning);
+            }
+            if domains.contains(&config.name) {
+                return Err(format!("a libvirt domain named '{0}' already exists (remove it with: vm-provisioner destroy {0})", config.name).into());
+            }
+            if configs.iter().any(|other| other.name == config.name) {
+                return Err(format!("another config in {} also creates '{}'", dir.display(), config.name).into());
+            }
+            assign_proxy_port(&mut config, &claimed)?;
+            Ok(config)
+        });
+        match checked {
+            Ok(config) => {
+                claimed.insert(config.proxy_port);
+                configs.push(config);
+            }
+            Err(e) => {
+                let file = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+                let error = e.to_string().trim().replace('\n', " ");
+                eoutln!("❌ {}: {}", file, error);
+                failed.push((file, error));
+            }
+        }
+    }
+    
+    if configs.is_empty() {
+        return Err(format!("None of the {} configs in {} can be created", total, dir.display()).into());
+    }
+    
+    outln!("\n📋 VMs to create ({} at a time):", parallel);
+    for config in &configs {
+        outln!("   {} ({} MB, {} vCPUs, {} GB)", config.name, config.memory_mb, config.vcpus, config.disk_size_gb);
+    }
+    if !skip_confirm {
+        let confirm = Confirm::new()
+            .with_prompt(format!("Proceed with creating {} VMs?", configs.len()))
+            .default(true)
+            .interact()?;
+            
+        if !confirm {
+            outln!("❌ VM creation cancelled");
             return Ok(());
         }
     }
     
-    // Save configuration for future reference
     let config_dir = format!("{}/.config/vm-provisioner", std::env::var("HOME")?);
-    std::fs::create_dir_all(&config_dir)?;
-    let config_file = format!("{}/{}.toml", config_dir, config.name);
-    std::fs::write(&config_file, toml::to_string_pretty(&config)?)?;
-    println!("💾 Configuration saved to: {}", config_file);
+    let mut passwords = VMPasswords::load_or_create(&config_dir)?;
+    for config in &configs {
+        save_vm_config(config)?;
+        passwords.add_vm(&config.name, &config.user_password);
+    }
+    passwords.save(&config_dir)?;
+    
+    // Installs of the same release would race each other downloading one ISO; fetch each once up front
+    let mut downloads: HashMap<String, Result<(), String>> = HashMap::new();
+    let mut ready = Vec::new();
+    for config in configs {
+        let provisioner = AppVMProvisioner::new(config.clone());
+        if let Some(iso) = provisioner.downloaded_iso_path() {
+            let download = downloads.entry(iso)
+                .or_insert_with(|| provisioner.download_only().map_err(|e| e.to_string()));
+            if let Err(e) = download {
+                failed.push((config.name, format!("ISO download failed: {}", e)));
+                continue;
+            }
+        }
+        ready.push(config);
+    }
+    
+    let slots = Arc::new(tokio::sync::Semaphore::new(parallel));
+    let mut installs = tokio::task::JoinSet::new();
+    for config in ready {
+        let slots = slots.clone();
+        let runtime = tokio::runtime::Handle::current();
+        installs.spawn(async move {
+            let _slot = slots.acquire_owned().await;
+            let name = config.name.clone();
+            // provision_vm blocks on virt-install, so each install gets a thread of its own
+            let result = tokio::task::spawn_blocking(move || {
+                let provisioner = AppVMProvisioner::new(config)
+                    .assume_yes(true)
+                    .iso_verified(true);
+                runtime.block_on(provisioner.provision_vm()).map_err(|e| e.to_string())
+            }).await.unwrap_or_else(|e| Err(e.to_string()));
+            (name, result)
+        });
+    }
+    
+    let mut succeeded = Vec::new();
+    while let Some(install) = installs.join_next().await {
+        match install? {
+            (name, Ok(())) => succeeded.push(name),
+            (name, Err(e)) => failed.push((name, e)),
+        }
+    }
+    succeeded.sort();
+    
+    outln!("\n📊 Summary:");
+    for name in &succeeded {
+        outln!("   ✅ {}", name);
+    }
+    for (name, error) in &failed {
+        outln!("   ❌ {}: {}", name, error.trim().replace('\n', " "));
+    }
+    outln!("   Passwords: {}/vm-passwords.toml (or: vm-provisioner passwords)", config_dir);
+    
+    if !failed.is_empty() {
+        return Err(format!("{} of {} VMs could not be created", failed.len(), total).into());
+    }
+    Ok(())
+}
+
+async fn start_vm(
+    name: String,
+    seamless: bool,
+    proxy_port: Option<u16>,
+    inject: Vec<(PathBuf, String)>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    outln!("▶️  Starting VM: {}", name);
+    
+    // Load VM configuration
+    let config_file = format!("{}/.config/vm-provisioner/{}.toml", 
+                             std::env::var("HOME")?, name);
+    
+    if !Path::new(&config_file).exists() {
+        eoutln!("❌ VM configuration not found: {}", name);
+        eoutln!("   Available VMs:");
+        list_vms()?;
+        std::process::exit(1);
+    }
+    
+    let content = std::fs::read_to_string(&config_file)?;
+    let mut config = AppVMConfig::from_toml(&content)?;
+    
+    if !check_lifetime(&name, &config).await? {
+        return Ok(());
+    }
+    
+    override_proxy_port(&mut config, proxy_port);
+    // A `start` or `run` still running for this VM keeps its proxy and guest agent connection
+    let reuse_proxy = window_proxy_running(&name);
+    let transport = proxy_transport(&config);
+    if seamless && !reuse_proxy {
+        ensure_proxy_available(&name, &transport)?;
+    }
+    
+    // Files for the guest, checked now rather than after the VM has booted
+    let injections: Vec<(PathBuf, String)> = config.start_user_data.iter()
+        .map(|path| (path.clone(), USER_DATA_GUEST_PATH.to_string()))
+        .chain(inject)
+        .collect();
+    if let Some((missing, _)) = injections.iter().find(|(file, _)| !file.is_file()) {
+        return Err(format!("File to inject not found: {}", missing.display()).into());
+    }
+    
+    // Start the VM
+    let provisioner = AppVMProvisioner::new(config.clone());
+    provisioner.start_vm()?;
+    
+    // Start window proxy for seamless integration
+    if !seamless {
+        outln!("ℹ️  Window proxy not started (--seamless false); seamless windows, push and open are unavailable");
+        if !injections.is_empty() {
+            eoutln!("⚠️  {} file(s) were not injected; injecting needs the window proxy", injections.len());
+        }
+    } else if reuse_proxy {
+        outln!("🪟 Window proxy for {} is already running; reusing it", name);
+        for (file, guest_path) in &injections {
+            if let Err(e) = push_file(&name, file, Some(guest_path)) {
+                eoutln!("⚠️  {} was not injected: {}", file.display(), e);
+            }
+        }
+    }
+    let mut events = None;
+    if seamless && !reuse_proxy {
+        let (events_tx, events_rx) = std::sync::mpsc::channel();
+        spawn_window_proxy(&name, &config, transport, Some(events_tx))?;
+        
+        outln!("⏳ Waiting for guest agent (up to {}s)...", GUEST_AGENT_TIMEOUT.as_secs());
+        if wait_for_guest_agent(&events_rx, GUEST_AGENT_TIMEOUT)? {
+            outln!("✅ Guest agent connected");
+            for (file, guest_path) in &injections {
+                push_file(&name, file, Some(guest_path))?;
+            }
+        } else {
+            warn_guest_agent_missing(&name);
+            if !injections.is_empty() {
+                eoutln!("⚠️  {} file(s) were not injected", injections.len());
+            }
+        }
+        events = Some(events_rx);
+    }
+    
+    match config.effective_clipboard_transport() {
+        ClipboardTransport::Spice => outln!("   Clipboard sharing via SPICE viewer"),
+        ClipboardTransport::Custom => outln!("   Clipboard sharing via the window proxy"),
+        ClipboardTransport::None => {}
+    }
+    
+    // Display login credentials
+    outln!("\n🔑 VM Login Credentials:");
+    outln!("   Username: user");
+    outln!("   Password: {}", config.user_password);
+    outln!("   Console: {}", libvirt::virsh_hint(&format!("console {}", name)));
+    
+    if let Some(events) = events {
+        serve_window_proxy(&name, &events);
+    }
+    Ok(())
+}
+
+/// Keep this process, and with it the window proxy, alive until the VM stops.
+/// `push`, `open` and online `passwd` all go through the proxy's control socket.
+fn serve_window_proxy(name: &str, events: &std::sync::mpsc::Receiver<WindowMessage>) {
+    use std::sync::mpsc::RecvTimeoutError;
+    
+    outln!("\n🪟 Serving the window proxy for {} until it shuts down", name);
+    outln!("   Ctrl+C stops the proxy only; the VM keeps running (vm-provisioner stop {})", name);
+    
+    let mut next_check = std::time::Instant::now() + VM_STATE_POLL_INTERVAL;
+    loop {
+        // The proxy logs guest messages itself; these copies only keep the channel drained
+        match events.recv_timeout(next_check.saturating_duration_since(std::time::Instant::now())) {
+            Ok(_) => continue,
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                eoutln!("⚠️  Window proxy for {} stopped", name);
+                return;
+            }
+        }
+        
+        let status = get_vm_status(name);
+        if matches!(status.as_str(), "shut off" | "crashed" | "not created") {
+            outln!("⏹️  {} is {}; window proxy stopped", name, status);
+            return;
+        }
+        next_check = std::time::Instant::now() + VM_STATE_POLL_INTERVAL;
+    }
+}
+
+/// Launch the window proxy in the background, returning once it is listening
+fn spawn_window_proxy(
+    name: &str,
+    config: &AppVMConfig,
+    mut transport: Transport,
+    events: Option<std::sync::mpsc::Sender<WindowMessage>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    outln!("🪟 Starting window proxy...");
+    
+    // Only the VM itself may connect over vsock; its context ID is known once it runs
+    if let Transport::Vsock { cid, .. } = &mut transport {
+        *cid = provisioner::domain_vsock_cid(name);
+    }
+    
+    let port = transport_port(&transport);
+    let mut integration = VMIntegrationHost::new(name.to_string()).listen_on(transport);
+    if config.open_urls_on_host {
+        integration = integration.allow_open_url(config.open_url_allowlist.clone());
+    }
+    if config.enable_notifications {
+        integration = integration.forward_notifications();
+    }
+    if config.effective_clipboard_transport() == ClipboardTransport::Custom {
+        integration = integration.share_clipboard();
+    }
+    if let Some(events) = events {
+        integration = integration.forward_events(events);
+    }
+    if config.proxy_tls {
+        let path = tls::host_identity_path(name)?;
+        if !path.exists() {
+            return Err(format!("{} is missing; the guest agent only trusts the identity it was installed with, \
+                                so reinstall it: vm-provisioner reprovision {}", path.display(), name).into());
+        }
+        integration = integration.require_tls(tls::Identity::load(&path.to_string_lossy())?);
+    }
+    let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        if let Err(e) = integration.start(ready_tx) {
+            eoutln!("Window integration error: {}", e);
+        }
+    });
+    
+    // Only report success once the proxy is actually listening
+    match ready_rx.recv_timeout(std::time::Duration::from_secs(5)) {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => return Err(format!(
+            "Window proxy failed to start: {} (the VM is running; stop it with: vm-provisioner stop {})", e, name
+        ).into()),
+        Err(_) => return Err("Window proxy did not start within 5 seconds".into()),
+    }
+    
+    if let Some(port) = port {
+        VMState::update(name, |state| state.proxy_port = Some(port))?;
+    }
+    outln!("✅ Window proxy started");
+    Ok(())
+}
+
+/// Whether a `start` or `run` for this VM still serves its window proxy
+fn window_proxy_running(name: &str) -> bool {
+    std::os::unix::net::UnixStream::connect(window_proxy::control_socket_path(name)).is_ok()
+}
+
+/// The TCP or vsock port a transport listens on; unix sockets are per VM already
+fn transport_port(transport: &Transport) -> Option<u16> {
+    match transport {
+        Transport::Tcp(addr) => Some(addr.port()),
+        Transport::Vsock { port, .. } => u16::try_from(*port).ok(),
+        Transport::Unix(_) => None,
+    }
+}
+
+/// Like `Transport::ensure_available`, but names the VM whose proxy holds the port
+fn ensure_proxy_available(name: &str, transport: &Transport) -> Result<(), Box<dyn std::error::Error>> {
+    let Err(e) = transport.ensure_available() else {
+        return Ok(());
+    };
+    let holder = transport_port(transport).and_then(|port| {
+        configured_vm_names().ok()?.into_iter().find(|other| {
+            other != name
+                && VMState::load(other).is_ok_and(|state| state.proxy_port == Some(port))
+                && window_proxy_running(other)
+        })
+    });
+    match holder {
+        Some(other) => Err(format!(
+            "The window proxy of {} already listens on {}. Stop it first, or give {} a port of its own \
+             (proxy_port in `vm-provisioner config edit {}`, then reprovision)", other, transport, name, name
+        ).into()),
+        None => Err(e),
+    }
+}
+
+/// Window proxy ports in the configs of VMs other than `name`. A config that
+/// can't be read is skipped with a warning, as `list` does.
+fn claimed_proxy_ports(name: &str) -> Result<HashSet<u16>, Box<dyn std::error::Error>> {
+    let config_dir = format!("{}/.config/vm-provisioner", std::env::var("HOME")?);
+    let mut ports = HashSet::new();
+    let Ok(entries) = std::fs::read_dir(&config_dir) else {
+        return Ok(ports);
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|s| s.to_str()) != Some("toml") || is_passwords_file(&path) {
+            continue;
+        }
+        match std::fs::read_to_string(&path).map_err(Into::into).and_then(|content| AppVMConfig::from_toml(&content)) {
+            Ok(config) if config.name != name => {
+                ports.insert(config.proxy_port);
+            }
+            Ok(_) => {}
+            Err(e) => eoutln!("⚠️  Can't read {}, so its window proxy port may be handed out again: {}",
+                              path.display(), e.to_string().trim().replace('\n', " ")),
+        }
+    }
+    Ok(ports)
+}
+
+/// The first port from `port` upward that no other VM's window proxy uses
+fn free_proxy_port(port: u16, claimed: &HashSet<u16>) -> Option<u16> {
+    (port..=u16::MAX).find(|port| !claimed.contains(port))
+}
+
+/// Give a new VM a window proxy port of its own, so VMs can run side by side.
+/// The guest agent is built for this port, so it has to be settled before install.
+fn assign_proxy_port(config: &mut AppVMConfig, claimed: &HashSet<u16>) -> Result<(), Box<dyn std::error::Error>> {
+    if !claimed.contains(&config.proxy_port) {
+        return Ok(());
+    }
+    let port = free_proxy_port(config.proxy_port, claimed)
+        .ok_or_else(|| format!("No free window proxy port from {} upward", config.proxy_port))?;
+    outln!("ℹ️  Window proxy port {} is used by another VM; {} gets port {}", config.proxy_port, config.name, port);
+    config.proxy_port = port;
+    Ok(())
+}
+
+/// Apply a one-off `--proxy-port`. The guest agent keeps dialing the port it was
+/// installed with, so a different one only helps if the guest was changed to match.
+fn override_proxy_port(config: &mut AppVMConfig, proxy_port: Option<u16>) {
+    if let Some(port) = proxy_port.filter(|port| *port != config.proxy_port) {
+        outln!("ℹ️  Window proxy on port {} (the guest agent in {} was installed for port {})", 
+               port, config.name, config.proxy_port);
+        config.proxy_port = port;
+    }
+}
+
+/// Where the window proxy listens for this VM, warning when it has to fall back to TCP
+fn proxy_transport(config: &AppVMConfig) -> Transport {
+    let (transport, fallback) = Transport::select(config);
+    if let Some(reason) = fallback {
+        eoutln!("⚠️  {}; the window proxy falls back to {}", reason, transport);
+        eoutln!("   The guest agent in {} only reaches it if it was installed with proxy_transport = \"Tcp\"", config.name);
+    }
+    transport
+}
+
+/// Wait until the guest agent has connected, or `timeout` passes without it
+fn wait_for_guest_agent(
+    events: &std::sync::mpsc::Receiver<WindowMessage>,
+    timeout: std::time::Duration,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    use std::sync::mpsc::RecvTimeoutError;
+    
+    // The guest agent announces its applications right after connecting
+    let deadline = std::time::Instant::now() + timeout;
+    loop {
+        match events.recv_timeout(deadline.saturating_duration_since(std::time::Instant::now())) {
+            Ok(WindowMessage::ApplicationList { .. }) => return Ok(true),
+            Ok(_) => {}
+            Err(RecvTimeoutError::Timeout) => return Ok(false),
+            Err(RecvTimeoutError::Disconnected) => return Err("Window proxy stopped".into()),
+        }
+    }
+}
+
+fn warn_guest_agent_missing(name: &str) {
+    eoutln!("⚠️  The guest agent in {} has not connected.", name);
+    eoutln!("   Seamless windows, app launching and file transfer will not work until it does.");
+    eoutln!("   The agent is built during installation; check the build output in the VM with:");
+    eoutln!("     systemctl status guest-agent; less /var/log/kickstart-post-detailed.log");
+    eoutln!("   To rebuild the VM with a fresh agent: vm-provisioner reprovision {}", name);
+}
+
+/// Where `start_user_data` lands in the guest, relative to the user's home
+const USER_DATA_GUEST_PATH: &str = ".config/vm-provisioner/user-data";
+
+/// `FILE:GUESTPATH` for `start --inject`. The guest path must stay inside the
+/// guest user's home; the guest agent enforces this too.
+fn parse_injection(spec: &str) -> Result<(PathBuf, String), String> {
+    use std::path::Component;
+    
+    let (file, guest_path) = spec.split_once(':')
+        .filter(|(file, guest_path)| !file.is_empty() && !guest_path.is_empty())
+        .ok_or_else(|| format!("expected FILE:GUESTPATH, got {}", spec))?;
+    
+    let relative = Path::new(guest_path.strip_prefix("~/").unwrap_or(guest_path));
+    if relative.as_os_str().is_empty() || !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(format!("guest path {} must be relative to the home directory, without '..'", guest_path));
+    }
+    
+    Ok((PathBuf::from(file), guest_path.to_string()))
+}
+
+/// How long `start` and `run` wait for a freshly started VM's guest agent to connect
+const GUEST_AGENT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(180);
+
+/// How long `run` waits for the guest to confirm an application launch
+const APP_LAUNCH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// How long `passwd` waits for the guest agent to report the password change
+const PASSWORD_CHANGE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// How often a foreground `start` or `run` checks whether its VM is still up
+const VM_STATE_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+fn run_app(name: String, app: String, ephemeral: bool, proxy_port: Option<u16>) -> Result<(), Box<dyn std::error::Error>> {
+    let config_file = format!("{}/.config/vm-provisioner/{}.toml", 
+                             std::env::var("HOME")?, name);
+    if !Path::new(&config_file).exists() {
+        return Err(format!("VM configuration not found: {}", name).into());
+    }
+    let mut config = AppVMConfig::from_toml(&std::fs::read_to_string(&config_file)?)?;
+    
+    let running = get_vm_status(&name) == "running";
+    
+    // Another process already owns the proxy; hand the launch over to it
+    if running && window_proxy_running(&name) {
+        if ephemeral {
+            outln!("ℹ️  {} was already running, --ephemeral has no effect", name);
+        }
+        return open_app(&name, &app);
+    }
+    
+    override_proxy_port(&mut config, proxy_port);
+    let transport = proxy_transport(&config);
+    ensure_proxy_available(&name, &transport)?;
+    
+    outln!("🚀 Running {} in VM: {}", app, name);
+    let provisioner = AppVMProvisioner::new(config.clone());
+    if !running {
+        provisioner.start_vm()?;
+    }
+    
+    let (events_tx, events) = std::sync::mpsc::channel();
+    spawn_window_proxy(&name, &config, transport, Some(events_tx))?;
+    
+    outln!("⏳ Waiting for guest agent (up to {}s)...", GUEST_AGENT_TIMEOUT.as_secs());
+    if !wait_for_guest_agent(&events, GUEST_AGENT_TIMEOUT)? {
+        warn_guest_agent_missing(&name);
+        return Err(format!("Guest agent in {} did not connect within {}s", name, GUEST_AGENT_TIMEOUT.as_secs()).into());
+    }
+    
+    // The guest reports launches by display name, which may differ from what was asked for
+    let app_name = VMState::load(&name)?.applications.into_iter()
+        .find(|entry| entry.id == app || entry.name.eq_ignore_ascii_case(&app))
+        .map(|entry| entry.name)
+        .unwrap_or_else(|| app.clone());
+    open_app(&name, &app)?;
+    
+    let deadline = std::time::Instant::now() + APP_LAUNCH_TIMEOUT;
+    let pid = loop {
+        let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+        match events.recv_timeout(remaining) {
+            Ok(WindowMessage::ApplicationStarted { app_name: started, pid }) if started == app_name => break pid,
+            Ok(WindowMessage::GuestLog { level: protocol::LogLevel::Error, message }) if message.starts_with("Failed to launch") => {
+                return Err(message.into());
+            }
+            Ok(_) => {}
+            Err(_) => return Err(format!("{} did not report starting {} within {}s", name, app, APP_LAUNCH_TIMEOUT.as_secs()).into()),
+        }
+    };
+    outln!("✅ {} started in {} (PID: {})", app_name, name, pid);
+    
+    if !ephemeral {
+        serve_window_proxy(&name, &events);
+        return Ok(());
+    }
+    if running {
+        outln!("ℹ️  {} was already running, leaving it up when {} exits", name, app_name);
+        serve_window_proxy(&name, &events);
+        return Ok(());
+    }
+    
+    // Stop the VM once the app exits or the last window opened after launch closes
+    outln!("⏳ {} will stop when {} closes", name, app_name);
+    let mut windows = std::collections::HashSet::new();
+    loop {
+        match events.recv() {
+            Ok(WindowMessage::WindowCreated { id, .. }) => {
+                windows.insert(id);
+            }
+            Ok(WindowMessage::WindowDestroyed { id }) => {
+                if windows.remove(&id) && windows.is_empty() {
+                    break;
+                }
+            }
+            Ok(WindowMessage::ApplicationStopped { pid: stopped, .. }) if stopped == pid => break,
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+    
+    outln!("👋 {} closed", app_name);
+    provisioner.stop_vm()
+}
+
+/// Start the VM's lifetime on first start and arm the expiry timer. Returns false
+/// if the VM has already expired and must not be started.
+async fn check_lifetime(name: &str, config: &AppVMConfig) -> Result<bool, Box<dyn std::error::Error>> {
+    let Some(lifetime) = config.max_lifetime else {
+        return Ok(true);
+    };
+    
+    let now = state::now();
+    let expires_at = match VMState::load(name)?.expires_at {
+        Some(expires_at) => expires_at,
+        None => {
+            let expires_at = now + lifetime.as_secs();
+            VMState::update(name, |state| state.expires_at = Some(expires_at))?;
+            expires_at
+        }
+    };
+    
+    if expires_at <= now {
+        outln!("⌛ VM {} has expired (max_lifetime {})", name, config::format_duration(lifetime));
+        if config.auto_destroy_on_expiry || Confirm::new().with_prompt("Destroy it now?").default(false).interact()? {
+            destroy_vm(name.to_string(), true, false, false).await?;
+        }
+        return Ok(false);
+    }
+    
+    outln!("⌛ VM expires {}", state::describe_expiry(expires_at, now));
+    schedule_expiry(name, expires_at - now);
+    Ok(true)
+}
+
+/// Arm a transient systemd user timer that runs `vm-provisioner expire <name>`
+fn schedule_expiry(name: &str, secs: u64) {
+    let scheduled = std::env::current_exe().ok().and_then(|exe| {
+        std::process::Command::new("systemd-run")
+            .args(["--user", "--collect", "--quiet"])
+            .arg(format!("--unit=vm-provisioner-expire-{}", name))
+            .arg(format!("--on-active={}s", secs))
+            .arg(exe)
+            .args(["--connect", libvirt::uri(), "expire", name])
+            .status()
+            .ok()
+    });
+    
+    // An already-armed timer from an earlier start also makes this fail, which is fine
+    if !scheduled.is_some_and(|status| status.success()) {
+        outln!("   (could not arm an expiry timer; the VM will be stopped on the next start after it expires)");
+    }
+}
+
+async fn expire_vm(name: String) -> Result<(), Box<dyn std::error::Error>> {
+    let config = load_vm_config(&name)?;
+    let Some(expires_at) = VMState::load(&name)?.expires_at else {
+        return Ok(());
+    };
+    if expires_at > state::now() {
+        outln!("⌛ VM {} expires {}", name, state::describe_expiry(expires_at, state::now()));
+        return Ok(());
+    }
+    
+    outln!("⌛ VM {} has reached its maximum lifetime", name);
+    if config.auto_destroy_on_expiry {
+        destroy_vm(name, true, false, false).await
+    } else {
+        AppVMProvisioner::new(config).stop_vm()
+    }
+}
+
+async fn stop_vm(name: String) -> Result<(), Box<dyn std::error::Error>> {
+    outln!("⏹️  Stopping VM: {}", name);
+    
+    // Load VM configuration
+    let config_file = format!("{}/.config/vm-provisioner/{}.toml", 
+                             std::env::var("HOME")?, name);
+    
+    if !Path::new(&config_file).exists() {
+        eoutln!("❌ VM configuration not found: {}", name);
+        std::process::exit(1);
+    }
+    
+    let content = std::fs::read_to_string(&config_file)?;
+    let config = AppVMConfig::from_toml(&content)?;
+    
+    let provisioner = AppVMProvisioner::new(config);
+    provisioner.stop_vm()?;
+    
+    outln!("✅ VM stopped");
+    
+    Ok(())
+}
+
+fn list_vms() -> Result<(), Box<dyn std::error::Error>> {
+    outln!("📋 Available VMs:");
+    outln!("================");
+    
+    let config_dir = format!("{}/.config/vm-provisioner", std::env::var("HOME")?);
+    
+    if !Path::new(&config_dir).exists() {
+        outln!("No VMs configured yet.");
+        outln!("Create one with: vm-provisioner create");
+        return Ok(());
+    }
+    
+    // List all .toml files
+    let (mut listed, mut unreadable) = (0, 0);
+    for entry in std::fs::read_dir(&config_dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        
+        if path.extension().and_then(|s| s.to_str()) == Some("toml") && !is_passwords_file(&path) {
+            listed += 1;
+            let content = std::fs::read_to_string(&path)?;
+            let config = match AppVMConfig::from_toml(&content) {
+                Ok(config) => config,
+                Err(e) => {
+                    // Still list it, so a VM doesn't just vanish after a typo in its config
+                    unreadable += 1;
+                    let name = path.file_stem().unwrap_or_default().to_string_lossy();
+                    outln!("  {} [{}]", name, get_vm_status(&name));
+                    eoutln!("    ⚠️  Can't read {}: {}", path.display(), e.to_string().trim().replace('\n', " "));
+                    continue;
+                }
+            };
+            
+            // Check VM status
+            let status = get_vm_status(&config.name);
+            
+            outln!("  {} [{}]", config.name, status);
+            outln!("    System Packages: {:?}", config.system_packages);
+            outln!("    Flatpak Packages: {:?}", config.flatpak_packages);
+            outln!("    Memory: {} MB", config.memory_mb);
+            outln!("    Graphics: {:?}", config.graphics_backend);
+            if status == "running" || status == "paused" {
+                if let Some(display) = provisioner::current_display(&config.name) {
+                    outln!("    Display: {}", display);
+                }
+            }
+            if let Some(expires_at) = VMState::load(&config.name).ok().and_then(|state| state.expires_at) {
+                outln!("    Expires: {}", state::describe_expiry(expires_at, state::now()));
+            }
+        }
+    }
+    
+    if unreadable > 0 {
+        eoutln!("⚠️  {} of {} VM configs couldn't be read; fix them in a text editor", unreadable, listed);
+    }
+    
+    Ok(())
+}
+
+fn list_usb_devices() -> Result<(), Box<dyn std::error::Error>> {
+    let devices = provisioner::host_usb_devices()?;
+    outln!("🔌 Host USB devices (pass one through with: vm-provisioner create --usb ID):");
+    for device in devices {
+        outln!("   {}  {}", device.id, device.description);
+    }
+    Ok(())
+}
+
+/// Names of all configured VMs, sorted
+fn configured_vm_names() -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let config_dir = format!("{}/.config/vm-provisioner", std::env::var("HOME")?);
+    if !Path::new(&config_dir).exists() {
+        return Ok(Vec::new());
+    }
+    
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(&config_dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|s| s.to_str()) == Some("toml") {
+            let content = std::fs::read_to_string(&path)?;
+            if let Ok(config) = AppVMConfig::from_toml(&content) {
+                names.push(config.name);
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+fn format_kib(kib: u64) -> String {
+    format!("{:.1} GiB", kib as f64 / (1024.0 * 1024.0))
+}
+
+async fn top(interval: u64) -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::IsTerminal;
+    
+    let interval = std::time::Duration::from_secs(interval.max(1));
+    let clear = !output::is_plain() && std::io::stdout().is_terminal();
+    let mut previous: HashMap<String, (u64, std::time::Instant)> = HashMap::new();
+    
+    loop {
+        let names = configured_vm_names()?;
+        let mut rows = Vec::new();
+        for name in &names {
+            let Some(stats) = provisioner::domain_stats(name) else {
+                previous.remove(name);
+                continue;
+            };
+            let now = std::time::Instant::now();
+            let cpu = previous.get(name)
+                .map(|(cpu_time, at)| provisioner::cpu_percent(*cpu_time, stats.cpu_time, now - *at, stats.vcpus));
+            previous.insert(name.clone(), (stats.cpu_time, now));
+            rows.push((name, stats, cpu));
+        }
+        
+        if clear {
+            print!("\x1b[H\x1b[2J");
+        }
+        outln!("📊 {} of {} VMs running (every {}s, Ctrl+C to quit)", rows.len(), names.len(), interval.as_secs());
+        outln!("{:<24} {:<8} {:>6}  {}", "NAME", "STATE", "CPU%", "MEMORY USED/MAX");
+        for (name, stats, cpu) in rows {
+            let state = if stats.paused { "paused" } else { "running" };
+            let cpu = cpu.map_or("-".to_string(), |cpu| format!("{:.1}", cpu));
+            let used = stats.memory_used_kib.map_or("-".to_string(), format_kib);
+            outln!("{:<24} {:<8} {:>6}  {} / {}", name, state, cpu, used, format_kib(stats.memory_max_kib));
+        }
+        if !clear {
+            outln!();
+        }
+        
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+            _ = tokio::time::sleep(interval) => {}
+        }
+    }
+}
+
+async fn destroy_vm(name: String, skip_confirm: bool, snapshot_first: bool, plan_only: bool) -> Result<(), Box<dyn std::error::Error>> {
+    outln!("🗑️  Preparing to destroy VM: {}", name);
+    
+    // Load configuration
+    let config_dir = format!("{}/.config/vm-provisioner", std::env::var("HOME")?);
+    let config_file = format!("{}/{}.toml", config_dir, name);
+    let config = if Path::new(&config_file).exists() {
+        let content = std::fs::read_to_string(&config_file)?;
+        Some(AppVMConfig::from_toml(&content)?)
+    } else {
+        None
+    };
+    // Expiry timers destroy VMs unattended, where the password store can't be unlocked
+    let mut passwords = match VMPasswords::load_or_create(&config_dir) {
+        Ok(passwords) => Some(passwords),
+        Err(e) => {
+            outln!("⚠️  Password entry for {} is left in place: {}", name, e);
+            None
+        }
+    };
+    
+    outln!("📋 This would remove:");
+    match &config {
+        Some(config) => {
+            let plan = AppVMProvisioner::new(config.clone()).destroy_plan();
+            if plan.domain {
+                outln!("   Domain:       {} (libvirt definition and NVRAM)", name);
+            }
+            for disk in &plan.disks {
+                outln!("   Disk:         {}", disk);
+            }
+            if let Some(save) = &plan.managed_save {
+                outln!("   Saved state:  {}", save);
+            }
+            if !plan.snapshots.is_empty() {
+                outln!("   Snapshots:    {}", plan.snapshots.join(", "));
+            }
+            outln!("   Config:       {}", config_file);
+        }
+        None => outln!("   (no config found at {}, nothing else is touched)", config_file),
+    }
+    if passwords.as_ref().is_some_and(|p| p.vms.contains_key(&name)) {
+        outln!("   Password:     entry for {} in {}/vm-passwords.toml", name, config_dir);
+    }
+    
+    if plan_only {
+        outln!("ℹ️  Dry run, nothing was removed");
+        return Ok(());
+    }
+    
+    if !skip_confirm {
+        outln!("⚠️  This will permanently delete the VM and all its data!");
+        
+        let typed: String = Input::new()
+            .with_prompt(format!("Type the VM name ({}) to confirm", name))
+            .allow_empty(true)
+            .interact_text()?;
+            
+        if typed.trim() != name {
+            outln!("❌ Destruction cancelled");
+            return Ok(());
+        }
+    }
+    
+    if let Some(config) = config {
+        let snapshot = snapshot_first || config.auto_snapshot_on_destroy;
+        let provisioner = AppVMProvisioner::new(config);
+        if snapshot {
+            provisioner.save_recovery_snapshot()?;
+        }
+        provisioner.destroy_vm()?;
+        
+        // Remove configuration file
+        std::fs::remove_file(&config_file)?;
+        if let Ok(identity) = tls::host_identity_path(&name) {
+            let _ = std::fs::remove_file(identity);
+        }
+        VMState::remove(&name)?;
+    }
+    
+    if let Some(passwords) = passwords.as_mut() {
+        if passwords.remove_vm(&name) {
+            passwords.save(&config_dir)?;
+        }
+    }
+    
+    outln!("✅ VM destroyed");
+    
+    Ok(())
+}
+
+async fn reprovision_vm(name: String, skip_confirm: bool) -> Result<(), Box<dyn std::error::Error>> {
+    outln!("♻️  Preparing to reprovision VM: {}", name);
+    
+    let mut config = load_vm_config(&name)?;
+    config.validate()?;
+    if let Some(warning) = config.adjust_graphics_for_arch(std::env::consts::ARCH)? {
+        outln!("⚠️  {}", warning);
+    }
+    
+    if !skip_confirm {
+        outln!("⚠️  This will delete the VM's disk and reinstall it from its saved configuration!");
+        
+        let confirm = Confirm::new()
+            .with_prompt("Are you sure?")
+            .default(false)
+            .interact()?;
+            
+        if !confirm {
+            outln!("❌ Reprovision cancelled");
+            return Ok(());
+        }
+    }
+    
+    // Tear down the old domain and disk, but keep the config and password entry
+    let provisioner = AppVMProvisioner::new(config.clone()).assume_yes(skip_confirm);
+    if config.auto_snapshot_on_destroy {
+        provisioner.save_recovery_snapshot()?;
+    }
+    provisioner.destroy_vm()?;
+    provisioner.provision_vm().await?;
+    
+    outln!("\n✅ VM reprovisioned successfully!");
+    outln!("   VM Name: {}", config.name);
+    outln!("   Username: user");
+    outln!("   Password: {}", config.user_password);
+    outln!("   Start with: vm-provisioner start {}", config.name);
+    
+    Ok(())
+}
+
+/// Send a file to the guest, into ~/Downloads or to `destination` under its home
+fn push_file(name: &str, file: &Path, destination: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Read;
+    use std::os::unix::net::UnixStream;
+    
+    outln!("📤 Pushing {} to VM: {}", file.display(), name);
+    
+    let size = std::fs::metadata(file)?.len();
+    if size > protocol::MAX_FILE_TRANSFER_SIZE {
+        return Err(format!("File is too large ({} bytes, limit is {} bytes)", 
+                           size, protocol::MAX_FILE_TRANSFER_SIZE).into());
+    }
+    
+    let file_name = file
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or("Invalid file name")?
+        .to_string();
+    let sha256 = provisioner::sha256_file(&file.to_string_lossy())?;
+    
+    let control_path = window_proxy::control_socket_path(name);
+    let mut conn = UnixStream::connect(&control_path)
+        .map_err(|e| format!("Window proxy for {} is not running ({}). Start the VM first.", name, e))?;
+    
+    let transfer_id = std::process::id();
+    protocol::send_message(&mut conn, &WindowMessage::FileTransferStart {
+        transfer_id,
+        file_name: file_name.clone(),
+        size,
+        sha256,
+        destination: destination.map(str::to_string),
+    })?;
+    
+    let mut source = std::fs::File::open(file)?;
+    let mut buffer = vec![0u8; protocol::FILE_TRANSFER_CHUNK_SIZE];
+    loop {
+        let n = source.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        protocol::send_message(&mut conn, &WindowMessage::FileTransferChunk {
+            transfer_id,
+            data: buffer[..n].to_vec(),
+        })?;
+    }
+    
+    protocol::send_message(&mut conn, &WindowMessage::FileTransferEnd { transfer_id })?;
+    
+    let guest_path = destination.map(str::to_string).unwrap_or_else(|| format!("Downloads/{}", file_name));
+    outln!("✅ Sent {} ({} bytes) to ~/{}", file_name, size, guest_path.trim_start_matches("~/"));
+    
+    Ok(())
+}
+
+fn open_app(name: &str, app: &str) -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::net::UnixStream;
+    
+    // The guest validates the ID too; checking here just gives a friendlier error
+    let known = VMState::load(name)?.applications;
+    if !known.is_empty() && !known.iter().any(|entry| entry.id == app || entry.name.eq_ignore_ascii_case(app)) {
+        let names: Vec<&str> = known.iter().map(|entry| entry.name.as_str()).collect();
+        return Err(format!("Unknown application '{}' in {}. Available: {}", app, name, names.join(", ")).into());
+    }
+    
+    let control_path = window_proxy::control_socket_path(name);
+    let mut conn = UnixStream::connect(&control_path)
+        .map_err(|e| format!("Window proxy for {} is not running ({}). Start the VM first.", name, e))?;
+    protocol::send_message(&mut conn, &WindowMessage::OpenApp { app_id: app.to_string() })?;
+    
+    outln!("🚀 Asked {} to launch {}", name, app);
+    Ok(())
+}
+
+fn connect_console(name: String) -> Result<(), Box<dyn std::error::Error>> {
+    outln!("🖥️  Connecting to VM console: {}", name);
+    
+    libvirt::virsh()
+        .args(&["console", &name])
+        .status()?;
+    
+    Ok(())
+}
+
+/// `console --log`: follow the kickstart post-install log through the QEMU guest agent
+/// (which the installer runs too), else read it off the disk, else filter the serial console
+fn show_post_install_log(name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Write;
+    
+    let running = provisioner::list_running_domains()?.iter().any(|domain| domain == name);
+    if running && provisioner::qemu_agent_available(name) {
+        match provisioner::AgentFile::open_first(name, &provisioner::post_install_log_paths()) {
+            Ok(mut log) => {
+                outln!("📜 Following {} in {} through the guest agent (Ctrl+C to stop)", provisioner::POST_INSTALL_LOG, name);
+                let mut stdout = std::io::stdout();
+                loop {
+                    let chunk = log.read()?;
+                    if chunk.is_empty() {
+                        std::thread::sleep(std::time::Duration::from_secs(1));
+                        continue;
+                    }
+                    stdout.write_all(&chunk)?;
+                    stdout.flush()?;
+                }
+            }
+            Err(e) => eoutln!("⚠️  Guest agent can't open the log ({}), reading the disk instead", e),
+        }
+    }
+    
+    match provisioner::read_guest_file_tail(name, provisioner::POST_INSTALL_LOG, POST_INSTALL_LOG_LINES) {
+        Ok(tail) => {
+            outln!("📜 Last {} lines of {} on {}'s disk:", POST_INSTALL_LOG_LINES, provisioner::POST_INSTALL_LOG, name);
+            print!("{}", tail);
+            return Ok(());
+        }
+        Err(e) if running => eoutln!("⚠️  {}", e),
+        Err(e) => return Err(e),
+    }
+    
+    outln!("📜 No guest agent or readable disk; showing post-install lines from the serial console (Ctrl+] to stop)");
+    provisioner::grep_serial_console(name)
+}
+
+/// Lines of the post-install log shown when it's read from the disk
+const POST_INSTALL_LOG_LINES: usize = 100;
+
+/// Lines of each log `logs` shows before following
+const LOG_LINES: usize = 200;
+
+/// How often `logs --follow` checks for new lines
+const LOG_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// A line of the guest journal or of the host's window-proxy log
+#[derive(Debug, PartialEq)]
+struct LogLine {
+    /// Microseconds since the Unix epoch
+    at: u64,
+    source: &'static str,
+    text: String,
+}
+
+/// Parse `<secs>.<micros> <text>`, the layout of `journalctl -o short-unix` and of
+/// the proxy log. Journal lines also carry the hostname, which is dropped.
+fn parse_log_line(line: &str, source: &'static str, has_hostname: bool) -> Option<LogLine> {
+    let (timestamp, text) = line.split_once(' ')?;
+    let (secs, micros) = timestamp.split_once('.').unwrap_or((timestamp, "0"));
+    let micros = format!("{:0<6}", micros).get(..6)?.parse::<u64>().ok()?;
+    let at = secs.parse::<u64>().ok()? * 1_000_000 + micros;
+    
+    let text = if has_hostname { text.split_once(' ')?.1 } else { text };
+    Some(LogLine { at, source, text: text.to_string() })
+}
+
+/// Read the guest journal for `units` through the QEMU guest agent: the last
+/// `LOG_LINES` lines, or what came after `cursor`. Returns the lines and the
+/// cursor to continue from.
+fn fetch_guest_journal(name: &str, units: &[String], cursor: Option<&str>) -> Result<(Vec<LogLine>, Option<String>), Box<dyn std::error::Error>> {
+    let mut args: Vec<String> = ["-o", "short-unix", "--no-pager", "--show-cursor"].map(String::from).to_vec();
+    for unit in units {
+        args.extend(["-u".to_string(), unit.clone()]);
+    }
+    match cursor {
+        Some(cursor) => args.push(format!("--after-cursor={}", cursor)),
+        None => args.extend(["-n".to_string(), LOG_LINES.to_string()]),
+    }
+    
+    let output = provisioner::guest_exec(name, "journalctl", &args)?;
+    let mut next_cursor = None;
+    let mut lines = Vec::new();
+    for line in output.lines() {
+        if let Some(cursor) = line.strip_prefix("-- cursor: ") {
+            next_cursor = Some(cursor.to_string());
+        } else if let Some(line) = parse_log_line(line, "guest", true) {
+            lines.push(line);
+        }
+    }
+    Ok((lines, next_cursor))
+}
+
+/// Complete lines appended to the proxy log since `offset`, which is advanced past them
+fn read_proxy_log(path: &Path, offset: &mut u64) -> Result<Vec<LogLine>, Box<dyn std::error::Error>> {
+    use std::io::{Read, Seek, SeekFrom};
+    
+    let mut file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    // The proxy starts the log over once it grows too big
+    if file.metadata()?.len() < *offset {
+        *offset = 0;
+    }
+    file.seek(SeekFrom::Start(*offset))?;
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+    
+    let complete = content.rfind('\n').map_or(0, |end| end + 1);
+    *offset += complete as u64;
+    Ok(content[..complete].lines().filter_map(|line| parse_log_line(line, "proxy", false)).collect())
+}
+
+/// Local wall-clock time of `at` as `HH:MM:SS.mmm`
+fn format_log_time(at: u64) -> String {
+    let secs = (at / 1_000_000) as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&secs, &mut tm) }.is_null() {
+        return format!("{}.{:03}", secs, at % 1_000_000 / 1000);
+    }
+    format!("{:02}:{:02}:{:02}.{:03}", tm.tm_hour, tm.tm_min, tm.tm_sec, at % 1_000_000 / 1000)
+}
+
+fn print_log_lines(mut lines: Vec<LogLine>) {
+    lines.sort_by_key(|line| line.at);
+    for line in lines {
+        outln!("{} {:<5} {}", format_log_time(line.at), line.source, line.text);
+    }
+}
+
+fn show_logs(name: &str, follow: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let config = load_vm_config(name)?;
+    let units = ["guest-agent.service".to_string(), format!("autologin@tty{}.service", config.autologin_tty)];
+    let proxy_log = state::proxy_log_path(name)?;
+    
+    let running = provisioner::list_running_domains()?.iter().any(|domain| domain == name);
+    let agent = running && provisioner::qemu_agent_available(name);
+    if !running {
+        eoutln!("⚠️  {} is not running; showing only the host's window-proxy log", name);
+    } else if !agent {
+        eoutln!("⚠️  {}'s QEMU guest agent doesn't answer; showing only the host's window-proxy log", name);
+    }
+    
+    let mut proxy_offset = 0;
+    let mut lines = read_proxy_log(&proxy_log, &mut proxy_offset)?;
+    lines.drain(..lines.len().saturating_sub(LOG_LINES));
+    let mut cursor = None;
+    if agent {
+        let (journal, next) = fetch_guest_journal(name, &units, None)?;
+        lines.extend(journal);
+        cursor = next;
+    }
+    print_log_lines(lines);
+    if !follow {
+        return Ok(());
+    }
+    
+    loop {
+        std::thread::sleep(LOG_POLL_INTERVAL);
+        let mut lines = read_proxy_log(&proxy_log, &mut proxy_offset)?;
+        if agent {
+            match fetch_guest_journal(name, &units, cursor.as_deref()) {
+                Ok((journal, next)) => {
+                    lines.extend(journal);
+                    cursor = next.or(cursor);
+                }
+                Err(e) => eoutln!("⚠️  Can't read the guest journal: {}", e),
+            }
+        }
+        print_log_lines(lines);
+    }
+}
+
+fn show_status(name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let config = load_vm_config(name)?;
+    let domstate = provisioner::list_domains()?
+        .contains(&config.name)
+        .then(|| get_vm_status(&config.name));
+    
+    let mut state = VMState::load(&config.name)?;
+    let phase = state::reconcile_phase(state.phase, domstate.as_deref());
+    if phase != state.phase {
+        VMState::set_phase(&config.name, phase)?;
+        state = VMState::load(&config.name)?;
+        
+        // A detached install just finished; the domain still has the install-time memory
+        if phase == VMPhase::Installed {
+            AppVMProvisioner::new(config.clone()).apply_runtime_memory()?;
+        }
+    }
+    
+    outln!("📊 Status of VM: {}", config.name);
+    outln!("   Domain: {}", domstate.as_deref().unwrap_or("not created"));
+    match state.phase {
+        VMPhase::Installing => outln!("   Installation: installing (watch with: vm-provisioner console {})", config.name),
+        VMPhase::Installed => outln!("   Installation: complete"),
+        VMPhase::InstallFailed => outln!("   Installation: failed (retry with: vm-provisioner reprovision {})", config.name),
+        VMPhase::Unknown => outln!("   Installation: unknown"),
+    }
+    if state.install_attempts > 1 {
+        outln!("   Install attempts: {}", state.install_attempts);
+    }
+    if let Some(failure) = &state.last_failure {
+        outln!("   Last failure: {}", failure);
+    }
+    match (state.expires_at, config.max_lifetime) {
+        (Some(expires_at), _) => outln!("   Expires: {}", state::describe_expiry(expires_at, state::now())),
+        (None, Some(lifetime)) => outln!("   Expires: {} after first start", config::format_duration(lifetime)),
+        (None, None) => {}
+    }
+    if !state.applications.is_empty() {
+        let names: Vec<&str> = state.applications.iter().map(|app| app.name.as_str()).collect();
+        outln!("   Applications: {}", names.join(", "));
+    }
+    
+    if matches!(domstate.as_deref(), Some("running" | "paused")) {
+        match provisioner::current_display(&config.name) {
+            Some(display) => outln!("   Display: {}", display),
+            None => outln!("   Display: none (headless)"),
+        }
+    }
+    if let Some(listen) = &config.spice_listen {
+        outln!("   SPICE listens on: {}", listen);
+    }
+    if let Some(password) = &config.spice_password {
+        outln!("   SPICE password: {}", password);
+    }
+    
+    if domstate.as_deref() == Some("running") {
+        let proxy_running = std::os::unix::net::UnixStream::connect(window_proxy::control_socket_path(&config.name)).is_ok();
+        outln!("   Window proxy: {}", if proxy_running { "running" } else { "not running" });
+        
+        match state.agent_connected_at {
+            Some(at) => {
+                let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs();
+                outln!("   Guest agent: last connected {}s ago", now.saturating_sub(at));
+            }
+            None => {
+                outln!("   Guest agent: never connected");
+                if state.phase == VMPhase::Installed {
+                    warn_guest_agent_missing(&config.name);
+                }
+            }
+        }
+    }
+    
+    Ok(())
+}
+
+fn rebuild_vm(name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let config = load_vm_config(name)?;
+    if get_vm_status(&config.name) != "running" {
+        return Err(format!("{} must be running to rebuild (start it with: vm-provisioner start {})", name, name).into());
+    }
+    if !provisioner::qemu_agent_available(&config.name) {
+        return Err(format!("{}'s QEMU guest agent doesn't answer; VMs installed without qemu-guest-agent \
+                            need a full reinstall: vm-provisioner reprovision {}", name, name).into());
+    }
+    AppVMProvisioner::new(config).rebuild()
+}
+
+fn pause_vm(name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let config = load_vm_config(name)?;
+    match get_vm_status(&config.name).as_str() {
+        "running" => AppVMProvisioner::new(config).pause_vm(),
+        "paused" => {
+            outln!("ℹ️  {} is already paused", name);
+            Ok(())
+        }
+        state => Err(format!("Can't pause {}: it is {}", name, state).into()),
+    }
+}
+
+fn resume_vm(name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let config = load_vm_config(name)?;
+    match get_vm_status(&config.name).as_str() {
+        "paused" => AppVMProvisioner::new(config).resume_vm(),
+        "running" => {
+            outln!("ℹ️  {} is already running", name);
+            Ok(())
+        }
+        state => Err(format!("Can't resume {}: it is {} (start it with: vm-provisioner start {})", name, state, name).into()),
+    }
+}
+
+fn set_resources(name: &str, memory: Option<u64>, vcpus: Option<u32>) -> Result<(), Box<dyn std::error::Error>> {
+    if memory.is_none() && vcpus.is_none() {
+        return Err("Nothing to change: give --memory and/or --vcpus".into());
+    }
+    
+    let previous = load_vm_config(name)?;
+    let mut config = previous.clone();
+    if let Some(memory) = memory {
+        config.memory_mb = memory;
+    }
+    if let Some(vcpus) = vcpus {
+        config.vcpus = vcpus;
+    }
+    if config.memory_mb == 0 || config.vcpus == 0 {
+        return Err("Memory and vCPUs must be at least 1".into());
+    }
+    config.validate_installed()?;
+    if config.memory_mb == previous.memory_mb && config.vcpus == previous.vcpus {
+        outln!("ℹ️  {} already has {} MB and {} vCPUs", name, config.memory_mb, config.vcpus);
+        return Ok(());
+    }
+    
+    let status = get_vm_status(name);
+    let provisioner = AppVMProvisioner::new(config.clone());
+    if status != "not created" {
+        // The persisted definition, for every boot from now on
+        provisioner.apply_resources(&previous)?;
+    }
+    
+    outln!("✅ Saved {}", save_vm_config(&config)?);
+    
+    match status.as_str() {
+        "running" | "paused" => {
+            let pending = provisioner.resize_live(&previous)?;
+            for note in &pending {
+                outln!("⚠️  {}", note);
+            }
+            if !pending.is_empty() {
+                outln!("   Restart the VM to apply it: vm-provisioner stop {} && vm-provisioner start {}", name, name);
+            }
+        }
+        "not created" => outln!("ℹ️  {} isn't created yet; the new values are used when it is", name),
+        _ => outln!("ℹ️  {} is {}; the new values apply on its next start", name, status),
+    }
+    
+    Ok(())
+}
+
+fn resize_disk(name: &str, size_gb: u64, grow_fs: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = load_vm_config(name)?;
+    if size_gb < config.disk_size_gb {
+        return Err(format!("{} has a {} GB disk; disks can only grow", name, config.disk_size_gb).into());
+    }
+    if size_gb == config.disk_size_gb && !grow_fs {
+        outln!("ℹ️  {} already has a {} GB disk", name, size_gb);
+        return Ok(());
+    }
+    
+    let status = get_vm_status(name);
+    let provisioner = AppVMProvisioner::new(config.clone());
+    if size_gb > config.disk_size_gb {
+        match status.as_str() {
+            "not created" => {}
+            // qemu-img can't safely touch an image QEMU has open
+            "shut off" => provisioner.resize_disk(size_gb)?,
+            _ => return Err(format!("{} is {}; its disk can only be resized while it's shut down: \
+                                     vm-provisioner stop {}", name, status, name).into()),
+        }
+        
+        config.disk_size_gb = size_gb;
+        outln!("✅ Saved {}", save_vm_config(&config)?);
+    }
+    
+    if status == "not created" {
+        outln!("ℹ️  {} isn't created yet; it gets a {} GB disk when it is", name, size_gb);
+    } else if grow_fs {
+        provisioner.grow_guest_filesystem()?;
+    } else {
+        outln!("ℹ️  The guest's partition and filesystem still end where they did. Grow them with:");
+        outln!("   vm-provisioner resize-disk {} --size {} --grow-fs", name, size_gb);
+    }
+    
+    Ok(())
+}
+
+fn get_vm_status(name: &str) -> String {
+    match libvirt::virsh()
+        .args(&["domstate", name])
+        .output()
+    {
+        Ok(output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).trim().to_string()
+        }
+        _ => "not created".to_string()
+    }
+}
+
+fn connect_displays(names: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    if names.is_empty() {
+        outln!("ℹ️  No running VMs");
+        return Ok(());
+    }
+    
+    for name in names {
+        match provisioner::current_display(name) {
+            Some(display) => {
+                outln!("🖥️  {}: {}", name, display);
+                provisioner::launch_viewer(name, &display)?;
+            }
+            None => outln!("⏭️  {}: no graphical display (headless or not running), skipping", name),
+        }
+    }
+    
+    Ok(())
+}
+
+fn show_passwords() -> Result<(), Box<dyn std::error::Error>> {
+    let config_dir = format!("{}/.config/vm-provisioner", std::env::var("HOME")?);
+    let password_file = format!("{}/vm-passwords.toml", config_dir);
     
-    // Save password to centralized password file
-    let mut passwords = VMPasswords::load_or_create(&config_dir)?;
-    passwords.add_vm(&config.name, &config.user_password);
-    passwords.save(&config_dir)?;
+    if !Path::new(&password_file).exists() {
+        outln!("❌ No password file found");
+        outln!("   Create a VM first to generate passwords");
+        return Ok(());
+    }
     
-    // Create and provision VM
-    let provisioner = AppVMProvisioner::new(config.clone());
-    provisioner.provision_vm().await?;
+    let passwords = VMPasswords::load_or_create(&config_dir)?;
+    
+    if passwords.vms.is_empty() {
+        outln!("ℹ️  No VM passwords stored yet");
+        return Ok(());
+    }
+    
+    outln!("🔑 VM Login Credentials:");
+    outln!("   File: {}", password_file);
+    outln!();
+    
+    for (vm_name, password) in &passwords.vms {
+        outln!("   {} | user:{}", vm_name, password);
+    }
     
-    println!("\n✅ VM created successfully!");
-    println!("   VM Name: {}", config.name);
-    println!("   Username: user");
-    println!("   Password: {}", config.user_password);
-    println!("   Config: {}", config_file);
-    println!("   Passwords: {}/.config/vm-provisioner/vm-passwords.toml", std::env::var("HOME")?);
-    println!("   Start with: vm-provisioner start {}", config.name);
+    outln!("\n💡 Usage:");
+    outln!("   {}", libvirt::virsh_hint("console <vm-name>"));
+    outln!("   vm-provisioner start <vm-name>  # Shows password");
     
     Ok(())
 }
 
-async fn start_vm(name: String, seamless: bool) -> Result<(), Box<dyn std::error::Error>> {
-    println!("▶️  Starting VM: {}", name);
-    
-    // Load VM configuration
-    let config_file = format!("{}/.config/vm-provisioner/{}.toml", 
-                             std::env::var("HOME")?, name);
+fn revert_snapshot(name: &str, snapshot_name: &str, force: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let config = load_vm_config(name)?;
     
-    if !Path::new(&config_file).exists() {
-        eprintln!("❌ VM configuration not found: {}", name);
-        eprintln!("   Available VMs:");
-        list_vms()?;
-        std::process::exit(1);
+    if get_vm_status(name) == "running" && !force {
+        return Err(format!("VM '{0}' is running. Stop it first, or pass --force to discard its current state: \
+                            vm-provisioner snapshot-revert {0} {1} --force", name, snapshot_name).into());
     }
     
-    let content = std::fs::read_to_string(&config_file)?;
-    let config = toml::from_str::<AppVMConfig>(&content)?;
+    AppVMProvisioner::new(config).revert_snapshot(snapshot_name)?;
     
-    // Start the VM
-    let provisioner = AppVMProvisioner::new(config.clone());
-    provisioner.start_vm()?;
+    outln!("✅ Reverted to snapshot {}", snapshot_name);
+    outln!("   VM state: {}", get_vm_status(name));
     
-    // Start window proxy for seamless integration (always enabled now)
-    println!("🪟 Starting window proxy...");
+    Ok(())
+}
+
+fn clone_vm(source: String, target: String, live: bool, quiesce: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let config_dir = format!("{}/.config/vm-provisioner", std::env::var("HOME")?);
+    let config_file = format!("{}/{}.toml", config_dir, target);
     
-    // Launch window proxy in background  
-    let vm_name_clone = name.clone();
-    std::thread::spawn(move || {
-        let mut integration = VMIntegrationHost::new(vm_name_clone);
-        if let Err(e) = integration.start() {
-            eprintln!("Window integration error: {}", e);
-        }
-    });
+    if Path::new(&config_file).exists() || provisioner::list_domains()?.contains(&target) {
+        return Err(format!("VM '{}' already exists", target).into());
+    }
+    
+    let config = AppVMProvisioner::new(load_vm_config(&source)?).clone_vm(&target, live, quiesce)?;
+    
+    save_vm_config(&config)?;
+    VMState::set_phase(&target, VMPhase::Installed)?;
+    
+    let mut passwords = VMPasswords::load_or_create(&config_dir)?;
+    passwords.add_vm(&config.name, &config.user_password);
+    passwords.save(&config_dir)?;
     
-    println!("✅ Window proxy started");
-    println!("   Waiting for guest agent connection...");
+    outln!("\n✅ VM cloned successfully!");
+    outln!("   VM Name: {}", config.name);
+    outln!("   Start with: vm-provisioner start {}", config.name);
     
-    if config.enable_clipboard {
-        println!("   Clipboard sharing enabled");
+    Ok(())
+}
+
+/// The part of a config that's safe to hand to someone else: no password, and no
+/// fixed MAC address, which belongs to the original VM
+fn exported_config(config: &AppVMConfig) -> AppVMConfig {
+    let mut exported = config.clone();
+    exported.user_password = String::new();
+    exported.spice_password = None;
+    exported.mac_address = None;
+    exported
+}
+
+fn export_vm(name: &str, out: &Path, with_disk: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let config = load_vm_config(name)?;
+    if out.exists() {
+        return Err(format!("{} already exists, refusing to overwrite it", out.display()).into());
     }
     
-    // Display login credentials
-    println!("\n🔑 VM Login Credentials:");
-    println!("   Username: user");
-    println!("   Password: {}", config.user_password);
-    println!("   Console: sudo virsh console {}", name);
+    let text = format!("# Exported from vm-provisioner; create the VM with: vm-provisioner import {}\n{}",
+                       out.file_name().unwrap_or_default().to_string_lossy(),
+                       toml::to_string_pretty(&exported_config(&config))?);
+    if with_disk {
+        match get_vm_status(name).as_str() {
+            "shut off" => {}
+            "not created" => return Err(format!("{} has no disk yet; export it without --with-disk", name).into()),
+            state => return Err(format!("{} is {}; shut it down first so its disk is consistent: vm-provisioner stop {}", 
+                                        name, state, name).into()),
+        }
+        AppVMProvisioner::new(config).export_bundle(&text, out)?;
+    } else {
+        std::fs::write(out, text)?;
+    }
     
+    outln!("✅ Exported {} to {}", name, out.display());
+    outln!("   The password isn't included; `vm-provisioner import` generates a new one");
     Ok(())
 }
 
-async fn stop_vm(name: String) -> Result<(), Box<dyn std::error::Error>> {
-    println!("⏹️  Stopping VM: {}", name);
+fn import_vm(file: &Path, name: Option<String>) -> Result<(), Box<dyn std::error::Error>> {
+    let bundle = provisioner::is_bundle(file).map_err(|e| format!("Can't read {}: {}", file.display(), e))?;
+    let text = if bundle {
+        provisioner::bundle_config(file)?
+    } else {
+        std::fs::read_to_string(file)?
+    };
+    let mut config = AppVMConfig::from_toml(&text).map_err(|e| format!("{}: {}", file.display(), e))?;
     
-    // Load VM configuration
-    let config_file = format!("{}/.config/vm-provisioner/{}.toml", 
-                             std::env::var("HOME")?, name);
+    if let Some(name) = name {
+        if bundle {
+            // The imported system keeps the hostname it was installed with
+            config.hostname = Some(config.guest_hostname().to_string());
+        }
+        config.name = name;
+    }
     
-    if !Path::new(&config_file).exists() {
-        eprintln!("❌ VM configuration not found: {}", name);
-        std::process::exit(1);
+    let config_dir = format!("{}/.config/vm-provisioner", std::env::var("HOME")?);
+    let config_file = format!("{}/{}.toml", config_dir, config.name);
+    if Path::new(&config_file).exists() || provisioner::list_domains()?.contains(&config.name) {
+        return Err(format!("VM '{}' already exists; import it under another name with: vm-provisioner import {} --name <name>", 
+                           config.name, file.display()).into());
     }
     
-    let content = std::fs::read_to_string(&config_file)?;
-    let config = toml::from_str::<AppVMConfig>(&content)?;
+    config.user_password = config::generate_password(config.password_length, config.password_vm_prefix);
+    if config.spice_needs_password() {
+        config.spice_password = Some(config::generate_password(DEFAULT_SPICE_PASSWORD_LENGTH, false));
+    }
+    config.validate()?;
+    if let Some(warning) = config.adjust_graphics_for_arch(std::env::consts::ARCH)? {
+        outln!("⚠️  {}", warning);
+    }
+    let claimed = claimed_proxy_ports(&config.name)?;
+    if !bundle {
+        assign_proxy_port(&mut config, &claimed)?;
+    } else if claimed.contains(&config.proxy_port) {
+        // The imported guest agent already dials this port
+        outln!("⚠️  Window proxy port {} is also used by another VM; don't run them at the same time", config.proxy_port);
+    }
     
-    let provisioner = AppVMProvisioner::new(config);
-    provisioner.stop_vm()?;
+    outln!("📥 Importing VM: {}", config.name);
+    let provisioner = AppVMProvisioner::new(config.clone());
+    if bundle {
+        provisioner.import_disk(file)?;
+        VMState::set_phase(&config.name, VMPhase::Installed)?;
+    }
+    
+    save_vm_config(&config)?;
+    outln!("💾 Configuration saved to: {}", config_file);
+    let mut passwords = VMPasswords::load_or_create(&config_dir)?;
+    passwords.add_vm(&config.name, &config.user_password);
+    passwords.save(&config_dir)?;
     
-    println!("✅ VM stopped");
+    if bundle {
+        outln!("🔑 Setting the new password in the imported disk...");
+        provisioner.set_password_offline(&config.user_password).map_err(|e| format!(
+            "{}. The guest still has the exported VM's password; set a new one with: vm-provisioner passwd {}", e, config.name
+        ))?;
+    }
     
+    outln!("\n✅ VM imported successfully!");
+    outln!("   VM Name: {}", config.name);
+    outln!("   Username: user");
+    outln!("   Password: {}", config.user_password);
+    if bundle {
+        outln!("   Start with: vm-provisioner start {}", config.name);
+    } else {
+        outln!("   Install it with: vm-provisioner reprovision {}", config.name);
+    }
     Ok(())
 }
 
-fn list_vms() -> Result<(), Box<dyn std::error::Error>> {
-    println!("📋 Available VMs:");
-    println!("================");
+fn change_password(name: String, prompt: bool) -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::net::UnixStream;
     
     let config_dir = format!("{}/.config/vm-provisioner", std::env::var("HOME")?);
+    let mut config = load_vm_config(&name)?;
     
-    if !Path::new(&config_dir).exists() {
-        println!("No VMs configured yet.");
-        println!("Create one with: vm-provisioner create");
-        return Ok(());
+    let password = if prompt {
+        dialoguer::Password::new()
+            .with_prompt(format!("New password for user in {}", name))
+            .with_confirmation("Repeat password", "Passwords don't match")
+            .interact()?
+    } else {
+        config::generate_password(config.password_length, config.password_vm_prefix)
+    };
+    if password.chars().any(char::is_control) {
+        return Err("Passwords cannot contain control characters".into());
     }
     
-    // List all .toml files
-    for entry in std::fs::read_dir(&config_dir)? {
-        let entry = entry?;
-        let path = entry.path();
-        
-        if path.extension().and_then(|s| s.to_str()) == Some("toml") {
-            let content = std::fs::read_to_string(&path)?;
-            if let Ok(config) = toml::from_str::<AppVMConfig>(&content) {
-                // Check VM status
-                let status = get_vm_status(&config.name);
-                
-                println!("  {} [{}]", config.name, status);
-                println!("    System Packages: {:?}", config.system_packages);
-                println!("    Flatpak Packages: {:?}", config.flatpak_packages);
-                println!("    Memory: {} MB", config.memory_mb);
-                println!("    Graphics: {:?}", config.graphics_backend);
+    match get_vm_status(&name).as_str() {
+        "running" => {
+            // Only the guest agent can change it while the guest runs, through the window proxy
+            let mut conn = UnixStream::connect(window_proxy::control_socket_path(&name))
+                .map_err(|e| format!(
+                    "The guest agent in {} is not reachable ({}). Keep `vm-provisioner start {}` running, \
+                     or stop the VM to change the password offline.", name, e, name
+                ))?;
+            outln!("🔑 Changing the password through the guest agent in {}...", name);
+            protocol::send_message(&mut conn, &WindowMessage::ChangePassword { new: password.clone() })?;
+            
+            // Only stored once the guest confirms, or the stored password would lock the user out
+            conn.set_read_timeout(Some(PASSWORD_CHANGE_TIMEOUT))?;
+            let reply = protocol::read_frame(&mut conn)
+                .map_err(|e| format!("No answer from the guest agent in {} ({}); the stored password is unchanged", name, e))?;
+            match bincode::deserialize::<WindowMessage>(&reply)? {
+                WindowMessage::PasswordChanged { error: None } => {}
+                WindowMessage::PasswordChanged { error: Some(e) } => {
+                    return Err(format!("The guest agent in {} could not change the password: {}", name, e).into());
+                }
+                other => return Err(format!("Unexpected answer from the guest agent: {:?}", other).into()),
             }
         }
+        "not created" => return Err(format!("VM '{}' does not exist", name).into()),
+        _ => {
+            outln!("🔑 Setting the password in {}'s disk image; it applies from the next boot", name);
+            AppVMProvisioner::new(config.clone()).set_password_offline(&password)?;
+        }
     }
     
+    config.user_password = password;
+    save_vm_config(&config)?;
+    let mut passwords = VMPasswords::load_or_create(&config_dir)?;
+    passwords.add_vm(&name, &config.user_password);
+    passwords.save(&config_dir)?;
+    
+    outln!("✅ New password for user in {}: {}", name, config.user_password);
     Ok(())
 }
 
-async fn destroy_vm(name: String, skip_confirm: bool) -> Result<(), Box<dyn std::error::Error>> {
-    println!("🗑️  Preparing to destroy VM: {}", name);
+fn recover_vm(name: String, skip_confirm: bool) -> Result<(), Box<dyn std::error::Error>> {
+    outln!("📦 Preparing to recover VM: {}", name);
+    
+    let config_dir = format!("{}/.config/vm-provisioner", std::env::var("HOME")?);
+    let config_file = format!("{}/{}.toml", config_dir, name);
+    
+    if Path::new(&config_file).exists() || provisioner::list_domains()?.contains(&name) {
+        return Err(format!("VM '{0}' still exists. Destroy it first with: vm-provisioner destroy {0}", name).into());
+    }
     
     if !skip_confirm {
-        println!("⚠️  This will permanently delete the VM and all its data!");
-        
         let confirm = Confirm::new()
-            .with_prompt("Are you sure?")
+            .with_prompt("Restore the most recent recovery snapshot?")
             .default(false)
             .interact()?;
             
         if !confirm {
-            println!("❌ Destruction cancelled");
+            outln!("❌ Recovery cancelled");
             return Ok(());
         }
     }
     
-    // Load configuration
-    let config_file = format!("{}/.config/vm-provisioner/{}.toml", 
-                             std::env::var("HOME")?, name);
+    let config = provisioner::restore_recovery_snapshot(&name)?;
     
-    if Path::new(&config_file).exists() {
-        let content = std::fs::read_to_string(&config_file)?;
-        let config = toml::from_str::<AppVMConfig>(&content)?;
-        
-        let provisioner = AppVMProvisioner::new(config);
-        provisioner.destroy_vm()?;
-        
-        // Remove configuration file
-        std::fs::remove_file(&config_file)?;
+    save_vm_config(&config)?;
+    
+    let mut passwords = VMPasswords::load_or_create(&config_dir)?;
+    passwords.add_vm(&config.name, &config.user_password);
+    passwords.save(&config_dir)?;
+    
+    outln!("\n✅ VM recovered successfully!");
+    outln!("   Start with: vm-provisioner start {}", config.name);
+    
+    Ok(())
+}
+
+fn show_config(name: &str, show_password: bool, format: ConfigFormat) -> Result<(), Box<dyn std::error::Error>> {
+    // Loading through AppVMConfig fills in defaults for fields missing from the file
+    let mut config = load_vm_config(name)?;
+    if !show_password {
+        config.user_password = "<redacted>".to_string();
+        if config.spice_password.is_some() {
+            config.spice_password = Some("<redacted>".to_string());
+        }
     }
     
-    println!("✅ VM destroyed");
+    let rendered = match format {
+        ConfigFormat::Toml => toml::to_string_pretty(&config)?,
+        ConfigFormat::Json => serde_json::to_string_pretty(&config)?,
+        ConfigFormat::Yaml => serde_yaml::to_string(&config)?,
+    };
+    outln!("{}", rendered.trim_end());
     
     Ok(())
 }
 
-fn connect_console(name: String) -> Result<(), Box<dyn std::error::Error>> {
-    println!("🖥️  Connecting to VM console: {}", name);
+fn edit_config(name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
     
-    std::process::Command::new("virsh")
-        .args(&["console", &name])
-        .status()?;
+    let config_dir = format!("{}/.config/vm-provisioner", std::env::var("HOME")?);
+    let config_file = format!("{}/{}.toml", config_dir, name);
+    let previous = load_vm_config(name)?;
+    let original = std::fs::read_to_string(&config_file)?;
+    
+    // Edit a copy next to the original, which stays untouched until the result checks out
+    let draft = format!("{}/.{}.toml.edit", config_dir, name);
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&draft)?
+        .write_all(original.as_bytes())?;
+    
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    
+    let config = loop {
+        // Through the shell, so editors configured with arguments ("code --wait") work
+        let status = std::process::Command::new("sh")
+            .args(["-c", &format!("{} \"$1\"", editor), "sh", &draft])
+            .status()?;
+        if !status.success() {
+            std::fs::remove_file(&draft)?;
+            return Err(format!("{} exited with {}; {} left unchanged", editor, status, config_file).into());
+        }
+        
+        let edited = std::fs::read_to_string(&draft)?;
+        if edited == original {
+            std::fs::remove_file(&draft)?;
+            outln!("ℹ️  No changes");
+            return Ok(());
+        }
+        
+        match check_edited_config(name, &edited) {
+            Ok(config) => break config,
+            Err(e) => {
+                eoutln!("❌ {}", e);
+                let again = Confirm::new()
+                    .with_prompt("Edit again?")
+                    .default(true)
+                    .interact()?;
+                if !again {
+                    std::fs::remove_file(&draft)?;
+                    outln!("❌ Changes discarded, {} left unchanged", config_file);
+                    return Ok(());
+                }
+            }
+        }
+    };
+    
+    std::fs::rename(&draft, &config_file)?;
+    outln!("✅ Saved {}", config_file);
+    
+    if get_vm_status(name) != "not created" {
+        if let Err(e) = AppVMProvisioner::new(config.clone()).apply_resources(&previous) {
+            eoutln!("⚠️  Failed to update the VM definition: {}", e);
+        }
+    }
+    for note in edit_notes(&previous, &config) {
+        outln!("⚠️  {}", note);
+    }
     
     Ok(())
 }
 
+/// Parse and validate an edited config, which must still describe VM `name`
+fn check_edited_config(name: &str, text: &str) -> Result<AppVMConfig, Box<dyn std::error::Error>> {
+    let config = AppVMConfig::from_toml(text).map_err(|e| format!("Invalid configuration: {}", e))?;
+    if config.name != name {
+        return Err(format!("name can't be changed by editing (was {}, now {})", name, config.name).into());
+    }
+    config.validate_installed().map_err(|e| format!("Invalid configuration: {}", e))?;
+    Ok(config)
+}
 
-fn get_vm_status(name: &str) -> String {
-    match std::process::Command::new("virsh")
-        .args(&["domstate", name])
-        .output()
+/// When the edited settings take effect, for the changes that don't apply right away
+fn edit_notes(previous: &AppVMConfig, config: &AppVMConfig) -> Vec<String> {
+    let mut notes = Vec::new();
+    if previous.memory_mb != config.memory_mb || previous.vcpus != config.vcpus {
+        notes.push("CPU and memory changes take effect on the VM's next boot".to_string());
+    }
+    if previous.cpu_pinning != config.cpu_pinning || previous.cpu_topology != config.cpu_topology {
+        notes.push(format!("CPU pinning and topology only apply to a rebuilt VM: vm-provisioner reprovision {}", config.name));
+    }
+    if previous.proxy_transport != config.proxy_transport || previous.proxy_tls != config.proxy_tls {
+        notes.push(format!("The window proxy transport and TLS only apply to a rebuilt VM: vm-provisioner reprovision {}", config.name));
+    }
+    if previous.spice_listen != config.spice_listen || previous.spice_password != config.spice_password {
+        notes.push(format!("The SPICE listen address and password only apply to a rebuilt VM: vm-provisioner reprovision {}", config.name));
+    }
+    if previous.shared_folders != config.shared_folders {
+        notes.push(format!("Shared folders only apply to a rebuilt VM: vm-provisioner reprovision {}", config.name));
+    }
+    if previous.usb_devices != config.usb_devices {
+        notes.push(format!("USB devices only apply to a rebuilt VM: vm-provisioner reprovision {}", config.name));
+    }
+    if previous.disk_size_gb != config.disk_size_gb {
+        notes.push(format!("The disk size only applies to a rebuilt VM: vm-provisioner reprovision {}", config.name));
+    }
+    if previous.system_packages != config.system_packages
+        || previous.flatpak_packages != config.flatpak_packages
+        || previous.auto_launch_apps != config.auto_launch_apps
     {
-        Ok(output) if output.status.success() => {
-            String::from_utf8_lossy(&output.stdout).trim().to_string()
-        }
-        _ => "not created".to_string()
+        notes.push(format!("Package changes apply to the running VM with: vm-provisioner rebuild {}", config.name));
     }
+    notes
 }
 
-fn show_passwords() -> Result<(), Box<dyn std::error::Error>> {
+fn load_vm_config(name: &str) -> Result<AppVMConfig, Box<dyn std::error::Error>> {
+    let config_file = format!("{}/.config/vm-provisioner/{}.toml", 
+                             std::env::var("HOME")?, name);
+    
+    if !Path::new(&config_file).exists() {
+        return Err(format!("VM configuration not found: {}", name).into());
+    }
+    
+    let content = std::fs::read_to_string(&config_file)?;
+    AppVMConfig::from_toml(&content).map_err(|e| format!("{}: {}", config_file, e).into())
+}
+
+/// Write a VM's config to `~/.config/vm-provisioner/<name>.toml`, returning the path
+fn save_vm_config(config: &AppVMConfig) -> Result<String, Box<dyn std::error::Error>> {
     let config_dir = format!("{}/.config/vm-provisioner", std::env::var("HOME")?);
-    let password_file = format!("{}/vm-passwords.toml", config_dir);
+    std::fs::create_dir_all(&config_dir)?;
+    let config_file = format!("{}/{}.toml", config_dir, config.name);
+    std::fs::write(&config_file, toml::to_string_pretty(config)?)?;
+    Ok(config_file)
+}
+
+/// Subcommands whose first argument is a VM name
+fn vm_name_commands(command: &clap::Command) -> Vec<String> {
+    command.get_subcommands()
+        .filter(|sub| !sub.is_hide_set())
+        .filter(|sub| sub.get_positionals().next().is_some_and(|arg| arg.get_id() == "name"))
+        .map(|sub| sub.get_name().to_string())
+        .collect()
+}
+
+/// clap's completion script for `shell`, plus hooks completing VM names where
+/// the shell makes that easy (bash, zsh and fish); the names come from
+/// `completions --list-names` so they track the configs at completion time
+fn completion_script(shell: Shell) -> String {
+    let mut command = Cli::command();
+    let mut buffer = Vec::new();
+    clap_complete::generate(shell, &mut command, "vm-provisioner", &mut buffer);
+    let script = String::from_utf8_lossy(&buffer).into_owned();
+    let commands = vm_name_commands(&command);
     
-    if !Path::new(&password_file).exists() {
-        println!("❌ No password file found");
-        println!("   Create a VM first to generate passwords");
-        return Ok(());
+    match shell {
+        Shell::Bash => {
+            let Some(generated) = regex::Regex::new(r"complete -F (\S+)").unwrap()
+                .captures(&script)
+                .map(|captures| captures[1].to_string())
+            else {
+                return script;
+            };
+            let hook = format!(r#"
+_vm_provisioner_names() {{
+    if [[ ${{COMP_CWORD}} -eq 2 && ${{COMP_WORDS[2]}} != -* ]]; then
+        case "${{COMP_WORDS[1]}}" in
+            {commands})
+                COMPREPLY=( $(compgen -W "$(vm-provisioner completions --list-names 2>/dev/null)" -- "${{COMP_WORDS[2]}}") )
+                return 0
+                ;;
+        esac
+    fi
+    {generated} "$@"
+}}
+"#, commands = commands.join("|"));
+            script.replace(&format!("complete -F {} ", generated), "complete -F _vm_provisioner_names ") + &hook
+        }
+        Shell::Zsh => {
+            let hook = r#"#compdef vm-provisioner
+
+_vm_provisioner_names() {
+    local -a names
+    names=(${(f)"$(vm-provisioner completions --list-names 2>/dev/null)"})
+    _describe -t vms 'VM name' names
+}
+"#;
+            script.replacen("#compdef vm-provisioner
+", hook, 1)
+                .replace(":name -- VM name:_default'", ":name -- VM name:_vm_provisioner_names'")
+        }
+        Shell::Fish => format!(
+            "{}complete -c vm-provisioner -n \"__fish_seen_subcommand_from {}\" -f -a \"(vm-provisioner completions --list-names)\"\n",
+            script, commands.join(" ")
+        ),
+        _ => script,
     }
+}
+
+/// `vm-passwords.toml` sits next to the VM configs but isn't one
+fn is_passwords_file(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| name == "vm-passwords.toml")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
     
-    let passwords = VMPasswords::load_or_create(&config_dir)?;
+    fn create_args(args: &[&str]) -> CreateArgs {
+        let cli = Cli::try_parse_from(["vm-provisioner", "create"].iter().chain(args)).unwrap();
+        match cli.command {
+            Commands::Create(args) => *args,
+            _ => unreachable!(),
+        }
+    }
     
-    if passwords.vms.is_empty() {
-        println!("ℹ️  No VM passwords stored yet");
-        return Ok(());
+    #[test]
+    fn edited_config_must_parse_validate_and_keep_its_name() {
+        let config = AppVMConfig::new("edit-vm".to_string(), 4096, 2, 20, vec![], vec![]);
+        let text = toml::to_string_pretty(&config).unwrap();
+        assert_eq!(check_edited_config("edit-vm", &text).unwrap().memory_mb, 4096);
+        
+        assert!(check_edited_config("other-vm", &text).unwrap_err().to_string().contains("name can't be changed"));
+        assert!(check_edited_config("edit-vm", "memory_mb = ").is_err());
+        
+        let invalid = text.replace("vcpus = 2", "vcpus = 2\nhostname = \"not a hostname\"");
+        assert!(check_edited_config("edit-vm", &invalid).is_err());
+        
+        // The install ISO may be gone once the VM is installed
+        let iso_gone = text.replace("vcpus = 2", "vcpus = 2\niso_path = \"/nonexistent/fedora.iso\"");
+        assert!(check_edited_config("edit-vm", &iso_gone).unwrap().iso_path.is_some());
+    }
+    
+    #[test]
+    fn edit_notes_explain_deferred_changes() {
+        let previous = AppVMConfig::new("edit-vm".to_string(), 4096, 2, 20, vec![], vec![]);
+        assert!(edit_notes(&previous, &previous).is_empty());
+        
+        let mut config = previous.clone();
+        config.memory_mb = 8192;
+        config.system_packages.push("gimp".to_string());
+        let notes = edit_notes(&previous, &config);
+        assert_eq!(notes.len(), 2);
+        assert!(notes[0].contains("next boot"));
+        assert!(notes[1].contains("rebuild edit-vm"));
     }
     
-    println!("🔑 VM Login Credentials:");
-    println!("   File: {}", password_file);
-    println!();
+    #[test]
+    fn passwords_format_is_detected_from_the_header() {
+        let plaintext = "[vms]\nfirefox-vm = \"vm-abc\"\n";
+        assert_eq!(PasswordsFormat::detect(plaintext).unwrap(), PasswordsFormat::Plaintext(plaintext));
+        
+        let encrypted = "# vm-provisioner-passwords v2 aes-256-gcm argon2id m=65536,t=3,p=4\nsalt = \"00\"\n";
+        assert_eq!(PasswordsFormat::detect(encrypted).unwrap(), PasswordsFormat::AesGcmV2(PASSWORDS_KDF, "salt = \"00\"\n"));
+        
+        let incomplete = "# vm-provisioner-passwords v2 aes-256-gcm argon2id m=65536,t=3\n";
+        assert!(PasswordsFormat::detect(incomplete).unwrap_err().to_string().contains("incomplete"));
+        
+        let future = "# vm-provisioner-passwords v3 age\n...";
+        assert!(PasswordsFormat::detect(future).unwrap_err().to_string().contains("v3 age"));
+    }
     
-    for (vm_name, password) in &passwords.vms {
-        println!("   {} | user:{}", vm_name, password);
+    #[test]
+    fn sealed_passwords_open_only_with_the_passphrase() {
+        // A cheap cost keeps the test fast; opening must use the cost it was sealed with
+        let kdf = KdfParams { memory_kib: 64, passes: 1, lanes: 1 };
+        let sealed = EncryptedPasswords::seal(b"[vms]\n", "correct horse", kdf).unwrap();
+        assert_eq!(sealed.open("correct horse", kdf).unwrap(), b"[vms]\n");
+        assert!(sealed.open("wrong horse", kdf).is_err());
+        assert!(sealed.open("correct horse", KdfParams { passes: 2, ..kdf }).is_err());
+        
+        // Fresh salt and nonce every time
+        let again = EncryptedPasswords::seal(b"[vms]\n", "correct horse", kdf).unwrap();
+        assert_ne!((&sealed.salt, &sealed.nonce), (&again.salt, &again.nonce));
+        
+        let mut tampered = sealed;
+        tampered.ciphertext.replace_range(0..2, if tampered.ciphertext.starts_with("00") { "01" } else { "00" });
+        assert!(tampered.open("correct horse", kdf).is_err());
     }
     
-    println!("\n💡 Usage:");
-    println!("   sudo virsh console <vm-name>");
-    println!("   vm-provisioner start <vm-name>  # Shows password");
+    #[test]
+    fn no_encrypt_refuses_an_encrypted_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_dir = dir.path().to_str().unwrap();
+        assert!(check_store_unencrypted(config_dir).is_ok());
+        
+        std::fs::write(dir.path().join("vm-passwords.toml"), "[vms]\n").unwrap();
+        assert!(check_store_unencrypted(config_dir).is_ok());
+        
+        std::fs::write(dir.path().join("vm-passwords.toml"), "# vm-provisioner-passwords v2 aes-256-gcm argon2id m=65536,t=3,p=4\n").unwrap();
+        assert!(check_store_unencrypted(config_dir).unwrap_err().to_string().contains("--no-encrypt"));
+    }
     
-    Ok(())
+    #[test]
+    fn hex_round_trips_and_rejects_junk() {
+        assert_eq!(hex_encode(&[0x00, 0xab, 0xff]), "00abff");
+        assert_eq!(hex_decode("00abff").unwrap(), [0x00, 0xab, 0xff]);
+        assert!(hex_decode("abc").is_err());
+        assert!(hex_decode("zz").is_err());
+    }
+    
+    #[test]
+    fn unreadable_password_store_is_an_error_not_an_empty_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_dir = dir.path().to_str().unwrap();
+        assert!(VMPasswords::load_or_create(config_dir).unwrap().vms.is_empty());
+        
+        std::fs::write(dir.path().join("vm-passwords.toml"), "[vms\nbroken").unwrap();
+        assert!(VMPasswords::load_or_create(config_dir).unwrap_err().to_string().contains("not a valid password store"));
+    }
+    
+    #[test]
+    fn create_flags_without_config_file_use_defaults() {
+        let config = create_args(&["--system", "vim", "--memory", "8192"]).into_config().unwrap();
+        assert_eq!(config.name, "vim-vm");
+        assert_eq!(config.memory_mb, 8192);
+        assert_eq!(config.vcpus, DEFAULT_VCPUS);
+        assert_eq!(config.disk_size_gb, DEFAULT_DISK_GB);
+        assert!(config.system_packages.contains(&"vim".to_string()));
+    }
+    
+    #[test]
+    fn create_flags_override_config_file() {
+        let mut base = AppVMConfig::new("base-vm".to_string(), 2048, 4, 40, vec![], vec!["org.example.A".to_string()]);
+        base.hostname = Some("base".to_string());
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), toml::to_string_pretty(&base).unwrap()).unwrap();
+        let path = file.path().to_str().unwrap();
+        
+        let config = create_args(&["--config", path, "--memory", "8192", "--flatpak", "org.example.B"])
+            .into_config()
+            .unwrap();
+        
+        // Flags that were given win...
+        assert_eq!(config.memory_mb, 8192);
+        // ...everything else comes from the file, not the CLI defaults
+        assert_eq!(config.name, "base-vm");
+        assert_eq!(config.vcpus, 4);
+        assert_eq!(config.disk_size_gb, 40);
+        assert_eq!(config.hostname.as_deref(), Some("base"));
+        // Packages are layered on top of the file's
+        assert_eq!(config.flatpak_packages, vec!["org.example.A", "org.example.B"]);
+        assert!(config.auto_launch_apps.contains(&"flatpak run org.example.B".to_string()));
+        
+        let config = create_args(&["--config", path, "--name", "other-vm", "--vm-dir", "/srv/vms/"]).into_config().unwrap();
+        assert_eq!(config.name, "other-vm");
+        assert_eq!(config.memory_mb, 2048);
+        assert_eq!(config.vm_dir, "/srv/vms");
+    }
+    
+    #[test]
+    fn injections_must_target_the_guest_home() {
+        assert_eq!(parse_injection("token.json:~/.config/app/token.json").unwrap(),
+                   (PathBuf::from("token.json"), "~/.config/app/token.json".to_string()));
+        assert!(parse_injection("token.json").is_err());
+        assert!(parse_injection("token.json:").is_err());
+        assert!(parse_injection("token.json:/etc/profile.d/x.sh").is_err());
+        assert!(parse_injection("token.json:~/../root/x").is_err());
+    }
+    
+    #[test]
+    fn log_lines_parse_journal_and_proxy_timestamps() {
+        assert_eq!(parse_log_line("1700000000.123456 work-vm guest-agent[812]: Connected", "guest", true),
+                   Some(LogLine { at: 1_700_000_000_123_456, source: "guest", text: "guest-agent[812]: Connected".to_string() }));
+        assert_eq!(parse_log_line("1700000000.5 Guest agent connected", "proxy", false).unwrap().at, 1_700_000_000_500_000);
+        assert_eq!(parse_log_line("-- No entries --", "guest", true), None);
+    }
+    
+    #[test]
+    fn proxy_log_is_read_incrementally_by_complete_lines() {
+        use std::io::Write;
+        
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, "1700000000.000001 Guest agent connected\n1700000001.000000 Warn: par").unwrap();
+        
+        let mut offset = 0;
+        let lines = read_proxy_log(file.path(), &mut offset).unwrap();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].text, "Guest agent connected");
+        
+        writeln!(file, "tial").unwrap();
+        let lines = read_proxy_log(file.path(), &mut offset).unwrap();
+        assert_eq!(lines.iter().map(|line| line.text.as_str()).collect::<Vec<_>>(), vec!["Warn: partial"]);
+        assert!(read_proxy_log(file.path(), &mut offset).unwrap().is_empty());
+        
+        // A log that started over is read from the beginning
+        file.as_file().set_len(0).unwrap();
+        assert!(read_proxy_log(file.path(), &mut offset).unwrap().is_empty());
+        assert_eq!(offset, 0);
+    }
+    
+    #[test]
+    fn completions_offer_vm_names_for_commands_taking_one() {
+        let commands = vm_name_commands(&Cli::command());
+        for command in ["start", "stop", "destroy", "console"] {
+            assert!(commands.iter().any(|c| c == command), "{} should complete VM names", command);
+        }
+        assert!(!commands.iter().any(|c| c == "create" || c == "expire"));
+        
+        let bash = completion_script(Shell::Bash);
+        assert!(bash.contains("complete -F _vm_provisioner_names"));
+        assert!(bash.contains("start|stop|"));
+        let zsh = completion_script(Shell::Zsh);
+        assert!(zsh.starts_with("#compdef vm-provisioner\n"));
+        assert!(zsh.contains(":name -- VM name:_vm_provisioner_names"));
+        assert!(!zsh.contains(":name -- VM name:_default"));
+        assert!(completion_script(Shell::Fish).contains("completions --list-names"));
+    }
+    
+    #[test]
+    fn create_all_needs_at_least_one_install_at_a_time() {
+        let cli = Cli::try_parse_from(["vm-provisioner", "create-all", "fleet", "--parallel", "3"]).unwrap();
+        assert!(matches!(cli.command, Commands::CreateAll { parallel: 3, yes: false, .. }));
+        assert!(Cli::try_parse_from(["vm-provisioner", "create-all", "fleet", "--parallel", "0"]).is_err());
+    }
+    
+    #[test]
+    fn exported_configs_leave_out_the_password_and_mac() {
+        let mut config = AppVMConfig::new("work-vm".to_string(), 4096, 2, 20, vec!["git".to_string()], Vec::new());
+        config.mac_address = Some("52:54:00:12:34:56".to_string());
+        config.spice_password = Some("display-secret".to_string());
+        
+        let exported = exported_config(&config);
+        assert!(exported.user_password.is_empty());
+        assert_eq!(exported.spice_password, None);
+        assert_eq!(exported.mac_address, None);
+        
+        let text = toml::to_string_pretty(&exported).unwrap();
+        assert!(!text.contains(&config.user_password));
+        let imported = AppVMConfig::from_toml(&text).unwrap();
+        assert_eq!(imported.system_packages, config.system_packages);
+    }
+    
+    #[test]
+    fn new_vms_skip_proxy_ports_other_vms_use() {
+        let claimed: HashSet<u16> = [9999, 10000, 10002].into_iter().collect();
+        assert_eq!(free_proxy_port(9998, &claimed), Some(9998));
+        assert_eq!(free_proxy_port(9999, &claimed), Some(10001));
+        assert_eq!(free_proxy_port(u16::MAX, &[u16::MAX].into_iter().collect()), None);
+        
+        let mut config = AppVMConfig::new("work-vm".to_string(), 4096, 2, 20, Vec::new(), Vec::new());
+        assign_proxy_port(&mut config, &claimed).unwrap();
+        assert_eq!(config.proxy_port, 10001);
+    }
+    
+    #[test]
+    fn start_runs_the_window_proxy_unless_turned_off() {
+        let cli = Cli::try_parse_from(["vm-provisioner", "start", "work-vm"]).unwrap();
+        assert!(matches!(cli.command, Commands::Start { seamless: true, .. }));
+        let cli = Cli::try_parse_from(["vm-provisioner", "start", "work-vm", "--seamless", "false"]).unwrap();
+        assert!(matches!(cli.command, Commands::Start { seamless: false, .. }));
+    }
 }
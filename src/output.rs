@@ -0,0 +1,97 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Plain output: no emoji and no ANSI escapes, for log scrapers and screen readers
+static PLAIN: AtomicBool = AtomicBool::new(false);
+
+/// Select plain output when `--plain` was given or `NO_COLOR` is set to anything
+pub fn init(plain: bool) {
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    PLAIN.store(plain || no_color, Ordering::Relaxed);
+}
+
+/// Whether plain output was selected
+pub fn is_plain() -> bool {
+    PLAIN.load(Ordering::Relaxed)
+}
+
+/// Text as it should be shown under the current output settings
+pub fn render(text: &str) -> String {
+    if PLAIN.load(Ordering::Relaxed) {
+        plain(text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// `println!` that honours `--plain`/`NO_COLOR`
+macro_rules! outln {
+    () => { println!() };
+    ($($arg:tt)*) => { println!("{}", $crate::output::render(&format!($($arg)*))) };
+}
+
+/// `eprintln!` that honours `--plain`/`NO_COLOR`
+macro_rules! eoutln {
+    () => { eprintln!() };
+    ($($arg:tt)*) => { eprintln!("{}", $crate::output::render(&format!($($arg)*))) };
+}
+
+fn is_emoji(c: char) -> bool {
+    matches!(c as u32,
+        0x1F000..=0x1FAFF   // Pictographs, emoticons, transport, supplemental symbols
+        | 0x2300..=0x23FF   // Misc technical (⌛ ⏳ ⏹)
+        | 0x25A0..=0x25FF   // Geometric shapes (▶)
+        | 0x2600..=0x27BF   // Misc symbols and dingbats (⚠ ✅ ❌)
+        | 0x2B00..=0x2BFF   // Arrows and stars (⬆ ⭐)
+        | 0xFE0F            // Emoji presentation selector
+        | 0x200D            // Zero-width joiner
+    )
+}
+
+/// Strip emoji (with the spacing that followed them) and ANSI escape sequences
+pub fn plain(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // CSI sequence: ESC [ parameters final-byte
+            if chars.peek() == Some(&'[') {
+                chars.next();
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            continue;
+        }
+        
+        if is_emoji(c) {
+            while chars.peek().is_some_and(|&c| is_emoji(c)) {
+                chars.next();
+            }
+            while chars.peek() == Some(&' ') {
+                chars.next();
+            }
+            continue;
+        }
+        
+        out.push(c);
+    }
+    
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn plain_output_strips_emoji_and_ansi() {
+        assert_eq!(plain("✅ VM started successfully!"), "VM started successfully!");
+        assert_eq!(plain("   ⚠️  Starting libvirtd..."), "   Starting libvirtd...");
+        assert_eq!(plain("🗑️  Destroying VM: test"), "Destroying VM: test");
+        assert_eq!(plain("\x1b[1;32mready\x1b[0m (host → guest)"), "ready (host → guest)");
+        assert_eq!(plain("no decoration"), "no decoration");
+    }
+}
@@ -0,0 +1,397 @@
+//! Wire protocol between the host window proxy and the guest agent. Both binaries
+//! compile this same file, so the two sides can't drift apart.
+
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use serde::{Serialize, Deserialize};
+
+/// Version of the `WindowMessage` protocol. Bump whenever `WindowMessage` changes.
+pub const PROTOCOL_VERSION: u32 = 14;
+
+/// Messages sent from guest to host about window state
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum WindowMessage {
+    // Handshake sent by the guest on connect. Must stay the first variant so
+    // it decodes the same way across protocol versions.
+    ProtocolHello {
+        version: u32,
+    },
+    
+    // Window lifecycle
+    WindowCreated { 
+        id: u32, 
+        title: String,
+        width: u32,
+        height: u32,
+        x: i32,
+        y: i32,
+        app_name: String,
+    },
+    WindowDestroyed { 
+        id: u32 
+    },
+    WindowMoved { 
+        id: u32, 
+        x: i32, 
+        y: i32 
+    },
+    WindowResized { 
+        id: u32, 
+        width: u32, 
+        height: u32 
+    },
+    WindowTitleChanged { 
+        id: u32, 
+        title: String 
+    },
+    WindowFocusChanged { 
+        id: u32, 
+        focused: bool 
+    },
+    
+    // Application lifecycle
+    ApplicationStarted { 
+        app_name: String,
+        pid: u32,
+    },
+    ApplicationStopped { 
+        app_name: String,
+        pid: u32,
+    },
+    
+    // File transfer (host → guest), written to ~/Downloads
+    FileTransferStart {
+        transfer_id: u32,
+        file_name: String,
+        size: u64,
+        sha256: String,
+        destination: Option<String>,  // Path under the guest's home; None: ~/Downloads
+    },
+    FileTransferChunk {
+        transfer_id: u32,
+        data: Vec<u8>,
+    },
+    FileTransferEnd {
+        transfer_id: u32,
+    },
+    
+    // Diagnostics from the guest agent, shown by the host proxy
+    GuestLog {
+        level: LogLevel,
+        message: String,
+    },
+    
+    // Window z-order, bottom to top (from _NET_CLIENT_LIST_STACKING)
+    StackingOrder {
+        ids: Vec<u32>,
+    },
+    
+    // Link clicked in the guest that should open in the host browser
+    OpenUrl {
+        url: String,
+    },
+    
+    // Applications installed in the guest, sent on connect
+    ApplicationList {
+        apps: Vec<AppEntry>,
+    },
+    
+    // Launch an application from the guest's application list (host → guest)
+    OpenApp {
+        app_id: String,
+    },
+    
+    // Desktop notification raised by a guest app, re-raised on the host
+    Notification {
+        app: String,
+        summary: String,
+        body: String,
+        icon: Option<String>,
+    },
+    
+    // The host window was closed; close the guest window gracefully (host → guest)
+    CloseRequested {
+        id: u32,
+    },
+    
+    // Change the guest user's password (host → guest)
+    ChangePassword {
+        new: String,
+    },
+    
+    // Result of `ChangePassword`, `error` is None once the password changed (guest → host)
+    PasswordChanged {
+        error: Option<String>,
+    },
+    
+    // Heartbeat: the guest pings every `PING_INTERVAL`, the host answers with the same `seq`
+    Ping {
+        seq: u64,
+    },
+    Pong {
+        seq: u64,
+    },
+    
+    // Input on a proxied host window, injected into the guest window (host → guest).
+    // `key` is a Linux evdev key code, as Wayland reports it.
+    KeyEvent {
+        id: u32,
+        key: u32,
+        pressed: bool,
+    },
+    PointerEvent {
+        id: u32,
+        action: PointerAction,
+    },
+    
+    // Clipboard sync, both ways, with `clipboard_transport = "Custom"`
+    Clipboard(ClipboardMessage),
+}
+
+/// What the pointer did over a proxied window
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum PointerAction {
+    /// Position relative to the window's top left corner
+    Motion { x: i32, y: i32 },
+    /// `button` is a Linux evdev code (`BTN_LEFT` is 0x110)
+    Button { button: u32, pressed: bool },
+    /// Whole wheel steps; positive scrolls down or right
+    Scroll { dx: i32, dy: i32 },
+}
+
+/// Severity of a `GuestLog` message
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+/// An application the guest can launch, identified by its desktop file ID
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct AppEntry {
+    pub id: String,
+    pub name: String,
+    pub icon: Option<String>,
+}
+
+/// How often the guest agent pings the host
+pub const PING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Silence after which either side considers the other gone
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Largest file accepted by `push`
+pub const MAX_FILE_TRANSFER_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Payload size of a single `FileTransferChunk`
+pub const FILE_TRANSFER_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Largest frame accepted from a peer, well above a file chunk or a big clipboard
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Largest clipboard payload synced, text or not, leaving room in the frame for the MIME type
+pub const MAX_CLIPBOARD_DATA_SIZE: usize = MAX_FRAME_SIZE - 4096;
+
+/// Clipboard sync between host and guest. Text uses the `String` variants; other
+/// contents, such as images, travel as raw bytes with their MIME type. The guest
+/// sends what it copied as `SetClipboard*`, the host sends its clipboard as
+/// `ClipboardContent`/`ClipboardData`, when it changes or on `GetClipboard`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ClipboardMessage {
+    SetClipboard(String),
+    GetClipboard,
+    ClipboardContent(String),
+    SetClipboardData { mime_type: String, data: Vec<u8> },
+    ClipboardData { mime_type: String, data: Vec<u8> },
+}
+
+/// What's on the clipboard, as synced between host and VM
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClipboardContents {
+    Text(String),
+    Data { mime_type: String, data: Vec<u8> },
+}
+
+impl ClipboardContents {
+    /// The message handing these contents to the other side: `ClipboardContent*`
+    /// from the host, `SetClipboard*` from the guest
+    pub fn into_message(self, from_host: bool) -> ClipboardMessage {
+        match (self, from_host) {
+            (ClipboardContents::Text(text), true) => ClipboardMessage::ClipboardContent(text),
+            (ClipboardContents::Text(text), false) => ClipboardMessage::SetClipboard(text),
+            (ClipboardContents::Data { mime_type, data }, true) => ClipboardMessage::ClipboardData { mime_type, data },
+            (ClipboardContents::Data { mime_type, data }, false) => ClipboardMessage::SetClipboardData { mime_type, data },
+        }
+    }
+}
+
+/// Record `current` as the clipboard and return it if it differs from `known`.
+/// `known` also holds what the other side last set, so its own copy isn't echoed back.
+pub fn clipboard_changed<T: Clone + PartialEq>(known: &mut T, current: T) -> Option<T> {
+    if *known == current {
+        return None;
+    }
+    known.clone_from(&current);
+    Some(current)
+}
+
+/// Plain text under its MIME or X11 selection target names
+pub fn is_text_type(mime_type: &str) -> bool {
+    mime_type.starts_with("text/plain") || matches!(mime_type, "UTF8_STRING" | "STRING" | "TEXT")
+}
+
+/// Which of the clipboard's offered MIME types to sync: plain text when offered,
+/// otherwise an image, PNG first. Other types (HTML, file lists, ...) aren't synced.
+pub fn pick_clipboard_type<'a>(types: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let types: Vec<&str> = types.into_iter().map(str::trim).collect();
+    types.iter().find(|t| is_text_type(t))
+        .or_else(|| types.iter().find(|t| **t == "image/png"))
+        .or_else(|| types.iter().find(|t| t.starts_with("image/")))
+        .copied()
+}
+
+/// Turn the bytes read for `mime_type` into contents worth syncing, or `None`
+/// when they are too big for a frame
+pub fn clipboard_contents(mime_type: &str, data: Vec<u8>) -> Option<ClipboardContents> {
+    if is_text_type(mime_type) {
+        // Replacement characters for invalid UTF-8 can make the text longer than the bytes read
+        let text = String::from_utf8_lossy(&data).into_owned();
+        (text.len() <= MAX_CLIPBOARD_DATA_SIZE).then_some(ClipboardContents::Text(text))
+    } else if data.len() > MAX_CLIPBOARD_DATA_SIZE {
+        None
+    } else {
+        Some(ClipboardContents::Data { mime_type: mime_type.to_string(), data })
+    }
+}
+
+/// Read one frame: a little-endian `u32` length, then that many bytes. Loops over
+/// short reads, and leaves anything after the frame for the next call.
+pub fn read_frame(stream: &mut impl Read) -> std::io::Result<Vec<u8>> {
+    let mut len_buf = [0u8; 4];
+    stream.read_exact(&mut len_buf)?;
+    let len = u32::from_le_bytes(len_buf) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+                                       format!("frame of {} bytes exceeds the {} byte limit", len, MAX_FRAME_SIZE)));
+    }
+    
+    let mut data = vec![0u8; len];
+    stream.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// Write one frame in the format `read_frame` expects
+pub fn write_frame(stream: &mut impl Write, data: &[u8]) -> std::io::Result<()> {
+    let len = u32::try_from(data.len())
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "frame too large"))?;
+    stream.write_all(&len.to_le_bytes())?;
+    stream.write_all(data)
+}
+
+/// Send a message as one frame. A message larger than `MAX_FRAME_SIZE` is refused
+/// without writing anything, since the peer would drop the connection over it.
+pub fn send_message(stream: &mut impl Write, msg: &WindowMessage) -> Result<(), Box<dyn std::error::Error>> {
+    let data = bincode::serialize(msg)?;
+    if data.len() > MAX_FRAME_SIZE {
+        return Err(format!("message of {} bytes exceeds the {} byte frame limit", data.len(), MAX_FRAME_SIZE).into());
+    }
+    write_frame(stream, &data)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// Hands out at most one byte per `read`, like a slow socket
+    struct Trickle<'a>(&'a [u8]);
+    
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let Some((first, rest)) = self.0.split_first() else {
+                return Ok(0);
+            };
+            buf[0] = *first;
+            self.0 = rest;
+            Ok(1)
+        }
+    }
+    
+    #[test]
+    fn frames_survive_short_reads_and_coalescing() {
+        let big = vec![7u8; 100_000];
+        let mut wire = Vec::new();
+        write_frame(&mut wire, &big).unwrap();
+        write_frame(&mut wire, b"second").unwrap();
+        
+        // Two frames in one buffer
+        let mut coalesced = wire.as_slice();
+        assert_eq!(read_frame(&mut coalesced).unwrap(), big);
+        assert_eq!(read_frame(&mut coalesced).unwrap(), b"second");
+        assert!(read_frame(&mut coalesced).is_err());
+        
+        // One byte at a time
+        let mut trickle = Trickle(&wire);
+        assert_eq!(read_frame(&mut trickle).unwrap().len(), big.len());
+        assert_eq!(read_frame(&mut trickle).unwrap(), b"second");
+    }
+    
+    #[test]
+    fn oversized_frames_are_rejected() {
+        let mut wire = ((MAX_FRAME_SIZE + 1) as u32).to_le_bytes().as_slice().to_vec();
+        wire.extend_from_slice(b"ignored");
+        let error = read_frame(&mut wire.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        
+        // Nor are they sent, which keeps the connection up
+        let mut sent = Vec::new();
+        let huge = WindowMessage::GuestLog { level: LogLevel::Info, message: "x".repeat(MAX_FRAME_SIZE) };
+        assert!(send_message(&mut sent, &huge).is_err());
+        assert!(sent.is_empty());
+    }
+    
+    #[test]
+    fn clipboard_changes_are_reported_once_and_the_other_sides_copies_not_echoed() {
+        let mut known = String::new();
+        assert_eq!(clipboard_changed(&mut known, "host text".to_string()), Some("host text".to_string()));
+        assert_eq!(clipboard_changed(&mut known, "host text".to_string()), None);
+        
+        // The VM set the clipboard; the host now reports the same text
+        known = "vm text".to_string();
+        assert_eq!(clipboard_changed(&mut known, "vm text".to_string()), None);
+    }
+    
+    #[test]
+    fn clipboard_prefers_text_then_png_then_other_images() {
+        assert_eq!(pick_clipboard_type(["text/html", "text/plain;charset=utf-8", "image/png"]), Some("text/plain;charset=utf-8"));
+        assert_eq!(pick_clipboard_type(["image/bmp", "image/png", "text/html"]), Some("image/png"));
+        assert_eq!(pick_clipboard_type(["image/jpeg"]), Some("image/jpeg"));
+        assert_eq!(pick_clipboard_type(["TARGETS", "TIMESTAMP", "UTF8_STRING"]), Some("UTF8_STRING"));
+        assert_eq!(pick_clipboard_type(["text/uri-list"]), None);
+        
+        let image = ClipboardContents::Data { mime_type: "image/png".to_string(), data: vec![0x89, b'P'] };
+        assert!(matches!(image.clone().into_message(true), ClipboardMessage::ClipboardData { mime_type, .. } if mime_type == "image/png"));
+        assert!(matches!(image.into_message(false), ClipboardMessage::SetClipboardData { mime_type, .. } if mime_type == "image/png"));
+    }
+    
+    #[test]
+    fn oversized_clipboard_contents_are_not_synced() {
+        assert_eq!(clipboard_contents("UTF8_STRING", b"hi".to_vec()), Some(ClipboardContents::Text("hi".to_string())));
+        assert!(clipboard_contents("text/plain", vec![b'a'; MAX_CLIPBOARD_DATA_SIZE]).is_some());
+        assert!(clipboard_contents("text/plain", vec![b'a'; MAX_CLIPBOARD_DATA_SIZE + 1]).is_none());
+        // Each invalid byte becomes a three-byte replacement character
+        assert!(clipboard_contents("text/plain", vec![0xff; MAX_CLIPBOARD_DATA_SIZE / 2]).is_none());
+        assert!(clipboard_contents("image/png", vec![0; MAX_CLIPBOARD_DATA_SIZE]).is_some());
+        assert!(clipboard_contents("image/png", vec![0; MAX_CLIPBOARD_DATA_SIZE + 1]).is_none());
+    }
+    
+    #[test]
+    fn handshake_stays_the_first_variant() {
+        // A mismatched agent must still be understood to say which version it speaks
+        let hello = bincode::serialize(&WindowMessage::ProtocolHello { version: 7 }).unwrap();
+        assert_eq!(hello, [0, 0, 0, 0, 7, 0, 0, 0]);
+    }
+}
@@ -1,311 +1,836 @@
 use std::fs;
-use std::path::Path;
-use std::process::Command;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::collections::VecDeque;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::config::{AppVMConfig, GraphicsBackend};
+use dialoguer::Confirm;
+
+use crate::config::{AppVMConfig, ClipboardTransport, DisplaySetup, GraphicsBackend, IdentityMode, MirrorCredentials, NetworkMode, Preallocation, ProvisioningMethod, ProxyTransport, SessionType, VpnConfig, VpnProvider};
+use crate::libvirt;
+use crate::state::{VMPhase, VMState};
+use crate::tls;
+
+/// How many times a network-interrupted installation is attempted in total
+const MAX_INSTALL_ATTEMPTS: u32 = 3;
+
+/// A virt-install run that exited unsuccessfully
+#[derive(Debug, thiserror::Error)]
+#[error("VM installation failed with exit code {code:?}: {reason}{}", format_install_tail(.tail))]
+pub struct InstallError {
+    code: Option<i32>,
+    reason: String,
+    network: bool,
+    tail: Vec<String>,
+}
+
+/// Installer output kept to explain a failed install
+const INSTALL_TAIL_LINES: usize = 20;
+
+/// Where a line of install output came from
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum InstallSource {
+    VirtInstall,
+    Console,
+}
+
+/// A line of install output, or the end of one of virt-install's output streams
+enum InstallOutput {
+    Line(InstallSource, String),
+    Closed,
+}
+
+/// Everything wrong with the host at once, so first-run problems can be fixed in one pass
+#[derive(Debug, Default)]
+pub struct PrerequisiteReport {
+    pub missing_commands: Vec<String>,
+    pub failed_checks: Vec<String>,
+}
+
+impl PrerequisiteReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing_commands.is_empty() && self.failed_checks.is_empty()
+    }
+}
+
+impl std::fmt::Display for PrerequisiteReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Prerequisite check failed:")?;
+        if !self.missing_commands.is_empty() {
+            write!(f, "\n  Missing commands: {}", self.missing_commands.join(", "))?;
+            write!(f, "\n    Install them with: sudo dnf install libvirt virt-install qemu-img")?;
+        }
+        for check in &self.failed_checks {
+            write!(f, "\n  {}", check)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for PrerequisiteReport {}
+
+/// Everything of a VM's that lives in libvirt or under `vm_dir`, as `destroy_vm` would remove it
+pub struct DestroyPlan {
+    pub domain: bool,
+    pub disks: Vec<String>,
+    pub managed_save: Option<String>,
+    pub snapshots: Vec<String>,
+}
+
+/// What virt-install boots a new VM from
+enum InstallMedia {
+    Kickstart { iso_path: String, initrd_files: Vec<String> },  // The kickstart comes first
+    CloudInit { seed_path: String },
+    Installed,  // The disk already holds a system, e.g. one from `import`
+}
 
 pub struct AppVMProvisioner {
     config: AppVMConfig,
+    wait_for_install: bool,
+    assume_yes: bool,
+    iso_verified: bool,
 }
 
 impl AppVMProvisioner {
     pub fn new(config: AppVMConfig) -> Self {
-        Self { config }
+        Self { config, wait_for_install: true, assume_yes: false, iso_verified: false }
+    }
+    
+    /// The downloaded ISO was verified earlier in this run (`create-all` fetches
+    /// each one once for the whole batch), so it isn't checksummed again
+    pub fn iso_verified(mut self, verified: bool) -> Self {
+        self.iso_verified = verified;
+        self
+    }
+    
+    /// Answer yes to prompts, e.g. retrying an install after a network failure
+    pub fn assume_yes(mut self, yes: bool) -> Self {
+        self.assume_yes = yes;
+        self
+    }
+    
+    /// Block until virt-install finishes (the default), or launch it detached
+    pub fn wait_for_install(mut self, wait: bool) -> Self {
+        self.wait_for_install = wait;
+        self
+    }
+    
+    /// Show what `provision_vm` would do: write and print the kickstart (or cloud-init
+    /// user-data) and print the virt-install command, without downloading anything,
+    /// creating the disk or touching libvirt
+    pub fn dry_run(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let disk_path = format!("{}/{}.qcow2", self.config.vm_dir, self.config.name);
+        let media = match self.config.provisioning {
+            ProvisioningMethod::CloudInit => {
+                outln!("📄 cloud-init user-data:");
+                println!("{}", self.render_cloud_init_user_data()?);
+                InstallMedia::CloudInit { seed_path: self.seed_path() }
+            }
+            ProvisioningMethod::Kickstart => {
+                let initrd_files = self.generate_kickstart_config()?;
+                outln!("📄 Kickstart: {}", initrd_files[0]);
+                println!("{}", fs::read_to_string(&initrd_files[0])?);
+                InstallMedia::Kickstart { iso_path: self.install_iso_path(), initrd_files }
+            }
+        };
+        
+        let (args, credentials) = self.virt_install_args(&media, &disk_path)?;
+        let sudo = (!libvirt::is_session()).then_some("sudo");
+        let command = sudo.into_iter()
+            .chain(["virt-install", "--connect", libvirt::uri()])
+            .chain(args.iter().map(String::as_str))
+            .map(shell_quote)
+            .collect::<Vec<_>>()
+            .join(" ");
+        outln!();
+        outln!("🛠️  virt-install command:");
+        println!("{}", credentials.map_or_else(|| command.clone(), |creds| creds.redact(&command)));
+        if self.config.spice_password.is_some() {
+            outln!("   The SPICE password is set with `virsh update-device` afterwards, not passed to virt-install");
+        }
+        Ok(())
     }
     
     pub async fn provision_vm(&self) -> Result<(), Box<dyn std::error::Error>> {
-        println!("🚀 Starting Application VM provisioning...");
-        println!("   System packages: {:?}", self.config.system_packages);
-        println!("   Flatpak packages: {:?}", self.config.flatpak_packages);
+        outln!("🚀 Starting Application VM provisioning...");
+        outln!("   System packages: {:?}", self.config.system_packages);
+        outln!("   Flatpak packages: {:?}", self.config.flatpak_packages);
         
         // Check prerequisites
         self.check_prerequisites()?;
         
-        // Download Fedora ISO
-        let iso_path = self.download_fedora_iso()?;
-        
-        // Create VM disk
-        let disk_path = self.create_vm_disk()?;
+        let (media, disk_path) = match (self.config.provisioning, &self.config.cloud_image) {
+            (ProvisioningMethod::CloudInit, Some(image)) => {
+                // The cloud image becomes the disk; cloud-init configures it on first boot
+                let disk_path = self.create_disk_from_cloud_image(image)?;
+                let seed_path = self.generate_cloud_init_config()?;
+                (InstallMedia::CloudInit { seed_path }, disk_path)
+            }
+            (ProvisioningMethod::CloudInit, None) => return Err("provisioning = \"CloudInit\" needs a cloud_image".into()),
+            (ProvisioningMethod::Kickstart, _) => {
+                // Download Fedora ISO
+                let iso_path = self.download_fedora_iso()?;
+                
+                // Create VM disk
+                let disk_path = self.create_vm_disk()?;
+                
+                // Generate kickstart configuration
+                let initrd_files = self.generate_kickstart_config()?;
+                (InstallMedia::Kickstart { iso_path, initrd_files }, disk_path)
+            }
+        };
         
-        // Generate kickstart configuration
-        let kickstart_path = self.generate_kickstart_config()?;
+        // Start automated installation, retrying when the mirror drops out mid-install
+        let mut attempt = 1;
+        loop {
+            VMState::update(&self.config.name, |state| {
+                state.phase = VMPhase::Installing;
+                state.install_attempts = attempt;
+            })?;
+            
+            let error = match self.start_installation(&media, &disk_path) {
+                Ok(()) => {
+                    self.apply_spice_password()?;
+                    break;
+                }
+                Err(e) => e,
+            };
+            
+            VMState::update(&self.config.name, |state| {
+                state.phase = VMPhase::InstallFailed;
+                state.last_failure = Some(error.to_string());
+            })?;
+            
+            let network = error.downcast_ref::<InstallError>().is_some_and(|e| e.network);
+            if !network || attempt >= MAX_INSTALL_ATTEMPTS || !self.confirm_install_retry(attempt)? {
+                return Err(error);
+            }
+            
+            self.cleanup_failed_install();
+            attempt += 1;
+        }
         
-        // Start automated installation
-        self.start_installation(&iso_path, &disk_path, &kickstart_path)?;
+        if !self.wait_for_install {
+            outln!("🚀 Installation running in the background");
+            if self.config.install_memory_mb.is_some() {
+                outln!("   Runtime memory is applied once `vm-provisioner status {}` sees the install finish", self.config.name);
+            }
+            outln!("   Check progress with: vm-provisioner status {}", self.config.name);
+            outln!("   Watch the installer with: vm-provisioner console {}", self.config.name);
+            return Ok(());
+        }
+        VMState::set_phase(&self.config.name, VMPhase::Installed)?;
+        self.apply_runtime_memory()?;
         
         // Configure window management integration
         self.setup_window_management()?;
         
-        println!("✅ Application VM provisioned successfully!");
-        println!("   VM Name: {}", self.config.name);
-        println!("   System packages: {:?}", self.config.system_packages);
-        println!("   Flatpak packages: {:?}", self.config.flatpak_packages);
-        println!("   Graphics: {:?}", self.config.graphics_backend);
-        println!("   Clipboard: {}", if self.config.enable_clipboard { "Enabled" } else { "Disabled" });
+        outln!("✅ Application VM provisioned successfully!");
+        outln!("   VM Name: {}", self.config.name);
+        outln!("   System packages: {:?}", self.config.system_packages);
+        outln!("   Flatpak packages: {:?}", self.config.flatpak_packages);
+        outln!("   Graphics: {:?}", self.config.graphics_backend);
+        outln!("   Clipboard: {:?}", self.config.effective_clipboard_transport());
         
         Ok(())
     }
     
-    fn check_prerequisites(&self) -> Result<(), Box<dyn std::error::Error>> {
-        println!("🔍 Checking prerequisites...");
+    /// Check the host for everything provisioning needs, reporting all problems together
+    fn check_prerequisites(&self) -> Result<(), PrerequisiteReport> {
+        outln!("🔍 Checking prerequisites...");
+        let mut report = PrerequisiteReport::default();
         
         let required_commands = ["virsh", "virt-install", "qemu-img"];
         for cmd in &required_commands {
-            if Command::new("which").arg(cmd).output()?.status.success() {
-                println!("  ✓ {}", cmd);
+            if command_exists(cmd) {
+                outln!("  ✓ {}", cmd);
             } else {
-                return Err(format!("Missing required command: {}", cmd).into());
+                outln!("  ✗ {}", cmd);
+                report.missing_commands.push(cmd.to_string());
             }
         }
         
-        // Check if libvirtd is running
-        let status = Command::new("systemctl")
-            .args(&["is-active", "libvirtd"])
-            .output()?;
+        // The NoCloud seed ISO can be built with either
+        if self.config.provisioning == ProvisioningMethod::CloudInit {
+            if command_exists("genisoimage") || command_exists("xorriso") {
+                outln!("  ✓ genisoimage/xorriso");
+            } else {
+                outln!("  ✗ genisoimage/xorriso");
+                report.missing_commands.push("genisoimage".to_string());
+            }
+        }
+        
+        match self.check_vm_dir() {
+            Ok(()) => outln!("  ✓ {}", self.config.vm_dir),
+            Err(e) => {
+                outln!("  ✗ {}", self.config.vm_dir);
+                report.failed_checks.push(e);
+            }
+        }
+        
+        if !self.config.usb_devices.is_empty() {
+            match self.check_usb_devices() {
+                Ok(()) => outln!("  ✓ USB devices"),
+                Err(e) => {
+                    outln!("  ✗ USB devices");
+                    report.failed_checks.push(e);
+                }
+            }
+        }
+        
+        if self.config.enable_hugepages {
+            let memory_mb = self.config.install_memory_mb.unwrap_or(self.config.memory_mb).max(self.config.memory_mb);
+            match self.check_hugepages(memory_mb) {
+                Ok(()) => outln!("  ✓ hugepages"),
+                Err(e) => {
+                    outln!("  ✗ hugepages");
+                    report.failed_checks.push(e);
+                }
+            }
+        }
+        
+        // Check if libvirtd is running; a session daemon is started on demand
+        let active = libvirt::is_session() || Command::new("systemctl")
+            .args(["is-active", "libvirtd"])
+            .output()
+            .is_ok_and(|output| output.status.success());
             
-        if !status.status.success() {
-            println!("  ⚠️  Starting libvirtd...");
-            Command::new("sudo")
-                .args(&["systemctl", "start", "libvirtd"])
-                .status()?;
+        if !active {
+            outln!("  ⚠️  Starting libvirtd...");
+            match Command::new("sudo").args(["systemctl", "start", "libvirtd"]).status() {
+                Ok(status) if status.success() => outln!("  ✓ libvirtd started"),
+                Ok(status) => report.failed_checks.push(format!(
+                    "libvirtd is not running and `sudo systemctl start libvirtd` failed ({})", status
+                )),
+                Err(e) => report.failed_checks.push(format!(
+                    "libvirtd is not running and could not be started: {}", e
+                )),
+            }
         }
         
-        Ok(())
+        if report.is_ok() {
+            Ok(())
+        } else {
+            Err(report)
+        }
+    }
+    
+    /// The ISO a kickstart install boots: the configured one, or where the download goes
+    fn install_iso_path(&self) -> String {
+        match &self.config.iso_path {
+            Some(iso) => iso.to_string_lossy().to_string(),
+            None => format!("{}/{}", self.config.vm_dir, self.config.distro.iso_file_name(std::env::consts::ARCH)),
+        }
+    }
+    
+    /// Where `provision_vm` downloads the install ISO to, if it downloads one
+    pub fn downloaded_iso_path(&self) -> Option<String> {
+        (self.config.provisioning == ProvisioningMethod::Kickstart && self.config.iso_path.is_none())
+            .then(|| self.install_iso_path())
     }
     
     fn download_fedora_iso(&self) -> Result<String, Box<dyn std::error::Error>> {
+        // A user-supplied ISO skips the download entirely
+        if let Some(local_iso) = &self.config.iso_path {
+            let iso_path = local_iso.to_string_lossy().to_string();
+            outln!("📦 Using local ISO: {}", iso_path);
+            
+            if let Some(expected) = &self.config.iso_sha256 {
+                outln!("🔐 Verifying ISO checksum...");
+                let actual = sha256_file(&iso_path)?;
+                if !actual.eq_ignore_ascii_case(expected) {
+                    return Err(format!("ISO checksum mismatch for {}: expected {}, got {}", 
+                                       iso_path, expected, actual).into());
+                }
+            }
+            
+            return Ok(iso_path);
+        }
+        
         let arch = std::env::consts::ARCH;
-        let iso_name = format!("fedora-minimal-{}.iso", arch);
-        let iso_path = format!("{}/{}", self.config.vm_dir, iso_name);
+        let download_url = self.config.distro.iso_url(arch)?;
+        let iso_path = self.install_iso_path();
+        if self.iso_verified && Path::new(&iso_path).exists() {
+            return Ok(iso_path);
+        }
         
+        // A cached ISO may be a truncated earlier download; replace it once if so
         if Path::new(&iso_path).exists() {
-            println!("📦 Using existing Fedora ISO");
-            return Ok(iso_path);
+            outln!("📦 Using existing Fedora ISO");
+            match self.verify_iso_checksum(&iso_path) {
+                Ok(()) => return Ok(iso_path),
+                Err(e) => {
+                    eoutln!("⚠️  {}, downloading it again", e);
+                    remove_file_with_sudo(Path::new(&iso_path))?;
+                }
+            }
         }
         
-        println!("📥 Downloading Fedora ISO...");
+        // Download next to the ISO and only move it into place once complete, so an
+        // interrupted download is resumed next time instead of mistaken for the ISO
+        let part_path = format!("{}.part", iso_path);
+        if Path::new(&part_path).exists() {
+            outln!("📥 Resuming interrupted Fedora ISO download...");
+        } else {
+            outln!("📥 Downloading Fedora ISO...");
+        }
         
-        let download_url = match arch {
-            "x86_64" => "https://download.fedoraproject.org/pub/fedora/linux/releases/41/Server/x86_64/iso/Fedora-Server-netinst-x86_64-41-1.4.iso",
-            "aarch64" => "https://download.fedoraproject.org/pub/fedora/linux/releases/41/Server/aarch64/iso/Fedora-Server-netinst-aarch64-41-1.4.iso",
-            _ => return Err(format!("Unsupported architecture: {}", arch).into()),
-        };
+        // A part file that is already complete makes curl fail the resume; the size check decides
+        let output = self.run_curl(Command::new("curl").args(["-L", "--fail", "-C", "-", "-o", &part_path, &download_url]))?;
+        match (output.status.success(), self.check_download_size(&part_path, &download_url)) {
+            (_, Ok(())) => {}
+            (false, Err(e)) => return Err(format!(
+                "Failed to download the Fedora ISO from {} ({}); run the command again to resume", download_url, e
+            ).into()),
+            (true, Err(e)) => {
+                // The server sent everything it had; resuming won't fix that, start over next time
+                let _ = remove_file_with_sudo(Path::new(&part_path));
+                return Err(format!("Downloaded ISO is incomplete and was removed: {}", e).into());
+            }
+        }
+        fs::rename(&part_path, &iso_path)?;
         
-        Command::new("curl")
-            .args(&["-L", "-o", &iso_path, download_url])
-            .status()?;
+        if let Err(e) = self.verify_iso_checksum(&iso_path) {
+            let _ = remove_file_with_sudo(Path::new(&iso_path));
+            return Err(format!("Downloaded ISO is corrupt and was removed: {}", e).into());
+        }
             
         Ok(iso_path)
     }
     
+    /// Compare a download against the server's `Content-Length`. Servers that don't
+    /// send one pass; the checksum verification still catches a bad file.
+    fn check_download_size(&self, path: &str, url: &str) -> Result<(), String> {
+        let actual = fs::metadata(path).map_or(0, |metadata| metadata.len());
+        let headers = self.run_curl(Command::new("curl").args(["-sIL", "--fail", url]))
+            .map_err(|e| e.to_string())?;
+        let Some(expected) = final_content_length(&String::from_utf8_lossy(&headers.stdout)) else {
+            return Ok(());
+        };
+        
+        if actual != expected {
+            return Err(format!("got {} of {} bytes", actual, expected));
+        }
+        Ok(())
+    }
+    
+    /// `vm_dir` takes the VM disk, written with sudo, and unless it's cached or
+    /// `iso_path` is set, the ISO, which curl downloads as the invoking user
+    fn check_vm_dir(&self) -> Result<(), String> {
+        let dir = &self.config.vm_dir;
+        if is_writable_dir(Path::new(dir)) {
+            return Ok(());
+        }
+        
+        // Nothing runs with sudo for a session connection; its default pool may not exist yet
+        if libvirt::is_session() {
+            fs::create_dir_all(dir).map_err(|e| format!("Can't create vm_dir {}: {}", dir, e))?;
+            if !is_writable_dir(Path::new(dir)) {
+                return Err(format!("vm_dir {} is not writable; choose another with --vm-dir", dir));
+            }
+            return Ok(());
+        }
+        
+        let sudo_writable = Command::new("sudo")
+            .args(["test", "-d", dir, "-a", "-w", dir])
+            .status()
+            .is_ok_and(|status| status.success());
+        if !sudo_writable {
+            return Err(format!("vm_dir {} is not a writable directory, even with sudo; create it or choose another with --vm-dir", dir));
+        }
+        
+        let downloads_iso = self.config.provisioning == ProvisioningMethod::Kickstart
+            && self.config.iso_path.is_none()
+            && !Path::new(&self.install_iso_path()).exists();
+        if downloads_iso {
+            return Err(format!("vm_dir {} is only writable with sudo, but the ISO is downloaded into it as your user; \
+                                make it writable, set iso_path, or choose another --vm-dir", dir));
+        }
+        Ok(())
+    }
+    
+    /// `create --download-only`: fetch and verify the install media, then stop
+    pub fn download_only(&self) -> Result<(), Box<dyn std::error::Error>> {
+        match self.config.provisioning {
+            ProvisioningMethod::CloudInit => {
+                outln!("📦 Cloud-init installs use the local cloud image; nothing to download");
+            }
+            ProvisioningMethod::Kickstart => {
+                self.check_vm_dir()?;
+                let iso_path = self.download_fedora_iso()?;
+                outln!("✅ ISO ready: {}", iso_path);
+            }
+        }
+        Ok(())
+    }
+    
+    /// Check a downloaded ISO against the SHA-256 in the release's CHECKSUM file
+    fn verify_iso_checksum(&self, iso_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if self.config.skip_iso_checksum {
+            outln!("   Skipping ISO checksum verification");
+            return Ok(());
+        }
+        
+        let arch = std::env::consts::ARCH;
+        let checksum_url = self.config.distro.checksum_url(arch)?;
+        let iso_name = self.config.distro.iso_remote_name(arch)?;
+        
+        outln!("🔐 Verifying ISO checksum...");
+        let output = self.run_curl(Command::new("curl")
+            .args(["-fsSL", &checksum_url])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped()))?;
+        if !output.status.success() {
+            return Err(format!("Could not fetch {} ({}); pass --skip-checksum for mirrors without one", 
+                               checksum_url, String::from_utf8_lossy(&output.stderr).trim()).into());
+        }
+        
+        let expected = parse_checksum_file(&String::from_utf8_lossy(&output.stdout), &iso_name)
+            .ok_or_else(|| format!("{} has no SHA-256 entry for {}", checksum_url, iso_name))?;
+        let actual = sha256_file(iso_path)?;
+        if !actual.eq_ignore_ascii_case(&expected) {
+            return Err(format!("ISO checksum mismatch for {}: expected {}, got {}", iso_path, expected, actual).into());
+        }
+        
+        outln!("   ✓ Checksum matches");
+        Ok(())
+    }
+    
+    /// Run curl, passing mirror credentials through a config on stdin so they don't show up in `ps`
+    fn run_curl(&self, curl: &mut Command) -> Result<std::process::Output, Box<dyn std::error::Error>> {
+        let credentials = MirrorCredentials::load(&self.config)?;
+        if credentials.is_some() {
+            curl.args(["--config", "-"]).stdin(Stdio::piped());
+        }
+        
+        let mut child = curl.spawn()?;
+        if let (Some(creds), Some(mut stdin)) = (credentials, child.stdin.take()) {
+            let escape = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+            writeln!(stdin, "user = \"{}:{}\"", escape(&creds.user), escape(&creds.password))?;
+        }
+        Ok(child.wait_with_output()?)
+    }
+    
     fn create_vm_disk(&self) -> Result<String, Box<dyn std::error::Error>> {
         let disk_path = format!("{}/{}.qcow2", self.config.vm_dir, self.config.name);
         
         // Remove existing disk if it exists (with sudo)
-        Command::new("sudo")
-            .args(&["rm", "-f", &disk_path])
+        libvirt::privileged("rm")
+            .args(["-f", &disk_path])
             .status()?;
         
-        println!("💾 Creating VM disk ({} GB)...", self.config.disk_size_gb);
+        outln!("💾 Creating VM disk ({} GB)...", self.config.disk_size_gb);
         
-        Command::new("sudo")
-            .args(&[
-                "qemu-img", "create", "-f", "qcow2",
-                &disk_path,
-                &format!("{}G", self.config.disk_size_gb)
-            ])
+        let mut args = vec!["create".to_string(), "-f".to_string(), "qcow2".to_string()];
+        if let Some(options) = self.qcow2_options() {
+            outln!("   qcow2 options: {}", options);
+            args.push("-o".to_string());
+            args.push(options);
+        }
+        args.push(disk_path.clone());
+        args.push(format!("{}G", self.config.disk_size_gb));
+        
+        let status = libvirt::privileged("qemu-img")
+            .args(&args)
             .status()?;
+        if !status.success() {
+            return Err(format!("qemu-img create failed for {}", disk_path).into());
+        }
             
         Ok(disk_path)
     }
     
-    fn generate_kickstart_config(&self) -> Result<String, Box<dyn std::error::Error>> {
-        let kickstart_dir = format!("/tmp/{}-kickstart", self.config.name);
-        fs::create_dir_all(&kickstart_dir)?;
+    /// VM disk converted from a cloud image and grown to `disk_size_gb`
+    fn create_disk_from_cloud_image(&self, image: &Path) -> Result<String, Box<dyn std::error::Error>> {
+        let disk_path = format!("{}/{}.qcow2", self.config.vm_dir, self.config.name);
         
-        let kickstart_path = format!("{}/kickstart.cfg", kickstart_dir);
+        libvirt::privileged("rm")
+            .args(["-f", &disk_path])
+            .status()?;
         
-        println!("🏗️  Generating kickstart configuration...");
+        outln!("💾 Creating VM disk from {} ({} GB)...", image.display(), self.config.disk_size_gb);
         
-        // Build package list from system packages, separating build deps from runtime deps
-        let mut base_packages = vec![
-            "@core".to_string(),
-            "@base-x".to_string(),
-            "i3".to_string(),
-            "i3status".to_string(),
-            "i3lock".to_string(),
-            "dmenu".to_string(),
-            "rofi".to_string(),
-            "xorg-x11-server-Xorg".to_string(),
-            "xorg-x11-xinit".to_string(),
-            "xset".to_string(),  // This is critical for X11 readiness check
-            "xrandr".to_string(),
-            "wmctrl".to_string(),
-            "xwininfo".to_string(),
-            "pipewire".to_string(),
-            "wl-clipboard".to_string(),
-            "spice-vdagent".to_string(),
-            "kitty".to_string(),
-            "git".to_string(), // Needed for cloning spice-autorandr
-        ];
+        let mut args = vec!["convert".to_string(), "-O".to_string(), "qcow2".to_string()];
+        if let Some(options) = self.qcow2_options() {
+            outln!("   qcow2 options: {}", options);
+            args.push("-o".to_string());
+            args.push(options);
+        }
+        args.push(image.to_string_lossy().to_string());
+        args.push(disk_path.clone());
         
-        // Add user-specified system packages (filter out build deps)
-        for pkg in &self.config.system_packages {
-            if !pkg.contains("-devel") && !pkg.contains("autoconf") && 
-               !pkg.contains("automake") && !pkg.contains("libtool") &&
-               !pkg.contains("pkgconfig") && !pkg.contains("gcc") && !pkg.contains("make") {
-                base_packages.push(pkg.clone());
-            }
+        let status = libvirt::privileged("qemu-img").args(&args).status()?;
+        if !status.success() {
+            return Err(format!("qemu-img convert failed for {}", image.display()).into());
         }
         
-        let packages = base_packages.join("\n");
+        let size = format!("{}G", self.config.disk_size_gb);
+        let status = libvirt::privileged("qemu-img")
+            .args(["resize", &disk_path, &size])
+            .status()?;
+        if !status.success() {
+            return Err(format!("qemu-img resize failed for {} (is the image larger than {}?)", disk_path, size).into());
+        }
         
-        // Build Flatpak configuration if flatpak packages specified
-        let flatpak_config = if !self.config.flatpak_packages.is_empty() {
-            let mut config = String::from(r#"
-# Install and configure Flatpak
-dnf install -y flatpak
-
-# Add Flathub repository
-flatpak remote-add --if-not-exists flathub https://flathub.org/repo/flathub.flatpakrepo
-
-# Install Flatpak packages
-"#);
-            for package in &self.config.flatpak_packages {
-                config.push_str(&format!("flatpak install -y flathub {}\n", package));
-            }
-            
-            config.push_str("\n# Verify installations\nflatpak list\n");
-            config
+        Ok(disk_path)
+    }
+    
+    /// Where the NoCloud seed ISO for a cloud-init install goes
+    fn seed_path(&self) -> String {
+        format!("{}/{}-seed.iso", self.config.vm_dir, self.config.name)
+    }
+    
+    /// Write cloud-init's `user-data` and `meta-data` and pack them into a NoCloud
+    /// seed ISO next to the disk, returning the ISO's path
+    fn generate_cloud_init_config(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let cloud_init_dir = format!("/tmp/{}-cloud-init", self.config.name);
+        fs::create_dir_all(&cloud_init_dir)?;
+        
+        outln!("🏗️  Generating cloud-init configuration...");
+        
+        let user_data = format!("{}/user-data", cloud_init_dir);
+        let meta_data = format!("{}/meta-data", cloud_init_dir);
+        fs::write(&user_data, self.render_cloud_init_user_data()?)?;
+        fs::write(&meta_data, self.render_cloud_init_meta_data())?;
+        
+        // NoCloud finds the seed by its volume label
+        let seed_path = self.seed_path();
+        let mut command = if command_exists("genisoimage") {
+            libvirt::privileged("genisoimage")
         } else {
-            "".to_string()
+            let mut command = libvirt::privileged("xorriso");
+            command.args(["-as", "mkisofs"]);
+            command
         };
+        let output = command
+            .args(["-output", &seed_path, "-volid", "cidata", "-joliet", "-rock", &user_data, &meta_data])
+            .output()?;
+        if !output.status.success() {
+            return Err(format!("Failed to build the cloud-init seed ISO: {}", 
+                               String::from_utf8_lossy(&output.stderr).trim()).into());
+        }
         
-        // Build auto-launch configuration
-        let auto_launch_config = if !self.config.auto_launch_apps.is_empty() {
-            let mut config = String::from("\n# Auto-launch applications\n");
-            for (i, app_cmd) in self.config.auto_launch_apps.iter().enumerate() {
-                config.push_str(&format!(r#"
-# Auto-launch service {}
-cat > /etc/systemd/system/auto-launch-{}.service << 'EOF'
-[Unit]
-Description=Auto Launch Application {}
-After=graphical-session.target
-Wants=display-manager.service
-
-[Service]
-Type=simple
-User=user
-Environment="DISPLAY=:0"
-Environment="XDG_RUNTIME_DIR=/run/user/1000"
-Environment="XDG_SESSION_TYPE=x11"
-ExecStartPre=/bin/bash -c 'while ! pgrep -x Xorg; do sleep 1; done'
-ExecStart={}
-Restart=on-failure
-RestartSec=5
-
-[Install]
-WantedBy=graphical.target
-EOF
-
-systemctl enable auto-launch-{}.service
-"#, i + 1, i + 1, i + 1, app_cmd, i + 1));
+        Ok(seed_path)
+    }
+    
+    /// `#cloud-config` user-data: the same user, password, packages, flatpaks and
+    /// auto-launch units as the kickstart. The VM powers off once cloud-init is done,
+    /// which is how `provision` tells the install has finished.
+    fn render_cloud_init_user_data(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let mut packages: Vec<&str> = self.config.system_packages.iter()
+            .map(String::as_str)
+            .filter(|pkg| !is_build_dependency(pkg))
+            .collect();
+        
+        let mut runcmd: Vec<Vec<String>> = Vec::new();
+        if !self.config.flatpak_packages.is_empty() {
+            packages.push("flatpak");
+            runcmd.push(["flatpak", "remote-add", "--if-not-exists", "flathub", "https://flathub.org/repo/flathub.flatpakrepo"]
+                .map(String::from).to_vec());
+            for package in &self.config.flatpak_packages {
+                runcmd.push(["flatpak", "install", "-y", "flathub", package].map(String::from).to_vec());
             }
-            config
-        } else {
-            "".to_string()
-        };
+        }
         
-        // Build guest agent service configuration
-        let app_config = format!(r#"
-# Create guest agent service
-cat > /etc/systemd/system/guest-agent.service << 'EOF'
-[Unit]
-Description=VM Guest Agent for Window Management
-After=graphical.target
-Wants=autologin@tty1.service
-
-[Service]
-Type=simple
-User=user
-Environment="DISPLAY=:0"
-Environment="XDG_RUNTIME_DIR=/run/user/1000"
-Environment="XDG_SESSION_TYPE=x11"
-ExecStartPre=/bin/bash -c 'while ! pgrep -x Xorg; do sleep 1; done'
-ExecStart=/usr/local/bin/guest-agent
-Restart=on-failure
-RestartSec=3
-
-[Install]
-WantedBy=graphical.target
-EOF
-
-# Enable the services
-systemctl enable guest-agent.service
-
-{}
-
-# Set multi-user target as default (since we're using auto-login)
-systemctl set-default multi-user.target"#,
-            self.get_autologin_config(),
-        );
+        let mut write_files = Vec::new();
+        for (_, tag) in &self.config.shared_folders {
+            write_files.push(serde_json::json!({
+                "path": format!("/etc/systemd/system/{}", shared_folder_unit_name(tag)),
+                "content": shared_folder_unit(tag),
+            }));
+            runcmd.push(vec!["mkdir".to_string(), "-p".to_string(), format!("/mnt/{}", tag)]);
+            runcmd.push(vec!["systemctl".to_string(), "enable".to_string(), shared_folder_unit_name(tag)]);
+        }
+        for (i, app_cmd) in self.config.auto_launch_apps.iter().enumerate() {
+            write_files.push(serde_json::json!({
+                "path": format!("/etc/systemd/system/auto-launch-{}.service", i + 1),
+                "content": self.autolaunch_unit(i + 1, app_cmd),
+            }));
+            runcmd.push(vec!["systemctl".to_string(), "enable".to_string(), format!("auto-launch-{}.service", i + 1)]);
+        }
         
-        // Build clipboard daemon configuration if enabled
-        let clipboard_config = if self.config.enable_clipboard {
-            r#"
-# Setup clipboard sharing daemon
-cat > /etc/systemd/system/clipboard-proxy.service << 'EOF'
-[Unit]
-Description=Clipboard Proxy Service
-After=cage-app.service
-
-[Service]
-Type=simple
-User=user
-Environment="WAYLAND_DISPLAY=wayland-0"
-ExecStart=/usr/local/bin/clipboard-proxy
-Restart=on-failure
-
-[Install]
-WantedBy=multi-user.target
-EOF
-
-# Create clipboard proxy script (will be replaced by actual proxy later)
-cat > /usr/local/bin/clipboard-proxy << 'EOF'
-#!/bin/bash
-# Placeholder for clipboard proxy
-# This will be replaced by the actual virtio-based clipboard proxy
-while true; do
-    sleep 60
-done
-EOF
-chmod +x /usr/local/bin/clipboard-proxy
-
-systemctl enable clipboard-proxy.service"#
-        } else {
-            ""
-        };
+        // Listing users without "default" skips the image's own user, so ours gets UID 1000
+        let user_data = serde_json::json!({
+            "hostname": self.config.guest_hostname(),
+            "users": [{
+                "name": "user",
+                "groups": ["sudo"],
+                "shell": "/bin/bash",
+                "lock_passwd": false,
+            }],
+            "chpasswd": {
+                "expire": false,
+                "users": [{ "name": "user", "password": self.config.user_password, "type": "text" }],
+            },
+            "ssh_pwauth": false,
+            "package_update": true,
+            "packages": packages,
+            "write_files": write_files,
+            "runcmd": runcmd,
+            "power_state": { "mode": "poweroff", "condition": true },
+        });
         
-        // Build audio configuration if enabled
-        let audio_config = if self.config.enable_audio {
-            r#"
-# Enable PipeWire audio
-systemctl --user enable pipewire pipewire-pulse wireplumber"#
-        } else {
-            ""
+        Ok(format!("#cloud-config\n{}", serde_yaml::to_string(&user_data)?))
+    }
+    
+    /// NoCloud meta-data; the instance ID ties cloud-init's first-boot run to this install
+    fn render_cloud_init_meta_data(&self) -> String {
+        format!("instance-id: {}\nlocal-hostname: {}\n", self.config.name, self.config.guest_hostname())
+    }
+    
+    /// Serial device logging the console to `console_log`, for virt-install `--serial`
+    /// and virt-xml. It stays a pty so `virsh console` keeps working.
+    fn serial_log_arg(&self) -> Option<String> {
+        self.config.console_log.as_ref()
+            .map(|path| format!("pty,log.file={},log.append=on", path.display()))
+    }
+    
+    /// virt-install `--vcpus`, with the guest's CPU topology when configured
+    fn vcpus_arg(&self) -> String {
+        match self.config.cpu_topology {
+            Some((sockets, cores, threads)) => format!("{},sockets={},cores={},threads={}", 
+                                                       self.config.vcpus, sockets, cores, threads),
+            None => self.config.vcpus.to_string(),
+        }
+    }
+    
+    /// virt-install `--cputune` pinning vCPU n to the n-th host CPU of `cpu_pinning`
+    fn cputune_arg(&self) -> Option<String> {
+        let pins = self.config.cpu_pinning.as_ref()?;
+        Some(pins.iter()
+            .enumerate()
+            .map(|(vcpu, cpu)| format!("vcpupin{0}.vcpu={0},vcpupin{0}.cpuset={1}", vcpu, cpu))
+            .collect::<Vec<_>>()
+            .join(","))
+    }
+    
+    /// virt-install `--network` for `network_mode`, with the fixed MAC address if any
+    fn network_arg(&self) -> String {
+        let network = match &self.config.network_mode {
+            NetworkMode::None => return "none".to_string(),
+            NetworkMode::Bridge(iface) => format!("bridge={},model=virtio", iface),
+            // A session daemon can't use libvirt's virtual networks; QEMU's user networking is its NAT
+            NetworkMode::Nat | NetworkMode::VpnOnly if libvirt::is_session() => "user,model=virtio".to_string(),
+            NetworkMode::Nat | NetworkMode::VpnOnly => "network=default,model=virtio".to_string(),
+        };
+        match &self.config.mac_address {
+            Some(mac) => format!("{},mac={}", network, mac),
+            None => network,
+        }
+    }
+    
+    /// virt-install `--hostdev` for each of `usb_devices`
+    fn hostdev_args(&self) -> Vec<String> {
+        self.config.usb_devices.iter()
+            .filter_map(|id| id.split_once(':'))
+            .map(|(vendor, product)| format!("0x{}:0x{}", vendor, product))
+            .collect()
+    }
+    
+    /// `usb_devices` must be plugged in, or libvirt refuses to start the domain
+    fn check_usb_devices(&self) -> Result<(), String> {
+        if self.config.usb_devices.is_empty() {
+            return Ok(());
+        }
+        let host = host_usb_devices().map_err(|e| format!("Can't check usb_devices: {}", e))?;
+        let missing: Vec<&str> = self.config.usb_devices.iter()
+            .filter(|id| !host.iter().any(|device| device.id == **id))
+            .map(String::as_str)
+            .collect();
+        if !missing.is_empty() {
+            return Err(format!("USB device(s) {} not connected to this host (list them with: vm-provisioner usb)", missing.join(", ")));
+        }
+        Ok(())
+    }
+    
+    /// Where the installer's serial console is logged. Waited installs always log it,
+    /// falling back to a per-VM file, so Anaconda's progress can be followed.
+    fn install_console_log(&self) -> Option<PathBuf> {
+        self.config.console_log.clone().or_else(|| {
+            self.wait_for_install
+                .then(|| PathBuf::from(format!("{}/{}-install.log", libvirt::log_dir(), self.config.name)))
+        })
+    }
+    
+    /// Point the defined domain's serial console at `console_log`; applies from the next boot
+    fn apply_console_log(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(serial) = self.serial_log_arg() else {
+            return Ok(());
         };
         
-        // Build firewall rules
-        let firewall_rules = self.config.firewall_rules
-            .iter()
-            .map(|rule| format!("iptables -A {}", rule))
-            .collect::<Vec<_>>()
-            .join("\n");
+        let output = libvirt::tool("virt-xml")
+            .args([&self.config.name, "--edit", "--serial", &serial])
+            .output()?;
+        if !output.status.success() {
+            return Err(format!("virt-xml failed to set the console log: {}", 
+                               String::from_utf8_lossy(&output.stderr).trim()).into());
+        }
+        
+        Ok(())
+    }
+    
+    /// `qemu-img create -o` value, or `None` to keep qemu-img's defaults
+    fn qcow2_options(&self) -> Option<String> {
+        let mut options = Vec::new();
+        if let Some(size) = self.config.qcow2_cluster_size {
+            options.push(format!("cluster_size={}", size));
+        }
+        if self.config.qcow2_preallocation != Preallocation::Off {
+            options.push(format!("preallocation={}", self.config.qcow2_preallocation.as_arg()));
+        }
+        
+        (!options.is_empty()).then(|| options.join(","))
+    }
+    
+    /// Write the kickstart and the files that go into the installer's initrd with it
+    fn generate_kickstart_config(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let kickstart_dir = format!("/tmp/{}-kickstart", self.config.name);
+        fs::create_dir_all(&kickstart_dir)?;
+        
+        let kickstart_path = format!("{}/kickstart.cfg", kickstart_dir);
+        
+        outln!("🏗️  Generating kickstart configuration...");
+        
+        fs::write(&kickstart_path, self.render_kickstart())?;
+        let mut files = vec![kickstart_path];
+        
+        // The window proxy's TLS identity goes the same way as the VPN config below
+        if self.config.proxy_tls {
+            use std::os::unix::fs::OpenOptionsExt;
+            let target = format!("{}/{}", kickstart_dir, GUEST_TLS_FILE);
+            fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(&target)?
+                .write_all(tls::ensure_host_identity(&self.config.name)?.as_bytes())?;
+            files.push(target);
+        }
+        
+        // The VPN config carries keys, so it travels next to the kickstart rather than inside it
+        if let (NetworkMode::VpnOnly, Some(vpn)) = (&self.config.network_mode, &self.config.vpn_config) {
+            let mut staged = vec![(vpn.config_path.as_str(), format!("{}/{}.conf", kickstart_dir, VPN_NAME))];
+            if let Some(credentials) = &vpn.credentials_path {
+                staged.push((credentials.as_str(), format!("{}/{}.auth", kickstart_dir, VPN_NAME)));
+            }
+            for (source, target) in staged {
+                use std::os::unix::fs::PermissionsExt;
+                fs::copy(source, &target)?;
+                fs::set_permissions(&target, fs::Permissions::from_mode(0o600))?;
+                files.push(target);
+            }
+        }
         
-        // Generate the complete kickstart file
-        let kickstart_content = format!(r#"# Kickstart file for Application VM
+        Ok(files)
+    }
+    
+    /// Assemble the complete kickstart file from its sections
+    fn render_kickstart(&self) -> String {
+        format!(r#"# Kickstart file for Application VM
 # Generated for: {}
 
 # Installation settings
@@ -338,7 +863,7 @@ firewall --enabled
 
 # Enable comprehensive logging for debugging
 set -x
-exec > >(tee -a /var/log/kickstart-post-detailed.log) 2>&1
+exec > >(tee -a {post_install_log}) 2>&1
 echo "=== Post-installation script started at $(date) ==="
 
 # Check what packages were actually installed in the base install
@@ -348,7 +873,7 @@ rpm -qa | grep -E "(i3|xset|xrandr|kitty|git|rofi)" | sort
 # Verify critical packages and install if missing
 echo "=== Verifying critical packages ==="
 MISSING_PACKAGES=()
-for pkg in i3 xset xrandr kitty git rofi wmctrl xwininfo spice-vdagent; do
+for pkg in i3 xset xrandr kitty git rofi wmctrl xwininfo xdotool spice-vdagent; do
     if ! rpm -q $pkg &>/dev/null; then
         echo "Missing package: $pkg"
         MISSING_PACKAGES+=($pkg)
@@ -376,60 +901,34 @@ echo "user ALL=(ALL) NOPASSWD: ALL" >> /etc/sudoers.d/user
 mkdir -p /home/user/.config
 cat > /home/user/.config/environment << 'EOF'
 DISPLAY=:0
-XDG_SESSION_TYPE=x11
+XDG_SESSION_TYPE={}
 EOF
 
 {}
 
+# Configure firewall rules
 {}
 
+# Configure DNS
 {}
 
-# Configure firewall rules
+# Configure swap
 {}
 
-# Install build tools and compile guest agent
-dnf install -y rust cargo git
-
-# Create guest agent source
-mkdir -p /tmp/guest-agent-build
-cat > /tmp/guest-agent-build/Cargo.toml << 'EOF'
-[package]
-name = "guest-agent"
-version = "0.1.0"
-edition = "2021"
-
-[dependencies]
-serde = {{ version = "1.0", features = ["derive"] }}
-bincode = "1.3"
-regex = "1.10"
-EOF
-
-# Copy guest agent source (this would be injected from the host)
-# For now, create a minimal version
-cat > /tmp/guest-agent-build/src/main.rs << 'EOF'
-fn main() {{
-    println!("Guest agent placeholder - will be replaced with full implementation");
-    std::thread::sleep(std::time::Duration::from_secs(60));
-}}
-EOF
+# Configure display scaling
+{}
 
-mkdir -p /tmp/guest-agent-build/src
-cd /tmp/guest-agent-build
-cargo build --release
-cp target/release/guest-agent /usr/local/bin/guest-agent
-chmod +x /usr/local/bin/guest-agent
+# Mount shared folders
+{}
 
-# Cleanup build files
-cd /
-rm -rf /tmp/guest-agent-build
+{}
 
 # Disable unnecessary services
 systemctl disable bluetooth
 systemctl disable cups
 
 # Set hostname  
-echo "{}" > /etc/hostname
+{}
 
 # Final verification and status report
 echo "=== FINAL VERIFICATION ==="
@@ -437,7 +936,7 @@ echo "Date: $(date)"
 echo ""
 
 echo "Critical packages status:"
-for pkg in i3 xset xrandr kitty git rofi wmctrl xwininfo spice-vdagent; do
+for pkg in i3 xset xrandr kitty git rofi wmctrl xwininfo xdotool spice-vdagent; do
     if rpm -q $pkg &>/dev/null; then
         echo "✓ $pkg: INSTALLED"
     else
@@ -445,6 +944,14 @@ for pkg in i3 xset xrandr kitty git rofi wmctrl xwininfo spice-vdagent; do
     fi
 done
 
+echo ""
+echo "Guest agent status:"
+if [ -x /usr/local/bin/guest-agent ]; then
+    echo "✓ guest-agent: INSTALLED"
+else
+    echo "✗ guest-agent: MISSING (build failed, see the cargo output above)"
+fi
+
 echo ""
 echo "spice-autorandr status:"
 if [ -f /usr/local/bin/spice-autorandr ]; then
@@ -493,622 +1000,3864 @@ echo "Check logs at /var/log/kickstart-post.log and /var/log/kickstart-post-deta
 dnf clean all
 
 %end
-
+{}{}
 # Reboot after installation
 reboot"#,
             self.config.name,
             self.config.user_password,
-            packages,
-            flatpak_config,
-            auto_launch_config,
-            app_config,
-            clipboard_config,
-            audio_config,
-            firewall_rules,
-            self.config.name
-        );
-        
-        fs::write(&kickstart_path, kickstart_content)?;
-        Ok(kickstart_path)
+            self.render_packages(),
+            self.render_flatpak_block(),
+            self.render_autolaunch(),
+            self.config.session_type.as_env(),
+            self.render_services(),
+            self.render_firewall(),
+            self.render_dns(),
+            self.render_swap(),
+            self.render_display_scale(),
+            self.render_shared_folders(),
+            self.render_guest_agent_build(),
+            self.render_hostname(),
+            self.render_vpn_files(),
+            self.render_tls_files(),
+            post_install_log = POST_INSTALL_LOG
+        )
     }
     
-    fn start_installation(&self, _iso_path: &str, disk_path: &str, kickstart_path: &str) 
-        -> Result<(), Box<dyn std::error::Error>> {
-        println!("🚀 Starting VM installation...");
-        
-        let arch = std::env::consts::ARCH;
-        let install_location = match arch {
-            "x86_64" => "https://dl.fedoraproject.org/pub/fedora/linux/releases/41/Server/x86_64/os/",
-            "aarch64" => "https://dl.fedoraproject.org/pub/fedora/linux/releases/41/Everything/aarch64/os/",
-            _ => return Err(format!("Unsupported architecture: {}", arch).into()),
+    /// `%post --nochroot` section copying the VPN config from the installer's initrd
+    /// into the installed system; empty unless the VM is `VpnOnly`
+    fn render_vpn_files(&self) -> String {
+        let Some((vpn, provider)) = self.vpn() else {
+            return String::new();
         };
         
-        let memory_str = self.config.memory_mb.to_string();
-        let vcpus_str = self.config.vcpus.to_string();
-        let disk_arg = format!("path={},size={},format=qcow2,bus=virtio", 
-                               disk_path, self.config.disk_size_gb);
-        
-        // Configure graphics based on backend and architecture
-        let arch = std::env::consts::ARCH;
-        let graphics_args = match self.config.graphics_backend {
-            GraphicsBackend::VirtioGpu => {
-                if arch == "aarch64" {
-                    // ARM64: Use virtio video with spice-autorandr for auto-resize
-                    vec!["--graphics", "spice", "--video", "virtio", 
-                         "--channel", "spicevmc,target_type=virtio,name=com.redhat.spice.0"]
-                } else {
-                    // x86_64: Use QXL for better performance
-                    vec!["--graphics", "spice,listen=127.0.0.1", "--video", "qxl", 
-                         "--channel", "spicevmc,target_type=virtio,name=com.redhat.spice.0"]
-                }
-            },
-            GraphicsBackend::QxlSpice => {
-                if arch == "aarch64" {
-                    vec!["--graphics", "spice", "--video", "virtio", 
-                         "--channel", "spicevmc,target_type=virtio,name=com.redhat.spice.0"]
-                } else {
-                    vec!["--graphics", "spice,listen=127.0.0.1", "--video", "qxl", 
-                         "--channel", "spicevmc,target_type=virtio,name=com.redhat.spice.0"]
-                }
-            },
-            GraphicsBackend::VncOnly => {
-                vec!["--graphics", "vnc,listen=127.0.0.1,port=5900"]
-            },
+        let config = match provider {
+            VpnProvider::WireGuard => format!("/etc/wireguard/{}.conf", VPN_NAME),
+            VpnProvider::OpenVpn => format!("/etc/openvpn/client/{}.conf", VPN_NAME),
         };
+        let mut section = format!(r#"
+# VPN client config, injected into the installer's initrd next to the kickstart
+%post --nochroot
+sysroot="${{ANA_INSTALL_PATH:-/mnt/sysimage}}"
+install -D -m 600 /{name}.conf "$sysroot{config}"
+"#, name = VPN_NAME, config = config);
         
-        let mut virt_install_args = vec![
-            "--name", &self.config.name,
-            "--memory", &memory_str,
-            "--vcpus", &vcpus_str,
-            "--disk", &disk_arg,
-            "--location", install_location,
-            "--initrd-inject", kickstart_path,
-            "--extra-args", "inst.ks=file:/kickstart.cfg console=tty0 console=ttyS0,115200n8",
-            "--network", "network=default,model=virtio",
-            "--noautoconsole",
-            "--wait", "-1",
-        ];
-        
-        // Add graphics arguments
-        for arg in graphics_args {
-            virt_install_args.push(arg);
+        if let (VpnProvider::OpenVpn, Some(_)) = (provider, &vpn.credentials_path) {
+            let auth = format!("/etc/openvpn/client/{}.auth", VPN_NAME);
+            section.push_str(&format!(r#"install -D -m 600 /{name}.auth "$sysroot{auth}"
+sed -i '/^[[:space:]]*auth-user-pass/d' "$sysroot{config}"
+echo "auth-user-pass {auth}" >> "$sysroot{config}"
+"#, name = VPN_NAME, auth = auth, config = config));
         }
         
-        // Add sound if enabled
-        if self.config.enable_audio {
-            if arch == "aarch64" {
-                // ARM64: Use virtio sound model
-                virt_install_args.extend_from_slice(&["--sound", "model=virtio"]);
-            } else {
-                // x86_64: Use default sound
-                virt_install_args.extend_from_slice(&["--sound", "default"]);
-            }
+        section.push_str("%end\n");
+        section
+    }
+    
+    /// `%post --nochroot` section copying the window proxy's TLS identity from the
+    /// installer's initrd to where the guest agent reads it; empty without `proxy_tls`
+    fn render_tls_files(&self) -> String {
+        if !self.config.proxy_tls {
+            return String::new();
         }
         
-        // Add USB controller if needed
-        if self.config.enable_usb_passthrough {
-            virt_install_args.extend_from_slice(&["--controller", "usb,model=qemu-xhci"]);
+        format!(r#"
+# Window proxy TLS identity, injected into the installer's initrd next to the kickstart
+%post --nochroot
+sysroot="${{ANA_INSTALL_PATH:-/mnt/sysimage}}"
+install -D -m 600 /{file} "$sysroot{path}"
+chroot "$sysroot" chown -R user:user /home/user/.config/vm-provisioner
+%end
+"#, file = GUEST_TLS_FILE, path = tls::GUEST_IDENTITY_PATH)
+    }
+    
+    /// The VPN client config and provider of a `VpnOnly` VM
+    fn vpn(&self) -> Option<(&VpnConfig, VpnProvider)> {
+        if self.config.network_mode != NetworkMode::VpnOnly {
+            return None;
         }
-        
-        println!("⏳ Running automated installation (15-20 minutes)...");
-        
-        let status = Command::new("sudo")
-            .arg("virt-install")
-            .args(&virt_install_args)
-            .status()?;
-            
-        if !status.success() {
-            return Err(format!("VM installation failed with exit code: {:?}", status.code()).into());
+        let vpn = self.config.vpn_config.as_ref()?;
+        Some((vpn, vpn.provider().ok()?))
+    }
+    
+    /// Build the guest agent in the VM from the sources this binary was built from
+    fn render_guest_agent_build(&self) -> String {
+        let mut script = format!(r#"# Install build tools and compile guest agent
+dnf install -y rust cargo git
+
+mkdir -p /tmp/guest-agent-build/src
+cat > /tmp/guest-agent-build/Cargo.toml << '{eof}'
+{manifest}{eof}
+"#, eof = GUEST_AGENT_SOURCE_EOF, manifest = GUEST_AGENT_MANIFEST);
+        for (file, source) in GUEST_AGENT_SOURCES {
+            script.push_str(&format!("cat > /tmp/guest-agent-build/src/{file} << '{eof}'\n{source}{eof}\n",
+                                     file = file, source = source, eof = GUEST_AGENT_SOURCE_EOF));
         }
-        
-        println!("✅ Installation completed!");
-        
-        Ok(())
+        script.push_str(r#"
+cd /tmp/guest-agent-build
+cargo build --release
+cp target/release/guest-agent /usr/local/bin/guest-agent
+chmod +x /usr/local/bin/guest-agent
+
+# Cleanup build files
+cd /
+rm -rf /tmp/guest-agent-build"#);
+        script
     }
     
-    fn setup_window_management(&self) -> Result<(), Box<dyn std::error::Error>> {
-        println!("🪟 Setting up window management integration...");
-        
-        // This is where we'd set up the virtio channel for window management
-        // For now, we'll configure the VM to be ready for the host integration
-        
-        match self.config.graphics_backend {
-            GraphicsBackend::VirtioGpu => {
-                println!("   Configured for VirtIO-GPU acceleration");
-                println!("   Cage compositor will start automatically");
-            },
-            GraphicsBackend::QxlSpice => {
-                println!("   Configured for SPICE protocol");
-                println!("   Connect with: remote-viewer spice://localhost:5900");
-            },
-            GraphicsBackend::VncOnly => {
-                println!("   VNC fallback mode");
-                println!("   Connect with: vncviewer localhost:5900");
-            },
+    /// Static hostname, or none at all when it is randomized per boot: systemd
+    /// then takes the `system.hostname` credential passed in through SMBIOS
+    fn render_hostname(&self) -> String {
+        match self.config.identity_mode {
+            IdentityMode::Stable => format!("echo \"{}\" > /etc/hostname", self.config.guest_hostname()),
+            IdentityMode::RandomizePerBoot => "rm -f /etc/hostname".to_string(),
         }
+    }
+    
+    /// Package list for the `%packages` section
+    fn render_packages(&self) -> String {
+        // Build package list from system packages, separating build deps from runtime deps
+        let mut base_packages = vec![
+            "@core".to_string(),
+            "@base-x".to_string(),
+            "i3".to_string(),
+            "i3status".to_string(),
+            "i3lock".to_string(),
+            "dmenu".to_string(),
+            "rofi".to_string(),
+            "xorg-x11-server-Xorg".to_string(),
+            "xorg-x11-xinit".to_string(),
+            "xset".to_string(),  // This is critical for X11 readiness check
+            "xrandr".to_string(),
+            "wmctrl".to_string(),
+            "xwininfo".to_string(),
+            "xprop".to_string(),  // Window stacking order for the guest agent
+            "xdotool".to_string(),  // Input from proxied host windows
+            "xclip".to_string(),  // Clipboard sync with clipboard_transport = "Custom"
+            "pipewire".to_string(),
+            "wl-clipboard".to_string(),
+            "spice-vdagent".to_string(),
+            "qemu-guest-agent".to_string(),  // Host-side log access (vm-provisioner logs)
+            "kitty".to_string(),
+            "git".to_string(), // Needed for cloning spice-autorandr
+        ];
         
-        if self.config.enable_clipboard {
-            println!("   Clipboard sharing enabled (requires host agent)");
+        // Add user-specified system packages (filter out build deps)
+        for pkg in &self.config.system_packages {
+            if !is_build_dependency(pkg) {
+                base_packages.push(pkg.clone());
+            }
         }
         
-        Ok(())
-    }
-    
-    pub fn start_vm(&self) -> Result<(), Box<dyn std::error::Error>> {
-        println!("▶️  Starting VM: {}", self.config.name);
+        if self.config.enable_spice_folder_sharing {
+            base_packages.push("spice-webdavd".to_string());
+        }
         
-        Command::new("virsh")
-            .args(&["start", &self.config.name])
-            .status()?;
-            
-        // Wait for VM to boot
-        thread::sleep(Duration::from_secs(5));
+        if self.config.enable_zram {
+            base_packages.push("zram-generator".to_string());
+        }
         
-        // Launch SPICE viewer for immediate functionality
-        match self.config.graphics_backend {
-            GraphicsBackend::VirtioGpu | GraphicsBackend::QxlSpice => {
-                println!("🖥️  Launching SPICE viewer...");
-                let vm_name = self.config.name.clone();
-                std::thread::spawn(move || {
-                    std::thread::sleep(Duration::from_secs(5)); // Wait for VM to start SPICE
-                    
-                    // Get the actual SPICE port from virsh
-                    if let Ok(output) = std::process::Command::new("virsh")
-                        .args(&["domdisplay", &vm_name])
-                        .output()
-                    {
-                        if let Ok(display) = String::from_utf8(output.stdout) {
-                            let display = display.trim();
-                            if !display.is_empty() {
-                                let _ = std::process::Command::new("remote-viewer")
-                                    .arg(display)
-                                    .spawn();
-                                return;
-                            }
-                        }
-                    }
-                    
-                    // Fallback to default port
-                    let _ = std::process::Command::new("remote-viewer")
-                        .arg("spice://127.0.0.1:5900")
-                        .spawn();
-                });
-                println!("   SPICE viewer will launch automatically");
-                println!("   Or get connection info with: virsh domdisplay {}", self.config.name);
-            },
-            GraphicsBackend::VncOnly => {
-                println!("   Connect with: vncviewer localhost:5900");
-            },
+        if let Some((_, provider)) = self.vpn() {
+            base_packages.push("iptables-nft".to_string());
+            base_packages.push(match provider {
+                VpnProvider::WireGuard => "wireguard-tools".to_string(),
+                VpnProvider::OpenVpn => "openvpn".to_string(),
+            });
         }
         
-        println!("✅ VM started successfully!");
+        if self.config.enable_notifications {
+            base_packages.push("dunst".to_string());
+        }
         
-        Ok(())
-    }
-    
-    pub fn stop_vm(&self) -> Result<(), Box<dyn std::error::Error>> {
-        println!("⏹️  Stopping VM: {}", self.config.name);
+        if self.config.session_type == SessionType::Wayland {
+            base_packages.push("sway".to_string());
+            base_packages.push("xorg-x11-server-Xwayland".to_string());
+        } else if self.config.display_scale.is_some() {
+            base_packages.push("xrdb".to_string());
+        }
         
-        Command::new("virsh")
-            .args(&["shutdown", &self.config.name])
-            .status()?;
-            
-        Ok(())
+        base_packages.join("\n")
     }
     
-    pub fn destroy_vm(&self) -> Result<(), Box<dyn std::error::Error>> {
-        println!("🗑️  Destroying VM: {}", self.config.name);
-        
-        // Check if VM exists first
-        let list_output = Command::new("virsh")
-            .args(&["list", "--all"])
-            .output()?;
-        
-        if !String::from_utf8_lossy(&list_output.stdout).contains(&self.config.name) {
-            println!("   VM {} not found in virsh list", self.config.name);
-            // Still try to clean up disk
-        } else {
-            // Force stop if running
-            println!("   Force stopping VM...");
-            let destroy_output = Command::new("virsh")
-                .args(&["destroy", &self.config.name])
-                .output();
-            
-            match destroy_output {
-                Ok(output) => {
-                    if output.status.success() {
-                        println!("   VM stopped successfully");
-                    } else {
-                        println!("   VM stop failed or already stopped: {}", 
-                                String::from_utf8_lossy(&output.stderr));
-                    }
-                }
-                Err(e) => println!("   Error stopping VM: {}", e),
+    /// Flatpak installation commands for `%post` (empty without flatpaks)
+    fn render_flatpak_block(&self) -> String {
+        // Build Flatpak configuration if flatpak packages specified
+        if !self.config.flatpak_packages.is_empty() {
+            let mut config = String::from(r#"
+# Install and configure Flatpak
+dnf install -y flatpak
+
+# Add Flathub repository
+flatpak remote-add --if-not-exists flathub https://flathub.org/repo/flathub.flatpakrepo
+
+# Install Flatpak packages
+"#);
+            for package in &self.config.flatpak_packages {
+                config.push_str(&format!("flatpak install -y flathub {}\n", package));
             }
             
-            std::thread::sleep(std::time::Duration::from_secs(3));
-            
-            // Undefine VM (remove from libvirt)
-            println!("   Removing VM definition...");
-            let undefine_output = Command::new("virsh")
-                .args(&["undefine", &self.config.name, "--remove-all-storage", "--nvram"])
-                .output();
-            
-            match undefine_output {
-                Ok(output) => {
-                    if output.status.success() {
-                        println!("   VM definition removed with storage");
-                    } else {
-                        println!("   Undefine with storage failed: {}", 
-                                String::from_utf8_lossy(&output.stderr));
-                        println!("   Trying without storage flags...");
-                        
-                        // Try simpler undefine
-                        let simple_undefine = Command::new("virsh")
-                            .args(&["undefine", &self.config.name])
-                            .output()?;
-                        
-                        if simple_undefine.status.success() {
-                            println!("   VM definition removed (without storage)");
-                        } else {
-                            println!("   Simple undefine also failed: {}", 
-                                    String::from_utf8_lossy(&simple_undefine.stderr));
-                        }
-                    }
-                }
-                Err(e) => {
-                    println!("   Error running undefine: {}", e);
-                }
-            }
+            config.push_str("\n# Verify installations\nflatpak list\n");
+            config
+        } else {
+            "".to_string()
         }
-        
-        // Remove disk manually
-        let disk_path = format!("{}/{}.qcow2", self.config.vm_dir, self.config.name);
-        if Path::new(&disk_path).exists() {
-            println!("   Removing disk image: {}", disk_path);
-            match fs::remove_file(&disk_path) {
-                Ok(_) => println!("   ✅ Disk removed successfully"),
-                Err(e) => {
-                    println!("   Permission denied ({}), trying with sudo...", e);
-                    let sudo_result = Command::new("sudo")
-                        .args(&["rm", "-f", &disk_path])
-                        .output();
-                        
-                    match sudo_result {
-                        Ok(output) => {
-                            if output.status.success() {
-                                println!("   ✅ Disk removed with sudo");
-                            } else {
-                                println!("   ❌ Failed to remove disk even with sudo: {}", 
-                                        String::from_utf8_lossy(&output.stderr));
-                            }
-                        }
-                        Err(e) => println!("   ❌ Sudo command failed: {}", e),
-                    }
-                }
+    }
+    
+    /// Systemd units that auto-launch the configured applications
+    fn render_autolaunch(&self) -> String {
+        // Build auto-launch configuration
+        if !self.config.auto_launch_apps.is_empty() {
+            let mut config = String::from("\n# Auto-launch applications\n");
+            for (i, app_cmd) in self.config.auto_launch_apps.iter().enumerate() {
+                config.push_str(&format!(r#"
+# Auto-launch service {n}
+cat > /etc/systemd/system/auto-launch-{n}.service << 'EOF'
+{}EOF
+
+systemctl enable auto-launch-{n}.service
+"#, self.autolaunch_unit(i + 1, app_cmd), n = i + 1));
             }
+            config
         } else {
-            println!("   Disk image not found at: {}", disk_path);
+            "".to_string()
         }
-        
-        // Final verification
-        let final_check = Command::new("virsh")
-            .args(&["list", "--all"])
-            .output()?;
-        
-        if String::from_utf8_lossy(&final_check.stdout).contains(&self.config.name) {
-            println!("   ⚠️  Warning: VM still appears in virsh list");
-            println!("   You may need to manually run: virsh undefine {}", self.config.name);
-        } else {
-            println!("   ✅ VM successfully removed from libvirt");
+    }
+    
+    /// `--memorybacking` value, `None` for ordinary private memory. virtiofsd maps
+    /// guest memory, so shared folders need it shared.
+    fn memory_backing_arg(&self) -> Option<String> {
+        let mut options = Vec::new();
+        if self.config.enable_hugepages {
+            options.push("hugepages=on");
+        }
+        if !self.config.shared_folders.is_empty() {
+            options.push("source.type=memfd,access.mode=shared");
+        }
+        (!options.is_empty()).then(|| options.join(","))
+    }
+    
+    /// One `--filesystem` value per shared folder
+    fn filesystem_args(&self) -> Vec<String> {
+        self.config.shared_folders.iter()
+            .map(|(path, tag)| format!("{},{},driver.type=virtiofs", path.display(), tag))
+            .collect()
+    }
+    
+    /// Mount units for `shared_folders`, each tag mounted at /mnt/<tag>
+    fn render_shared_folders(&self) -> String {
+        if self.config.shared_folders.is_empty() {
+            return "# No shared folders".to_string();
         }
         
-        println!("✅ VM destruction completed");
-        
-        Ok(())
+        let mut config = String::new();
+        for (_, tag) in &self.config.shared_folders {
+            config.push_str(&format!(r#"mkdir -p /mnt/{tag}
+cat > /etc/systemd/system/{unit} << 'EOF'
+{}EOF
+systemctl enable {unit}
+"#, shared_folder_unit(tag), tag = tag, unit = shared_folder_unit_name(tag)));
+        }
+        config
     }
     
-    fn get_autologin_config(&self) -> String {
-        if self.config.enable_auto_login {
-            let mut result = r#"
-# Configure auto-login with i3 via systemd
-# Create auto-login service that starts X11 with i3
-cat > /etc/systemd/system/autologin@.service << 'EOF'
-[Unit]
-Description=Auto Login for %i
-After=systemd-user-sessions.service plymouth-quit-wait.service
-After=plymouth-quit.service gdm.service
-Before=getty@tty1.service
+    /// Unit file for the `index`th auto-launched application
+    fn autolaunch_unit(&self, index: usize, app_cmd: &str) -> String {
+        format!(r#"[Unit]
+Description=Auto Launch Application {}
+After=graphical-session.target
+Wants=display-manager.service
 
 [Service]
-ExecStart=-/sbin/agetty -o '-p -f user' --noclear --autologin user %i $TERM
-Type=idle
-Restart=always
-RestartSec=0
-UtmpIdentifier=%I
-TTYPath=/dev/%i
-TTYReset=yes
-TTYVHangup=yes
-TTYVTDisallocate=yes
-KillMode=process
-IgnoreSIGPIPE=no
-SendSIGHUP=yes
+Type=simple
+User=user
+Environment="DISPLAY=:0"
+Environment="XDG_RUNTIME_DIR=/run/user/1000"
+Environment="XDG_SESSION_TYPE={session}"
+ExecStartPre=/bin/bash -c 'while ! pgrep -x {server}; do sleep 1; done'
+ExecStart={}
+Restart=on-failure
+RestartSec=5
 
 [Install]
-WantedBy=getty.target
-EOF
+WantedBy=graphical.target
+"#, index, app_cmd,
+            session = self.config.session_type.as_env(),
+            server = self.config.session_type.x_server())
+    }
+    
+    /// Where the guest agent dials the host window proxy. Over TCP, `_gateway`
+    /// resolves to the host side of the VM network (nss-myhostname); a `Unix`
+    /// proxy has to be forwarded to the agent's default socket path.
+    fn guest_agent_endpoint(&self) -> String {
+        match self.config.proxy_transport {
+            ProxyTransport::Vsock => format!("vsock:2:{}", self.config.proxy_port),
+            ProxyTransport::Unix => "/tmp/vm-window-proxy.sock".to_string(),
+            ProxyTransport::Tcp => format!("_gateway:{}", self.config.proxy_port),
+        }
+    }
+    
+    /// Unit file for the guest agent
+    fn guest_agent_unit(&self) -> String {
+        format!(r#"[Unit]
+Description=VM Guest Agent for Window Management
+After=graphical.target
+Wants=autologin@tty{tty}.service
 
-# Enable auto-login on tty1
-systemctl enable autologin@tty1.service
+[Service]
+Type=simple
+User=user
+Environment="DISPLAY=:0"
+Environment="XDG_RUNTIME_DIR=/run/user/1000"
+Environment="XDG_SESSION_TYPE={session}"
+Environment="GUEST_AGENT_DEBOUNCE_MS=1000"
+Environment="GUEST_AGENT_LOG_LEVEL=warn"
+{clipboard}{tls}ExecStartPre=/bin/bash -c 'while ! pgrep -x {server}; do sleep 1; done'
+ExecStart=/usr/local/bin/guest-agent {endpoint}
+Restart=on-failure
+RestartSec=3
 
-# Enable spice-vdagentd socket for auto-resize (starts daemon on demand)
-systemctl enable spice-vdagentd.socket
+[Install]
+WantedBy=graphical.target
+"#,
+            tty = self.config.autologin_tty,
+            session = self.config.session_type.as_env(),
+            server = self.config.session_type.x_server(),
+            endpoint = self.guest_agent_endpoint(),
+            // The custom clipboard transport is synced by the agent itself
+            clipboard = if self.config.effective_clipboard_transport() == ClipboardTransport::Custom {
+                "Environment=\"GUEST_AGENT_CLIPBOARD=1\"\n"
+            } else {
+                ""
+            },
+            tls = if self.config.proxy_tls {
+                format!("Environment=\"GUEST_AGENT_TLS={}\"\n", tls::GUEST_IDENTITY_PATH)
+            } else {
+                String::new()
+            },
+        )
+    }
+    
+    /// Guest agent, auto-login and audio service setup
+    fn render_services(&self) -> String {
+        // Build guest agent service configuration
+        let app_config = format!(r#"
+# Create guest agent service
+cat > /etc/systemd/system/guest-agent.service << 'EOF'
+{}EOF
 
-# Create .xinitrc for user to start i3
-cat > /home/user/.xinitrc << 'EOF'
-#!/bin/bash
+# Enable the services
+systemctl enable guest-agent.service
 
-# Comprehensive logging for debugging
-exec > /tmp/xinitrc.log 2>&1
-echo "=== .xinitrc started at $(date) ==="
-set -x
+# Let the host run journalctl and read files through the QEMU guest agent (vm-provisioner logs)
+echo 'FILTER_RPC_ARGS="--allow-rpcs=guest-ping,guest-sync,guest-sync-delimited,guest-info,guest-get-time,guest-shutdown,guest-fsfreeze-status,guest-fsfreeze-freeze,guest-fsfreeze-thaw,guest-exec,guest-exec-status,guest-file-open,guest-file-read,guest-file-close"' >> /etc/sysconfig/qemu-ga
+systemctl enable qemu-guest-agent.service
 
-# Set up X11 environment
-export DISPLAY=:0
-export XDG_RUNTIME_DIR="/run/user/$(id -u)"
+{}
 
-# Add Flatpak paths to XDG_DATA_DIRS for dmenu integration
-export XDG_DATA_DIRS="/usr/local/share:/usr/share:/var/lib/flatpak/exports/share:$HOME/.local/share/flatpak/exports/share:$XDG_DATA_DIRS"
+# Set multi-user target as default (since we're using auto-login)
+systemctl set-default multi-user.target"#,
+            self.guest_agent_unit(),
+            self.get_autologin_config(),
+        );
+        
+        // Build audio configuration if enabled
+        let audio_config = if self.config.enable_audio {
+            r#"
+# Enable PipeWire audio
+systemctl --user enable pipewire pipewire-pulse wireplumber"#
+        } else {
+            ""
+        };
+        
+        // Serve remote-viewer's shared folder over the webdav channel
+        let folder_sharing_config = if self.config.enable_spice_folder_sharing {
+            r#"
+# Enable SPICE folder sharing
+systemctl enable spice-webdavd.service"#
+        } else {
+            ""
+        };
+        
+        // Hand http(s) links to the guest agent, which forwards them to the host browser
+        let open_url_config = if self.config.open_urls_on_host {
+            r#"
+# Open links on the host
+cat > /usr/share/applications/vm-open-url.desktop << 'EOF'
+[Desktop Entry]
+Type=Application
+Name=Open on Host
+Exec=/usr/local/bin/guest-agent open-url %u
+MimeType=x-scheme-handler/http;x-scheme-handler/https;
+NoDisplay=true
+EOF
+mkdir -p /etc/xdg
+cat >> /etc/xdg/mimeapps.list << 'EOF'
+[Default Applications]
+x-scheme-handler/http=vm-open-url.desktop
+x-scheme-handler/https=vm-open-url.desktop
+EOF"#
+        } else {
+            ""
+        };
+        
+        // dunst receives the guest's notifications and hands each one to the guest agent
+        let notification_config = if self.config.enable_notifications {
+            r#"
+# Forward desktop notifications to the host
+cat > /usr/local/bin/guest-agent-notify << 'EOF'
+#!/bin/sh
+# Called by dunst with: appname summary body icon urgency
+exec /usr/local/bin/guest-agent notify "$1" "$2" "$3" "$4"
+EOF
+chmod +x /usr/local/bin/guest-agent-notify
+mkdir -p /etc/xdg/dunst
+cat > /etc/xdg/dunst/dunstrc << 'EOF'
+[global]
+    follow = none
 
-echo "Environment: DISPLAY=$DISPLAY, XDG_RUNTIME_DIR=$XDG_RUNTIME_DIR"
-echo "XDG_DATA_DIRS: $XDG_DATA_DIRS"
+# Shown on the host instead of inside the VM
+[forward-to-host]
+    summary = "*"
+    script = /usr/local/bin/guest-agent-notify
+    skip_display = yes
+EOF"#
+        } else {
+            ""
+        };
+        
+        format!("{}\n\n{}\n\n{}\n\n{}\n\n{}", app_config, audio_config, folder_sharing_config, open_url_config, notification_config)
+    }
+    
+    /// iptables commands for the configured firewall rules
+    fn render_firewall(&self) -> String {
+        let mut firewall = self.config.firewall_rules
+            .iter()
+            .map(|rule| format!("iptables -A {}", rule))
+            .collect::<Vec<_>>()
+            .join("\n");
+        
+        if let Some(killswitch) = self.render_killswitch() {
+            firewall.push_str(&killswitch);
+        }
+        firewall
+    }
+    
+    /// Boot-time killswitch for `VpnOnly` VMs: before any interface comes up, all
+    /// egress is dropped except through the tunnel, to the VPN servers, and DHCP.
+    /// It lives in its own chain so it stacks with `firewall_rules`.
+    fn render_killswitch(&self) -> Option<String> {
+        let (vpn, provider) = self.vpn()?;
+        let (interface, service) = match provider {
+            VpnProvider::WireGuard => (VPN_NAME.to_string(), format!("wg-quick@{}.service", VPN_NAME)),
+            VpnProvider::OpenVpn => ("tun+".to_string(), format!("openvpn-client@{}.service", VPN_NAME)),
+        };
+        
+        // Validation already checked the config; without servers the tunnel just can't connect
+        let mut allowed: Vec<String> = vpn.endpoints().unwrap_or_default()
+            .iter()
+            .map(|endpoint| format!(
+                "{} -A VPN_KILLSWITCH -d {} -p {} --dport {} -j ACCEPT",
+                if endpoint.address.is_ipv6() { "ip6tables" } else { "iptables" },
+                endpoint.address, endpoint.protocol, endpoint.port,
+            ))
+            .collect();
+        if self.config.proxy_transport == ProxyTransport::Tcp {
+            // The window proxy on the host, through libvirt's default network gateway
+            allowed.push(format!("iptables -A VPN_KILLSWITCH -d 192.168.122.1 -p tcp --dport {} -j ACCEPT", self.config.proxy_port));
+        }
+        
+        Some(format!(r#"
 
-# Wait for X11 to be ready with timeout
-echo "Waiting for X11 to be ready..."
-timeout=30
-count=0
-while ! DISPLAY=:0 xset q &>/dev/null; do
-    if [ $count -ge $timeout ]; then
-        echo "X11 timeout after ${timeout}s, proceeding anyway..."
-        break
-    fi
-    echo "X11 not ready, waiting... ($count/$timeout)"
-    sleep 1
-    count=$((count + 1))
+# VPN killswitch: only the tunnel, the VPN servers and DHCP may leave the VM
+cat > /usr/local/sbin/vpn-killswitch << 'EOF'
+#!/bin/sh
+set -e
+for ipt in iptables ip6tables; do
+    $ipt -N VPN_KILLSWITCH 2>/dev/null || $ipt -F VPN_KILLSWITCH
+    $ipt -C OUTPUT -j VPN_KILLSWITCH 2>/dev/null || $ipt -I OUTPUT 1 -j VPN_KILLSWITCH
+    $ipt -A VPN_KILLSWITCH -o lo -j ACCEPT
+    $ipt -A VPN_KILLSWITCH -o {interface} -j ACCEPT
 done
-echo "X11 check completed (timeout: $count/$timeout)"
-
-# Ensure X11 authority is properly set
-echo "Setting X11 authority..."
-xauth generate :0 . trusted
-echo "X11 authority set"
+iptables -A VPN_KILLSWITCH -p udp --sport 68 --dport 67 -j ACCEPT
+{allowed}
+iptables -A VPN_KILLSWITCH -j DROP
+ip6tables -A VPN_KILLSWITCH -j DROP
+EOF
+chmod 755 /usr/local/sbin/vpn-killswitch
 
-# Start SPICE agent user session (system daemon should already be running)
-if command -v spice-vdagent >/dev/null 2>&1; then
-    echo "Starting spice-vdagent..."
-    DISPLAY=:0 XDG_RUNTIME_DIR="/run/user/$(id -u)" spice-vdagent &
-    sleep 1
-    echo "spice-vdagent started"
-else
-    echo "spice-vdagent not found!"
-fi
+cat > /etc/systemd/system/vpn-killswitch.service << 'EOF'
+[Unit]
+Description=Drop network traffic that bypasses the VPN
+DefaultDependencies=no
+Before=network-pre.target
+Wants=network-pre.target
+
+[Service]
+Type=oneshot
+RemainAfterExit=yes
+ExecStart=/usr/local/sbin/vpn-killswitch
+
+[Install]
+WantedBy=multi-user.target
+EOF
+
+systemctl enable vpn-killswitch.service {service}"#,
+            interface = interface, allowed = allowed.join("\n"), service = service))
+    }
+    
+    /// DNS overrides; nothing is written when the DHCP-provided resolvers are kept
+    fn render_dns(&self) -> String {
+        let servers = &self.config.dns_servers;
+        let search = &self.config.dns_search;
+        
+        if servers.is_empty() && search.is_empty() {
+            return "# Using DHCP-provided DNS".to_string();
+        }
+        
+        if servers.is_empty() {
+            // Search domains alone must not replace the DHCP servers, so leave NetworkManager alone
+            return format!(
+                "mkdir -p /etc/systemd/resolved.conf.d\ncat > /etc/systemd/resolved.conf.d/90-vm-provisioner-dns.conf << 'EOF'\n[Resolve]\nDomains={}\nEOF",
+                search.join(" ")
+            );
+        }
+        
+        // NetworkManager's global DNS replaces whatever each connection got from DHCP
+        let mut conf = String::from("[global-dns]\n");
+        if !search.is_empty() {
+            conf.push_str(&format!("searches={}\n", search.join(",")));
+        }
+        conf.push_str(&format!("\n[global-dns-domain-*]\nservers={}\n", servers.join(",")));
+        
+        format!(
+            "mkdir -p /etc/NetworkManager/conf.d\ncat > /etc/NetworkManager/conf.d/90-vm-provisioner-dns.conf << 'EOF'\n{}EOF",
+            conf
+        )
+    }
+    
+    /// zram device or swap file setup. Without either, Fedora's default zram is turned off too.
+    fn render_swap(&self) -> String {
+        // An empty zram-generator.conf overrides the distribution default of zram0
+        let disable_zram = "touch /etc/systemd/zram-generator.conf";
+        
+        match (self.config.enable_zram, self.config.swap_mb) {
+            (true, size) => format!(
+                "cat > /etc/systemd/zram-generator.conf << 'EOF'\n[zram0]\nzram-size = {}\ncompression-algorithm = zstd\nEOF",
+                size.unwrap_or(self.config.memory_mb / 4)
+            ),
+            (false, Some(size)) => format!(
+                "{}\nfallocate -l {}M /swapfile\nchmod 600 /swapfile\nmkswap /swapfile\necho '/swapfile none swap defaults 0 0' >> /etc/fstab",
+                disable_zram, size
+            ),
+            (false, None) => disable_zram.to_string(),
+        }
+    }
+    
+    /// HiDPI scaling: Xft.dpi and toolkit scale variables under X11, output scaling under Sway.
+    /// The viewer is left at 100% zoom so the guest resolution keeps matching its window.
+    fn render_display_scale(&self) -> String {
+        let Some(scale) = self.config.display_scale else {
+            return "# Using the default display scale".to_string();
+        };
+        
+        match self.config.session_type {
+            SessionType::X11 => {
+                // GDK only scales by whole numbers; GDK_DPI_SCALE corrects the text size
+                let gdk_scale = scale.round().max(1.0);
+                format!(r#"cat > /etc/profile.d/display-scale.sh << 'EOF'
+export GDK_SCALE={}
+export GDK_DPI_SCALE={:.3}
+export QT_SCALE_FACTOR={}
+EOF
+# i3 title bars, rofi and other Xft text follow Xft.dpi (loaded by .xinitrc)
+echo 'Xft.dpi: {}' > /home/user/.Xresources
+chown user:user /home/user/.Xresources"#,
+                    gdk_scale, scale / gdk_scale, scale, (96.0 * scale).round())
+            }
+            SessionType::Wayland => format!(
+                "[ -f /home/user/.config/sway/config ] && echo 'output * scale {}' >> /home/user/.config/sway/config",
+                scale
+            ),
+        }
+    }
+    
+    /// Arguments for `virt-install` (run under sudo), with the mirror credentials
+    /// embedded in them so output can be redacted
+    fn virt_install_args(&self, media: &InstallMedia, disk_path: &str) 
+        -> Result<(Vec<String>, Option<MirrorCredentials>), Box<dyn std::error::Error>> {
+        let (source_args, credentials) = match media {
+            InstallMedia::Kickstart { iso_path, initrd_files } => {
+                // Prefer an explicit install tree, then a local ISO, then the Fedora mirrors
+                let arch = std::env::consts::ARCH;
+                let install_tree;
+                let install_location = if let Some(location) = &self.config.install_location {
+                    location.as_str()
+                } else if self.config.iso_path.is_some() {
+                    iso_path.as_str()
+                } else {
+                    install_tree = self.config.distro.install_tree_url(arch)?;
+                    install_tree.as_str()
+                };
+                outln!("   Install source: {}", install_location);
+                
+                // Anaconda and virt-install take basic-auth credentials as part of the URL
+                let credentials = MirrorCredentials::load(&self.config)?;
+                let install_location = credentials.as_ref()
+                    .map(|creds| creds.embed_in_url(install_location))
+                    .unwrap_or_else(|| install_location.to_string());
+                
+                let mut args = vec!["--location".to_string(), install_location];
+                for file in initrd_files {
+                    args.extend(["--initrd-inject".to_string(), file.clone()]);
+                }
+                args.extend([
+                    "--extra-args".to_string(), "inst.ks=file:/kickstart.cfg console=tty0 console=ttyS0,115200n8".to_string(),
+                ]);
+                (args, credentials)
+            }
+            InstallMedia::CloudInit { seed_path } => {
+                outln!("   Install source: cloud image with seed {}", seed_path);
+                
+                // Cloud images rarely match an osinfo entry; don't let that stop the import
+                let args = vec![
+                    "--import".to_string(),
+                    "--disk".to_string(), format!("path={},device=cdrom", seed_path),
+                    "--osinfo".to_string(), "detect=on,require=off".to_string(),
+                ];
+                (args, None)
+            }
+            InstallMedia::Installed => {
+                let args = vec![
+                    "--import".to_string(),
+                    "--osinfo".to_string(), "detect=on,require=off".to_string(),
+                ];
+                (args, None)
+            }
+        };
+        
+        // Anaconda may need more (or less) than the VM's runtime allocation
+        let memory_str = self.config.install_memory_mb.unwrap_or(self.config.memory_mb).to_string();
+        let vcpus_str = self.vcpus_arg();
+        let mut disk_arg = format!("path={},size={},format=qcow2,bus=virtio,cache={},io={}", 
+                                   disk_path, self.config.disk_size_gb,
+                                   self.config.disk_cache.as_arg(), self.config.disk_io.as_arg());
+        let iothreads_str = self.config.iothreads.map(|n| n.to_string());
+        if iothreads_str.is_some() {
+            // Run disk IO on the first dedicated IO thread
+            disk_arg.push_str(",driver.iothread=1");
+        }
+        
+        // Only one clipboard path may be active: with the custom transport,
+        // stop SPICE from syncing the clipboard as well
+        let spice_clipboard = match self.config.effective_clipboard_transport() {
+            ClipboardTransport::Spice => "",
+            ClipboardTransport::Custom | ClipboardTransport::None => ",clipboard.copypaste=no",
+        };
+        // The SPICE password stays off virt-install's command line, where `ps` and --dry-run
+        // would show it: until `apply_spice_password` sets it, the display is loopback only
+        let spice_password = self.config.spice_password.is_some();
+        let install_listen = match self.config.spice_listen.as_deref() {
+            Some(listen) if !self.config.spice_needs_password() => listen,
+            _ => "127.0.0.1",
+        };
+        let spice_any = format!("spice{}", spice_clipboard);
+        let spice_listen = format!("spice,listen={}{}", install_listen, spice_clipboard);
+        
+        // Configure graphics based on backend and architecture
+        let arch = std::env::consts::ARCH;
+        let display = self.config.graphics_backend.display_setup(arch)
+            .ok_or_else(|| format!("Graphics backend {:?} is not supported on {}", 
+                                   self.config.graphics_backend, arch))?;
+        let graphics_args = match display {
+            DisplaySetup::Spice { video, localhost_only } => {
+                let spice = if localhost_only || self.config.spice_listen.is_some() || spice_password { &spice_listen } else { &spice_any };
+                vec!["--graphics", spice, "--video", video, 
+                     "--channel", "spicevmc,target_type=virtio,name=com.redhat.spice.0"]
+            },
+            DisplaySetup::Vnc => {
+                vec!["--graphics", "vnc,listen=127.0.0.1,port=5900"]
+            },
+        };
+        
+        let mut virt_install_args = vec![
+            "--name", &self.config.name,
+            "--memory", &memory_str,
+            "--vcpus", &vcpus_str,
+            "--disk", &disk_arg,
+        ];
+        virt_install_args.extend(source_args.iter().map(String::as_str));
+        let network_arg = self.network_arg();
+        virt_install_args.extend_from_slice(&[
+            "--network", &network_arg,
+            "--noautoconsole",
+        ]);
+        
+        // Without --wait, virt-install returns as soon as the installer has booted
+        if self.wait_for_install {
+            virt_install_args.extend_from_slice(&["--wait", "-1"]);
+        }
+        
+        // Add graphics arguments
+        for arg in graphics_args {
+            virt_install_args.push(arg);
+        }
+        
+        // Webdav channel for SPICE folder sharing
+        if self.config.enable_spice_folder_sharing && matches!(display, DisplaySetup::Spice { .. }) {
+            virt_install_args.extend_from_slice(&[
+                "--channel", "spiceport,source.channel=org.spice-space.webdav.0,target.type=virtio,target.name=org.spice-space.webdav.0",
+            ]);
+        }
+        
+        // Add sound if enabled
+        if self.config.enable_audio {
+            if arch == "aarch64" {
+                // ARM64: Use virtio sound model
+                virt_install_args.extend_from_slice(&["--sound", "model=virtio"]);
+            } else {
+                // x86_64: Use default sound
+                virt_install_args.extend_from_slice(&["--sound", "default"]);
+            }
+        }
+        
+        let memory_backing = self.memory_backing_arg();
+        if let Some(memory_backing) = &memory_backing {
+            virt_install_args.extend_from_slice(&["--memorybacking", memory_backing]);
+        }
+        let filesystems = self.filesystem_args();
+        for filesystem in &filesystems {
+            virt_install_args.extend_from_slice(&["--filesystem", filesystem]);
+        }
+        let memballoon = if self.config.memory_balloon { "virtio" } else { "none" };
+        virt_install_args.extend_from_slice(&["--memballoon", memballoon]);
+        
+        // Dedicated IO threads
+        if let Some(iothreads) = &iothreads_str {
+            virt_install_args.extend_from_slice(&["--iothreads", iothreads]);
+        }
+        
+        // Pinned vCPUs see the host CPU as it is, unless extra args pick a model
+        let cputune = self.cputune_arg();
+        if let Some(cputune) = &cputune {
+            virt_install_args.extend_from_slice(&["--cputune", cputune]);
+            if !self.config.extra_virt_install_args.iter().any(|arg| arg == "--cpu" || arg.starts_with("--cpu=")) {
+                virt_install_args.extend_from_slice(&["--cpu", "host-passthrough"]);
+            }
+        }
+        
+        // Persistent console log, covering the installer too
+        let console_log = self.install_console_log();
+        let serial_log = console_log.as_ref()
+            .map(|path| format!("pty,log.file={},log.append=on", path.display()));
+        if let (Some(serial), Some(path)) = (&serial_log, &console_log) {
+            outln!("   Console log: {}", path.display());
+            virt_install_args.extend_from_slice(&["--serial", serial]);
+        }
+        
+        // Guest agent channel; libvirt picks a free context ID
+        if self.config.proxy_transport == ProxyTransport::Vsock {
+            virt_install_args.extend_from_slice(&["--vsock", "cid.auto=yes"]);
+        }
+        
+        // Add USB controller if needed, and the devices passed through
+        if self.config.enable_usb_passthrough || !self.config.usb_devices.is_empty() {
+            virt_install_args.extend_from_slice(&["--controller", "usb,model=qemu-xhci"]);
+        }
+        let hostdevs = self.hostdev_args();
+        for hostdev in &hostdevs {
+            virt_install_args.extend_from_slice(&["--hostdev", hostdev]);
+        }
+        
+        // User-supplied escape hatch, appended last so it can refine anything above
+        for arg in &self.config.extra_virt_install_args {
+            virt_install_args.push(arg);
+        }
+        
+        let virt_install_args = virt_install_args.into_iter().map(String::from).collect();
+        Ok((virt_install_args, credentials))
+    }
+    
+    fn start_installation(&self, media: &InstallMedia, disk_path: &str) 
+        -> Result<(), Box<dyn std::error::Error>> {
+        outln!("🚀 Starting VM installation...");
+        
+        let (virt_install_args, credentials) = self.virt_install_args(media, disk_path)?;
+        let console_log = self.install_console_log();
+        
+        if self.wait_for_install {
+            outln!("⏳ Running automated installation, following the installer console...");
+        } else {
+            outln!("⏳ Launching automated installation (15-20 minutes, detached)...");
+        }
+        
+        let mut child = libvirt::privileged_tool("virt-install")
+            .args(&virt_install_args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        
+        let (output_tx, output_rx) = mpsc::channel();
+        if let Some(pipe) = child.stdout.take() {
+            forward_install_output(pipe, output_tx.clone(), InstallSource::VirtInstall);
+        }
+        if let Some(pipe) = child.stderr.take() {
+            forward_install_output(pipe, output_tx.clone(), InstallSource::VirtInstall);
+        }
+        
+        // Anaconda only reports progress on the serial console, which virtlogd writes as root
+        let mut console_tail = None;
+        if let (true, Some(path)) = (self.wait_for_install, &console_log) {
+            let mut tail = if libvirt::is_session() {
+                Command::new("tail")
+            } else {
+                let mut sudo = Command::new("sudo");
+                sudo.args(["-n", "tail"]);
+                sudo
+            };
+            let tail = tail
+                .args(["-F", "-n", "0"])
+                .arg(path)
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn();
+            if let Ok(mut tail) = tail {
+                if let Some(pipe) = tail.stdout.take() {
+                    forward_install_output(pipe, output_tx.clone(), InstallSource::Console);
+                }
+                console_tail = Some(tail);
+            }
+        }
+        drop(output_tx);
+        
+        // Echo virt-install's output and the installer's progress, keeping virt-install's
+        // stderr to classify failures and the last lines of everything to explain them.
+        // Anaconda's own download errors only show up on the console.
+        let mut stderr = String::new();
+        let mut console_network_failure = false;
+        let mut tail = VecDeque::with_capacity(INSTALL_TAIL_LINES);
+        let mut open_streams = 2;
+        while open_streams > 0 {
+            let (source, line) = match output_rx.recv() {
+                Ok(InstallOutput::Line(source, line)) => (source, line),
+                Ok(InstallOutput::Closed) => {
+                    open_streams -= 1;
+                    continue;
+                }
+                Err(_) => break,
+            };
+            let line = match &credentials {
+                Some(creds) => creds.redact(&line),
+                None => line,
+            };
+            
+            if source == InstallSource::Console {
+                if let Some(progress) = install_progress(&line) {
+                    outln!("   {}", progress);
+                }
+                console_network_failure |= is_network_failure(&line);
+            } else {
+                eoutln!("{}", line);
+                stderr.push_str(&line);
+                stderr.push('\n');
+            }
+            
+            let line = line.trim_end();
+            if !line.is_empty() {
+                if tail.len() == INSTALL_TAIL_LINES {
+                    tail.pop_front();
+                }
+                tail.push_back(line.to_string());
+            }
+        }
+        let status = child.wait()?;
+        if let Some(mut console_tail) = console_tail {
+            let _ = console_tail.kill();
+            let _ = console_tail.wait();
+        }
+            
+        if !status.success() {
+            return Err(Box::new(InstallError {
+                code: status.code(),
+                reason: failure_reason(&stderr),
+                network: console_network_failure || is_network_failure(&stderr),
+                tail: tail.into(),
+            }));
+        }
+        
+        outln!("✅ Installation completed!");
+        
+        Ok(())
+    }
+    
+    fn setup_window_management(&self) -> Result<(), Box<dyn std::error::Error>> {
+        outln!("🪟 Setting up window management integration...");
+        
+        // This is where we'd set up the virtio channel for window management
+        // For now, we'll configure the VM to be ready for the host integration
+        
+        match self.config.graphics_backend {
+            GraphicsBackend::VirtioGpu => {
+                outln!("   Configured for VirtIO-GPU acceleration");
+                outln!("   Cage compositor will start automatically");
+            },
+            GraphicsBackend::QxlSpice => {
+                outln!("   Configured for SPICE protocol");
+                outln!("   Connect with: vm-provisioner connect {}", self.config.name);
+            },
+            GraphicsBackend::VncOnly => {
+                outln!("   VNC fallback mode");
+                outln!("   Connect with: vm-provisioner connect {}", self.config.name);
+            },
+        }
+        
+        match self.config.effective_clipboard_transport() {
+            ClipboardTransport::Spice => outln!("   Clipboard sharing via SPICE (spice-vdagent)"),
+            ClipboardTransport::Custom => outln!("   Clipboard sharing via the guest agent (requires the window proxy of start)"),
+            ClipboardTransport::None => outln!("   Clipboard sharing disabled"),
+        }
+        
+        Ok(())
+    }
+    
+    pub fn start_vm(&self) -> Result<(), Box<dyn std::error::Error>> {
+        outln!("▶️  Starting VM: {}", self.config.name);
+        
+        self.apply_console_log()?;
+        self.apply_random_identity()?;
+        if self.config.enable_hugepages {
+            self.check_hugepages(self.config.memory_mb)?;
+        }
+        self.check_usb_devices()?;
+        
+        libvirt::virsh()
+            .args(&["start", &self.config.name])
+            .status()?;
+            
+        self.apply_cpu_shares()?;
+        
+        // libvirt picks the display port; wait until it's assigned and listening
+        outln!("🖥️  Waiting for the display...");
+        let display = wait_for_display(&self.config.name);
+        if let Err(e) = VMState::update(&self.config.name, |state| state.display = display.clone()) {
+            eoutln!("⚠️  Failed to record the display: {}", e);
+        }
+        
+        match (display, self.config.graphics_backend) {
+            (Some(display), GraphicsBackend::VirtioGpu | GraphicsBackend::QxlSpice) => {
+                outln!("🖥️  Launching SPICE viewer on {}", display);
+                if let Err(e) = launch_viewer(&self.config.name, &display) {
+                    eoutln!("⚠️  Failed to launch remote-viewer: {}", e);
+                }
+            },
+            (Some(display), GraphicsBackend::VncOnly) => {
+                outln!("   Connect with: remote-viewer {}", display);
+            },
+            (None, _) => {
+                eoutln!("⚠️  {} didn't bring up a display within {}s; check: virsh domdisplay {}", 
+                        self.config.name, DISPLAY_WAIT.as_secs(), self.config.name);
+            },
+        }
+        
+        outln!("✅ VM started successfully!");
+        
+        Ok(())
+    }
+    
+    /// Freeze the VM's vCPUs; memory stays allocated
+    pub fn pause_vm(&self) -> Result<(), Box<dyn std::error::Error>> {
+        outln!("⏸️  Pausing VM: {}", self.config.name);
+        self.run_virsh_checked(&["suspend", &self.config.name])?;
+        outln!("✅ VM paused (continue with: vm-provisioner resume {})", self.config.name);
+        Ok(())
+    }
+    
+    /// Continue a paused VM and reopen its SPICE viewer, which may have been closed meanwhile
+    pub fn resume_vm(&self) -> Result<(), Box<dyn std::error::Error>> {
+        outln!("▶️  Resuming VM: {}", self.config.name);
+        self.run_virsh_checked(&["resume", &self.config.name])?;
+        
+        if self.config.graphics_backend != GraphicsBackend::VncOnly {
+            outln!("🖥️  Launching SPICE viewer...");
+            open_spice_viewer(&self.config.name);
+        }
+        outln!("✅ VM resumed");
+        Ok(())
+    }
+    
+    /// Bring a running VM in line with its config without reinstalling: install the
+    /// system packages and Flatpaks it lacks, and rewrite the auto-start entries and
+    /// the guest agent service. Everything runs through the QEMU guest agent.
+    pub fn rebuild(&self) -> Result<(), Box<dyn std::error::Error>> {
+        outln!("🔧 Rebuilding VM: {}", self.config.name);
+        
+        let installed_rpms = guest_exec(&self.config.name, "rpm", &["-qa".to_string(), "--qf".to_string(), "%{NAME}\n".to_string()])?;
+        let system = missing_packages(&self.config.system_packages, &installed_rpms);
+        
+        // No flatpak command means no Flatpaks yet
+        let installed_flatpaks = guest_exec(&self.config.name, "flatpak", &["list".to_string(), "--app".to_string(), "--columns=application".to_string()])
+            .unwrap_or_default();
+        let flatpaks = missing_packages(&self.config.flatpak_packages, &installed_flatpaks);
+        
+        match (system.is_empty(), flatpaks.is_empty()) {
+            (true, true) => outln!("   All packages are installed"),
+            _ => {
+                if !system.is_empty() {
+                    outln!("   Installing system packages: {}", system.join(" "));
+                }
+                if !flatpaks.is_empty() {
+                    outln!("   Installing Flatpaks: {}", flatpaks.join(" "));
+                }
+                outln!("   (this can take a while)");
+            }
+        }
+        outln!("   Rewriting auto-start entries and the guest agent service");
+        
+        let script = self.render_rebuild_script(&system, &flatpaks);
+        guest_exec_with_timeout(&self.config.name, "/bin/bash", &["-c".to_string(), script], REBUILD_TIMEOUT)?;
+        
+        outln!("✅ VM rebuilt; auto-started apps launch from the next login");
+        outln!("⚠️  Removed packages stay installed, and kernel, desktop and other base system changes");
+        outln!("   still need a full reinstall: vm-provisioner reprovision {}", self.config.name);
+        Ok(())
+    }
+    
+    /// Shell script run in the guest by `rebuild`
+    fn render_rebuild_script(&self, system: &[String], flatpaks: &[String]) -> String {
+        let mut script = String::from("set -e\n");
+        if !system.is_empty() {
+            script.push_str(&format!("dnf install -y {}\n", system.join(" ")));
+        }
+        if !flatpaks.is_empty() {
+            script.push_str("dnf install -y flatpak\n");
+            script.push_str("flatpak remote-add --if-not-exists flathub https://flathub.org/repo/flathub.flatpakrepo\n");
+            script.push_str(&format!("flatpak install -y --noninteractive flathub {}\n", flatpaks.join(" ")));
+        }
+        
+        // Replace the auto-start entries and units rather than adding to them
+        script.push_str(r#"
+sed -i '/^exec --no-startup-id /d' /home/user/.config/i3/config
+for unit in /etc/systemd/system/auto-launch-*.service; do
+    [ -e "$unit" ] || continue
+    systemctl disable "$(basename "$unit")"
+    rm -f "$unit"
+done"#);
+        script.push_str(&self.render_i3_autostart());
+        script.push('\n');
+        script.push_str(&self.render_autolaunch());
+        script.push_str(&format!(r#"
+cat > /etc/systemd/system/guest-agent.service << 'EOF'
+{}EOF
+
+chown -R user:user /home/user/.config
+systemctl daemon-reload
+systemctl restart guest-agent.service
+"#, self.guest_agent_unit()));
+        script
+    }
+    
+    fn run_virsh_checked(&self, args: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+        let output = libvirt::virsh().args(args).output()?;
+        if !output.status.success() {
+            return Err(format!("virsh {} failed: {}", args[0], 
+                               String::from_utf8_lossy(&output.stderr).trim()).into());
+        }
+        Ok(())
+    }
+    
+    pub fn stop_vm(&self) -> Result<(), Box<dyn std::error::Error>> {
+        outln!("⏹️  Stopping VM: {}", self.config.name);
+        
+        libvirt::virsh()
+            .args(&["shutdown", &self.config.name])
+            .status()?;
+        
+        // The next start may get a different port
+        VMState::update(&self.config.name, |state| {
+            state.display = None;
+            state.proxy_port = None;
+        })?;
+            
+        Ok(())
+    }
+    
+    pub fn destroy_vm(&self) -> Result<(), Box<dyn std::error::Error>> {
+        outln!("🗑️  Destroying VM: {}", self.config.name);
+        
+        // Check if VM exists first
+        let list_output = libvirt::virsh()
+            .args(&["list", "--all"])
+            .output()?;
+        
+        if !String::from_utf8_lossy(&list_output.stdout).contains(&self.config.name) {
+            outln!("   VM {} not found in virsh list", self.config.name);
+            // Still try to clean up disk
+        } else {
+            // Force stop if running
+            outln!("   Force stopping VM...");
+            let destroy_output = libvirt::virsh()
+                .args(&["destroy", &self.config.name])
+                .output();
+            
+            match destroy_output {
+                Ok(output) => {
+                    if output.status.success() {
+                        outln!("   VM stopped successfully");
+                    } else {
+                        outln!("   VM stop failed or already stopped: {}", 
+                                String::from_utf8_lossy(&output.stderr));
+                    }
+                }
+                Err(e) => outln!("   Error stopping VM: {}", e),
+            }
+            
+            std::thread::sleep(std::time::Duration::from_secs(3));
+            
+            // Undefine VM (remove from libvirt)
+            outln!("   Removing VM definition...");
+            let undefine_output = libvirt::virsh()
+                .args(["undefine", &self.config.name, "--remove-all-storage", "--nvram", "--managed-save", "--snapshots-metadata"])
+                .output();
+            
+            match undefine_output {
+                Ok(output) => {
+                    if output.status.success() {
+                        outln!("   VM definition removed with storage");
+                    } else {
+                        outln!("   Undefine with storage failed: {}", 
+                                String::from_utf8_lossy(&output.stderr));
+                        outln!("   Trying without storage flags...");
+                        
+                        // Try simpler undefine
+                        let simple_undefine = libvirt::virsh()
+                            .args(&["undefine", &self.config.name])
+                            .output()?;
+                        
+                        if simple_undefine.status.success() {
+                            outln!("   VM definition removed (without storage)");
+                        } else {
+                            outln!("   Simple undefine also failed: {}", 
+                                    String::from_utf8_lossy(&simple_undefine.stderr));
+                        }
+                    }
+                }
+                Err(e) => {
+                    outln!("   Error running undefine: {}", e);
+                }
+            }
+        }
+        
+        // Remove disk manually
+        let disk_path = format!("{}/{}.qcow2", self.config.vm_dir, self.config.name);
+        if Path::new(&disk_path).exists() {
+            outln!("   Removing disk image: {}", disk_path);
+            match fs::remove_file(&disk_path) {
+                Ok(_) => outln!("   ✅ Disk removed successfully"),
+                Err(e) => {
+                    outln!("   Permission denied ({}), trying with sudo...", e);
+                    let sudo_result = libvirt::privileged("rm")
+                        .args(["-f", &disk_path])
+                        .output();
+                        
+                    match sudo_result {
+                        Ok(output) => {
+                            if output.status.success() {
+                                outln!("   ✅ Disk removed with sudo");
+                            } else {
+                                outln!("   ❌ Failed to remove disk even with sudo: {}", 
+                                        String::from_utf8_lossy(&output.stderr));
+                            }
+                        }
+                        Err(e) => outln!("   ❌ Sudo command failed: {}", e),
+                    }
+                }
+            }
+        } else {
+            outln!("   Disk image not found at: {}", disk_path);
+        }
+        
+        // Final verification
+        let final_check = libvirt::virsh()
+            .args(&["list", "--all"])
+            .output()?;
+        
+        if String::from_utf8_lossy(&final_check.stdout).contains(&self.config.name) {
+            outln!("   ⚠️  Warning: VM still appears in virsh list");
+            outln!("   You may need to manually run: virsh undefine {}", self.config.name);
+        } else {
+            outln!("   ✅ VM successfully removed from libvirt");
+        }
+        
+        outln!("✅ VM destruction completed");
+        
+        Ok(())
+    }
+    
+    fn confirm_install_retry(&self, attempt: u32) -> Result<bool, Box<dyn std::error::Error>> {
+        outln!("🌐 Installation failed because of a network error (attempt {}/{})", attempt, MAX_INSTALL_ATTEMPTS);
+        if self.assume_yes {
+            outln!("   Retrying with the same disk...");
+            return Ok(true);
+        }
+        
+        Ok(Confirm::new()
+            .with_prompt("Retry the installation? (the ISO is kept and the disk is repartitioned)")
+            .default(true)
+            .interact()?)
+    }
+    
+    /// Remove the half-installed domain so virt-install can define it again, keeping the disk
+    fn cleanup_failed_install(&self) {
+        let _ = libvirt::virsh()
+            .args(["destroy", &self.config.name])
+            .output();
+        let _ = libvirt::virsh()
+            .args(["undefine", &self.config.name, "--nvram"])
+            .output();
+    }
+    
+    /// Set the guest user's password in the disk image of a stopped VM
+    /// (`virt-customize`), so the new password is in effect from the next boot
+    pub fn set_password_offline(&self, password: &str) -> Result<(), Box<dyn std::error::Error>> {
+        use std::os::unix::fs::OpenOptionsExt;
+        
+        // Passed through a private file so it doesn't show up in the process list
+        let password_file = std::env::temp_dir().join(format!("vm-provisioner-passwd-{}", std::process::id()));
+        let mut file = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&password_file)?;
+        file.write_all(password.as_bytes())?;
+        drop(file);
+        
+        let output = libvirt::privileged_tool("virt-customize")
+            .args(["-d", &self.config.name, "--password", &format!("user:file:{}", password_file.display())])
+            .output();
+        let _ = fs::remove_file(&password_file);
+        
+        let output = output.map_err(|e| format!("Cannot run virt-customize ({}); install it with: sudo dnf install guestfs-tools", e))?;
+        if !output.status.success() {
+            return Err(format!("virt-customize failed: {}", 
+                               String::from_utf8_lossy(&output.stderr).trim()).into());
+        }
+        
+        Ok(())
+    }
+    
+    /// Set `spice_password`, and a `spice_listen` that needs it, in the persisted domain
+    /// (`virsh update-device` from a private file), so the password never shows up in the
+    /// process list. A display that keeps its address gets the password right away
+    fn apply_spice_password(&self) -> Result<(), Box<dyn std::error::Error>> {
+        use std::os::unix::fs::OpenOptionsExt;
+        
+        let Some(xml) = self.spice_graphics_xml() else {
+            return Ok(());
+        };
+        outln!("🔐 Setting the SPICE display password...");
+        
+        let xml_file = std::env::temp_dir().join(format!("vm-provisioner-graphics-{}.xml", std::process::id()));
+        let mut file = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&xml_file)?;
+        file.write_all(xml.as_bytes())?;
+        drop(file);
+        
+        let xml_path = xml_file.to_string_lossy();
+        let configured = self.run_virsh_checked(&["update-device", &self.config.name, &xml_path, "--config"]);
+        // QEMU can change a running display's password but not its address
+        let live = !self.config.spice_needs_password() && libvirt::virsh()
+            .args(["update-device", &self.config.name, &xml_path, "--live"])
+            .output()
+            .is_ok_and(|output| output.status.success());
+        let _ = fs::remove_file(&xml_file);
+        configured?;
+        
+        if self.config.spice_needs_password() {
+            outln!("   The display listens on {} from the VM's next boot", self.config.spice_listen.as_deref().unwrap_or_default());
+        } else if !live {
+            outln!("   The password takes effect on the VM's next boot");
+        }
+        Ok(())
+    }
+    
+    /// `<graphics>` device with the configured SPICE listen address and password
+    fn spice_graphics_xml(&self) -> Option<String> {
+        let password = self.config.spice_password.as_deref()?;
+        let listen = self.config.spice_listen.as_deref().unwrap_or("127.0.0.1");
+        let clipboard = match self.config.effective_clipboard_transport() {
+            ClipboardTransport::Spice => "",
+            ClipboardTransport::Custom | ClipboardTransport::None => "\n  <clipboard copypaste='no'/>",
+        };
+        Some(format!(
+            "<graphics type='spice' autoport='yes' passwd='{password}'>\n  <listen type='address' address='{listen}'/>{clipboard}\n</graphics>\n",
+            password = xml_escape(password),
+            listen = xml_escape(listen),
+        ))
+    }
+    
+    /// Give the defined domain a new MAC address and hostname before it boots
+    fn apply_random_identity(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.config.identity_mode != IdentityMode::RandomizePerBoot {
+            return Ok(());
+        }
+        
+        let mut bytes = [0u8; 13];
+        fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+        let mac = local_mac_address(bytes[..6].try_into()?);
+        let hostname = random_hostname(&bytes[6..]);
+        outln!("🎭 New identity: MAC {}, hostname {}", mac, hostname);
+        
+        let sysinfo = format!("type=smbios,oemStrings.entry0=io.systemd.credential:system.hostname={}", hostname);
+        for (option, value) in [("--network", format!("mac={}", mac)), ("--sysinfo", sysinfo)] {
+            let output = libvirt::tool("virt-xml")
+                .args([&self.config.name, "--edit", option, &value])
+                .output()?;
+            if !output.status.success() {
+                return Err(format!("virt-xml failed to randomize the identity: {}", 
+                                   String::from_utf8_lossy(&output.stderr).trim()).into());
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Whether the host has enough free hugepages to back `memory_mb`; libvirt's own
+    /// error for this only says that the memory couldn't be allocated
+    fn check_hugepages(&self, memory_mb: u64) -> Result<(), String> {
+        let meminfo = fs::read_to_string("/proc/meminfo")
+            .map_err(|e| format!("enable_hugepages is set but /proc/meminfo can't be read: {}", e))?;
+        check_free_hugepages(&meminfo, memory_mb)
+    }
+    
+    /// Set the running domain's CPU weight relative to other VMs
+    fn apply_cpu_shares(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(shares) = self.config.cpu_shares else {
+            return Ok(());
+        };
+        
+        outln!("⚖️  Setting CPU shares: {}", shares);
+        let output = libvirt::virsh()
+            .args(["schedinfo", &self.config.name, "--set", &format!("cpu_shares={}", shares), "--live"])
+            .output()?;
+        if !output.status.success() {
+            return Err(format!("virsh schedinfo failed: {}", 
+                               String::from_utf8_lossy(&output.stderr).trim()).into());
+        }
+        
+        Ok(())
+    }
+    
+    /// Switch the persisted domain from the install-time memory to `memory_mb`
+    pub fn apply_runtime_memory(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let install_memory = match self.config.install_memory_mb {
+            Some(mb) if mb != self.config.memory_mb => mb,
+            _ => return Ok(()),
+        };
+        
+        outln!("🧠 Setting runtime memory: {} MB (installed with {} MB)", self.config.memory_mb, install_memory);
+        self.set_persistent_memory(install_memory)
+    }
+    
+    /// Write `memory_mb` and `vcpus` into the persisted domain definition when they
+    /// differ from `previous`; they take effect on the next boot
+    pub fn apply_resources(&self, previous: &AppVMConfig) -> Result<(), Box<dyn std::error::Error>> {
+        if self.config.memory_mb != previous.memory_mb {
+            outln!("🧠 Setting memory: {} MB (was {} MB)", self.config.memory_mb, previous.memory_mb);
+            self.set_persistent_memory(previous.memory_mb)?;
+        }
+        
+        if self.config.vcpus != previous.vcpus {
+            outln!("🧮 Setting vCPUs: {} (was {})", self.config.vcpus, previous.vcpus);
+            
+            // Same as memory: the vCPU count can't exceed the maximum
+            let vcpus = self.config.vcpus.to_string();
+            let updates: [&[&str]; 2] = if self.config.vcpus < previous.vcpus {
+                [&["--config"], &["--config", "--maximum"]]
+            } else {
+                [&["--config", "--maximum"], &["--config"]]
+            };
+            for flags in updates {
+                let output = libvirt::virsh()
+                    .args(["setvcpus", &self.config.name, &vcpus])
+                    .args(flags)
+                    .output()?;
+                if !output.status.success() {
+                    return Err(format!("virsh setvcpus failed: {}", 
+                                       String::from_utf8_lossy(&output.stderr).trim()).into());
+                }
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Apply `memory_mb` and `vcpus` to the running domain where they differ from
+    /// `previous`, within the maxima it was booted with. Returns the changes that have
+    /// to wait for a reboot because they exceed those maxima
+    pub fn resize_live(&self, previous: &AppVMConfig) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let stats = domain_stats(&self.config.name)
+            .ok_or_else(|| format!("{} isn't running", self.config.name))?;
+        let mut pending = Vec::new();
+        
+        if self.config.memory_mb != previous.memory_mb {
+            let max_mb = stats.memory_max_kib / 1024;
+            if self.config.memory_mb > max_mb {
+                pending.push(format!("{} MB of memory is above the {} MB maximum {} was booted with; \
+                                      the maximum only rises on reboot", self.config.memory_mb, max_mb, self.config.name));
+            } else {
+                outln!("🧠 Resizing live memory: {} MB (was {} MB)", self.config.memory_mb, previous.memory_mb);
+                self.run_virsh_checked(&["setmem", &self.config.name, &format!("{}M", self.config.memory_mb), "--live"])?;
+            }
+        }
+        
+        if self.config.vcpus != previous.vcpus {
+            if self.config.vcpus > stats.vcpus_max {
+                pending.push(format!("{} vCPUs is above the maximum of {} {} was booted with; \
+                                      the maximum only rises on reboot", self.config.vcpus, stats.vcpus_max, self.config.name));
+            } else {
+                outln!("🧮 Setting live vCPUs: {} (was {})", self.config.vcpus, previous.vcpus);
+                self.run_virsh_checked(&["setvcpus", &self.config.name, &self.config.vcpus.to_string(), "--live"])?;
+            }
+        }
+        
+        Ok(pending)
+    }
+    
+    /// Set the persisted domain's memory to `memory_mb`, coming from `previous_mb`
+    fn set_persistent_memory(&self, previous_mb: u64) -> Result<(), Box<dyn std::error::Error>> {
+        // The current allocation can never exceed the maximum, so order the two updates accordingly
+        let memory = format!("{}M", self.config.memory_mb);
+        let updates: [&str; 2] = if self.config.memory_mb < previous_mb {
+            ["setmem", "setmaxmem"]
+        } else {
+            ["setmaxmem", "setmem"]
+        };
+        
+        for command in updates {
+            let output = libvirt::virsh()
+                .args([command, &self.config.name, &memory, "--config"])
+                .output()?;
+            if !output.status.success() {
+                return Err(format!("virsh {} failed: {}", command, 
+                                   String::from_utf8_lossy(&output.stderr).trim()).into());
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Grow the VM's disk image to `size_gb`. The VM must be shut off; the guest's
+    /// partition and filesystem are left as they are (see `grow_guest_filesystem`)
+    pub fn resize_disk(&self, size_gb: u64) -> Result<(), Box<dyn std::error::Error>> {
+        let disk_path = format!("{}/{}.qcow2", self.config.vm_dir, self.config.name);
+        let output = libvirt::privileged("qemu-img")
+            .args(["info", "--output=json", &disk_path])
+            .output()?;
+        if !output.status.success() {
+            return Err(format!("qemu-img info failed for {}: {}", disk_path, 
+                               String::from_utf8_lossy(&output.stderr).trim()).into());
+        }
+        let current = qcow2_virtual_size(&String::from_utf8_lossy(&output.stdout))
+            .ok_or_else(|| format!("qemu-img info reported no size for {}", disk_path))?;
+        
+        // The config can lag behind the image, e.g. after a resize by hand
+        let requested = size_gb * 1024 * 1024 * 1024;
+        if requested <= current {
+            return Err(format!("{} is already {:.1} GB; disks can only grow, so give a --size above that",
+                               disk_path, current as f64 / (1024.0 * 1024.0 * 1024.0)).into());
+        }
+        
+        outln!("💽 Growing {} to {} GB...", disk_path, size_gb);
+        let output = libvirt::privileged("qemu-img")
+            .args(["resize", &disk_path, &format!("{}G", size_gb)])
+            .output()?;
+        if !output.status.success() {
+            return Err(format!("qemu-img resize failed for {}: {}", disk_path, 
+                               String::from_utf8_lossy(&output.stderr).trim()).into());
+        }
+        Ok(())
+    }
+    
+    /// Boot the VM if it's off and grow its root partition and filesystem into the
+    /// space `resize_disk` added, through the QEMU guest agent
+    pub fn grow_guest_filesystem(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let name = &self.config.name;
+        if domain_stats(name).is_none() {
+            outln!("▶️  Starting {} to grow its filesystem...", name);
+            self.run_virsh_checked(&["start", name])?;
+        }
+        
+        outln!("⏳ Waiting for the QEMU guest agent...");
+        let started = std::time::Instant::now();
+        while !qemu_agent_available(name) {
+            if started.elapsed() > AGENT_BOOT_TIMEOUT {
+                return Err(format!("{}'s QEMU guest agent didn't answer within {}s; grow the filesystem in the guest instead: \
+                                    sudo growpart /dev/vda <partition> && sudo xfs_growfs / (or resize2fs)", 
+                                   name, AGENT_BOOT_TIMEOUT.as_secs()).into());
+            }
+            thread::sleep(Duration::from_secs(2));
+        }
+        
+        outln!("📐 Growing the root partition and filesystem...");
+        guest_exec_with_timeout(name, "/bin/sh", &["-c".to_string(), GROW_ROOT_SCRIPT.to_string()], GROW_FS_TIMEOUT)?;
+        outln!("✅ {}'s root filesystem uses the whole disk", name);
+        Ok(())
+    }
+    
+    /// What `destroy_vm` would remove, without touching anything
+    pub fn destroy_plan(&self) -> DestroyPlan {
+        let virsh = |args: &[&str]| {
+            libvirt::virsh()
+                .args(args)
+                .output()
+                .ok()
+                .filter(|output| output.status.success())
+                .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+        };
+        
+        let dominfo = virsh(&["dominfo", &self.config.name]);
+        let mut disks = virsh(&["domblklist", "--details", &self.config.name])
+            .map(|list| parse_domblklist_disks(&list))
+            .unwrap_or_default();
+        
+        // Removed by hand even when libvirt doesn't know about it
+        let disk_path = format!("{}/{}.qcow2", self.config.vm_dir, self.config.name);
+        if Path::new(&disk_path).exists() && !disks.contains(&disk_path) {
+            disks.insert(0, disk_path);
+        }
+        
+        let managed_save = dominfo.as_deref()
+            .filter(|info| has_managed_save(info))
+            .map(|_| format!("{}/{}.save", libvirt::save_dir(), self.config.name));
+        let snapshots = virsh(&["snapshot-list", "--name", &self.config.name])
+            .map(|list| list.lines().map(str::trim).filter(|l| !l.is_empty()).map(String::from).collect())
+            .unwrap_or_default();
+        
+        DestroyPlan {
+            domain: dominfo.is_some(),
+            disks,
+            managed_save,
+            snapshots,
+        }
+    }
+    
+    /// Copy the disk, domain definition and config aside so a destroyed VM can be recovered
+    pub fn save_recovery_snapshot(&self) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        let stamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let snapshot_dir = recovery_dir(&self.config.name)?.join(stamp.to_string());
+        fs::create_dir_all(&snapshot_dir)?;
+        
+        outln!("💾 Saving recovery snapshot to {}", snapshot_dir.display());
+        
+        let xml = libvirt::virsh()
+            .args(["dumpxml", "--inactive", &self.config.name])
+            .output()?;
+        if xml.status.success() {
+            fs::write(snapshot_dir.join("domain.xml"), &xml.stdout)?;
+        } else {
+            outln!("   ⚠️  No libvirt definition found, saving disk and config only");
+        }
+        
+        let disk_path = format!("{}/{}.qcow2", self.config.vm_dir, self.config.name);
+        if Path::new(&disk_path).exists() {
+            // Stop the guest so the copy is consistent; it is about to be destroyed anyway
+            let _ = libvirt::virsh()
+                .args(["destroy", &self.config.name])
+                .output();
+            
+            outln!("   Copying disk image: {}", disk_path);
+            if let Err(e) = copy_disk(Path::new(&disk_path), &snapshot_dir.join("disk.qcow2")) {
+                let _ = fs::remove_dir_all(&snapshot_dir);
+                return Err(format!("Failed to snapshot disk, VM left untouched: {}", e).into());
+            }
+        } else {
+            outln!("   ⚠️  Disk image not found at: {}", disk_path);
+        }
+        
+        fs::write(snapshot_dir.join("config.toml"), toml::to_string_pretty(&self.config)?)?;
+        
+        prune_recovery_snapshots(&self.config.name, self.config.recovery_snapshots_to_keep)?;
+        
+        outln!("   ✅ Recovery snapshot saved");
+        Ok(snapshot_dir)
+    }
+    
+    /// Checkpoint the VM with an internal qcow2 snapshot (includes memory when running)
+    pub fn create_snapshot(&self, snapshot_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        outln!("📸 Creating snapshot {} of VM: {}", snapshot_name, self.config.name);
+        
+        let output = libvirt::virsh()
+            .args(["snapshot-create-as", &self.config.name, snapshot_name])
+            .output()?;
+        if !output.status.success() {
+            return Err(format!("virsh snapshot-create-as failed: {}", 
+                               String::from_utf8_lossy(&output.stderr).trim()).into());
+        }
+        
+        outln!("✅ Snapshot created");
+        Ok(())
+    }
+    
+    /// `virsh snapshot-list` for the VM, as printed by virsh
+    pub fn list_snapshots(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let output = libvirt::virsh()
+            .args(["snapshot-list", &self.config.name])
+            .output()?;
+        if !output.status.success() {
+            return Err(format!("virsh snapshot-list failed: {}", 
+                               String::from_utf8_lossy(&output.stderr).trim()).into());
+        }
+        
+        Ok(String::from_utf8_lossy(&output.stdout).trim_end().to_string())
+    }
+    
+    /// Roll the VM's disk (and memory, if it was captured) back to a snapshot
+    pub fn revert_snapshot(&self, snapshot_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        outln!("⏪ Reverting VM {} to snapshot {}", self.config.name, snapshot_name);
+        
+        let output = libvirt::virsh()
+            .args(["snapshot-revert", &self.config.name, snapshot_name])
+            .output()?;
+        if !output.status.success() {
+            return Err(format!("virsh snapshot-revert failed: {}", 
+                               String::from_utf8_lossy(&output.stderr).trim()).into());
+        }
+        
+        Ok(())
+    }
+    
+    /// Copy this VM into a new domain named `target`, returning the clone's config.
+    ///
+    /// Offline clones go through virt-clone and need the source shut off. Live clones
+    /// redirect the source's writes to a temporary external snapshot, copy the now
+    /// quiescent base image, then merge the overlay back; the copy is crash-consistent
+    /// unless `quiesce` freezes the guest filesystems through qemu-guest-agent.
+    pub fn clone_vm(&self, target: &str, live: bool, quiesce: bool) -> Result<AppVMConfig, Box<dyn std::error::Error>> {
+        let source = &self.config.name;
+        let source_disk = PathBuf::from(format!("{}/{}.qcow2", self.config.vm_dir, source));
+        let target_disk = PathBuf::from(format!("{}/{}.qcow2", self.config.vm_dir, target));
+        if target_disk.exists() {
+            return Err(format!("Disk image {} already exists, refusing to overwrite it", target_disk.display()).into());
+        }
+        
+        let running = list_running_domains()?.contains(source);
+        if running && !live {
+            return Err(format!("VM '{0}' is running. Stop it first or clone it live with: vm-provisioner clone {0} {1} --live", 
+                               source, target).into());
+        }
+        
+        outln!("🐑 Cloning VM {} to {}", source, target);
+        
+        if running {
+            let xml = libvirt::virsh()
+                .args(["dumpxml", "--inactive", source])
+                .output()?;
+            if !xml.status.success() {
+                return Err(format!("virsh dumpxml failed: {}", 
+                                   String::from_utf8_lossy(&xml.stderr).trim()).into());
+            }
+            let xml_path = std::env::temp_dir().join(format!("vm-provisioner-clone-{}.xml", target));
+            fs::write(&xml_path, &xml.stdout)?;
+            
+            let copied = self.copy_live_disk(&source_disk, &target_disk, quiesce);
+            let defined = copied.and_then(|_| {
+                outln!("   Defining {} in libvirt...", target);
+                run_virt_clone(&[
+                    "--original-xml", &xml_path.to_string_lossy(),
+                    "--name", target,
+                    "--file", &target_disk.to_string_lossy(),
+                    "--preserve-data",
+                ])
+            });
+            let _ = fs::remove_file(&xml_path);
+            if let Err(e) = defined {
+                let _ = fs::remove_file(&target_disk);
+                return Err(e);
+            }
+        } else {
+            outln!("   Copying disk image with virt-clone...");
+            run_virt_clone(&[
+                "--original", source,
+                "--name", target,
+                "--file", &target_disk.to_string_lossy(),
+            ])?;
+        }
+        
+        // The guest keeps the source's hostname and user, so the clone's config does too
+        let mut config = self.config.clone();
+        config.hostname = Some(self.config.guest_hostname().to_string());
+        config.name = target.to_string();
+        // virt-clone gave the copy a new MAC address; a fixed one belongs to the source
+        config.mac_address = None;
+        
+        outln!("   ✅ Clone created");
+        Ok(config)
+    }
+    
+    /// Copy the disk of a running VM by freezing it behind a temporary external snapshot
+    fn copy_live_disk(&self, source_disk: &Path, target_disk: &Path, quiesce: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let overlay = source_disk.with_extension("clone-overlay.qcow2");
+        
+        outln!("   Redirecting writes to a temporary snapshot{}...", if quiesce { " (quiesced)" } else { "" });
+        let output = libvirt::virsh()
+            .args(live_snapshot_args(&self.config.name, &overlay, quiesce))
+            .output()?;
+        if !output.status.success() {
+            return Err(format!("virsh snapshot-create-as failed: {}", 
+                               String::from_utf8_lossy(&output.stderr).trim()).into());
+        }
+        
+        // Merge back even when the copy fails, the source must not be left on the overlay
+        outln!("   Copying disk image: {}", source_disk.display());
+        let copied = copy_disk(source_disk, target_disk);
+        
+        outln!("   Merging snapshot back into {}...", source_disk.display());
+        let output = libvirt::virsh()
+            .args(["blockcommit", &self.config.name, "vda", "--active", "--pivot", "--wait"])
+            .output()?;
+        if output.status.success() {
+            if fs::remove_file(&overlay).is_err() {
+                let _ = libvirt::privileged("rm").arg("-f").arg(&overlay).status();
+            }
+        } else {
+            eoutln!("   ⚠️  blockcommit failed, {} is still running on {}: {}", self.config.name, overlay.display(),
+                    String::from_utf8_lossy(&output.stderr).trim());
+            eoutln!("   Merge it manually with: virsh blockcommit {} vda --active --pivot --wait", self.config.name);
+        }
+        
+        if let Err(e) = copied {
+            let _ = fs::remove_file(target_disk);
+            return Err(e);
+        }
+        Ok(())
+    }
+    
+    /// Write `config_text` and this VM's disk into a tar bundle at `out`, for `import`.
+    /// The disk is read with sudo when needed, so the VM should be shut off.
+    pub fn export_bundle(&self, config_text: &str, out: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let disk_name = format!("{}.qcow2", self.config.name);
+        let disk_path = Path::new(&self.config.vm_dir).join(&disk_name);
+        if !disk_path.exists() {
+            return Err(format!("Disk image {} not found", disk_path.display()).into());
+        }
+        
+        let staging = std::env::temp_dir().join(format!("vm-provisioner-export-{}", std::process::id()));
+        fs::create_dir_all(&staging)?;
+        let written = fs::write(staging.join(BUNDLE_CONFIG), config_text).map_err(Into::into).and_then(|_| {
+            outln!("📦 Bundling {} ({} GB virtual) into {}...", disk_path.display(), self.config.disk_size_gb, out.display());
+            // Written through stdout so the bundle belongs to the user even when tar runs with sudo
+            let output = libvirt::privileged("tar")
+                .args(["--sparse", "-cf", "-", "-C"])
+                .arg(&staging)
+                .arg(BUNDLE_CONFIG)
+                .arg("-C")
+                .arg(&self.config.vm_dir)
+                .arg(format!("--transform=s|^{}$|{}|", regex::escape(&disk_name), BUNDLE_DISK))
+                .arg(&disk_name)
+                .stdout(fs::File::create(out)?)
+                .stderr(Stdio::piped())
+                .output()?;
+            if !output.status.success() {
+                return Err(format!("tar failed: {}", String::from_utf8_lossy(&output.stderr).trim()).into());
+            }
+            Ok(())
+        });
+        let _ = fs::remove_dir_all(&staging);
+        if written.is_err() {
+            let _ = fs::remove_file(out);
+        }
+        written
+    }
+    
+    /// Unpack the disk of an `export --with-disk` bundle as this VM's disk and define
+    /// the VM around it, with the current config's hardware
+    pub fn import_disk(&self, bundle: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let disk_path = format!("{}/{}.qcow2", self.config.vm_dir, self.config.name);
+        if Path::new(&disk_path).exists() {
+            return Err(format!("Disk image {} already exists, refusing to overwrite it", disk_path).into());
+        }
+        self.check_vm_dir()?;
+        
+        outln!("📦 Unpacking disk image to {}...", disk_path);
+        let output = libvirt::privileged("tar")
+            .arg("-xf")
+            .arg(bundle)
+            .arg("-C")
+            .arg(&self.config.vm_dir)
+            .arg(format!("--transform=s|^{}$|{}.qcow2|", BUNDLE_DISK, self.config.name))
+            .arg(BUNDLE_DISK)
+            .output()?;
+        if !output.status.success() {
+            let _ = libvirt::privileged("rm").args(["-f", &disk_path]).status();
+            return Err(format!("tar failed: {}", String::from_utf8_lossy(&output.stderr).trim()).into());
+        }
+        
+        if let Err(e) = self.define_installed(&disk_path) {
+            let _ = libvirt::privileged("rm").args(["-f", &disk_path]).status();
+            return Err(e);
+        }
+        Ok(())
+    }
+    
+    /// Define (without starting) a domain for an already installed `disk_path`
+    fn define_installed(&self, disk_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        outln!("   Defining {} in libvirt...", self.config.name);
+        let (args, _) = self.virt_install_args(&InstallMedia::Installed, disk_path)?;
+        let output = libvirt::privileged_tool("virt-install")
+            .args(&args)
+            .arg("--print-xml")
+            .output()?;
+        if !output.status.success() {
+            return Err(format!("virt-install failed: {}", String::from_utf8_lossy(&output.stderr).trim()).into());
+        }
+        
+        let xml_path = std::env::temp_dir().join(format!("vm-provisioner-import-{}.xml", self.config.name));
+        fs::write(&xml_path, &output.stdout)?;
+        let defined = self.run_virsh_checked(&["define", &xml_path.to_string_lossy()]);
+        let _ = fs::remove_file(&xml_path);
+        defined?;
+        self.apply_spice_password()
+    }
+    
+    /// i3 config lines auto-starting `auto_launch_apps`, appended to the config
+    fn render_i3_autostart(&self) -> String {
+        // Add auto-start commands for each application
+        let mut result = String::new();
+        for app_command in &self.config.auto_launch_apps {
+            result.push_str(&format!("\necho \"exec --no-startup-id {}\" >> /home/user/.config/i3/config", app_command));
+        }
+        
+        // Sway reads the same configuration syntax, auto-start entries included
+        if self.config.session_type == SessionType::Wayland {
+            result.push_str("\n\n# Sway uses the i3 config\nmkdir -p /home/user/.config/sway\ncp /home/user/.config/i3/config /home/user/.config/sway/config");
+        }
+        result
+    }
+    
+    fn get_autologin_config(&self) -> String {
+        if self.config.enable_auto_login {
+            let mut result = r#"
+# Configure auto-login with i3 via systemd
+# Create auto-login service that starts X11 with i3
+cat > /etc/systemd/system/autologin@.service << 'EOF'
+[Unit]
+Description=Auto Login for %i
+After=systemd-user-sessions.service plymouth-quit-wait.service
+After=plymouth-quit.service gdm.service
+Before=getty@%i.service
+
+[Service]
+ExecStart=-/sbin/agetty -o '-p -f user' --noclear --autologin user %i $TERM
+Type=idle
+Restart=always
+RestartSec=0
+UtmpIdentifier=%I
+TTYPath=/dev/%i
+TTYReset=yes
+TTYVHangup=yes
+TTYVTDisallocate=yes
+KillMode=process
+IgnoreSIGPIPE=no
+SendSIGHUP=yes
+
+[Install]
+WantedBy=getty.target
+EOF
+"#.to_string();
+            
+            let tty = self.config.autologin_tty;
+            result.push_str(&format!("\n# Enable auto-login on tty{}\nsystemctl enable autologin@tty{}.service\n", tty, tty));
+            result.push_str(r#"
+# Enable spice-vdagentd socket for auto-resize (starts daemon on demand)
+systemctl enable spice-vdagentd.socket
+
+"#);
+            
+            if self.config.session_type == SessionType::X11 {
+                result.push_str(r#"# Create .xinitrc for user to start i3
+cat > /home/user/.xinitrc << 'EOF'
+#!/bin/bash
+
+# Comprehensive logging for debugging
+exec > /tmp/xinitrc.log 2>&1
+echo "=== .xinitrc started at $(date) ==="
+set -x
+
+# Set up X11 environment
+export DISPLAY=:0
+export XDG_RUNTIME_DIR="/run/user/$(id -u)"
+
+# Add Flatpak paths to XDG_DATA_DIRS for dmenu integration
+export XDG_DATA_DIRS="/usr/local/share:/usr/share:/var/lib/flatpak/exports/share:$HOME/.local/share/flatpak/exports/share:$XDG_DATA_DIRS"
+
+echo "Environment: DISPLAY=$DISPLAY, XDG_RUNTIME_DIR=$XDG_RUNTIME_DIR"
+echo "XDG_DATA_DIRS: $XDG_DATA_DIRS"
+
+# Wait for X11 to be ready with timeout
+echo "Waiting for X11 to be ready..."
+timeout=30
+count=0
+while ! DISPLAY=:0 xset q &>/dev/null; do
+    if [ $count -ge $timeout ]; then
+        echo "X11 timeout after ${timeout}s, proceeding anyway..."
+        break
+    fi
+    echo "X11 not ready, waiting... ($count/$timeout)"
+    sleep 1
+    count=$((count + 1))
+done
+echo "X11 check completed (timeout: $count/$timeout)"
+
+# Ensure X11 authority is properly set
+echo "Setting X11 authority..."
+xauth generate :0 . trusted
+echo "X11 authority set"
+
+# Load X resources (Xft.dpi when a display scale is configured)
+[ -f "$HOME/.Xresources" ] && xrdb -merge "$HOME/.Xresources"
+
+# Start SPICE agent user session (system daemon should already be running)
+if command -v spice-vdagent >/dev/null 2>&1; then
+    echo "Starting spice-vdagent..."
+    DISPLAY=:0 XDG_RUNTIME_DIR="/run/user/$(id -u)" spice-vdagent &
+    sleep 1
+    echo "spice-vdagent started"
+else
+    echo "spice-vdagent not found!"
+fi
 
 # Check i3 before starting
 echo "Checking i3 installation..."
 which i3
 i3 --version
 
-# Start i3 window manager
-echo "About to exec i3..."
-exec i3
-EOF
-chmod +x /home/user/.xinitrc
-chown user:user /home/user/.xinitrc
+# Start i3 window manager
+echo "About to exec i3..."
+exec i3
+EOF
+chmod +x /home/user/.xinitrc
+chown user:user /home/user/.xinitrc
+
+"#);
+            }
+            
+            // Start the session from the autologin TTY's login shell
+            let start_session = match self.config.session_type {
+                SessionType::X11 => format!("exec startx -- vt{}", tty),
+                SessionType::Wayland => "export XDG_SESSION_TYPE=wayland\n        exec sway".to_string(),
+            };
+            result.push_str(&format!(r#"# Auto-start the session when user logs into tty{tty}
+cat > /home/user/.bash_profile << 'EOF'
+# Debug autologin
+echo "bash_profile executed at $(date)" >> /tmp/autologin.log
+echo "Current tty: $(tty)" >> /tmp/autologin.log
+echo "DISPLAY: $DISPLAY" >> /tmp/autologin.log
+echo "XDG_VTNR: $XDG_VTNR" >> /tmp/autologin.log
+
+# Auto-start the session on tty{tty} login
+if [[ -z $DISPLAY && -z $WAYLAND_DISPLAY ]]; then
+    # Check if we're on tty{tty} (multiple ways to detect)
+    if [[ $(tty) == "/dev/tty{tty}" ]] || [[ "$XDG_VTNR" -eq {tty} ]] || [[ $(fgconsole 2>/dev/null) -eq {tty} ]]; then
+        echo "Starting {session} session on tty{tty}..." | tee -a /tmp/autologin.log
+        {start_session}
+    else
+        echo "Not on tty{tty}, not starting a session" >> /tmp/autologin.log
+    fi
+else
+    echo "Session already running, not starting another" >> /tmp/autologin.log
+fi
+EOF
+chown user:user /home/user/.bash_profile
+
+"#, session = self.config.session_type.as_env()));
+            
+            if self.config.session_type == SessionType::X11 {
+                result.push_str(r#"# Create systemd user service as fallback for X11 startup
+mkdir -p /home/user/.config/systemd/user
+cat > /home/user/.config/systemd/user/startx.service << 'EOF'
+[Unit]
+Description=Start X11 session
+After=graphical-session-pre.target
+
+[Service]
+Type=oneshot
+ExecStart=/usr/bin/startx
+Environment=DISPLAY=:0
+Restart=no
+
+[Install]
+WantedBy=default.target
+EOF
+
+"#);
+            }
+            
+            result.push_str(r#"# Create user cache directory and fix permissions
+mkdir -p /home/user/.cache
+mkdir -p /home/user/.local/share
+mkdir -p /home/user/.local/bin
+
+# Fix ownership of all user directories
+chown -R user:user /home/user/.config
+chown -R user:user /home/user/.cache
+chown -R user:user /home/user/.local
+chown -R user:user /home/user/.*
+
+"#);
+            
+            if self.config.session_type == SessionType::X11 {
+                result.push_str(r#"# Enable the user service (will be activated when user session starts)
+sudo -u user systemctl --user enable startx.service
+
+"#);
+            }
+            
+            result.push_str(r#"# Create default i3 config
+mkdir -p /home/user/.config/i3
+cat > /home/user/.config/i3/config << 'EOF'
+# i3 config file
+set $mod Mod4
+
+# Font for window titles
+font pango:DejaVu Sans Mono 8
+
+# Use Mouse+$mod to drag floating windows
+floating_modifier $mod
+
+# Start a terminal
+bindsym $mod+Return exec kitty
+
+# Kill focused window
+bindsym $mod+Shift+q kill
+
+# Start rofi (app launcher) - better Flatpak support than dmenu
+bindsym $mod+d exec rofi -show drun -p "Applications"
+
+# Alternative: traditional dmenu 
+bindsym $mod+Shift+d exec dmenu_run -p "Run:" -fn "DejaVu Sans Mono-10"
+
+# Change focus
+bindsym $mod+j focus left
+bindsym $mod+k focus down
+bindsym $mod+l focus up
+bindsym $mod+semicolon focus right
+bindsym $mod+Left focus left
+bindsym $mod+Down focus down
+bindsym $mod+Up focus up
+bindsym $mod+Right focus right
+
+# Move focused window
+bindsym $mod+Shift+j move left
+bindsym $mod+Shift+k move down
+bindsym $mod+Shift+l move up
+bindsym $mod+Shift+semicolon move right
+bindsym $mod+Shift+Left move left
+bindsym $mod+Shift+Down move down
+bindsym $mod+Shift+Up move up
+bindsym $mod+Shift+Right move right
+
+# Workspaces
+bindsym $mod+1 workspace 1
+bindsym $mod+2 workspace 2
+bindsym $mod+3 workspace 3
+bindsym $mod+4 workspace 4
+bindsym $mod+5 workspace 5
+
+# Move container to workspace
+bindsym $mod+Shift+1 move container to workspace 1
+bindsym $mod+Shift+2 move container to workspace 2
+bindsym $mod+Shift+3 move container to workspace 3
+bindsym $mod+Shift+4 move container to workspace 4
+bindsym $mod+Shift+5 move container to workspace 5
+
+# Restart i3
+bindsym $mod+Shift+r restart
+
+# Exit i3
+bindsym $mod+Shift+e exec "i3-nagbar -t warning -m 'Exit i3?' -b 'Yes' 'i3-msg exit'"
+
+# Status bar
+bar {
+    status_command i3status
+}
+
+# Auto-start applications
+EOF
+
+# Add auto-start commands for installed applications"#);
+
+            result.push_str(&self.render_i3_autostart());
+            
+            result.push_str(r#"
+
+# Final comprehensive ownership fix for all user directories
+chown -R user:user /home/user/.config
+chown -R user:user /home/user/.cache
+chown -R user:user /home/user/.local
+chown -R user:user /home/user/.xinitrc
+chown -R user:user /home/user/.bash_profile
+
+# Ensure proper permissions for user directories
+chmod 755 /home/user/.config
+chmod 755 /home/user/.cache
+chmod 755 /home/user/.local
+
+# Install build dependencies for spice-autorandr (must be done in post-install)
+echo "Installing build dependencies for spice-autorandr..."
+dnf install -y gcc make autoconf automake libtool libXrandr-devel libX11-devel systemd-devel pkgconfig xorg-x11-proto-devel xorg-x11-util-macros
+
+# Install and configure spice-autorandr for automatic resolution adjustment
+echo "Building spice-autorandr..."
+cd /tmp
+if git clone https://github.com/seife/spice-autorandr.git; then
+    cd spice-autorandr
+    if autoreconf -is; then
+        if ./configure; then
+            if make; then
+                cp spice-autorandr /usr/local/bin/
+                chmod +x /usr/local/bin/spice-autorandr
+                echo "spice-autorandr installed successfully"
+            else
+                echo "ERROR: spice-autorandr make failed"
+            fi
+        else
+            echo "ERROR: spice-autorandr configure failed"
+        fi
+    else
+        echo "ERROR: spice-autorandr autoreconf failed"
+    fi
+else
+    echo "ERROR: spice-autorandr git clone failed"
+fi
+
+# Create systemd service for spice-autorandr
+cat > /etc/systemd/system/spice-autorandr.service << 'EOF'
+[Unit]
+Description=SPICE Auto Resolution Adjustment
+After=multi-user.target
+Wants=multi-user.target
+
+[Service]
+Type=simple
+ExecStart=/usr/local/bin/spice-autorandr
+Restart=always
+RestartSec=5
+Environment=DISPLAY=:0
+Environment=XDG_RUNTIME_DIR=/run/user/1000
+User=user
+Group=user
+StandardOutput=journal
+StandardError=journal
+
+[Install]
+WantedBy=multi-user.target
+EOF
+
+# Enable the spice-autorandr service
+systemctl enable spice-autorandr.service"#);
+
+            result
+        } else {
+            "".to_string()
+        }
+    }
+}
+
+/// SHA-256 of a file as a lowercase hex string
+pub fn sha256_file(path: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let output = Command::new("sha256sum").arg(path).output()?;
+    if !output.status.success() {
+        return Err(format!("sha256sum failed for {}", path).into());
+    }
+    
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()
+        .map(|s| s.to_string())
+        .ok_or_else(|| "Unexpected sha256sum output".into())
+}
+
+/// SHA-256 for `file_name` from a Fedora CHECKSUM file (`SHA256 (<file>) = <hash>` lines,
+/// wrapped in a PGP signature)
+fn parse_checksum_file(content: &str, file_name: &str) -> Option<String> {
+    let prefix = format!("SHA256 ({}) = ", file_name);
+    content.lines()
+        .find_map(|line| line.trim().strip_prefix(&prefix))
+        .map(|hash| hash.trim().to_string())
+        .filter(|hash| hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Remove a file, retrying with sudo for root-owned libvirt storage
+fn remove_file_with_sudo(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if fs::remove_file(path).is_ok() {
+        return Ok(());
+    }
+    
+    let output = libvirt::privileged("rm")
+        .arg("-f")
+        .arg(path)
+        .output()?;
+    if !output.status.success() {
+        return Err(format!("Failed to remove {}: {}", path.display(), 
+                           String::from_utf8_lossy(&output.stderr).trim()).into());
+    }
+    Ok(())
+}
+
+/// Whether the invoking user can create files in `path`
+fn is_writable_dir(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    
+    let Ok(path_c) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    path.is_dir() && unsafe { libc::access(path_c.as_ptr(), libc::W_OK | libc::X_OK) } == 0
+}
+
+/// The most useful line of virt-install's stderr to show the user
+fn failure_reason(stderr: &str) -> String {
+    let lines: Vec<&str> = stderr.lines().map(str::trim).filter(|l| !l.is_empty()).collect();
+    lines.iter()
+        .rev()
+        .find(|l| l.starts_with("ERROR"))
+        .or(lines.last())
+        .map(|l| l.to_string())
+        .unwrap_or_else(|| "no output from virt-install".to_string())
+}
+
+/// Send each line read from `pipe` to the install loop from a background thread.
+/// virt-install's streams report `Closed` at the end, so the loop knows when it's done.
+fn forward_install_output<R: Read + Send + 'static>(
+    pipe: R,
+    tx: mpsc::Sender<InstallOutput>,
+    source: InstallSource,
+) {
+    thread::spawn(move || {
+        // Serial console output is CRLF-terminated and not necessarily UTF-8
+        for line in BufReader::new(pipe).split(b'\n').map_while(Result::ok) {
+            let line = String::from_utf8_lossy(&line).trim_end_matches('\r').to_string();
+            if tx.send(InstallOutput::Line(source, line)).is_err() {
+                return;
+            }
+        }
+        if source == InstallSource::VirtInstall {
+            let _ = tx.send(InstallOutput::Closed);
+        }
+    });
+}
+
+/// The installer console lines worth showing: Anaconda's install steps, and the package
+/// count every hundred packages rather than each package
+fn install_progress(line: &str) -> Option<String> {
+    const STEPS: &[&str] = &[
+        "Starting package installation",
+        "Downloading packages",
+        "Preparing transaction",
+        "Configuring ",
+        "Performing post-installation setup tasks",
+        "Running post-installation scripts",
+        "Installing boot loader",
+        "Creating users",
+        "Generating initramfs",
+        "Storing configuration files and kickstarts",
+        "Installation complete",
+    ];
+    
+    let line = line.trim_matches(|c: char| c.is_whitespace() || c.is_control());
+    if let Some(package) = line.strip_prefix("Installing ") {
+        let counts = package.rsplit_once('(')?.1.strip_suffix(')')?;
+        let (done, total) = counts.split_once('/')?;
+        let (done, total): (u32, u32) = (done.parse().ok()?, total.parse().ok()?);
+        return (done == 1 || done % 100 == 0 || done == total)
+            .then(|| format!("📦 Installing packages ({}/{})", done, total));
+    }
+    STEPS.iter()
+        .any(|step| line.starts_with(step))
+        .then(|| line.to_string())
+}
 
-# Auto-start X11 when user logs into tty1
-cat > /home/user/.bash_profile << 'EOF'
-# Debug autologin
-echo "bash_profile executed at $(date)" >> /tmp/autologin.log
-echo "Current tty: $(tty)" >> /tmp/autologin.log
-echo "DISPLAY: $DISPLAY" >> /tmp/autologin.log
-echo "XDG_VTNR: $XDG_VTNR" >> /tmp/autologin.log
+/// `text` escaped for an XML attribute value
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('\'', "&apos;")
+        .replace('"', "&quot;")
+}
 
-# Auto-start X11 on tty1 login
-if [[ -z $DISPLAY ]]; then
-    # Check if we're on tty1 (multiple ways to detect)
-    if [[ $(tty) == "/dev/tty1" ]] || [[ "$XDG_VTNR" -eq 1 ]] || [[ $(fgconsole 2>/dev/null) -eq 1 ]]; then
-        echo "Starting X11 on tty1..." | tee -a /tmp/autologin.log
-        exec startx -- vt1
-    else
-        echo "Not on tty1, not starting X11" >> /tmp/autologin.log
-    fi
-else
-    echo "DISPLAY already set, not starting X11" >> /tmp/autologin.log
-fi
-EOF
-chown user:user /home/user/.bash_profile
+/// Last installer output for an `InstallError`, indented under the reason
+fn format_install_tail(tail: &[String]) -> String {
+    if tail.is_empty() {
+        return String::new();
+    }
+    let mut text = String::from("\nLast installer output:");
+    for line in tail {
+        text.push_str("\n    ");
+        text.push_str(line);
+    }
+    text
+}
 
-# Create systemd user service as fallback for X11 startup
-mkdir -p /home/user/.config/systemd/user
-cat > /home/user/.config/systemd/user/startx.service << 'EOF'
-[Unit]
-Description=Start X11 session
-After=graphical-session-pre.target
+/// Whether virt-install's stderr or an installer console line looks like the mirror or
+/// network dropped out
+fn is_network_failure(stderr: &str) -> bool {
+    const PATTERNS: &[&str] = &[
+        "could not resolve",
+        "temporary failure in name resolution",
+        "connection timed out",
+        "connection refused",
+        "connection reset",
+        "network is unreachable",
+        "failed to connect",
+        "couldn't fetch",
+        "error retrieving",
+        "unable to retrieve",
+        "curl error",
+        "http error",
+        "error downloading packages",
+        "failed to download metadata",
+        "error setting up base repository",
+    ];
+    
+    let stderr = stderr.to_lowercase();
+    PATTERNS.iter().any(|p| stderr.contains(p))
+}
 
-[Service]
-Type=oneshot
-ExecStart=/usr/bin/startx
-Environment=DISPLAY=:0
-Restart=no
+/// Directory holding recovery snapshots for a VM, one timestamped subdirectory each
+pub fn recovery_dir(vm_name: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let data_dir = match std::env::var("XDG_DATA_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var("HOME")?).join(".local/share"),
+    };
+    Ok(data_dir.join("vm-provisioner/recovery").join(vm_name))
+}
+
+/// Timestamps of the recovery snapshots saved for a VM, oldest first
+fn recovery_snapshots(vm_name: &str) -> Result<Vec<u64>, Box<dyn std::error::Error>> {
+    let dir = recovery_dir(vm_name)?;
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    
+    let mut stamps: Vec<u64> = fs::read_dir(&dir)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+        .collect();
+    stamps.sort_unstable();
+    Ok(stamps)
+}
+
+/// Snapshots that fall outside the newest `keep`; the newest is always kept
+fn snapshots_to_prune(stamps: &[u64], keep: usize) -> &[u64] {
+    let keep = keep.max(1);
+    &stamps[..stamps.len().saturating_sub(keep)]
+}
+
+fn prune_recovery_snapshots(vm_name: &str, keep: usize) -> Result<(), Box<dyn std::error::Error>> {
+    let dir = recovery_dir(vm_name)?;
+    for stamp in snapshots_to_prune(&recovery_snapshots(vm_name)?, keep) {
+        let old = dir.join(stamp.to_string());
+        outln!("   Removing old recovery snapshot: {}", old.display());
+        fs::remove_dir_all(&old)?;
+    }
+    Ok(())
+}
+
+/// Restore the most recent recovery snapshot of a VM, returning its saved configuration
+pub fn restore_recovery_snapshot(vm_name: &str) -> Result<AppVMConfig, Box<dyn std::error::Error>> {
+    let stamp = recovery_snapshots(vm_name)?
+        .last()
+        .copied()
+        .ok_or_else(|| format!("No recovery snapshots found for {} in {}", 
+                               vm_name, recovery_dir(vm_name).map(|d| d.display().to_string()).unwrap_or_default()))?;
+    let snapshot_dir = recovery_dir(vm_name)?.join(stamp.to_string());
+    
+    outln!("📦 Restoring recovery snapshot: {}", snapshot_dir.display());
+    
+    let config = AppVMConfig::from_toml(&fs::read_to_string(snapshot_dir.join("config.toml"))?)?;
+    
+    let snapshot_disk = snapshot_dir.join("disk.qcow2");
+    if snapshot_disk.exists() {
+        let disk_path = PathBuf::from(format!("{}/{}.qcow2", config.vm_dir, config.name));
+        if disk_path.exists() {
+            return Err(format!("Disk image {} already exists, refusing to overwrite it", disk_path.display()).into());
+        }
+        
+        outln!("   Copying disk image back to: {}", disk_path.display());
+        copy_disk(&snapshot_disk, &disk_path)?;
+    } else {
+        outln!("   ⚠️  Snapshot has no disk image, restoring definition only");
+    }
+    
+    let domain_xml = snapshot_dir.join("domain.xml");
+    if domain_xml.exists() {
+        outln!("   Redefining VM in libvirt...");
+        let output = libvirt::virsh()
+            .arg("define")
+            .arg(&domain_xml)
+            .output()?;
+        if !output.status.success() {
+            return Err(format!("virsh define failed: {}", 
+                               String::from_utf8_lossy(&output.stderr).trim()).into());
+        }
+    } else {
+        outln!("   ⚠️  Snapshot has no libvirt definition; define the VM manually from the restored disk");
+    }
+    
+    Ok(config)
+}
+
+/// Member names inside an `export --with-disk` bundle
+const BUNDLE_CONFIG: &str = "vm.toml";
+const BUNDLE_DISK: &str = "disk.qcow2";
+
+/// Whether `path` is an `export --with-disk` bundle rather than a bare config
+pub fn is_bundle(path: &Path) -> std::io::Result<bool> {
+    // tar headers carry "ustar" at offset 257
+    let mut header = [0u8; 262];
+    let mut file = fs::File::open(path)?;
+    let mut read = 0;
+    while read < header.len() {
+        match file.read(&mut header[read..])? {
+            0 => return Ok(false),
+            n => read += n,
+        }
+    }
+    Ok(&header[257..262] == b"ustar")
+}
+
+/// The config stored in an `export --with-disk` bundle
+pub fn bundle_config(bundle: &Path) -> Result<String, Box<dyn std::error::Error>> {
+    let output = Command::new("tar").arg("-xOf").arg(bundle).arg(BUNDLE_CONFIG).output()?;
+    if !output.status.success() {
+        return Err(format!("{} has no {}: {}", bundle.display(), BUNDLE_CONFIG, 
+                           String::from_utf8_lossy(&output.stderr).trim()).into());
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Sparse copy of a disk image, retrying with sudo for root-owned libvirt storage
+fn copy_disk(from: &Path, to: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let status = Command::new("cp")
+        .arg("--sparse=always")
+        .arg(from)
+        .arg(to)
+        .stderr(std::process::Stdio::null())
+        .status()?;
+    if status.success() {
+        return Ok(());
+    }
+    
+    outln!("   Permission denied, trying with sudo...");
+    let output = libvirt::privileged("cp")
+        .arg("--sparse=always")
+        .arg(from)
+        .arg(to)
+        .output()?;
+    if !output.status.success() {
+        return Err(format!("Failed to copy {} to {}: {}", from.display(), to.display(),
+                           String::from_utf8_lossy(&output.stderr).trim()).into());
+    }
+    Ok(())
+}
+
+/// `virsh snapshot-create-as` arguments for a metadata-free, disk-only snapshot that
+/// moves the domain's writes onto `overlay`
+fn live_snapshot_args(vm_name: &str, overlay: &Path, quiesce: bool) -> Vec<String> {
+    let mut args = vec![
+        "snapshot-create-as".to_string(),
+        vm_name.to_string(),
+        format!("{}-clone", vm_name),
+        "--disk-only".to_string(),
+        "--atomic".to_string(),
+        "--no-metadata".to_string(),
+        "--diskspec".to_string(),
+        format!("vda,snapshot=external,file={}", overlay.display()),
+    ];
+    if quiesce {
+        args.push("--quiesce".to_string());
+    }
+    args
+}
+
+fn run_virt_clone(args: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+    let output = libvirt::tool("virt-clone")
+        .args(args)
+        .output()?;
+    if !output.status.success() {
+        return Err(format!("virt-clone failed: {}", 
+                           String::from_utf8_lossy(&output.stderr).trim()).into());
+    }
+    Ok(())
+}
+
+/// Resource usage of a running or paused domain, from `virsh domstats`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DomainStats {
+    pub paused: bool,
+    /// CPU time used by the domain so far, in nanoseconds
+    pub cpu_time: u64,
+    pub vcpus: u32,
+    /// vCPUs the domain was booted with room for; live changes can't go above it
+    pub vcpus_max: u32,
+    /// Memory in use: what the guest reports through the balloon driver, else the
+    /// QEMU process's resident size on the host
+    pub memory_used_kib: Option<u64>,
+    pub memory_max_kib: u64,
+}
+
+impl DomainStats {
+    /// Parse `virsh domstats --state --cpu-total --balloon --vcpu` for one domain;
+    /// `None` unless it's running or paused
+    fn parse(output: &str) -> Option<Self> {
+        let stats: std::collections::HashMap<&str, u64> = output.lines()
+            .filter_map(|line| line.trim().split_once('='))
+            .filter_map(|(key, value)| Some((key, value.parse().ok()?)))
+            .collect();
+        
+        // virDomainState: 1 running, 3 paused
+        let paused = match stats.get("state.state")? {
+            1 => false,
+            3 => true,
+            _ => return None,
+        };
+        let guest_used = stats.get("balloon.available")
+            .zip(stats.get("balloon.unused"))
+            .map(|(available, unused)| available.saturating_sub(*unused));
+        
+        Some(DomainStats {
+            paused,
+            cpu_time: *stats.get("cpu.time")?,
+            vcpus: stats.get("vcpu.current").map_or(1, |n| *n as u32),
+            vcpus_max: stats.get("vcpu.maximum").or(stats.get("vcpu.current")).map_or(1, |n| *n as u32),
+            memory_used_kib: guest_used.or_else(|| stats.get("balloon.rss").copied()),
+            memory_max_kib: *stats.get("balloon.maximum")?,
+        })
+    }
+}
+
+/// `DomainStats` of a running or paused domain, `None` when it's shut off or unknown
+pub fn domain_stats(vm_name: &str) -> Option<DomainStats> {
+    let output = libvirt::virsh()
+        .args(["domstats", "--state", "--cpu-total", "--balloon", "--vcpu", vm_name])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    DomainStats::parse(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Share of the domain's vCPUs used between two `cpu.time` samples, in percent
+pub fn cpu_percent(previous_cpu_time: u64, cpu_time: u64, elapsed: Duration, vcpus: u32) -> f64 {
+    let capacity = elapsed.as_nanos() as f64 * vcpus.max(1) as f64;
+    if capacity == 0.0 {
+        return 0.0;
+    }
+    cpu_time.saturating_sub(previous_cpu_time) as f64 / capacity * 100.0
+}
+
+/// How long `start` waits for a new domain's display to accept connections
+const DISPLAY_WAIT: Duration = Duration::from_secs(30);
+
+/// Display URI of a running domain (`virsh domdisplay`), `None` for headless domains
+pub fn domain_display(vm_name: &str) -> Option<String> {
+    let output = libvirt::virsh()
+        .args(["domdisplay", vm_name])
+        .output()
+        .ok()?;
+    
+    let display = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.success() && !display.is_empty()).then_some(display)
+}
+
+/// Display URI of a running domain once its display accepts connections, `None`
+/// if that doesn't happen within `DISPLAY_WAIT`
+fn wait_for_display(vm_name: &str) -> Option<String> {
+    let deadline = std::time::Instant::now() + DISPLAY_WAIT;
+    loop {
+        if let Some(display) = domain_display(vm_name).filter(|display| display_listening(display)) {
+            return Some(display);
+        }
+        if std::time::Instant::now() >= deadline {
+            return None;
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+}
+
+/// Display URI of a running domain: the one recorded by `start` while it still
+/// answers, else asked from libvirt again (the VM was started some other way)
+pub fn current_display(vm_name: &str) -> Option<String> {
+    let recorded = VMState::load(vm_name).ok().and_then(|state| state.display);
+    if let Some(display) = recorded.filter(|display| display_listening(display)) {
+        return Some(display);
+    }
+    
+    let display = domain_display(vm_name)?;
+    let _ = VMState::update(vm_name, |state| state.display = Some(display.clone()));
+    Some(display)
+}
+
+/// Whether a display server is listening at the URI; displays that aren't on TCP
+/// (e.g. `spice+unix://`) are taken as ready
+fn display_listening(display: &str) -> bool {
+    use std::net::{TcpStream, ToSocketAddrs};
+    
+    let Some(address) = display_address(display) else {
+        return true;
+    };
+    address.to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .is_some_and(|addr| TcpStream::connect_timeout(&addr, Duration::from_secs(1)).is_ok())
+}
+
+/// `host:port` a `spice://` or `vnc://` display URI points at. SPICE with only TLS
+/// gives the port as `?tls-port=`, VNC as a display number counting from 5900
+fn display_address(display: &str) -> Option<String> {
+    let (scheme, rest) = display.split_once("://")?;
+    let (authority, query) = match rest.split_once('?') {
+        Some((authority, query)) => (authority, Some(query)),
+        None => (rest, None),
+    };
+    let authority = authority.trim_end_matches('/');
+    
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !host.ends_with(':') => (host, Some(port.parse::<u16>().ok()?)),
+        _ => (authority, None),
+    };
+    let port = match (scheme, port) {
+        ("vnc", Some(display)) if display < 5900 => display + 5900,
+        (_, Some(port)) => port,
+        ("spice", None) => query?.split('&')
+            .find_map(|param| param.strip_prefix("tls-port="))?
+            .parse().ok()?,
+        _ => return None,
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some(format!("{}:{}", host, port))
+}
+
+/// `Content-Length` of the last response in `curl -sIL` output, i.e. after redirects
+fn final_content_length(headers: &str) -> Option<u64> {
+    let mut length = None;
+    for line in headers.lines() {
+        if line.starts_with("HTTP/") {
+            length = None;
+        } else if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().ok();
+            }
+        }
+    }
+    length
+}
+
+/// Quote an argument for a POSIX shell when it needs it, so printed commands can be pasted
+fn shell_quote(arg: &str) -> String {
+    let safe = !arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./:=,+@%".contains(c));
+    if safe {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+/// Compare `/proc/meminfo`'s free hugepages against what `memory_mb` needs
+fn check_free_hugepages(meminfo: &str, memory_mb: u64) -> Result<(), String> {
+    let field = |name: &str| meminfo.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        if key.trim() != name {
+            return None;
+        }
+        value.split_whitespace().next()?.parse::<u64>().ok()
+    });
+    let (Some(total), Some(free), Some(size_kb)) = (field("HugePages_Total"), field("HugePages_Free"), field("Hugepagesize")) else {
+        return Err("enable_hugepages is set but this kernel reports no hugepages in /proc/meminfo".to_string());
+    };
+    
+    let needed = (memory_mb * 1024).div_ceil(size_kb.max(1));
+    if free < needed {
+        return Err(format!(
+            "enable_hugepages needs {} free {} kB hugepages for {} MB of memory, but only {} are free. \
+             Reserve more with: echo {} | sudo tee /proc/sys/vm/nr_hugepages",
+            needed, size_kb, memory_mb, free, total + needed - free));
+    }
+    Ok(())
+}
+
+/// Detailed log of the kickstart `%post` script on the installed system
+pub const POST_INSTALL_LOG: &str = "/var/log/kickstart-post-detailed.log";
+
+/// Where the installer mounts the system being installed
+const INSTALLER_SYSROOTS: [&str; 2] = ["/mnt/sysroot", "/mnt/sysimage"];
+
+/// Bytes asked for per `guest-file-read`
+const AGENT_READ_SIZE: usize = 64 * 1024;
+
+/// Where the post-install log can be while the installer runs, then once the system boots
+pub fn post_install_log_paths() -> Vec<String> {
+    INSTALLER_SYSROOTS.iter()
+        .map(|root| format!("{}{}", root, POST_INSTALL_LOG))
+        .chain(std::iter::once(POST_INSTALL_LOG.to_string()))
+        .collect()
+}
+
+/// Run a QEMU guest agent command, returning the `return` member of its reply
+fn qemu_agent_command(vm_name: &str, command: serde_json::Value) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    let output = libvirt::virsh()
+        .args(["qemu-agent-command", vm_name, &command.to_string()])
+        .output()?;
+    
+    if !output.status.success() {
+        return Err(format!("guest agent command failed: {}", 
+                           String::from_utf8_lossy(&output.stderr).trim()).into());
+    }
+    let mut reply: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    Ok(reply["return"].take())
+}
+
+/// Whether the domain's QEMU guest agent answers. The Fedora installer runs one,
+/// and so do installed guests (`qemu-guest-agent`).
+pub fn qemu_agent_available(vm_name: &str) -> bool {
+    qemu_agent_command(vm_name, serde_json::json!({ "execute": "guest-ping" })).is_ok()
+}
+
+/// How long a program run with `guest_exec` may take
+const GUEST_EXEC_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long `rebuild` waits for the package installs in the guest
+const REBUILD_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// How long `resize-disk --grow-fs` waits for a VM it booted to answer
+const AGENT_BOOT_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// How long growing the guest's filesystem may take, including installing growpart
+const GROW_FS_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Grow the partition holding `/` to the end of its disk, then its filesystem.
+/// growpart exits 1 when there's nothing to grow, which isn't an error here.
+const GROW_ROOT_SCRIPT: &str = r#"set -e
+source=$(findmnt -nvo SOURCE /)
+fstype=$(findmnt -no FSTYPE /)
+disk=/dev/$(lsblk -no PKNAME "$source")
+partition=$(cat "/sys/class/block/$(basename "$source")/partition")
+command -v growpart >/dev/null || dnf install -y -q cloud-utils-growpart
+growpart "$disk" "$partition" || [ $? -eq 1 ]
+case "$fstype" in
+    xfs) xfs_growfs / ;;
+    ext2|ext3|ext4) resize2fs "$source" ;;
+    btrfs) btrfs filesystem resize max / ;;
+    *) echo "don't know how to grow a $fstype filesystem" >&2; exit 1 ;;
+esac
+"#;
+
+/// systemd names mount units after their path; tags need no escaping
+fn shared_folder_unit_name(tag: &str) -> String {
+    format!("mnt-{}.mount", tag)
+}
+
+/// Mounts the virtiofs share `tag` at /mnt/<tag>; `nofail` keeps boot going when
+/// the host side is missing
+fn shared_folder_unit(tag: &str) -> String {
+    format!(r#"[Unit]
+Description=Shared folder {tag} from the host
+
+[Mount]
+What={tag}
+Where=/mnt/{tag}
+Type=virtiofs
+Options=nofail
 
 [Install]
-WantedBy=default.target
-EOF
+WantedBy=multi-user.target
+"#, tag = tag)
+}
 
-# Create user cache directory and fix permissions
-mkdir -p /home/user/.cache
-mkdir -p /home/user/.local/share
-mkdir -p /home/user/.local/bin
+/// The `virtual-size` from `qemu-img info --output=json`, in bytes
+fn qcow2_virtual_size(info: &str) -> Option<u64> {
+    serde_json::from_str::<serde_json::Value>(info).ok()?["virtual-size"].as_u64()
+}
 
-# Fix ownership of all user directories
-chown -R user:user /home/user/.config
-chown -R user:user /home/user/.cache
-chown -R user:user /home/user/.local
-chown -R user:user /home/user/.*
+/// Run a program in the guest through the QEMU guest agent and return its stdout
+pub fn guest_exec(vm_name: &str, path: &str, args: &[String]) -> Result<String, Box<dyn std::error::Error>> {
+    guest_exec_with_timeout(vm_name, path, args, GUEST_EXEC_TIMEOUT)
+}
 
-# Enable the user service (will be activated when user session starts)
-sudo -u user systemctl --user enable startx.service
+fn guest_exec_with_timeout(vm_name: &str, path: &str, args: &[String], timeout: Duration) -> Result<String, Box<dyn std::error::Error>> {
+    let exec = serde_json::json!({ "execute": "guest-exec", "arguments": { "path": path, "arg": args, "capture-output": true } });
+    let pid = qemu_agent_command(vm_name, exec)?["pid"].as_i64().ok_or("guest-exec returned no pid")?;
+    let status = serde_json::json!({ "execute": "guest-exec-status", "arguments": { "pid": pid } });
+    
+    let started = std::time::Instant::now();
+    loop {
+        let reply = qemu_agent_command(vm_name, status.clone())?;
+        if reply["exited"].as_bool() == Some(true) {
+            let output = |key: &str| -> Result<String, Box<dyn std::error::Error>> {
+                let bytes = decode_base64(reply[key].as_str().unwrap_or("")).ok_or("guest agent sent invalid base64")?;
+                Ok(String::from_utf8_lossy(&bytes).into_owned())
+            };
+            if reply["exitcode"].as_i64() != Some(0) {
+                return Err(format!("{} failed in the guest: {}", path, output("err-data")?.trim()).into());
+            }
+            return output("out-data");
+        }
+        if started.elapsed() > timeout {
+            return Err(format!("{} didn't finish in the guest within {}s", path, timeout.as_secs()).into());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
 
-# Create default i3 config
-mkdir -p /home/user/.config/i3
-cat > /home/user/.config/i3/config << 'EOF'
-# i3 config file
-set $mod Mod4
+/// A file in the guest opened for reading through the QEMU guest agent
+pub struct AgentFile<'a> {
+    vm_name: &'a str,
+    handle: i64,
+}
 
-# Font for window titles
-font pango:DejaVu Sans Mono 8
+impl<'a> AgentFile<'a> {
+    /// Open the first of `paths` that the guest can open
+    pub fn open_first(vm_name: &'a str, paths: &[String]) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut last_error: Box<dyn std::error::Error> = "no path to open".into();
+        for path in paths {
+            let open = serde_json::json!({ "execute": "guest-file-open", "arguments": { "path": path, "mode": "r" } });
+            match qemu_agent_command(vm_name, open) {
+                Ok(handle) => match handle.as_i64() {
+                    Some(handle) => return Ok(AgentFile { vm_name, handle }),
+                    None => last_error = format!("unexpected guest-file-open reply: {}", handle).into(),
+                },
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+    
+    /// What was written since the last read; empty at the end of the file
+    pub fn read(&mut self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let read = serde_json::json!({ "execute": "guest-file-read", "arguments": { "handle": self.handle, "count": AGENT_READ_SIZE } });
+        let reply = qemu_agent_command(self.vm_name, read)?;
+        let encoded = reply["buf-b64"].as_str().unwrap_or("");
+        Ok(decode_base64(encoded).ok_or("guest agent sent invalid base64")?)
+    }
+}
 
-# Use Mouse+$mod to drag floating windows
-floating_modifier $mod
+impl Drop for AgentFile<'_> {
+    fn drop(&mut self) {
+        let close = serde_json::json!({ "execute": "guest-file-close", "arguments": { "handle": self.handle } });
+        let _ = qemu_agent_command(self.vm_name, close);
+    }
+}
 
-# Start a terminal
-bindsym $mod+Return exec kitty
+/// Standard base64, as the guest agent sends file contents
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(text.len() / 4 * 3);
+    let (mut bits, mut bit_count) = (0u32, 0);
+    
+    for byte in text.bytes().filter(|b| !b.is_ascii_whitespace()).take_while(|b| *b != b'=') {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        bits = (bits << 6) | value as u32;
+        bit_count += 6;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            decoded.push((bits >> bit_count) as u8);
+            bits &= (1 << bit_count) - 1;
+        }
+    }
+    Some(decoded)
+}
 
-# Kill focused window
-bindsym $mod+Shift+q kill
+/// Last `lines` lines of a file on the domain's disks, read with guestfish without
+/// booting anything. The disks are opened read-only, so this works on running VMs too.
+pub fn read_guest_file_tail(vm_name: &str, path: &str, lines: usize) -> Result<String, Box<dyn std::error::Error>> {
+    let output = libvirt::tool("guestfish")
+        .args(["--ro", "-d", vm_name, "-i", "tail-n", &lines.to_string(), path])
+        .output()
+        .map_err(|e| format!("Can't run guestfish (install libguestfs-tools): {}", e))?;
+    
+    if !output.status.success() {
+        return Err(format!("guestfish couldn't read {}: {}", path, 
+                           String::from_utf8_lossy(&output.stderr).trim()).into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
 
-# Start rofi (app launcher) - better Flatpak support than dmenu
-bindsym $mod+d exec rofi -show drun -p "Applications"
+/// Attach to the serial console and print only the lines about the post-install script
+/// and failures, until the console is left with Ctrl+]
+pub fn grep_serial_console(vm_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut console = libvirt::virsh()
+        .args(["console", "--force", vm_name])
+        .stdout(Stdio::piped())
+        .spawn()?;
+    
+    let mut reader = BufReader::new(console.stdout.take().ok_or("virsh console has no stdout")?);
+    let mut line = Vec::new();
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        let text = String::from_utf8_lossy(&line);
+        let text = text.trim_end();
+        if is_post_install_line(text) {
+            // The console puts the terminal in raw mode, so newlines need their carriage return
+            print!("{}\r\n", text);
+            std::io::stdout().flush()?;
+        }
+        line.clear();
+    }
+    
+    console.wait()?;
+    Ok(())
+}
 
-# Alternative: traditional dmenu 
-bindsym $mod+Shift+d exec dmenu_run -p "Run:" -fn "DejaVu Sans Mono-10"
+/// Whether a serial console line is about the kickstart `%post` script or something failing
+fn is_post_install_line(line: &str) -> bool {
+    let line = line.to_lowercase();
+    ["%post", "post-install", "post-installation", "kickstart", "error", "fail", "traceback"]
+        .iter()
+        .any(|marker| line.contains(marker))
+}
 
-# Change focus
-bindsym $mod+j focus left
-bindsym $mod+k focus down
-bindsym $mod+l focus up
-bindsym $mod+semicolon focus right
-bindsym $mod+Left focus left
-bindsym $mod+Down focus down
-bindsym $mod+Up focus up
-bindsym $mod+Right focus right
+/// Name of the VPN connection in the guest: its config file and, for WireGuard, its interface
+const VPN_NAME: &str = "vpn0";
 
-# Move focused window
-bindsym $mod+Shift+j move left
-bindsym $mod+Shift+k move down
-bindsym $mod+Shift+l move up
-bindsym $mod+Shift+semicolon move right
-bindsym $mod+Shift+Left move left
-bindsym $mod+Shift+Down move down
-bindsym $mod+Shift+Up move up
-bindsym $mod+Shift+Right move right
+/// Name of the window proxy's TLS identity next to the kickstart
+const GUEST_TLS_FILE: &str = "proxy-tls.pem";
 
-# Workspaces
-bindsym $mod+1 workspace 1
-bindsym $mod+2 workspace 2
-bindsym $mod+3 workspace 3
-bindsym $mod+4 workspace 4
-bindsym $mod+5 workspace 5
+/// Guest agent sources the kickstart builds in the VM, the same files the host's
+/// guest-agent binary is built from, so both ends speak the same protocol
+const GUEST_AGENT_SOURCES: [(&str, &str); 3] = [
+    ("main.rs", include_str!("guest_agent.rs")),
+    ("protocol.rs", include_str!("protocol.rs")),
+    ("tls.rs", include_str!("tls.rs")),
+];
 
-# Move container to workspace
-bindsym $mod+Shift+1 move container to workspace 1
-bindsym $mod+Shift+2 move container to workspace 2
-bindsym $mod+Shift+3 move container to workspace 3
-bindsym $mod+Shift+4 move container to workspace 4
-bindsym $mod+Shift+5 move container to workspace 5
+/// Manifest for building the guest agent in the VM, with the crates its sources use
+const GUEST_AGENT_MANIFEST: &str = r#"[package]
+name = "guest-agent"
+version = "0.1.0"
+edition = "2021"
 
-# Restart i3
-bindsym $mod+Shift+r restart
+[dependencies]
+serde = { version = "1.0", features = ["derive"] }
+bincode = "1.3"
+regex = "1.10"
+libc = "0.2"
+rustls = { version = "0.23", default-features = false, features = ["ring", "std"] }
+rcgen = "0.13"
 
-# Exit i3
-bindsym $mod+Shift+e exec "i3-nagbar -t warning -m 'Exit i3?' -b 'Yes' 'i3-msg exit'"
+[profile.release]
+opt-level = 3
+strip = true
+"#;
+
+/// Heredoc delimiter for the embedded sources; no source line may equal it
+const GUEST_AGENT_SOURCE_EOF: &str = "GUEST_AGENT_SOURCE_EOF";
+
+fn command_exists(cmd: &str) -> bool {
+    Command::new("which")
+        .arg(cmd)
+        .output()
+        .is_ok_and(|output| output.status.success())
+}
+
+/// Build-time packages that the guest agent build installs and removes itself
+fn is_build_dependency(pkg: &str) -> bool {
+    ["-devel", "autoconf", "automake", "libtool", "pkgconfig", "gcc", "make"]
+        .iter()
+        .any(|marker| pkg.contains(marker))
+}
+
+/// Unicast, locally administered MAC address from random bytes
+fn local_mac_address(mut bytes: [u8; 6]) -> String {
+    bytes[0] = (bytes[0] & 0xfc) | 0x02;
+    bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":")
+}
 
-# Status bar
-bar {
-    status_command i3status
+/// Hostname in the style of Windows' defaults (`DESKTOP-` and seven characters),
+/// which is common enough on most networks not to stand out
+fn random_hostname(bytes: &[u8]) -> String {
+    const CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+    let suffix: String = bytes.iter().take(7).map(|b| CHARS[*b as usize % CHARS.len()] as char).collect();
+    format!("DESKTOP-{}", suffix)
 }
 
-# Auto-start applications
-EOF
+/// Whether the domain's persistent definition has a vsock device
+pub fn domain_has_vsock(vm_name: &str) -> bool {
+    libvirt::virsh()
+        .args(["dumpxml", "--inactive", vm_name])
+        .output()
+        .is_ok_and(|output| output.status.success() && String::from_utf8_lossy(&output.stdout).contains("<vsock"))
+}
 
-# Add auto-start commands for installed applications"#.to_string();
+/// Context ID of a running domain's vsock device, as assigned by libvirt
+pub fn domain_vsock_cid(vm_name: &str) -> Option<u32> {
+    let output = libvirt::virsh()
+        .args(["dumpxml", vm_name])
+        .output()
+        .ok()?;
+    
+    if !output.status.success() {
+        return None;
+    }
+    parse_vsock_cid(&String::from_utf8_lossy(&output.stdout))
+}
 
-            // Add auto-start commands for each application
-            for app_command in &self.config.auto_launch_apps {
-                result.push_str(&format!("\necho \"exec --no-startup-id {}\" >> /home/user/.config/i3/config", app_command));
+/// Source paths of the file-backed disks in `virsh domblklist --details` output,
+/// leaving out CD-ROMs such as install media and seed ISOs
+fn parse_domblklist_disks(list: &str) -> Vec<String> {
+    list.lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["file" | "block", "disk", _target, source] if *source != "-" => Some(source.to_string()),
+                _ => None,
             }
+        })
+        .collect()
+}
 
-            result.push_str(r#"
+/// A USB device on the host, as listed by lsusb
+#[derive(Debug, PartialEq)]
+pub struct UsbDevice {
+    /// `vendor:product`, lowercase
+    pub id: String,
+    pub description: String,
+}
 
-# Final comprehensive ownership fix for all user directories
-chown -R user:user /home/user/.config
-chown -R user:user /home/user/.cache
-chown -R user:user /home/user/.local
-chown -R user:user /home/user/.xinitrc
-chown -R user:user /home/user/.bash_profile
+/// Host USB devices, from `lsusb`
+pub fn host_usb_devices() -> Result<Vec<UsbDevice>, Box<dyn std::error::Error>> {
+    let output = Command::new("lsusb")
+        .output()
+        .map_err(|e| format!("Can't run lsusb (install usbutils): {}", e))?;
+    if !output.status.success() {
+        return Err(format!("lsusb failed: {}", String::from_utf8_lossy(&output.stderr).trim()).into());
+    }
+    Ok(parse_lsusb(&String::from_utf8_lossy(&output.stdout)))
+}
 
-# Ensure proper permissions for user directories
-chmod 755 /home/user/.config
-chmod 755 /home/user/.cache
-chmod 755 /home/user/.local
+/// Parse `Bus 001 Device 003: ID 046d:c52b Logitech, Inc. Unifying Receiver` lines,
+/// leaving out the root hubs, which can't be passed through
+fn parse_lsusb(output: &str) -> Vec<UsbDevice> {
+    output.lines()
+        .filter_map(|line| line.split_once(" ID "))
+        .map(|(_, device)| device.split_once(' ').unwrap_or((device, "")))
+        .filter(|(id, _)| !id.starts_with("1d6b:"))
+        .map(|(id, description)| UsbDevice { id: id.to_ascii_lowercase(), description: description.trim().to_string() })
+        .collect()
+}
 
-# Install build dependencies for spice-autorandr (must be done in post-install)
-echo "Installing build dependencies for spice-autorandr..."
-dnf install -y gcc make autoconf automake libtool libXrandr-devel libX11-devel systemd-devel pkgconfig xorg-x11-proto-devel xorg-x11-util-macros
+/// `desired` packages missing from `installed`, one name per line. Groups (`@name`)
+/// can't be checked this way and are always passed on; dnf skips installed ones.
+fn missing_packages(desired: &[String], installed: &str) -> Vec<String> {
+    let installed: std::collections::HashSet<&str> = installed.lines().map(str::trim).collect();
+    desired.iter()
+        .filter(|package| !installed.contains(package.as_str()))
+        .cloned()
+        .collect()
+}
 
-# Install and configure spice-autorandr for automatic resolution adjustment
-echo "Building spice-autorandr..."
-cd /tmp
-if git clone https://github.com/seife/spice-autorandr.git; then
-    cd spice-autorandr
-    if autoreconf -is; then
-        if ./configure; then
-            if make; then
-                cp spice-autorandr /usr/local/bin/
-                chmod +x /usr/local/bin/spice-autorandr
-                echo "spice-autorandr installed successfully"
-            else
-                echo "ERROR: spice-autorandr make failed"
-            fi
-        else
-            echo "ERROR: spice-autorandr configure failed"
-        fi
-    else
-        echo "ERROR: spice-autorandr autoreconf failed"
-    fi
-else
-    echo "ERROR: spice-autorandr git clone failed"
-fi
+/// Whether `virsh dominfo` reports a managed save image
+fn has_managed_save(dominfo: &str) -> bool {
+    dominfo.lines().any(|line| {
+        line.split_once(':')
+            .is_some_and(|(key, value)| key.trim() == "Managed save" && value.trim() == "yes")
+    })
+}
 
-# Create systemd service for spice-autorandr
-cat > /etc/systemd/system/spice-autorandr.service << 'EOF'
-[Unit]
-Description=SPICE Auto Resolution Adjustment
-After=multi-user.target
-Wants=multi-user.target
+/// `address` of the `<cid>` inside the domain XML's `<vsock>` element
+fn parse_vsock_cid(xml: &str) -> Option<u32> {
+    let vsock = &xml[xml.find("<vsock")?..];
+    let vsock = &vsock[..vsock.find("</vsock>").unwrap_or(vsock.len())];
+    let cid = &vsock[vsock.find("<cid")?..];
+    let cid = &cid[..cid.find('>')?];
+    
+    let value = cid.split_once("address=")?.1;
+    let quote = value.chars().next().filter(|c| *c == '\'' || *c == '"')?;
+    value[1..].split(quote).next()?.parse().ok()
+}
 
-[Service]
-Type=simple
-ExecStart=/usr/local/bin/spice-autorandr
-Restart=always
-RestartSec=5
-Environment=DISPLAY=:0
-Environment=XDG_RUNTIME_DIR=/run/user/1000
-User=user
-Group=user
-StandardOutput=journal
-StandardError=journal
+/// Open remote-viewer on the domain's SPICE display
+fn open_spice_viewer(vm_name: &str) {
+    match current_display(vm_name) {
+        Some(display) => {
+            if let Err(e) = launch_viewer(vm_name, &display) {
+                eoutln!("⚠️  Failed to launch remote-viewer: {}", e);
+            }
+        }
+        None => eoutln!("⚠️  {} has no display to open; check: virsh domdisplay {}", vm_name, vm_name),
+    }
+}
 
-[Install]
-WantedBy=multi-user.target
-EOF
+/// Open remote-viewer on a display URI, titled with the VM name
+pub fn launch_viewer(vm_name: &str, display: &str) -> Result<(), Box<dyn std::error::Error>> {
+    Command::new("remote-viewer")
+        .args(["--title", vm_name, display])
+        .spawn()?;
+    Ok(())
+}
 
-# Enable the spice-autorandr service
-systemctl enable spice-autorandr.service"#);
+/// Names of running libvirt domains
+pub fn list_running_domains() -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let output = libvirt::virsh()
+        .args(["list", "--state-running", "--name"])
+        .output()?;
+    
+    if !output.status.success() {
+        return Err(format!("virsh list failed: {}", 
+                           String::from_utf8_lossy(&output.stderr).trim()).into());
+    }
+    
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|l| l.trim().to_string())
+        .filter(|l| !l.is_empty())
+        .collect())
+}
 
-            result
-        } else {
-            "".to_string()
+/// Names of all libvirt domains, running or not
+pub fn list_domains() -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let output = libvirt::virsh()
+        .args(["list", "--all", "--name"])
+        .output()?;
+    
+    if !output.status.success() {
+        return Err(format!("virsh list failed: {}", 
+                           String::from_utf8_lossy(&output.stderr).trim()).into());
+    }
+    
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|l| l.trim().to_string())
+        .filter(|l| !l.is_empty())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn test_provisioner(configure: impl FnOnce(&mut AppVMConfig)) -> AppVMProvisioner {
+        let mut config = AppVMConfig::new(
+            "test-vm".to_string(),
+            4096,
+            2,
+            20,
+            vec!["qbittorrent".to_string()],
+            vec!["org.mozilla.firefox".to_string()],
+        );
+        configure(&mut config);
+        AppVMProvisioner::new(config)
+    }
+    
+    #[test]
+    fn packages_include_defaults_and_user_packages() {
+        let packages = test_provisioner(|_| {}).render_packages();
+        assert!(packages.lines().any(|l| l == "i3"));
+        assert!(packages.lines().any(|l| l == "qbittorrent"));
+    }
+    
+    #[test]
+    fn packages_filter_build_dependencies() {
+        let packages = test_provisioner(|c| {
+            c.system_packages.push("libX11-devel".to_string());
+            c.system_packages.push("gcc".to_string());
+        }).render_packages();
+        assert!(!packages.contains("libX11-devel"));
+        assert!(!packages.lines().any(|l| l == "gcc"));
+    }
+    
+    #[test]
+    fn flatpak_block_installs_each_package() {
+        let block = test_provisioner(|_| {}).render_flatpak_block();
+        assert!(block.contains("flatpak remote-add --if-not-exists flathub"));
+        assert!(block.contains("flatpak install -y flathub org.mozilla.firefox"));
+    }
+    
+    #[test]
+    fn flatpak_block_empty_without_flatpaks() {
+        let block = test_provisioner(|c| c.flatpak_packages.clear()).render_flatpak_block();
+        assert!(block.is_empty());
+    }
+    
+    #[test]
+    fn autolaunch_creates_service_per_app() {
+        let block = test_provisioner(|_| {}).render_autolaunch();
+        assert!(block.contains("auto-launch-1.service"));
+        assert!(block.contains("ExecStart=flatpak run org.mozilla.firefox"));
+    }
+    
+    #[test]
+    fn services_enable_pipewire_with_audio() {
+        let services = test_provisioner(|c| c.enable_audio = true).render_services();
+        assert!(services.contains("systemctl --user enable pipewire pipewire-pulse wireplumber"));
+        
+        let services = test_provisioner(|c| c.enable_audio = false).render_services();
+        assert!(!services.contains("pipewire-pulse"));
+    }
+    
+    #[test]
+    fn guest_agent_leaves_the_clipboard_alone_unless_custom() {
+        let unit = test_provisioner(|c| c.enable_clipboard = false).guest_agent_unit();
+        assert!(!unit.contains("GUEST_AGENT_CLIPBOARD"));
+        let unit = test_provisioner(|c| c.clipboard_transport = ClipboardTransport::Spice).guest_agent_unit();
+        assert!(!unit.contains("GUEST_AGENT_CLIPBOARD"));
+    }
+    
+    #[test]
+    fn guest_agent_syncs_the_clipboard_for_custom_transport() {
+        let provisioner = test_provisioner(|c| c.clipboard_transport = ClipboardTransport::Custom);
+        assert!(provisioner.guest_agent_unit().contains("Environment=\"GUEST_AGENT_CLIPBOARD=1\"\n"));
+        assert!(!provisioner.render_services().contains("clipboard-proxy"));
+    }
+    
+    #[test]
+    fn guest_agent_allows_the_host_to_run_journalctl() {
+        let provisioner = test_provisioner(|_| {});
+        assert!(provisioner.render_packages().contains("qemu-guest-agent"));
+        
+        let services = provisioner.render_services();
+        let filter = services.lines().find(|line| line.contains("FILTER_RPC_ARGS")).unwrap();
+        assert!(filter.ends_with(">> /etc/sysconfig/qemu-ga"));
+        for rpc in ["guest-exec", "guest-exec-status", "guest-file-read"] {
+            assert!(filter.contains(&format!("{},", rpc)));
+        }
+        assert!(services.contains("systemctl enable qemu-guest-agent.service"));
+    }
+    
+    #[test]
+    fn firewall_rules_become_iptables_commands() {
+        let rules = test_provisioner(|c| {
+            c.firewall_rules = vec!["OUTPUT -p tcp --dport 443 -j ACCEPT".to_string()];
+        }).render_firewall();
+        assert_eq!(rules, "iptables -A OUTPUT -p tcp --dport 443 -j ACCEPT");
+    }
+    
+    #[test]
+    fn kickstart_hostname_defaults_to_vm_name() {
+        let kickstart = test_provisioner(|_| {}).render_kickstart();
+        assert!(kickstart.contains("echo \"test-vm\" > /etc/hostname"));
+        
+        let kickstart = test_provisioner(|c| c.hostname = Some("box.example.com".to_string()))
+            .render_kickstart();
+        assert!(kickstart.contains("echo \"box.example.com\" > /etc/hostname"));
+    }
+    
+    #[test]
+    fn kickstart_assembles_all_sections() {
+        let provisioner = test_provisioner(|_| {});
+        let kickstart = provisioner.render_kickstart();
+        assert!(kickstart.contains("# Generated for: test-vm"));
+        assert!(kickstart.contains(&provisioner.render_packages()));
+        assert!(kickstart.contains(&provisioner.render_flatpak_block()));
+        assert!(kickstart.contains(&provisioner.render_firewall()));
+        assert!(kickstart.contains("guest-agent.service"));
+    }
+    
+    #[test]
+    fn kickstart_builds_the_real_guest_agent() {
+        let kickstart = test_provisioner(|_| {}).render_kickstart();
+        assert!(kickstart.contains(&format!("cat > /tmp/guest-agent-build/src/main.rs << '{eof}'\n{}{eof}\n",
+                                            include_str!("guest_agent.rs"), eof = GUEST_AGENT_SOURCE_EOF)));
+        assert!(kickstart.contains(include_str!("protocol.rs")));
+        assert!(kickstart.contains(include_str!("tls.rs")));
+        assert!(!kickstart.contains("placeholder"));
+        
+        // A source line could otherwise end the heredoc or the %post section early
+        for (file, source) in GUEST_AGENT_SOURCES {
+            assert!(source.ends_with('\n'), "{} must end with a newline", file);
+            assert!(!source.lines().any(|line| line == GUEST_AGENT_SOURCE_EOF || line.starts_with("%end")), "{}", file);
         }
     }
+    
+    #[test]
+    fn guest_agent_dials_the_configured_proxy_port() {
+        assert!(test_provisioner(|_| {}).render_services().contains("ExecStart=/usr/local/bin/guest-agent vsock:2:9999\n"));
+        
+        let services = test_provisioner(|c| {
+            c.proxy_transport = ProxyTransport::Tcp;
+            c.proxy_port = 10123;
+        }).render_services();
+        assert!(services.contains("ExecStart=/usr/local/bin/guest-agent _gateway:10123\n"));
+        
+        let services = test_provisioner(|c| c.proxy_transport = ProxyTransport::Unix).render_services();
+        assert!(services.contains("ExecStart=/usr/local/bin/guest-agent /tmp/vm-window-proxy.sock\n"));
+        assert!(!services.contains("GUEST_AGENT_TLS"));
+        
+        let provisioner = test_provisioner(|c| {
+            c.proxy_transport = ProxyTransport::Tcp;
+            c.proxy_tls = true;
+        });
+        assert!(provisioner.render_services().contains("Environment=\"GUEST_AGENT_TLS=/home/user/.config/vm-provisioner/proxy-tls.pem\"\n"));
+        assert!(provisioner.render_kickstart().contains("install -D -m 600 /proxy-tls.pem \"$sysroot/home/user/.config/vm-provisioner/proxy-tls.pem\""));
+    }
+    
+    #[test]
+    fn cloud_init_user_data_maps_the_config() {
+        let provisioner = test_provisioner(|c| {
+            c.provisioning = ProvisioningMethod::CloudInit;
+            c.user_password = "s3cret: \"quoted\"".to_string();
+            c.system_packages.push("libX11-devel".to_string());
+        });
+        let user_data = provisioner.render_cloud_init_user_data().unwrap();
+        assert!(user_data.starts_with("#cloud-config\n"));
+        
+        let parsed: serde_yaml::Value = serde_yaml::from_str(&user_data).unwrap();
+        assert_eq!(parsed["users"][0]["name"].as_str(), Some("user"));
+        assert_eq!(parsed["chpasswd"]["users"][0]["password"].as_str(), Some("s3cret: \"quoted\""));
+        let packages: Vec<&str> = parsed["packages"].as_sequence().unwrap().iter().filter_map(|p| p.as_str()).collect();
+        assert!(packages.contains(&"qbittorrent"));
+        assert!(packages.contains(&"flatpak"));
+        assert!(!packages.contains(&"libX11-devel"));
+        
+        let runcmd = parsed["runcmd"].as_sequence().unwrap();
+        assert!(runcmd.iter().any(|cmd| cmd[4].as_str() == Some("org.mozilla.firefox")));
+        assert!(runcmd.iter().any(|cmd| cmd[2].as_str() == Some("auto-launch-1.service")));
+        assert_eq!(parsed["write_files"][0]["path"].as_str(), Some("/etc/systemd/system/auto-launch-1.service"));
+        assert_eq!(parsed["power_state"]["mode"].as_str(), Some("poweroff"));
+        
+        assert_eq!(provisioner.render_cloud_init_meta_data(), "instance-id: test-vm\nlocal-hostname: test-vm\n");
+    }
+    
+    #[test]
+    fn destroy_plan_lists_disks_but_not_cdroms() {
+        let list = " Type   Device   Target   Source
+------------------------------------------------------------
+ file   disk     vda      /var/lib/libvirt/images/test-vm.qcow2
+ file   disk     vdb      /data/test-vm-data.qcow2
+ file   cdrom    sda      /var/lib/libvirt/images/test-vm-seed.iso
+ file   cdrom    sdb      -
+";
+        assert_eq!(parse_domblklist_disks(list), vec![
+            "/var/lib/libvirt/images/test-vm.qcow2".to_string(),
+            "/data/test-vm-data.qcow2".to_string(),
+        ]);
+        
+        assert!(has_managed_save("Name:           test-vm\nManaged save:   yes\n"));
+        assert!(!has_managed_save("Name:           test-vm\nManaged save:   no\n"));
+    }
+    
+    #[test]
+    fn random_identity_is_locally_administered() {
+        assert_eq!(local_mac_address([0xff, 0x00, 0x11, 0x22, 0x33, 0x44]), "fe:00:11:22:33:44");
+        assert_eq!(local_mac_address([0x00; 6]), "02:00:00:00:00:00");
+        assert_eq!(random_hostname(&[0, 1, 25, 26, 35, 36, 255]), "DESKTOP-ABZ09AD");
+        
+        let kickstart = test_provisioner(|c| c.identity_mode = IdentityMode::RandomizePerBoot).render_kickstart();
+        assert!(kickstart.contains("rm -f /etc/hostname"));
+        assert!(!kickstart.contains("> /etc/hostname"));
+    }
+    
+    #[test]
+    fn vsock_cid_is_read_from_domain_xml() {
+        let xml = "<domain>\n  <devices>\n    <vsock model='virtio'>\n      <cid auto='yes' address='3'/>\n    </vsock>\n  </devices>\n</domain>";
+        assert_eq!(parse_vsock_cid(xml), Some(3));
+        assert_eq!(parse_vsock_cid("<vsock model=\"virtio\"><cid address=\"42\" auto=\"no\"/></vsock>"), Some(42));
+        assert_eq!(parse_vsock_cid("<vsock model='virtio'><cid auto='yes'/></vsock>"), None);
+        assert_eq!(parse_vsock_cid("<domain><devices/></domain>"), None);
+    }
+    
+    #[test]
+    fn checksum_file_entries_are_found_by_name() {
+        let hash = "ab".repeat(32);
+        let content = format!("-----BEGIN PGP SIGNED MESSAGE-----\nHash: SHA256\n\n\
+# Fedora-Server-dvd-x86_64-41-1.4.iso: 2612854784 bytes\n\
+SHA256 (Fedora-Server-dvd-x86_64-41-1.4.iso) = {}\n\
+# Fedora-Server-netinst-x86_64-41-1.4.iso: 1120104448 bytes\n\
+SHA256 (Fedora-Server-netinst-x86_64-41-1.4.iso) = {}\n\
+-----BEGIN PGP SIGNATURE-----\n", "cd".repeat(32), hash);
+        
+        assert_eq!(parse_checksum_file(&content, "Fedora-Server-netinst-x86_64-41-1.4.iso"), Some(hash));
+        assert_eq!(parse_checksum_file(&content, "Fedora-Server-netinst-aarch64-41-1.4.iso"), None);
+        assert_eq!(parse_checksum_file("SHA256 (a.iso) = short", "a.iso"), None);
+    }
+    
+    #[test]
+    fn prerequisite_report_lists_every_problem() {
+        assert!(PrerequisiteReport::default().is_ok());
+        
+        let report = PrerequisiteReport {
+            missing_commands: vec!["virt-install".to_string(), "qemu-img".to_string()],
+            failed_checks: vec!["libvirtd is not running".to_string()],
+        };
+        assert!(!report.is_ok());
+        let message = report.to_string();
+        assert!(message.contains("Missing commands: virt-install, qemu-img"));
+        assert!(message.contains("\n  libvirtd is not running"));
+    }
+    
+    #[test]
+    fn recovery_pruning_keeps_newest_snapshots() {
+        let stamps = [100, 200, 300, 400];
+        assert_eq!(snapshots_to_prune(&stamps, 3), &[100]);
+        assert_eq!(snapshots_to_prune(&stamps, 10), &[] as &[u64]);
+        assert_eq!(snapshots_to_prune(&stamps, 0), &[100, 200, 300]);
+    }
+    
+    #[test]
+    fn live_clone_snapshot_is_disk_only_and_external() {
+        let args = live_snapshot_args("web", Path::new("/vms/web.clone-overlay.qcow2"), false);
+        assert_eq!(args[..3], ["snapshot-create-as", "web", "web-clone"]);
+        assert!(args.iter().any(|a| a == "--disk-only"));
+        assert!(args.iter().any(|a| a == "--no-metadata"));
+        assert!(args.contains(&"vda,snapshot=external,file=/vms/web.clone-overlay.qcow2".to_string()));
+        assert!(!args.iter().any(|a| a == "--quiesce"));
+        
+        let args = live_snapshot_args("web", Path::new("/vms/web.clone-overlay.qcow2"), true);
+        assert_eq!(args.last().unwrap(), "--quiesce");
+    }
+    
+    #[test]
+    fn dns_overrides_written_to_networkmanager() {
+        assert_eq!(test_provisioner(|_| {}).render_dns(), "# Using DHCP-provided DNS");
+        
+        let dns = test_provisioner(|c| {
+            c.dns_servers = vec!["10.0.0.53".to_string(), "9.9.9.9".to_string()];
+            c.dns_search = vec!["corp.example.com".to_string()];
+        }).render_dns();
+        assert!(dns.contains("/etc/NetworkManager/conf.d/90-vm-provisioner-dns.conf"));
+        assert!(dns.contains("searches=corp.example.com\n"));
+        assert!(dns.contains("[global-dns-domain-*]\nservers=10.0.0.53,9.9.9.9\n"));
+        
+        let dns = test_provisioner(|c| c.dns_search = vec!["corp.example.com".to_string()]).render_dns();
+        assert!(dns.contains("/etc/systemd/resolved.conf.d/"));
+        assert!(dns.contains("Domains=corp.example.com\n"));
+    }
+    
+    #[test]
+    fn autologin_follows_tty_and_session_type() {
+        let autologin = test_provisioner(|_| {}).get_autologin_config();
+        assert!(autologin.contains("systemctl enable autologin@tty1.service"));
+        assert!(autologin.contains("exec startx -- vt1"));
+        assert!(autologin.contains("cat > /home/user/.xinitrc"));
+        
+        let provisioner = test_provisioner(|c| {
+            c.autologin_tty = 3;
+            c.session_type = SessionType::Wayland;
+        });
+        let autologin = provisioner.get_autologin_config();
+        assert!(autologin.contains("systemctl enable autologin@tty3.service"));
+        assert!(autologin.contains("[[ $(tty) == \"/dev/tty3\" ]]"));
+        assert!(autologin.contains("exec sway"));
+        assert!(autologin.contains("cp /home/user/.config/i3/config /home/user/.config/sway/config"));
+        assert!(!autologin.contains("startx"));
+        
+        let services = provisioner.render_services();
+        assert!(services.contains("Wants=autologin@tty3.service"));
+        assert!(services.contains("Environment=\"XDG_SESSION_TYPE=wayland\""));
+        assert!(services.contains("pgrep -x Xwayland"));
+        assert!(provisioner.render_packages().lines().any(|l| l == "sway"));
+    }
+    
+    #[test]
+    fn swap_defaults_to_zram_sized_from_memory() {
+        let provisioner = test_provisioner(|_| {});
+        assert!(provisioner.render_swap().contains("[zram0]\nzram-size = 1024\n"));
+        assert!(provisioner.render_packages().lines().any(|l| l == "zram-generator"));
+        
+        let swap = test_provisioner(|c| c.swap_mb = Some(512)).render_swap();
+        assert!(swap.contains("zram-size = 512\n"));
+        
+        let swap = test_provisioner(|c| {
+            c.enable_zram = false;
+            c.swap_mb = Some(2048);
+        }).render_swap();
+        assert!(swap.contains("fallocate -l 2048M /swapfile"));
+        assert!(swap.contains("touch /etc/systemd/zram-generator.conf"));
+        
+        let swap = test_provisioner(|c| c.enable_zram = false).render_swap();
+        assert_eq!(swap, "touch /etc/systemd/zram-generator.conf");
+    }
+    
+    #[test]
+    fn display_scale_sets_dpi_and_toolkit_scale() {
+        assert_eq!(test_provisioner(|_| {}).render_display_scale(), "# Using the default display scale");
+        
+        let provisioner = test_provisioner(|c| c.display_scale = Some(1.5));
+        let scale = provisioner.render_display_scale();
+        assert!(scale.contains("export GDK_SCALE=2\n"));
+        assert!(scale.contains("export GDK_DPI_SCALE=0.750\n"));
+        assert!(scale.contains("export QT_SCALE_FACTOR=1.5\n"));
+        assert!(scale.contains("echo 'Xft.dpi: 144' > /home/user/.Xresources"));
+        assert!(provisioner.render_packages().lines().any(|l| l == "xrdb"));
+        
+        let scale = test_provisioner(|c| {
+            c.display_scale = Some(2.0);
+            c.session_type = SessionType::Wayland;
+        }).render_display_scale();
+        assert!(scale.contains("echo 'output * scale 2' >> /home/user/.config/sway/config"));
+    }
+    
+    #[test]
+    fn spice_folder_sharing_installs_webdavd() {
+        let provisioner = test_provisioner(|c| c.enable_spice_folder_sharing = true);
+        assert!(provisioner.render_packages().lines().any(|l| l == "spice-webdavd"));
+        assert!(provisioner.render_services().contains("systemctl enable spice-webdavd.service"));
+        
+        let provisioner = test_provisioner(|_| {});
+        assert!(!provisioner.render_packages().contains("spice-webdavd"));
+        assert!(!provisioner.render_services().contains("spice-webdavd"));
+    }
+    
+    #[test]
+    fn packages_always_include_window_tools() {
+        // The guest agent needs these to see any windows at all
+        let packages = test_provisioner(|c| c.system_packages.clear()).render_packages();
+        assert!(packages.lines().any(|l| l == "xwininfo"));
+        assert!(packages.lines().any(|l| l == "wmctrl"));
+        assert!(packages.lines().any(|l| l == "xdotool"));
+        assert!(test_provisioner(|_| {}).render_kickstart().contains("for pkg in i3 xset xrandr kitty git rofi wmctrl xwininfo"));
+    }
+    
+    #[test]
+    fn network_failures_are_detected() {
+        assert!(is_network_failure("ERROR    Error retrieving https://dl.fedoraproject.org/.../vmlinuz: Connection timed out"));
+        assert!(is_network_failure("curl: (6) Could not resolve host: dl.fedoraproject.org"));
+        assert!(!is_network_failure("ERROR    Disk /var/lib/libvirt/images/vm.qcow2 is already in use by other guests"));
+    }
+    
+    #[test]
+    fn anaconda_console_network_failures_are_detected() {
+        assert!(is_network_failure("Error downloading packages:"));
+        assert!(is_network_failure("  Curl error (6): Couldn't resolve host name for https://mirrors.fedoraproject.org/metalink?repo=fedora-40&arch=x86_64 [Could not resolve host: mirrors.fedoraproject.org]"));
+        assert!(is_network_failure("Failed to download metadata for repo 'fedora': Cannot download repomd.xml"));
+        assert!(!is_network_failure("Installing kitty.x86_64 (412/1320)"));
+        assert!(!is_network_failure("Installing boot loader"));
+    }
+    
+    #[test]
+    fn failure_reason_prefers_error_lines() {
+        let stderr = "Starting install...\nERROR    Couldn't fetch vmlinuz\nDomain installation does not appear to have been successful.\n";
+        assert_eq!(failure_reason(stderr), "ERROR    Couldn't fetch vmlinuz");
+        assert_eq!(failure_reason("just one line\n"), "just one line");
+        assert_eq!(failure_reason(""), "no output from virt-install");
+    }
+    
+    #[test]
+    fn decode_base64_reads_guest_agent_buffers() {
+        assert_eq!(decode_base64("").unwrap(), b"");
+        assert_eq!(decode_base64("Zg==").unwrap(), b"f");
+        assert_eq!(decode_base64("Zm9vYg==").unwrap(), b"foob");
+        assert_eq!(decode_base64("Zm9vYmFy").unwrap(), b"foobar");
+        assert_eq!(decode_base64("PT0+Pz8/\n").unwrap(), b"==>???");
+        assert!(decode_base64("Zm9v!").is_none());
+    }
+    
+    #[test]
+    fn post_install_log_is_found_in_the_installer_and_the_guest() {
+        assert!(test_provisioner(|_| {}).render_kickstart()
+            .contains("exec > >(tee -a /var/log/kickstart-post-detailed.log) 2>&1"));
+        assert_eq!(post_install_log_paths(), vec![
+            "/mnt/sysroot/var/log/kickstart-post-detailed.log",
+            "/mnt/sysimage/var/log/kickstart-post-detailed.log",
+            "/var/log/kickstart-post-detailed.log",
+        ]);
+        
+        assert!(is_post_install_line("Running post-installation scripts"));
+        assert!(is_post_install_line("[FAILED] Failed to start guest-agent.service"));
+        assert!(!is_post_install_line("Installing firefox (312/1024)"));
+    }
+    
+    #[test]
+    fn vpn_only_adds_a_killswitch_after_the_firewall_rules() {
+        let wg = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(wg.path(), "[Peer]\nEndpoint = 203.0.113.5:51820\n").unwrap();
+        let provisioner = test_provisioner(|c| {
+            c.firewall_rules = vec!["OUTPUT -p tcp --dport 443 -j ACCEPT".to_string()];
+            c.network_mode = NetworkMode::VpnOnly;
+            c.vpn_config = Some(VpnConfig {
+                provider: "wireguard".to_string(),
+                config_path: wg.path().to_string_lossy().to_string(),
+                credentials_path: None,
+            });
+        });
+        
+        let firewall = provisioner.render_firewall();
+        assert!(firewall.starts_with("iptables -A OUTPUT -p tcp --dport 443 -j ACCEPT\n"));
+        assert!(firewall.contains("$ipt -A VPN_KILLSWITCH -o vpn0 -j ACCEPT"));
+        assert!(firewall.contains("iptables -A VPN_KILLSWITCH -d 203.0.113.5 -p udp --dport 51820 -j ACCEPT"));
+        assert!(firewall.contains("ip6tables -A VPN_KILLSWITCH -j DROP"));
+        assert!(firewall.contains("systemctl enable vpn-killswitch.service wg-quick@vpn0.service"));
+        
+        assert!(provisioner.render_packages().lines().any(|l| l == "wireguard-tools"));
+        let kickstart = provisioner.render_kickstart();
+        assert!(kickstart.contains("install -D -m 600 /vpn0.conf \"$sysroot/etc/wireguard/vpn0.conf\""));
+        
+        let plain = test_provisioner(|_| {});
+        assert!(!plain.render_firewall().contains("VPN_KILLSWITCH"));
+        assert!(!plain.render_kickstart().contains("%post --nochroot"));
+    }
+    
+    #[test]
+    fn content_length_comes_from_the_final_response() {
+        let redirected = "HTTP/1.1 302 Found\r\nLocation: https://mirror/fedora.iso\r\nContent-Length: 138\r\n\r\n\
+                          HTTP/2 200\r\ncontent-length: 2364538880\r\n\r\n";
+        assert_eq!(final_content_length(redirected), Some(2364538880));
+        
+        // A final response without a length must not inherit the redirect's
+        let chunked = "HTTP/1.1 301 Moved\r\nContent-Length: 0\r\n\r\nHTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(final_content_length(chunked), None);
+    }
+    
+    #[test]
+    fn dry_run_commands_are_shell_quoted() {
+        assert_eq!(shell_quote("--vcpus"), "--vcpus");
+        assert_eq!(shell_quote("path=/var/lib/vm.qcow2,size=20"), "path=/var/lib/vm.qcow2,size=20");
+        assert_eq!(shell_quote("inst.ks=file:/kickstart.cfg console=tty0"), "'inst.ks=file:/kickstart.cfg console=tty0'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote(""), "''");
+    }
+    
+    #[test]
+    fn free_hugepages_must_cover_guest_memory() {
+        let meminfo = "MemTotal:       32768000 kB\nHugePages_Total:    2048\nHugePages_Free:     1024\nHugePages_Rsvd:        0\nHugepagesize:       2048 kB\n";
+        assert!(check_free_hugepages(meminfo, 2048).is_ok());
+        
+        // 4 GB needs 2048 pages of 2 MB; with 1024 free, another 1024 have to be reserved
+        let error = check_free_hugepages(meminfo, 4096).unwrap_err();
+        assert!(error.contains("needs 2048 free 2048 kB hugepages"), "{}", error);
+        assert!(error.contains("echo 3072 | sudo tee /proc/sys/vm/nr_hugepages"), "{}", error);
+        
+        assert!(check_free_hugepages("MemTotal: 1 kB\n", 1024).is_err());
+    }
+    
+    #[test]
+    fn cpu_topology_and_pinning_become_virt_install_args() {
+        let plain = test_provisioner(|c| c.vcpus = 4);
+        assert_eq!(plain.vcpus_arg(), "4");
+        assert_eq!(plain.cputune_arg(), None);
+        
+        let tuned = test_provisioner(|c| {
+            c.vcpus = 4;
+            c.cpu_topology = Some((1, 2, 2));
+            c.cpu_pinning = Some(vec![2, 3, 6, 7]);
+        });
+        assert_eq!(tuned.vcpus_arg(), "4,sockets=1,cores=2,threads=2");
+        assert_eq!(tuned.cputune_arg().unwrap(),
+                   "vcpupin0.vcpu=0,vcpupin0.cpuset=2,vcpupin1.vcpu=1,vcpupin1.cpuset=3,\
+                    vcpupin2.vcpu=2,vcpupin2.cpuset=6,vcpupin3.vcpu=3,vcpupin3.cpuset=7");
+    }
+    
+    #[test]
+    fn shared_folders_become_virtiofs_devices_and_guest_mounts() {
+        let plain = test_provisioner(|_| {});
+        assert_eq!(plain.memory_backing_arg(), None);
+        assert!(plain.filesystem_args().is_empty());
+        assert!(plain.render_kickstart().contains("# No shared folders"));
+        
+        let shared = test_provisioner(|c| {
+            c.shared_folders = vec![(PathBuf::from("/home/me/drop"), "drop".to_string())];
+            c.enable_hugepages = true;
+        });
+        assert_eq!(shared.memory_backing_arg().unwrap(), "hugepages=on,source.type=memfd,access.mode=shared");
+        assert_eq!(shared.filesystem_args(), vec!["/home/me/drop,drop,driver.type=virtiofs"]);
+        
+        let kickstart = shared.render_kickstart();
+        assert!(kickstart.contains("cat > /etc/systemd/system/mnt-drop.mount << 'EOF'"));
+        assert!(kickstart.contains("What=drop\nWhere=/mnt/drop\nType=virtiofs"));
+        assert!(kickstart.contains("systemctl enable mnt-drop.mount"));
+        assert!(shared.render_cloud_init_user_data().unwrap().contains("/etc/systemd/system/mnt-drop.mount"));
+    }
+    
+    #[test]
+    fn network_arg_follows_network_mode_and_mac() {
+        assert_eq!(test_provisioner(|_| {}).network_arg(), "network=default,model=virtio");
+        assert_eq!(test_provisioner(|c| c.network_mode = NetworkMode::None).network_arg(), "none");
+        
+        let provisioner = test_provisioner(|c| {
+            c.network_mode = NetworkMode::Bridge("br0".to_string());
+            c.mac_address = Some("52:54:00:ab:cd:ef".to_string());
+        });
+        assert_eq!(provisioner.network_arg(), "bridge=br0,model=virtio,mac=52:54:00:ab:cd:ef");
+    }
+    
+    #[test]
+    fn install_progress_shows_steps_and_sampled_package_counts() {
+        assert_eq!(install_progress("Starting package installation process\r").as_deref(),
+                   Some("Starting package installation process"));
+        assert_eq!(install_progress("Running post-installation scripts").as_deref(),
+                   Some("Running post-installation scripts"));
+        assert_eq!(install_progress("Installing bash.x86_64 (200/612)").as_deref(),
+                   Some("📦 Installing packages (200/612)"));
+        assert_eq!(install_progress("Installing bash.x86_64 (612/612)").as_deref(),
+                   Some("📦 Installing packages (612/612)"));
+        assert_eq!(install_progress("Installing bash.x86_64 (201/612)"), None);
+        assert_eq!(install_progress("[   12.345678] virtio_net virtio1 enp1s0: renamed"), None);
+    }
+    
+    #[test]
+    fn install_error_includes_the_installer_tail() {
+        let error = InstallError {
+            code: Some(1),
+            reason: "ERROR    Installation failed".to_string(),
+            network: false,
+            tail: vec!["Running post-installation scripts".to_string(), "Pane is dead".to_string()],
+        };
+        assert_eq!(error.to_string(), "VM installation failed with exit code Some(1): ERROR    Installation failed\n\
+                                       Last installer output:\n    Running post-installation scripts\n    Pane is dead");
+    }
+    
+    #[test]
+    fn notifications_forwarded_through_dunst_only_when_enabled() {
+        let provisioner = test_provisioner(|_| {});
+        assert!(!provisioner.render_services().contains("guest-agent-notify"));
+        assert!(!provisioner.render_packages().contains("dunst"));
+        
+        let provisioner = test_provisioner(|c| c.enable_notifications = true);
+        let services = provisioner.render_services();
+        assert!(services.contains("exec /usr/local/bin/guest-agent notify \"$1\" \"$2\" \"$3\" \"$4\""));
+        assert!(services.contains("script = /usr/local/bin/guest-agent-notify"));
+        assert!(provisioner.render_packages().lines().any(|l| l == "dunst"));
+    }
+    
+    #[test]
+    fn open_url_handler_registered_only_when_enabled() {
+        assert!(!test_provisioner(|_| {}).render_services().contains("vm-open-url.desktop"));
+        
+        let services = test_provisioner(|c| c.open_urls_on_host = true).render_services();
+        assert!(services.contains("Exec=/usr/local/bin/guest-agent open-url %u"));
+        assert!(services.contains("x-scheme-handler/https=vm-open-url.desktop"));
+    }
+    
+    #[test]
+    fn console_log_keeps_a_pty_serial() {
+        assert_eq!(test_provisioner(|_| {}).serial_log_arg(), None);
+        
+        let provisioner = test_provisioner(|c| c.console_log = Some(PathBuf::from("/var/log/libvirt/qemu/test-vm-console.log")));
+        assert_eq!(provisioner.serial_log_arg().as_deref(),
+                   Some("pty,log.file=/var/log/libvirt/qemu/test-vm-console.log,log.append=on"));
+    }
+    
+    #[test]
+    fn qcow2_options_only_when_configured() {
+        assert_eq!(test_provisioner(|_| {}).qcow2_options(), None);
+        
+        let options = test_provisioner(|c| {
+            c.qcow2_cluster_size = Some(2 * 1024 * 1024);
+            c.qcow2_preallocation = Preallocation::Metadata;
+        }).qcow2_options();
+        assert_eq!(options.as_deref(), Some("cluster_size=2097152,preallocation=metadata"));
+    }
+    
+    #[test]
+    fn domstats_give_cpu_and_memory_of_live_domains() {
+        let running = "Domain: 'work-vm'\n  state.state=1\n  state.reason=1\n  cpu.time=5000000000\n  \
+                       balloon.current=4194304\n  balloon.maximum=4194304\n  balloon.available=4000000\n  \
+                       balloon.unused=3000000\n  balloon.rss=2500000\n  vcpu.current=2\n  vcpu.maximum=2\n";
+        assert_eq!(DomainStats::parse(running), Some(DomainStats {
+            paused: false,
+            cpu_time: 5_000_000_000,
+            vcpus: 2,
+            vcpus_max: 2,
+            memory_used_kib: Some(1_000_000),
+            memory_max_kib: 4_194_304,
+        }));
+        
+        // Without guest memory stats the host-side RSS is used
+        let paused = "Domain: 'work-vm'\n  state.state=3\n  cpu.time=1\n  balloon.maximum=2048\n  balloon.rss=1024\n";
+        let stats = DomainStats::parse(paused).unwrap();
+        assert!(stats.paused);
+        assert_eq!((stats.vcpus, stats.vcpus_max, stats.memory_used_kib), (1, 1, Some(1024)));
+        
+        assert_eq!(DomainStats::parse("Domain: 'work-vm'\n  state.state=5\n  cpu.time=0\n"), None);
+    }
+    
+    #[test]
+    fn cpu_percent_is_relative_to_all_vcpus() {
+        let second = Duration::from_secs(1);
+        assert_eq!(cpu_percent(0, 1_000_000_000, second, 2), 50.0);
+        assert_eq!(cpu_percent(0, 2_000_000_000, second, 2), 100.0);
+        assert_eq!(cpu_percent(5, 1, second, 1), 0.0);
+    }
+    
+    #[test]
+    fn rebuild_installs_only_missing_packages() {
+        let desired = vec!["mpv".to_string(), "gimp".to_string(), "@development-tools".to_string()];
+        assert_eq!(missing_packages(&desired, "bash\nmpv\n"), vec!["gimp", "@development-tools"]);
+        
+        let provisioner = test_provisioner(|c| c.auto_launch_apps = vec!["mpv".to_string()]);
+        let script = provisioner.render_rebuild_script(&["gimp".to_string()], &[]);
+        assert!(script.starts_with("set -e\ndnf install -y gimp\n"));
+        assert!(!script.contains("flatpak install"));
+        assert!(script.contains("sed -i '/^exec --no-startup-id /d' /home/user/.config/i3/config"));
+        assert!(script.contains("echo \"exec --no-startup-id mpv\" >> /home/user/.config/i3/config"));
+        assert!(script.contains("systemctl enable auto-launch-1.service"));
+        assert!(script.contains(&provisioner.guest_agent_unit()));
+        
+        let script = provisioner.render_rebuild_script(&[], &["org.example.App".to_string()]);
+        assert!(script.contains("flatpak install -y --noninteractive flathub org.example.App\n"));
+    }
+    
+    #[test]
+    fn usb_devices_are_listed_and_passed_through() {
+        let lsusb = "Bus 002 Device 001: ID 1d6b:0003 Linux Foundation 3.0 root hub\n\
+                     Bus 001 Device 003: ID 046D:c52b Logitech, Inc. Unifying Receiver\n\
+                     Bus 001 Device 004: ID 1234:5678\n";
+        assert_eq!(parse_lsusb(lsusb), vec![
+            UsbDevice { id: "046d:c52b".to_string(), description: "Logitech, Inc. Unifying Receiver".to_string() },
+            UsbDevice { id: "1234:5678".to_string(), description: String::new() },
+        ]);
+        
+        let provisioner = test_provisioner(|c| c.usb_devices = vec!["046d:c52b".to_string()]);
+        assert_eq!(provisioner.hostdev_args(), vec!["0x046d:0xc52b"]);
+    }
+    
+    #[test]
+    fn display_addresses_come_from_domdisplay_uris() {
+        assert_eq!(display_address("spice://127.0.0.1:5901").as_deref(), Some("127.0.0.1:5901"));
+        assert_eq!(display_address("spice://localhost:5902/").as_deref(), Some("localhost:5902"));
+        assert_eq!(display_address("spice://127.0.0.1?tls-port=5903").as_deref(), Some("127.0.0.1:5903"));
+        assert_eq!(display_address("vnc://127.0.0.1:1").as_deref(), Some("127.0.0.1:5901"));
+        assert_eq!(display_address("vnc://127.0.0.1:5904").as_deref(), Some("127.0.0.1:5904"));
+        assert_eq!(display_address("spice+unix:///run/libvirt/qemu/spice.sock"), None);
+        assert_eq!(display_address("spice://127.0.0.1"), None);
+    }
+    
+    #[test]
+    fn virtual_size_comes_from_qemu_img_info() {
+        let info = r#"{
+    "virtual-size": 21474836480,
+    "filename": "/var/lib/libvirt/images/work-vm.qcow2",
+    "format": "qcow2",
+    "actual-size": 4391411712
+}"#;
+        assert_eq!(qcow2_virtual_size(info), Some(20 * 1024 * 1024 * 1024));
+        assert_eq!(qcow2_virtual_size("qemu-img: Could not open"), None);
+    }
+    
+    #[test]
+    fn bundles_are_told_apart_from_configs_by_their_tar_header() {
+        let mut header = vec![0u8; 512];
+        header[..7].copy_from_slice(b"vm.toml");
+        header[257..263].copy_from_slice(b"ustar\0");
+        let bundle = tempfile::NamedTempFile::new().unwrap();
+        fs::write(bundle.path(), &header).unwrap();
+        assert!(is_bundle(bundle.path()).unwrap());
+        
+        let config = tempfile::NamedTempFile::new().unwrap();
+        fs::write(config.path(), "name = \"work-vm\"\n").unwrap();
+        assert!(!is_bundle(config.path()).unwrap());
+    }
+    
+    #[test]
+    fn spice_password_stays_off_the_virt_install_command_line() {
+        let provisioner = test_provisioner(|c| {
+            c.spice_listen = Some("0.0.0.0".to_string());
+            c.spice_password = Some("display-secret".to_string());
+        });
+        let (args, _) = provisioner.virt_install_args(&InstallMedia::Installed, "/vms/test-vm.qcow2").unwrap();
+        assert!(args.iter().all(|arg| !arg.contains("display-secret")));
+        let graphics = args.iter().position(|arg| arg == "--graphics").map(|i| &args[i + 1]);
+        assert!(graphics.is_some_and(|spice| spice.starts_with("spice,listen=127.0.0.1")));
+        
+        let xml = provisioner.spice_graphics_xml().unwrap();
+        assert!(xml.contains("passwd='display-secret'"));
+        assert!(xml.contains("address='0.0.0.0'"));
+    }
+    
+    #[test]
+    fn spice_graphics_xml_escapes_the_password() {
+        assert_eq!(test_provisioner(|_| {}).spice_graphics_xml(), None);
+        let xml = test_provisioner(|c| c.spice_password = Some("a'b<&".to_string())).spice_graphics_xml().unwrap();
+        assert!(xml.contains("passwd='a&apos;b&lt;&amp;'"));
+        assert!(xml.contains("address='127.0.0.1'"));
+    }
 }
@@ -0,0 +1,162 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::AppEntry;
+
+/// Where a VM is in its lifecycle, as far as vm-provisioner knows
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VMPhase {
+    #[default]
+    Unknown,
+    Installing,
+    Installed,
+    InstallFailed,
+}
+
+/// Per-VM runtime state, kept apart from the user's configuration in
+/// `$XDG_STATE_HOME/vm-provisioner/<name>.toml` (`~/.local/state` by default)
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct VMState {
+    pub phase: VMPhase,
+    #[serde(default)]
+    pub updated_at: u64,  // Seconds since the Unix epoch
+    #[serde(default)]
+    pub install_attempts: u32,
+    #[serde(default)]
+    pub last_failure: Option<String>,
+    #[serde(default)]
+    pub applications: Vec<AppEntry>,  // As last reported by the guest agent
+    #[serde(default)]
+    pub agent_connected_at: Option<u64>,  // Last successful guest agent handshake
+    #[serde(default)]
+    pub expires_at: Option<u64>,  // Set on first start when the config has a max_lifetime
+    #[serde(default)]
+    pub display: Option<String>,  // Display URI libvirt assigned on the last start
+    #[serde(default)]
+    pub proxy_port: Option<u16>,  // TCP or vsock port the window proxy last listened on
+}
+
+impl VMState {
+    pub fn load(vm_name: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = state_path(vm_name)?;
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        
+        Ok(toml::from_str(&fs::read_to_string(&path)?)?)
+    }
+    
+    pub fn save(&self, vm_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let path = state_path(vm_name)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        
+        fs::write(&path, toml::to_string_pretty(self)?)?;
+        Ok(())
+    }
+    
+    /// Load, modify and save the VM's state, stamping the update time
+    pub fn update(vm_name: &str, f: impl FnOnce(&mut Self)) -> Result<(), Box<dyn std::error::Error>> {
+        let mut state = Self::load(vm_name)?;
+        f(&mut state);
+        state.updated_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        state.save(vm_name)
+    }
+    
+    /// Record a new phase for the VM
+    pub fn set_phase(vm_name: &str, phase: VMPhase) -> Result<(), Box<dyn std::error::Error>> {
+        Self::update(vm_name, |state| state.phase = phase)
+    }
+    
+    pub fn remove(vm_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let path = state_path(vm_name)?;
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+        Ok(())
+    }
+}
+
+fn state_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let state_dir = match std::env::var("XDG_STATE_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var("HOME")?).join(".local/state"),
+    };
+    Ok(state_dir.join("vm-provisioner"))
+}
+
+fn state_path(vm_name: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    Ok(state_dir()?.join(format!("{}.toml", vm_name)))
+}
+
+/// Where the window proxy logs what it hears from a VM's guest agent
+pub fn proxy_log_path(vm_name: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    Ok(state_dir()?.join(format!("{}-proxy.log", vm_name)))
+}
+
+/// Seconds since the Unix epoch
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Human-readable time left until `expires_at`, or "expired"
+pub fn describe_expiry(expires_at: u64, now: u64) -> String {
+    let Some(left) = expires_at.checked_sub(now).filter(|left| *left > 0) else {
+        return "expired".to_string();
+    };
+    
+    let (days, hours, minutes) = (left / 86400, left % 86400 / 3600, left % 3600 / 60);
+    if days > 0 {
+        format!("in {}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("in {}h {}m", hours, minutes)
+    } else {
+        format!("in {}m", minutes.max(1))
+    }
+}
+
+/// Reconcile the recorded phase with what libvirt reports (`virsh domstate`).
+///
+/// A detached install finishes when the installer reboots: the install-time
+/// domain is set to stop rather than reboot, so "shut off" means done.
+pub fn reconcile_phase(phase: VMPhase, domstate: Option<&str>) -> VMPhase {
+    match (phase, domstate) {
+        (VMPhase::Installing, Some("shut off")) => VMPhase::Installed,
+        (VMPhase::Installing, None) => VMPhase::InstallFailed,
+        (phase, _) => phase,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn detached_install_completes_when_domain_shuts_off() {
+        assert_eq!(reconcile_phase(VMPhase::Installing, Some("running")), VMPhase::Installing);
+        assert_eq!(reconcile_phase(VMPhase::Installing, Some("shut off")), VMPhase::Installed);
+        assert_eq!(reconcile_phase(VMPhase::Installing, None), VMPhase::InstallFailed);
+        assert_eq!(reconcile_phase(VMPhase::Installed, Some("running")), VMPhase::Installed);
+    }
+    
+    #[test]
+    fn expiry_is_described_relative_to_now() {
+        assert_eq!(describe_expiry(1000, 1000), "expired");
+        assert_eq!(describe_expiry(1000, 2000), "expired");
+        assert_eq!(describe_expiry(1030, 1000), "in 1m");
+        assert_eq!(describe_expiry(1000 + 3 * 3600 + 25 * 60, 1000), "in 3h 25m");
+        assert_eq!(describe_expiry(1000 + 2 * 86400 + 5 * 3600, 1000), "in 2d 5h");
+    }
+    
+    #[test]
+    fn state_round_trips_through_toml() {
+        let state = VMState { phase: VMPhase::Installing, updated_at: 42, ..Default::default() };
+        let parsed: VMState = toml::from_str(&toml::to_string_pretty(&state).unwrap()).unwrap();
+        assert_eq!(parsed.phase, VMPhase::Installing);
+        assert_eq!(parsed.updated_at, 42);
+    }
+}
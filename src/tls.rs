@@ -0,0 +1,328 @@
+//! TLS for the window proxy's TCP transport. Each VM gets its own self-signed
+//! certificate and key, which the host and the guest agent both hold; each end
+//! presents it and only accepts a peer presenting the very same certificate, so
+//! nothing else on the network can talk to either side. Both binaries compile
+//! this file.
+
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider, WebPkiSupportedAlgorithms};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::{ClientConfig, ClientConnection, Connection, DigitallySignedStruct, DistinguishedName, ServerConfig, ServerConnection, SignatureScheme};
+
+/// Name in the certificate; peers are pinned, so it's never looked up
+const SERVER_NAME: &str = "vm-provisioner";
+
+/// How long a peer gets to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where the guest agent finds the VM's certificate and key
+pub const GUEST_IDENTITY_PATH: &str = "/home/user/.config/vm-provisioner/proxy-tls.pem";
+
+/// A VM's certificate and key, ready for either end of the connection
+pub struct Identity {
+    server: Arc<ServerConfig>,
+    client: Arc<ClientConfig>,
+}
+
+impl Identity {
+    /// Parse a PEM file holding the certificate followed by its private key
+    pub fn from_pem(pem: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let cert = CertificateDer::from_pem_slice(pem.as_bytes())
+            .map_err(|e| format!("no certificate in the TLS identity: {}", e))?;
+        let key = PrivateKeyDer::from_pem_slice(pem.as_bytes())
+            .map_err(|e| format!("no private key in the TLS identity: {}", e))?;
+        
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let verifier = Arc::new(PinnedCert::new(cert.clone(), &provider));
+        
+        let server = ServerConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_client_cert_verifier(verifier.clone())
+            .with_single_cert(vec![cert.clone()], key.clone_key())?;
+        let client = ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .dangerous()
+            .with_custom_certificate_verifier(verifier)
+            .with_client_auth_cert(vec![cert], key)?;
+        
+        Ok(Self { server: Arc::new(server), client: Arc::new(client) })
+    }
+    
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let pem = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read TLS identity {}: {}", path, e))?;
+        Self::from_pem(&pem)
+    }
+}
+
+/// A new self-signed certificate and its key, as one PEM file
+pub fn generate_pem() -> Result<String, Box<dyn std::error::Error>> {
+    let rcgen::CertifiedKey { cert, key_pair } = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()])?;
+    Ok(format!("{}{}", cert.pem(), key_pair.serialize_pem()))
+}
+
+/// Where the host keeps a VM's TLS identity, next to its config
+pub fn host_identity_path(vm_name: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    Ok(PathBuf::from(std::env::var("HOME")?).join(".config/vm-provisioner").join(format!("{}-tls.pem", vm_name)))
+}
+
+/// The VM's TLS identity as PEM, generated and saved (readable by the owner only)
+/// the first time it's asked for
+pub fn ensure_host_identity(vm_name: &str) -> Result<String, Box<dyn std::error::Error>> {
+    use std::os::unix::fs::OpenOptionsExt;
+    
+    let path = host_identity_path(vm_name)?;
+    if path.exists() {
+        return Ok(std::fs::read_to_string(&path)?);
+    }
+    
+    let pem = generate_pem()?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?
+        .write_all(pem.as_bytes())?;
+    Ok(pem)
+}
+
+/// Run the server side of the handshake on a guest connection, rejecting peers
+/// that don't present the VM's certificate. The decrypted stream is returned as
+/// one end of a socket pair, which (unlike a TLS session) can be cloned for
+/// separate reader and writer threads.
+pub fn accept(tcp: TcpStream, identity: &Identity) -> std::io::Result<UnixStream> {
+    let conn = ServerConnection::new(identity.server.clone()).map_err(std::io::Error::other)?;
+    establish(Connection::Server(conn), tcp)
+}
+
+/// Client side of `accept`: connect to a host presenting the VM's certificate
+pub fn connect(tcp: TcpStream, identity: &Identity) -> std::io::Result<UnixStream> {
+    let name = ServerName::try_from(SERVER_NAME).map_err(std::io::Error::other)?;
+    let conn = ClientConnection::new(identity.client.clone(), name).map_err(std::io::Error::other)?;
+    establish(Connection::Client(conn), tcp)
+}
+
+fn establish(mut conn: Connection, mut tcp: TcpStream) -> std::io::Result<UnixStream> {
+    tcp.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    while conn.is_handshaking() {
+        if let Err(e) = conn.complete_io(&mut tcp) {
+            let _ = tcp.shutdown(Shutdown::Both);
+            return Err(e);
+        }
+    }
+    tcp.set_read_timeout(None)?;
+    
+    let (local, relayed) = UnixStream::pair()?;
+    std::thread::spawn(move || relay(conn, tcp, relayed));
+    Ok(local)
+}
+
+/// Move data between the TLS connection and the local end of the socket pair
+/// until either side closes
+fn relay(mut conn: Connection, mut tcp: TcpStream, mut local: UnixStream) {
+    let mut buf = [0u8; 16 * 1024];
+    
+    'relay: loop {
+        let mut fds = [
+            libc::pollfd { fd: tcp.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+            libc::pollfd { fd: local.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+        ];
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+            if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            break;
+        }
+        
+        if fds[0].revents != 0 {
+            match conn.read_tls(&mut tcp) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+            if conn.process_new_packets().is_err() {
+                // Let the peer know why with a TLS alert
+                let _ = conn.write_tls(&mut tcp);
+                break;
+            }
+            loop {
+                match conn.reader().read(&mut buf) {
+                    Ok(0) => break 'relay,
+                    Ok(n) => {
+                        if local.write_all(&buf[..n]).is_err() {
+                            break 'relay;
+                        }
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                    Err(_) => break 'relay,
+                }
+            }
+        }
+        
+        if fds[1].revents != 0 {
+            match local.read(&mut buf) {
+                Ok(0) | Err(_) => {
+                    conn.send_close_notify();
+                    let _ = conn.write_tls(&mut tcp);
+                    break;
+                }
+                Ok(n) => {
+                    if conn.writer().write_all(&buf[..n]).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+        
+        while conn.wants_write() {
+            if conn.write_tls(&mut tcp).is_err() {
+                break 'relay;
+            }
+        }
+    }
+    
+    let _ = tcp.shutdown(Shutdown::Both);
+    let _ = local.shutdown(Shutdown::Both);
+}
+
+/// Accepts exactly one certificate, as server or client. Handshake signatures
+/// are still checked, which proves the peer holds the matching key.
+#[derive(Debug)]
+struct PinnedCert {
+    cert: CertificateDer<'static>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl PinnedCert {
+    fn new(cert: CertificateDer<'static>, provider: &CryptoProvider) -> Self {
+        Self { cert, algorithms: provider.signature_verification_algorithms }
+    }
+    
+    fn check(&self, end_entity: &CertificateDer<'_>) -> Result<(), rustls::Error> {
+        if end_entity.as_ref() == self.cert.as_ref() {
+            Ok(())
+        } else {
+            Err(rustls::Error::InvalidCertificate(rustls::CertificateError::ApplicationVerificationFailure))
+        }
+    }
+}
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.check(end_entity).map(|_| ServerCertVerified::assertion())
+    }
+    
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+    
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+    
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+impl ClientCertVerifier for PinnedCert {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+    
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.check(end_entity).map(|_| ClientCertVerified::assertion())
+    }
+    
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+    
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+    
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    
+    /// Handshake a loopback connection, the host side with `host` and the guest with `guest`
+    fn handshake(host: Identity, guest: Identity) -> (std::io::Result<UnixStream>, std::io::Result<UnixStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || accept(listener.accept().unwrap().0, &host));
+        let client = connect(TcpStream::connect(addr).unwrap(), &guest);
+        (server.join().unwrap(), client)
+    }
+    
+    #[test]
+    fn peers_with_the_same_identity_talk_through_the_relay() {
+        let pem = generate_pem().unwrap();
+        let (host, guest) = handshake(Identity::from_pem(&pem).unwrap(), Identity::from_pem(&pem).unwrap());
+        let (mut host, mut guest) = (host.unwrap(), guest.unwrap());
+        
+        guest.write_all(b"hello").unwrap();
+        let mut buf = [0u8; 5];
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        
+        host.write_all(b"world").unwrap();
+        guest.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"world");
+        
+        // Closing one end closes the other
+        drop(host);
+        assert_eq!(guest.read(&mut buf).unwrap(), 0);
+    }
+    
+    #[test]
+    fn peers_with_another_identity_are_rejected() {
+        let host = Identity::from_pem(&generate_pem().unwrap()).unwrap();
+        let stranger = Identity::from_pem(&generate_pem().unwrap()).unwrap();
+        let (host, stranger) = handshake(host, stranger);
+        
+        // The stranger rejects the host's certificate, and the host never gets a session
+        assert!(stranger.is_err());
+        assert!(host.is_err());
+    }
+    
+    #[test]
+    fn identity_needs_certificate_and_key() {
+        let pem = generate_pem().unwrap();
+        let cert_only: String = pem.split_inclusive('\n')
+            .take_while(|line| !line.contains("PRIVATE KEY"))
+            .collect();
+        assert!(Identity::from_pem(&cert_only).is_err());
+        assert!(Identity::from_pem("").is_err());
+    }
+}
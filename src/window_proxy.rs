@@ -1,397 +1,1125 @@
 use std::collections::HashMap;
+use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
-use std::os::unix::net::UnixStream;
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use wayland_client::{Connection, Dispatch, QueueHandle, protocol::{
-    wl_compositor, wl_surface, wl_shm, wl_seat, wl_keyboard, wl_pointer,
-    wl_registry, wl_output,
+use wayland_client::{Connection, Dispatch, EventQueue, Proxy, QueueHandle, WEnum, protocol::{
+    wl_compositor, wl_surface, wl_shm, wl_shm_pool, wl_buffer, wl_seat, wl_keyboard, wl_pointer,
+    wl_registry,
 }};
 use wayland_protocols::xdg::shell::client::{
     xdg_wm_base, xdg_surface, xdg_toplevel,
 };
+use wayland_protocols::xdg::activation::v1::client::{
+    xdg_activation_v1, xdg_activation_token_v1,
+};
+
+use crate::config::{AppVMConfig, ProxyTransport};
+use crate::protocol::{clipboard_changed, clipboard_contents, is_text_type, pick_clipboard_type, read_frame, send_message, write_frame, ClipboardContents, ClipboardMessage, PointerAction, WindowMessage, HEARTBEAT_TIMEOUT, PING_INTERVAL, PROTOCOL_VERSION};
+use crate::state::VMState;
+use crate::tls;
+
+/// Size past which the proxy log starts over when a guest agent connects
+const MAX_PROXY_LOG_SIZE: u64 = 1024 * 1024;
+
+/// Timestamped record of a VM's guest agent connections and messages, read back
+/// by `vm-provisioner logs`. Logging is best effort: a log that can't be opened
+/// or written is skipped.
+struct ProxyLog(Option<std::fs::File>);
 
-use serde::{Serialize, Deserialize};
-
-/// Messages sent from guest to host about window state
-#[derive(Debug, Serialize, Deserialize)]
-pub enum WindowMessage {
-    // Window lifecycle
-    WindowCreated { 
-        id: u32, 
-        title: String,
-        width: u32,
-        height: u32,
-        x: i32,
-        y: i32,
-        app_name: String,
-    },
-    WindowDestroyed { 
-        id: u32 
-    },
-    WindowMoved { 
-        id: u32, 
-        x: i32, 
-        y: i32 
-    },
-    WindowResized { 
-        id: u32, 
-        width: u32, 
-        height: u32 
-    },
-    WindowTitleChanged { 
-        id: u32, 
-        title: String 
-    },
-    WindowFocusChanged { 
-        id: u32, 
-        focused: bool 
-    },
-    
-    // Application lifecycle
-    ApplicationStarted { 
-        app_name: String,
-        pid: u32,
-    },
-    ApplicationStopped { 
-        app_name: String,
-        pid: u32,
-    },
+impl ProxyLog {
+    fn open(vm_name: &str) -> Self {
+        let file = crate::state::proxy_log_path(vm_name).ok().and_then(|path| {
+            std::fs::create_dir_all(path.parent()?).ok()?;
+            let too_big = std::fs::metadata(&path).map(|m| m.len() > MAX_PROXY_LOG_SIZE).unwrap_or(false);
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(!too_big)
+                .write(true)
+                .truncate(too_big)
+                .open(&path)
+                .ok()
+        });
+        ProxyLog(file)
+    }
+    
+    /// Append `line` after the Unix time with microseconds, like `journalctl -o short-unix`
+    fn write(&mut self, line: &str) {
+        if let Some(file) = &mut self.0 {
+            let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+            let _ = writeln!(file, "{}.{:06} {}", now.as_secs(), now.subsec_micros(), line);
+        }
+    }
 }
 
 /// Represents a proxied window from a VM
 pub struct ProxiedWindow {
-    vm_window_id: u32,
     surface: wl_surface::WlSurface,
     xdg_surface: xdg_surface::XdgSurface,
     xdg_toplevel: xdg_toplevel::XdgToplevel,
+    buffer: Option<wl_buffer::WlBuffer>,  // Set once the compositor has configured the toplevel
     width: u32,
     height: u32,
-    title: String,
-}
-
-/// Main window proxy that manages VM windows on the host
-pub struct WindowProxy {
-    connection: Connection,
-    windows: Arc<Mutex<HashMap<u32, ProxiedWindow>>>,
-    vm_connection: Arc<Mutex<UnixStream>>,
-    compositor: Option<wl_compositor::WlCompositor>,
-    shm: Option<wl_shm::WlShm>,
-    xdg_wm_base: Option<xdg_wm_base::XdgWmBase>,
 }
 
-impl WindowProxy {
-    pub fn new(vm_socket_path: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        // Connect to host Wayland compositor
-        let connection = Connection::connect_to_env()?;
-        
-        // Connect to VM via Unix socket (or virtio channel)
-        let vm_connection = UnixStream::connect(vm_socket_path)?;
-        
-        Ok(Self {
-            connection,
-            windows: Arc::new(Mutex::new(HashMap::new())),
-            vm_connection: Arc::new(Mutex::new(vm_connection)),
-            compositor: None,
-            shm: None,
-            xdg_wm_base: None,
-        })
+impl ProxiedWindow {
+    /// Show a blank buffer of the guest window's size, which maps the toplevel.
+    /// The protocol carries no window contents, so the window only stands in for
+    /// the guest's: it takes input and its close button, and follows its size.
+    fn attach_placeholder(&mut self, shm: &wl_shm::WlShm, qh: &QueueHandle<AppState>) {
+        let buffer = match placeholder_buffer(shm, qh, self.width, self.height) {
+            Ok(buffer) => buffer,
+            Err(e) => {
+                eoutln!("⚠️  Failed to create a buffer for a VM window: {}", e);
+                return;
+            }
+        };
+        self.surface.attach(Some(&buffer), 0, 0);
+        self.surface.damage_buffer(0, 0, i32::MAX, i32::MAX);
+        self.surface.commit();
+        if let Some(old) = self.buffer.replace(buffer) {
+            old.destroy();
+        }
     }
     
-    pub fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        println!("🪟 Window Proxy started");
-        
-        // Setup Wayland globals
-        self.setup_wayland()?;
-        
-        // Spawn thread to handle VM messages
-        let windows = self.windows.clone();
-        let vm_conn = self.vm_connection.clone();
-        let compositor = self.compositor.clone();
-        let xdg_wm_base = self.xdg_wm_base.clone();
-        
-        std::thread::spawn(move || {
-            Self::handle_vm_messages(vm_conn, windows, compositor, xdg_wm_base);
-        });
-        
-        // Main Wayland event loop
-        loop {
-            self.connection.flush()?;
-            
-            // Process Wayland events
-            let mut event_queue = self.connection.new_event_queue();
-            event_queue.blocking_dispatch(&mut AppState::default())?;
+    /// Destroy the toplevel and the objects it was built on, innermost first
+    fn destroy(self) {
+        self.xdg_toplevel.destroy();
+        self.xdg_surface.destroy();
+        self.surface.destroy();
+        if let Some(buffer) = self.buffer {
+            buffer.destroy();
         }
     }
+}
+
+/// Largest side of a placeholder buffer, so a bogus guest size can't exhaust memory
+const MAX_WINDOW_SIDE: u32 = 8192;
+
+/// An opaque dark grey ARGB8888 buffer in a fresh shared memory file
+fn placeholder_buffer(shm: &wl_shm::WlShm, qh: &QueueHandle<AppState>, width: u32, height: u32) -> std::io::Result<wl_buffer::WlBuffer> {
+    let width = width.clamp(1, MAX_WINDOW_SIDE) as i32;
+    let height = height.clamp(1, MAX_WINDOW_SIDE) as i32;
+    let stride = width * 4;
+    
+    // SAFETY: memfd_create takes a NUL-terminated name and returns a new descriptor or -1
+    let fd = unsafe { libc::memfd_create(c"vm-window".as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // SAFETY: fd was just created and is owned by nothing else
+    let mut file = unsafe { std::fs::File::from_raw_fd(fd) };
+    file.write_all(&[0x30, 0x30, 0x30, 0xff].repeat((width * height) as usize))?;
     
-    fn setup_wayland(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let display = self.connection.display();
-        let mut event_queue = self.connection.new_event_queue();
+    let pool = shm.create_pool(std::os::fd::AsFd::as_fd(&file), stride * height, qh, ());
+    let buffer = pool.create_buffer(0, width, height, stride, wl_shm::Format::Argb8888, qh, ());
+    pool.destroy();
+    Ok(buffer)
+}
+
+/// Shows the guest's windows as native toplevels on the host's Wayland compositor
+/// and sends what the user does with them back to the guest
+pub struct WindowProxy {
+    event_queue: EventQueue<AppState>,
+    state: AppState,
+    native: NativeWindows,
+    guest: Arc<Mutex<Option<GuestStream>>>,
+}
+
+impl WindowProxy {
+    /// Connect to the host compositor. Input and close requests go to whichever
+    /// guest agent is connected through `guest` at the time.
+    fn new(guest: Arc<Mutex<Option<GuestStream>>>) -> Result<Self, Box<dyn std::error::Error>> {
+        let connection = Connection::connect_to_env()?;
+        let mut event_queue = connection.new_event_queue();
         let qh = event_queue.handle();
+        let windows = Arc::new(Mutex::new(HashMap::new()));
+        let mut state = AppState { windows: windows.clone(), ..AppState::default() };
         
-        // Get registry and bind globals
-        let _registry = display.get_registry(&qh, ());
+        // The roundtrip waits until every global has been advertised (and bound by the registry handler)
+        connection.display().get_registry(&qh, ());
+        event_queue.roundtrip(&mut state)?;
         
-        // This would normally bind compositor, shm, xdg_wm_base, etc.
-        // Simplified for example
+        let Some(xdg_wm_base) = state.xdg_wm_base.clone() else {
+            return Err("the host compositor does not support xdg-shell (xdg_wm_base); \
+                        run under a Wayland compositor such as GNOME, KDE Plasma or Sway".into());
+        };
+        let (Some(compositor), Some(shm)) = (state.compositor.clone(), state.shm.clone()) else {
+            return Err("the host compositor does not offer wl_compositor and wl_shm".into());
+        };
         
-        event_queue.blocking_dispatch(&mut AppState::default())?;
+        // Optional: without it the compositor alone decides which window is on top
+        let activation = state.activation.clone();
+        if activation.is_none() {
+            eoutln!("⚠️  The host compositor does not support xdg-activation-v1; windows raised in the VM won't be raised on the host");
+        }
         
-        Ok(())
+        let native = NativeWindows { connection, qh, compositor, shm, xdg_wm_base, activation, windows };
+        Ok(Self { event_queue, state, native, guest })
     }
     
-    fn handle_vm_messages(
-        vm_conn: Arc<Mutex<UnixStream>>,
-        windows: Arc<Mutex<HashMap<u32, ProxiedWindow>>>,
-        compositor: Option<wl_compositor::WlCompositor>,
-        xdg_wm_base: Option<xdg_wm_base::XdgWmBase>,
-    ) {
-        let mut buffer = [0u8; 4096];
-        
+    /// Dispatch host compositor events until the connection to it fails
+    fn run(mut self) -> Result<(), Box<dyn std::error::Error>> {
         loop {
-            let mut conn = vm_conn.lock().unwrap();
-            
-            match conn.read(&mut buffer) {
-                Ok(n) if n > 0 => {
-                    // Parse message from VM
-                    if let Ok(msg) = bincode::deserialize::<WindowMessage>(&buffer[..n]) {
-                        Self::handle_vm_message(msg, &windows, &compositor, &xdg_wm_base);
-                    }
-                }
-                _ => {
-                    std::thread::sleep(std::time::Duration::from_millis(10));
-                }
-            }
+            self.native.connection.flush()?;
+            self.event_queue.blocking_dispatch(&mut self.state)?;
+            forward_input(&mut self.state, &self.guest);
         }
     }
-    
-    fn handle_vm_message(
-        msg: WindowMessage,
-        windows: &Arc<Mutex<HashMap<u32, ProxiedWindow>>>,
-        compositor: &Option<wl_compositor::WlCompositor>,
-        xdg_wm_base: &Option<xdg_wm_base::XdgWmBase>,
-    ) {
+}
+
+/// The native toplevels standing in for the guest's windows, shared between the
+/// compositor event loop and the guest connection that creates and destroys them
+#[derive(Clone)]
+struct NativeWindows {
+    connection: Connection,
+    qh: QueueHandle<AppState>,
+    compositor: wl_compositor::WlCompositor,
+    shm: wl_shm::WlShm,
+    xdg_wm_base: xdg_wm_base::XdgWmBase,
+    activation: Option<xdg_activation_v1::XdgActivationV1>,
+    windows: Arc<Mutex<HashMap<u32, ProxiedWindow>>>,
+}
+
+impl NativeWindows {
+    /// Mirror a change the guest reported on the matching native window
+    fn apply(&self, msg: &WindowMessage) {
         match msg {
-            WindowMessage::WindowCreated { id, title, width, height, x, y, app_name } => {
-                println!("🪟 Creating native window for VM window {} '{}' ({}x{}+{}+{}) [{}]", 
-                         id, title, width, height, x, y, app_name);
+            WindowMessage::WindowCreated { id, title, width, height, .. } => {
+                // The surface carries the VM window ID, so input focus can be traced back to it
+                let surface = self.compositor.create_surface(&self.qh, *id);
+                let xdg_surface = self.xdg_wm_base.get_xdg_surface(&surface, &self.qh, *id);
+                let xdg_toplevel = xdg_surface.get_toplevel(&self.qh, *id);
+                xdg_toplevel.set_title(title.clone());
                 
-                // TODO: Create actual Wayland surface and XDG toplevel
-                // For now, just print the window info
-                let proxied_window = ProxiedWindow {
-                    vm_window_id: id,
-                    surface: unsafe { std::mem::zeroed() }, // Placeholder
-                    xdg_surface: unsafe { std::mem::zeroed() }, // Placeholder  
-                    xdg_toplevel: unsafe { std::mem::zeroed() }, // Placeholder
-                    width,
-                    height,
-                    title: title.clone(),
-                };
-                
-                windows.lock().unwrap().insert(id, proxied_window);
+                // The initial commit without a buffer asks the compositor for a configure
+                surface.commit();
                 
-                // TODO: Actually create the native window here
-                println!("   → Native window created for {}", title);
-            }
-            
-            WindowMessage::WindowDestroyed { id } => {
-                println!("🗑️  Destroying native window for VM window {}", id);
-                windows.lock().unwrap().remove(&id);
+                let window = ProxiedWindow { surface, xdg_surface, xdg_toplevel, buffer: None, width: *width, height: *height };
+                // A reused guest window ID replaces the stale toplevel
+                if let Some(stale) = self.windows.lock().unwrap().insert(*id, window) {
+                    stale.destroy();
+                }
             }
-            
+            WindowMessage::WindowDestroyed { id } => self.destroy(&[*id]),
             WindowMessage::WindowResized { id, width, height } => {
-                if let Some(window) = windows.lock().unwrap().get_mut(&id) {
-                    println!("📏 Resizing window {} to {}x{}", id, width, height);
-                    window.width = width;
-                    window.height = height;
-                    // TODO: Resize the actual Wayland surface
+                if let Some(window) = self.windows.lock().unwrap().get_mut(id) {
+                    window.width = *width;
+                    window.height = *height;
+                    // Not yet configured windows get the new size on their first configure
+                    if window.buffer.is_some() {
+                        window.attach_placeholder(&self.shm, &self.qh);
+                    }
                 }
             }
-            
             WindowMessage::WindowTitleChanged { id, title } => {
-                if let Some(window) = windows.lock().unwrap().get_mut(&id) {
-                    println!("📝 Window {} title changed to '{}'", id, title);
-                    window.title = title.clone();
-                    // TODO: Update the actual window title
-                    // window.xdg_toplevel.set_title(title);
+                if let Some(window) = self.windows.lock().unwrap().get(id) {
+                    window.xdg_toplevel.set_title(title.clone());
                 }
             }
-            
-            WindowMessage::WindowMoved { id, x, y } => {
-                println!("📍 Window {} moved to position ({}, {})", id, x, y);
-                // TODO: Update window position if supported
-            }
-            
-            WindowMessage::WindowFocusChanged { id, focused } => {
-                println!("🎯 Window {} focus changed: {}", id, focused);
-                // TODO: Update window focus state
-            }
-            
-            WindowMessage::ApplicationStarted { app_name, pid } => {
-                println!("🚀 Application started: {} (PID: {})", app_name, pid);
+            WindowMessage::StackingOrder { ids } => {
+                // xdg-shell has no raise/lower request, so only the topmost window can be
+                // acted on: it asks for activation, which the compositor may still refuse
+                let Some(activation) = &self.activation else {
+                    return;
+                };
+                let windows = self.windows.lock().unwrap();
+                let Some((top, window)) = ids.iter().rev().find_map(|id| windows.get(id).map(|w| (*id, w))) else {
+                    return;
+                };
+                outln!("🗂️  Asking the host compositor to activate window {}", top);
+                // The token comes back in a `done` event, where the window is activated
+                let token = activation.get_activation_token(&self.qh, top);
+                token.set_surface(&window.surface);
+                token.commit();
             }
-            
-            WindowMessage::ApplicationStopped { app_name, pid } => {
-                println!("⏹️  Application stopped: {} (PID: {})", app_name, pid);
+            // xdg-shell leaves placement and focus to the host compositor
+            _ => return,
+        }
+        if let Err(e) = self.connection.flush() {
+            eoutln!("Failed to flush Wayland requests: {}", e);
+        }
+    }
+    
+    /// Close the native windows of guest windows that are gone
+    fn destroy(&self, ids: &[u32]) {
+        let mut windows = self.windows.lock().unwrap();
+        for id in ids {
+            if let Some(window) = windows.remove(id) {
+                window.destroy();
             }
         }
+        drop(windows);
+        if let Err(e) = self.connection.flush() {
+            eoutln!("Failed to flush Wayland requests: {}", e);
+        }
+    }
+}
+
+/// Send what the user did with proxied windows to the guest. Without a connected
+/// guest agent nobody could act on it, so it is dropped.
+fn forward_input(state: &mut AppState, guest: &Mutex<Option<GuestStream>>) {
+    // Closing a proxied window asks the guest app to close; it stays open
+    // until the guest reports it destroyed, so unsaved work can be kept
+    let close_requests = std::mem::take(&mut state.close_requests).into_iter().map(|id| {
+        outln!("❎ Asking the VM to close window {}", id);
+        WindowMessage::CloseRequested { id }
+    });
+    let messages: Vec<WindowMessage> = close_requests.chain(std::mem::take(&mut state.input)).collect();
+    if messages.is_empty() {
+        return;
     }
     
-    fn send_to_vm(&self, msg: WindowMessage) -> Result<(), Box<dyn std::error::Error>> {
-        let data = bincode::serialize(&msg)?;
-        self.vm_connection.lock().unwrap().write_all(&data)?;
-        Ok(())
+    let mut guest = guest.lock().unwrap();
+    let Some(conn) = guest.as_mut() else {
+        return;
+    };
+    if let Err(e) = messages.iter().try_for_each(|msg| send_message(conn, msg)) {
+        eoutln!("⚠️  Failed to forward input to guest: {}", e);
+        *guest = None;
     }
 }
 
 // Simplified Wayland state for event handling
 #[derive(Default)]
 struct AppState {
-    // Would contain actual Wayland state
+    compositor: Option<wl_compositor::WlCompositor>,
+    shm: Option<wl_shm::WlShm>,
+    xdg_wm_base: Option<xdg_wm_base::XdgWmBase>,
+    close_requests: Vec<u32>,  // VM window IDs whose toplevel the user closed
+    seat: Option<wl_seat::WlSeat>,
+    keyboard: Option<wl_keyboard::WlKeyboard>,
+    pointer: Option<wl_pointer::WlPointer>,
+    keyboard_focus: Option<u32>,  // VM window ID of the focused proxied window
+    pointer_focus: Option<u32>,  // VM window ID of the proxied window under the pointer
+    pressed_keys: Vec<u32>,  // Released in the guest when the window loses focus
+    scroll: (f64, f64),  // Axis motion not yet making up a whole wheel step
+    input: Vec<WindowMessage>,  // Key and pointer events for the VM
+    windows: Arc<Mutex<HashMap<u32, ProxiedWindow>>>,  // Shared with NativeWindows
+    activation: Option<xdg_activation_v1::XdgActivationV1>,
+}
+
+/// Axis motion that makes up one wheel step, as compositors report a wheel click
+const SCROLL_STEP: f64 = 10.0;
+
+/// Whole wheel steps in the accumulated axis motion `pending`, keeping the remainder
+fn scroll_steps(pending: &mut f64, value: f64) -> i32 {
+    *pending += value;
+    let steps = (*pending / SCROLL_STEP).trunc();
+    *pending -= steps * SCROLL_STEP;
+    steps as i32
+}
+
+/// The VM window a proxied surface shows, if it is one
+fn surface_window(surface: &wl_surface::WlSurface) -> Option<u32> {
+    surface.data::<u32>().copied()
 }
 
 impl Dispatch<wl_registry::WlRegistry, ()> for AppState {
+    fn event(
+        state: &mut Self,
+        proxy: &wl_registry::WlRegistry,
+        event: wl_registry::Event,
+        _data: &(),
+        _conn: &Connection,
+        qhandle: &QueueHandle<Self>,
+    ) {
+        // Bind the globals windows are built from, at the highest version we know
+        let wl_registry::Event::Global { name, interface, version } = event else {
+            return;
+        };
+        match interface.as_str() {
+            "wl_compositor" => {
+                state.compositor = Some(proxy.bind(name, version.min(4), qhandle, ()));
+            }
+            "wl_shm" => {
+                state.shm = Some(proxy.bind(name, version.min(1), qhandle, ()));
+            }
+            "xdg_wm_base" => {
+                state.xdg_wm_base = Some(proxy.bind(name, version.min(3), qhandle, ()));
+            }
+            "xdg_activation_v1" => {
+                state.activation = Some(proxy.bind(name, version.min(1), qhandle, ()));
+            }
+            // Only the first seat's input reaches the VM
+            "wl_seat" if state.seat.is_none() => {
+                state.seat = Some(proxy.bind(name, version.min(5), qhandle, ()));
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<wl_compositor::WlCompositor, ()> for AppState {
     fn event(
         _state: &mut Self,
-        _proxy: &wl_registry::WlRegistry,
-        _event: wl_registry::Event,
+        _proxy: &wl_compositor::WlCompositor,
+        _event: wl_compositor::Event,
         _data: &(),
         _conn: &Connection,
         _qhandle: &QueueHandle<Self>,
     ) {
-        // Handle registry events
+        // wl_compositor has no events
     }
 }
 
-/// Clipboard proxy for sharing clipboard between host and VM
-pub struct ClipboardProxy {
-    host_clipboard: Arc<Mutex<String>>,
-    vm_connection: Arc<Mutex<UnixStream>>,
+impl Dispatch<wl_shm::WlShm, ()> for AppState {
+    fn event(
+        _state: &mut Self,
+        _proxy: &wl_shm::WlShm,
+        _event: wl_shm::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        // Supported pixel formats; ARGB8888 and XRGB8888 are always available
+    }
+}
+
+impl Dispatch<xdg_wm_base::XdgWmBase, ()> for AppState {
+    fn event(
+        _state: &mut Self,
+        proxy: &xdg_wm_base::XdgWmBase,
+        event: xdg_wm_base::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        // Unanswered pings get the client marked unresponsive
+        if let xdg_wm_base::Event::Ping { serial } = event {
+            proxy.pong(serial);
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub enum ClipboardMessage {
-    SetClipboard(String),
-    GetClipboard,
-    ClipboardContent(String),
+// User data is the VM window ID
+impl Dispatch<wl_surface::WlSurface, u32> for AppState {
+    fn event(
+        _state: &mut Self,
+        _proxy: &wl_surface::WlSurface,
+        _event: wl_surface::Event,
+        _data: &u32,
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        // Output enter/leave events are not needed
+    }
 }
 
-impl ClipboardProxy {
-    pub fn new(vm_socket_path: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let vm_connection = UnixStream::connect(vm_socket_path)?;
+impl Dispatch<wl_shm_pool::WlShmPool, ()> for AppState {
+    fn event(
+        _state: &mut Self,
+        _proxy: &wl_shm_pool::WlShmPool,
+        _event: wl_shm_pool::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        // wl_shm_pool has no events
+    }
+}
+
+impl Dispatch<wl_buffer::WlBuffer, ()> for AppState {
+    fn event(
+        _state: &mut Self,
+        _proxy: &wl_buffer::WlBuffer,
+        _event: wl_buffer::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        // Placeholder buffers never change, so a release needs no action
+    }
+}
+
+// User data is the VM window ID
+impl Dispatch<xdg_surface::XdgSurface, u32> for AppState {
+    fn event(
+        state: &mut Self,
+        proxy: &xdg_surface::XdgSurface,
+        event: xdg_surface::Event,
+        id: &u32,
+        _conn: &Connection,
+        qhandle: &QueueHandle<Self>,
+    ) {
+        // Every configure must be acknowledged before the surface may be mapped
+        let xdg_surface::Event::Configure { serial } = event else {
+            return;
+        };
+        proxy.ack_configure(serial);
         
-        Ok(Self {
-            host_clipboard: Arc::new(Mutex::new(String::new())),
-            vm_connection: Arc::new(Mutex::new(vm_connection)),
-        })
+        let mut windows = state.windows.lock().unwrap();
+        let (Some(shm), Some(window)) = (&state.shm, windows.get_mut(id)) else {
+            return;
+        };
+        if window.buffer.is_none() {
+            window.attach_placeholder(shm, qhandle);
+        }
+    }
+}
+
+// User data is the VM window ID
+impl Dispatch<xdg_toplevel::XdgToplevel, u32> for AppState {
+    fn event(
+        state: &mut Self,
+        _proxy: &xdg_toplevel::XdgToplevel,
+        event: xdg_toplevel::Event,
+        id: &u32,
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        // Size hints are not acted on yet
+        if let xdg_toplevel::Event::Close = event {
+            state.close_requests.push(*id);
+        }
+    }
+}
+
+impl Dispatch<xdg_activation_v1::XdgActivationV1, ()> for AppState {
+    fn event(
+        _state: &mut Self,
+        _proxy: &xdg_activation_v1::XdgActivationV1,
+        _event: xdg_activation_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        // xdg_activation_v1 has no events
+    }
+}
+
+// User data is the VM window ID to activate
+impl Dispatch<xdg_activation_token_v1::XdgActivationTokenV1, u32> for AppState {
+    fn event(
+        state: &mut Self,
+        proxy: &xdg_activation_token_v1::XdgActivationTokenV1,
+        event: xdg_activation_token_v1::Event,
+        id: &u32,
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        let xdg_activation_token_v1::Event::Done { token } = event else {
+            return;
+        };
+        // The window may have closed while the token was on its way
+        if let (Some(activation), Some(window)) = (&state.activation, state.windows.lock().unwrap().get(id)) {
+            activation.activate(token, &window.surface);
+        }
+        proxy.destroy();
+    }
+}
+
+impl Dispatch<wl_seat::WlSeat, ()> for AppState {
+    fn event(
+        state: &mut Self,
+        proxy: &wl_seat::WlSeat,
+        event: wl_seat::Event,
+        _data: &(),
+        _conn: &Connection,
+        qhandle: &QueueHandle<Self>,
+    ) {
+        // Follow the seat as keyboards and pointers come and go
+        let wl_seat::Event::Capabilities { capabilities: WEnum::Value(capabilities) } = event else {
+            return;
+        };
+        let has_keyboard = capabilities.contains(wl_seat::Capability::Keyboard);
+        match (has_keyboard, state.keyboard.take()) {
+            (true, None) => state.keyboard = Some(proxy.get_keyboard(qhandle, ())),
+            (true, keyboard) => state.keyboard = keyboard,
+            (false, Some(keyboard)) => {
+                state.release_keys();
+                keyboard.release();
+            }
+            (false, None) => {}
+        }
+        let has_pointer = capabilities.contains(wl_seat::Capability::Pointer);
+        match (has_pointer, state.pointer.take()) {
+            (true, None) => state.pointer = Some(proxy.get_pointer(qhandle, ())),
+            (true, pointer) => state.pointer = pointer,
+            (false, Some(pointer)) => {
+                state.pointer_focus = None;
+                pointer.release();
+            }
+            (false, None) => {}
+        }
+    }
+}
+
+impl AppState {
+    /// Let go of every key still held in the guest, so none stays stuck once
+    /// the window loses keyboard focus
+    fn release_keys(&mut self) {
+        let keys = std::mem::take(&mut self.pressed_keys);
+        if let Some(id) = self.keyboard_focus.take() {
+            self.input.extend(keys.into_iter().map(|key| WindowMessage::KeyEvent { id, key, pressed: false }));
+        }
     }
     
-    pub fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        println!("📋 Clipboard Proxy started");
-        
-        // Monitor host clipboard changes using wl-clipboard
-        let host_clip = self.host_clipboard.clone();
-        std::thread::spawn(move || {
-            Self::monitor_host_clipboard(host_clip);
-        });
-        
-        // Handle VM clipboard requests
-        let mut buffer = [0u8; 65536]; // Larger buffer for clipboard data
-        loop {
-            let mut conn = self.vm_connection.lock().unwrap();
-            
-            match conn.read(&mut buffer) {
-                Ok(n) if n > 0 => {
-                    if let Ok(msg) = bincode::deserialize::<ClipboardMessage>(&buffer[..n]) {
-                        match msg {
-                            ClipboardMessage::SetClipboard(content) => {
-                                // Set host clipboard
-                                *self.host_clipboard.lock().unwrap() = content.clone();
-                                Self::set_host_clipboard(&content);
-                            }
-                            ClipboardMessage::GetClipboard => {
-                                // Send current clipboard to VM
-                                let content = self.host_clipboard.lock().unwrap().clone();
-                                let response = ClipboardMessage::ClipboardContent(content);
-                                let data = bincode::serialize(&response).unwrap();
-                                let _ = conn.write_all(&data);
-                            }
-                            _ => {}
-                        }
-                    }
+    /// Queue a key press or release for the focused window, if one is focused
+    fn key(&mut self, key: u32, pressed: bool) {
+        let Some(id) = self.keyboard_focus else {
+            return;
+        };
+        if pressed {
+            self.pressed_keys.push(key);
+        } else {
+            self.pressed_keys.retain(|held| *held != key);
+        }
+        self.input.push(WindowMessage::KeyEvent { id, key, pressed });
+    }
+    
+    /// Queue a pointer action for the window under the pointer, if there is one
+    fn pointer(&mut self, action: PointerAction) {
+        if let Some(id) = self.pointer_focus {
+            self.input.push(WindowMessage::PointerEvent { id, action });
+        }
+    }
+}
+
+impl Dispatch<wl_keyboard::WlKeyboard, ()> for AppState {
+    fn event(
+        state: &mut Self,
+        _proxy: &wl_keyboard::WlKeyboard,
+        event: wl_keyboard::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        // Raw key codes are forwarded; the guest applies its own keymap, modifiers
+        // and key repeat, so the host keymap and repeat info are not needed
+        match event {
+            wl_keyboard::Event::Enter { surface, .. } => {
+                state.release_keys();
+                state.keyboard_focus = surface_window(&surface);
+            }
+            wl_keyboard::Event::Leave { .. } => state.release_keys(),
+            wl_keyboard::Event::Key { key, state: key_state, .. } => {
+                state.key(key, key_state == WEnum::Value(wl_keyboard::KeyState::Pressed));
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<wl_pointer::WlPointer, ()> for AppState {
+    fn event(
+        state: &mut Self,
+        _proxy: &wl_pointer::WlPointer,
+        event: wl_pointer::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        let action = match event {
+            wl_pointer::Event::Enter { surface, surface_x, surface_y, .. } => {
+                state.pointer_focus = surface_window(&surface);
+                state.scroll = (0.0, 0.0);
+                PointerAction::Motion { x: surface_x as i32, y: surface_y as i32 }
+            }
+            wl_pointer::Event::Leave { .. } => {
+                state.pointer_focus = None;
+                return;
+            }
+            wl_pointer::Event::Motion { surface_x, surface_y, .. } => {
+                PointerAction::Motion { x: surface_x as i32, y: surface_y as i32 }
+            }
+            wl_pointer::Event::Button { button, state: button_state, .. } => {
+                let pressed = button_state == WEnum::Value(wl_pointer::ButtonState::Pressed);
+                PointerAction::Button { button, pressed }
+            }
+            wl_pointer::Event::Axis { axis: WEnum::Value(axis), value, .. } => {
+                let (dx, dy) = match axis {
+                    wl_pointer::Axis::HorizontalScroll => (scroll_steps(&mut state.scroll.0, value), 0),
+                    wl_pointer::Axis::VerticalScroll => (0, scroll_steps(&mut state.scroll.1, value)),
+                    _ => return,
+                };
+                if dx == 0 && dy == 0 {
+                    return;
                 }
-                _ => {
-                    std::thread::sleep(std::time::Duration::from_millis(100));
+                PointerAction::Scroll { dx, dy }
+            }
+            _ => return,
+        };
+        state.pointer(action);
+    }
+}
+
+/// Clipboard proxy for sharing clipboard between host and VM
+#[derive(Clone)]
+pub struct ClipboardProxy {
+    host_clipboard: Arc<Mutex<ClipboardContents>>,
+    guest: Arc<Mutex<Option<GuestStream>>>,
+}
+
+impl ClipboardProxy {
+    /// Sync the host clipboard with whichever guest agent is connected through `guest`
+    fn new(guest: Arc<Mutex<Option<GuestStream>>>) -> Self {
+        Self {
+            host_clipboard: Arc::new(Mutex::new(ClipboardContents::Text(String::new()))),
+            guest,
+        }
+    }
+    
+    /// Push host clipboard changes to the guest from a thread of their own
+    fn start(&self) {
+        outln!("📋 Clipboard Proxy started");
+        let proxy = self.clone();
+        std::thread::spawn(move || proxy.monitor_host_clipboard());
+    }
+    
+    /// Act on a clipboard message from the guest
+    fn handle(&self, msg: ClipboardMessage) {
+        match msg {
+            ClipboardMessage::SetClipboard(text) => {
+                self.set_clipboard(ClipboardContents::Text(text));
+            }
+            ClipboardMessage::SetClipboardData { mime_type, data } => {
+                self.set_clipboard(ClipboardContents::Data { mime_type, data });
+            }
+            // Sent by the guest agent on connect; the monitor would only send later changes
+            ClipboardMessage::GetClipboard => {
+                let mut known = self.host_clipboard.lock().unwrap();
+                if let Some(contents) = Self::read_host_clipboard() {
+                    known.clone_from(&contents);
+                    self.send(contents);
                 }
             }
+            // Host → guest only
+            ClipboardMessage::ClipboardContent(_) | ClipboardMessage::ClipboardData { .. } => {}
         }
     }
     
-    fn monitor_host_clipboard(clipboard: Arc<Mutex<String>>) {
+    /// Set the host clipboard to what the VM copied. Holding the lock keeps the
+    /// monitor from reading the old value in between and pushing it back.
+    fn set_clipboard(&self, contents: ClipboardContents) {
+        let mut known = self.host_clipboard.lock().unwrap();
+        Self::set_host_clipboard(&contents);
+        *known = contents;
+    }
+    
+    /// Send the host clipboard to the connected guest, if any
+    fn send(&self, contents: ClipboardContents) {
+        let mut guest = self.guest.lock().unwrap();
+        let Some(conn) = guest.as_mut() else {
+            return;
+        };
+        if let Err(e) = send_message(conn, &WindowMessage::Clipboard(contents.into_message(true))) {
+            eoutln!("📋 Failed to push the host clipboard to the VM: {}", e);
+        }
+    }
+    
+    fn monitor_host_clipboard(&self) {
         // Use wl-paste to monitor clipboard changes
         loop {
-            if let Ok(output) = std::process::Command::new("wl-paste")
-                .output()
-            {
-                if output.status.success() {
-                    let content = String::from_utf8_lossy(&output.stdout).to_string();
-                    *clipboard.lock().unwrap() = content;
+            // A guest connecting later asks for the clipboard itself
+            if self.guest.lock().unwrap().is_some() {
+                // Read and compare under the lock, so a paste from the VM is never half-seen
+                let mut known = self.host_clipboard.lock().unwrap();
+                if let Some(contents) = Self::read_host_clipboard() {
+                    if let Some(contents) = clipboard_changed(&mut *known, contents) {
+                        self.send(contents);
+                    }
                 }
             }
             std::thread::sleep(std::time::Duration::from_secs(1));
         }
     }
     
-    fn set_host_clipboard(content: &str) {
+    /// The host clipboard in the representation worth syncing, or `None` when it's
+    /// empty, offers nothing usable or is too big to send
+    fn read_host_clipboard() -> Option<ClipboardContents> {
+        let types = std::process::Command::new("wl-paste").arg("--list-types").output().ok()?;
+        if !types.status.success() {
+            return None;
+        }
+        let types = String::from_utf8_lossy(&types.stdout);
+        let mime_type = pick_clipboard_type(types.lines())?;
+        
+        // Text is read without -t so wl-paste picks the best text encoding itself
+        let mut paste = std::process::Command::new("wl-paste");
+        paste.arg("--no-newline");
+        if !is_text_type(mime_type) {
+            paste.args(["--type", mime_type]);
+        }
+        let output = paste.output().ok().filter(|output| output.status.success())?;
+        clipboard_contents(mime_type, output.stdout)
+    }
+    
+    fn set_host_clipboard(contents: &ClipboardContents) {
         // Use wl-copy to set clipboard
-        let mut child = std::process::Command::new("wl-copy")
-            .stdin(std::process::Stdio::piped())
-            .spawn()
-            .expect("Failed to start wl-copy");
+        let mut wl_copy = std::process::Command::new("wl-copy");
+        let bytes = match contents {
+            ClipboardContents::Text(text) => text.as_bytes(),
+            ClipboardContents::Data { mime_type, data } => {
+                wl_copy.args(["--type", mime_type]);
+                data
+            }
+        };
+        let mut child = match wl_copy.stdin(std::process::Stdio::piped()).spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                eoutln!("📋 Failed to start wl-copy: {}", e);
+                return;
+            }
+        };
             
         if let Some(mut stdin) = child.stdin.take() {
-            let _ = stdin.write_all(content.as_bytes());
+            let _ = stdin.write_all(bytes);
         }
         
         let _ = child.wait();
     }
 }
 
+/// Port the window proxy listens on unless the VM config says otherwise
+pub const DEFAULT_PROXY_PORT: u16 = 9999;
+
+/// Where the window proxy listens for the guest agent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transport {
+    /// Vsock port on the host; once `cid` is known only that VM is accepted
+    Vsock { port: u32, cid: Option<u32> },
+    Unix(PathBuf),
+    Tcp(SocketAddr),
+}
+
+impl Transport {
+    /// The VM's configured transport, or TCP when that isn't available on this
+    /// host, along with why it fell back
+    pub fn select(config: &AppVMConfig) -> (Self, Option<String>) {
+        let vsock_ready = Path::new("/dev/vhost-vsock").exists() && crate::provisioner::domain_has_vsock(&config.name);
+        let runtime_dir = std::env::var("XDG_RUNTIME_DIR").ok().filter(|dir| !dir.is_empty());
+        choose_transport(
+            &config.name,
+            config.proxy_transport,
+            config.proxy_port,
+            config.proxy_bind_address,
+            vsock_ready,
+            runtime_dir.as_deref(),
+        )
+    }
+    
+    /// Fail early, before a VM is started, when another process already listens here
+    pub fn ensure_available(&self) -> Result<(), Box<dyn std::error::Error>> {
+        GuestListener::bind(self).map(drop)
+    }
+}
+
+impl std::fmt::Display for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Transport::Vsock { port, cid: Some(cid) } => write!(f, "vsock port {} (CID {})", port, cid),
+            Transport::Vsock { port, cid: None } => write!(f, "vsock port {}", port),
+            Transport::Unix(path) => write!(f, "unix socket {}", path.display()),
+            Transport::Tcp(addr) => write!(f, "tcp {}", addr),
+        }
+    }
+}
+
+fn choose_transport(
+    vm_name: &str,
+    requested: ProxyTransport,
+    port: u16,
+    bind_address: IpAddr,
+    vsock_ready: bool,
+    runtime_dir: Option<&str>,
+) -> (Transport, Option<String>) {
+    let tcp = Transport::Tcp(SocketAddr::new(bind_address, port));
+    match (requested, runtime_dir) {
+        (ProxyTransport::Vsock, _) if vsock_ready => (Transport::Vsock { port: port.into(), cid: None }, None),
+        (ProxyTransport::Vsock, _) => (tcp, Some(format!(
+            "vsock is unavailable ({} has no vsock device or /dev/vhost-vsock is missing)", vm_name
+        ))),
+        (ProxyTransport::Unix, Some(dir)) => {
+            let path = Path::new(dir).join("vm-provisioner").join(format!("{}-guest.sock", vm_name));
+            (Transport::Unix(path), None)
+        }
+        (ProxyTransport::Unix, None) => (tcp, Some("XDG_RUNTIME_DIR is not set".to_string())),
+        (ProxyTransport::Tcp, _) => (tcp, None),
+    }
+}
+
+/// Listening socket for the guest agent, whichever the transport
+enum GuestListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+    Vsock { fd: OwnedFd, cid: Option<u32> },
+}
+
+impl GuestListener {
+    fn bind(transport: &Transport) -> Result<Self, Box<dyn std::error::Error>> {
+        let in_use = |e: std::io::Error| -> Box<dyn std::error::Error> {
+            if e.kind() == std::io::ErrorKind::AddrInUse {
+                format!("Window proxy {} is already in use; pick another port with --proxy-port or proxy_port in the VM config", transport).into()
+            } else {
+                format!("Cannot listen on window proxy {}: {}", transport, e).into()
+            }
+        };
+        
+        match transport {
+            Transport::Tcp(addr) => TcpListener::bind(addr).map(Self::Tcp).map_err(in_use),
+            Transport::Unix(path) => {
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                // A socket nobody answers on is left over from an earlier run
+                if UnixStream::connect(path).is_ok() {
+                    return Err(in_use(std::io::ErrorKind::AddrInUse.into()));
+                }
+                let _ = std::fs::remove_file(path);
+                UnixListener::bind(path).map(Self::Unix).map_err(in_use)
+            }
+            Transport::Vsock { port, cid } => vsock_listen(*port)
+                .map(|fd| Self::Vsock { fd, cid: *cid })
+                .map_err(in_use),
+        }
+    }
+    
+    /// Next guest connection and a description of the peer
+    fn accept(&self) -> std::io::Result<(GuestStream, String)> {
+        match self {
+            Self::Tcp(listener) => listener.accept()
+                .map(|(stream, addr)| (GuestStream::Tcp(stream), addr.to_string())),
+            Self::Unix(listener) => listener.accept()
+                .map(|(stream, _)| (GuestStream::Unix(stream), "unix socket".to_string())),
+            Self::Vsock { fd, cid } => {
+                let (stream, peer) = vsock_accept(fd)?;
+                if cid.is_some_and(|cid| cid != peer) {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::PermissionDenied,
+                        format!("rejected vsock connection from CID {} (expected {})", peer, cid.unwrap_or_default()),
+                    ));
+                }
+                Ok((GuestStream::Unix(stream), format!("vsock CID {}", peer)))
+            }
+        }
+    }
+}
+
+fn vsock_addr(cid: u32, port: u32) -> libc::sockaddr_vm {
+    let mut addr: libc::sockaddr_vm = unsafe { std::mem::zeroed() };
+    addr.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+    addr.svm_cid = cid;
+    addr.svm_port = port;
+    addr
+}
+
+fn vsock_listen(port: u32) -> std::io::Result<OwnedFd> {
+    let fd = unsafe { libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    
+    let addr = vsock_addr(libc::VMADDR_CID_ANY, port);
+    let ret = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            &addr as *const libc::sockaddr_vm as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 || unsafe { libc::listen(fd.as_raw_fd(), 16) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    
+    Ok(fd)
+}
+
+/// Accept a vsock connection, returning it with the peer's context ID. The
+/// stream is carried in a `UnixStream`, which only needs plain read/write.
+fn vsock_accept(listener: &OwnedFd) -> std::io::Result<(UnixStream, u32)> {
+    let mut addr = vsock_addr(0, 0);
+    let mut len = std::mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t;
+    let fd = unsafe {
+        libc::accept4(
+            listener.as_raw_fd(),
+            &mut addr as *mut libc::sockaddr_vm as *mut libc::sockaddr,
+            &mut len,
+            libc::SOCK_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    
+    Ok((UnixStream::from(unsafe { OwnedFd::from_raw_fd(fd) }), addr.svm_cid))
+}
+
+/// Connection to the guest agent
+enum GuestStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl GuestStream {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        match self {
+            Self::Tcp(stream) => stream.try_clone().map(Self::Tcp),
+            Self::Unix(stream) => stream.try_clone().map(Self::Unix),
+        }
+    }
+    
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.set_read_timeout(timeout),
+            Self::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+}
+
+impl Read for GuestStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.read(buf),
+            Self::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for GuestStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.write(buf),
+            Self::Unix(stream) => stream.write(buf),
+        }
+    }
+    
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.flush(),
+            Self::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
 /// Main entry point for the host-side VM integration
 pub struct VMIntegrationHost {
-    window_proxy: Option<WindowProxy>,
-    clipboard_proxy: Option<ClipboardProxy>,
     vm_name: String,
+    url_allowlist: Option<Arc<Vec<String>>>,  // None: guest OpenUrl requests are refused
+    events: Option<Sender<WindowMessage>>,  // Copy of every guest message, for CLI commands
+    notifications: bool,  // Re-raise guest notifications on the host desktop
+    clipboard: bool,  // Sync the clipboard through the guest agent connection
+    transport: Transport,
+    tls: Option<Arc<tls::Identity>>,  // TCP guests must complete a TLS handshake with it
 }
 
 impl VMIntegrationHost {
     pub fn new(vm_name: String) -> Self {
         Self {
-            window_proxy: None,
-            clipboard_proxy: None,
             vm_name,
+            url_allowlist: None,
+            events: None,
+            notifications: false,
+            clipboard: false,
+            transport: Transport::Tcp(SocketAddr::from(([127, 0, 0, 1], DEFAULT_PROXY_PORT))),
+            tls: None,
         }
     }
     
-    pub fn start(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        println!("🚀 Starting VM Integration for: {}", self.vm_name);
+    /// Listen for the guest agent on `transport` instead of loopback TCP
+    pub fn listen_on(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+    
+    /// Only accept TCP guests that authenticate with the VM's TLS identity
+    pub fn require_tls(mut self, identity: tls::Identity) -> Self {
+        self.tls = Some(Arc::new(identity));
+        self
+    }
+    
+    /// Let the guest open http(s) links in the host browser. Links to allowlisted
+    /// domains open directly, anything else needs confirmation in the terminal.
+    pub fn allow_open_url(mut self, allowlist: Vec<String>) -> Self {
+        self.url_allowlist = Some(Arc::new(allowlist));
+        self
+    }
+    
+    /// Show the guest's desktop notifications on the host with notify-send
+    pub fn forward_notifications(mut self) -> Self {
+        self.notifications = true;
+        self
+    }
+    
+    /// Sync the host clipboard with the guest's (`clipboard_transport = "Custom"`)
+    pub fn share_clipboard(mut self) -> Self {
+        self.clipboard = true;
+        self
+    }
+    
+    /// Forward a copy of every message received from the guest to `events`
+    pub fn forward_events(mut self, events: Sender<WindowMessage>) -> Self {
+        self.events = Some(events);
+        self
+    }
+    
+    /// Run the integration, reporting through `ready` once both sockets are bound
+    /// (or why binding failed) so the caller doesn't claim success prematurely
+    pub fn start(&mut self, ready: Sender<Result<(), String>>) -> Result<(), Box<dyn std::error::Error>> {
+        outln!("🚀 Starting VM Integration for: {}", self.vm_name);
         
-        // TCP port for VM communication
-        let port = "0.0.0.0:9999";
+        let (listener, control_listener) = match self.bind() {
+            Ok(listeners) => {
+                let _ = ready.send(Ok(()));
+                listeners
+            }
+            Err(e) => {
+                let _ = ready.send(Err(e.to_string()));
+                return Err(e);
+            }
+        };
+        
+        // Currently connected guest agent, shared with the control socket
+        let guest: Arc<Mutex<Option<GuestStream>>> = Arc::new(Mutex::new(None));
+        // Control client waiting for the guest's answer to a password change
+        let control_reply: Arc<Mutex<Option<UnixStream>>> = Arc::new(Mutex::new(None));
         
-        // Create TCP server
-        use std::net::TcpListener;
-        let listener = TcpListener::bind(port)?;
-        println!("   Listening on TCP port: {}", port);
+        // Without a host compositor the guest is still served, just without native windows
+        let native = match WindowProxy::new(guest.clone()) {
+            Ok(window_proxy) => {
+                let native = window_proxy.native.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = window_proxy.run() {
+                        eoutln!("⚠️  Lost the host compositor, VM windows are no longer shown: {}", e);
+                    }
+                });
+                Some(native)
+            }
+            Err(e) => {
+                eoutln!("⚠️  VM windows won't be shown on the host: {}", e);
+                None
+            }
+        };
+        
+        let clipboard = self.clipboard.then(|| ClipboardProxy::new(guest.clone()));
+        if let Some(clipboard) = &clipboard {
+            clipboard.start();
+        }
         
         // Start window proxy server
         let vm_name = self.vm_name.clone();
+        let guest_clone = guest.clone();
+        let url_allowlist = self.url_allowlist.clone();
+        let events = self.events.clone();
+        let notifications = self.notifications;
+        let tls = self.tls.clone();
+        let control_reply_clone = control_reply.clone();
         std::thread::spawn(move || {
-            Self::run_socket_server(listener, vm_name);
+            Self::run_socket_server(listener, vm_name, guest_clone, control_reply_clone, native, clipboard, url_allowlist, events, notifications, tls);
         });
         
-        println!("✅ VM Integration running");
-        println!("   Waiting for guest agent connection...");
+        std::thread::spawn(move || {
+            Self::run_control_server(control_listener, guest, control_reply);
+        });
+        
+        outln!("✅ VM Integration running");
+        outln!("   Waiting for guest agent connection...");
         
         // Keep main thread alive
         loop {
@@ -399,82 +1127,512 @@ impl VMIntegrationHost {
         }
     }
     
-    fn run_socket_server(listener: std::net::TcpListener, vm_name: String) {
-        println!("🔌 TCP server started for VM: {} on port 9999", vm_name);
+    fn bind(&self) -> Result<(GuestListener, UnixListener), Box<dyn std::error::Error>> {
+        // Guest agent connection
+        let listener = GuestListener::bind(&self.transport)?;
+        outln!("   Listening on {}", self.transport);
         
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    println!("📡 Guest agent connected from: {:?}!", stream.peer_addr());
+        // Local control socket used by CLI commands (e.g. `push`) to reach the guest
+        let control_path = control_socket_path(&self.vm_name);
+        if let Some(parent) = std::path::Path::new(&control_path).parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let _ = std::fs::remove_file(&control_path);
+        let control_listener = UnixListener::bind(&control_path)
+            .map_err(|e| format!("cannot bind control socket {}: {}", control_path, e))?;
+        outln!("   Control socket: {}", control_path);
+        
+        Ok((listener, control_listener))
+    }
+    
+    #[allow(clippy::too_many_arguments)]
+    fn run_socket_server(
+        listener: GuestListener,
+        vm_name: String,
+        guest: Arc<Mutex<Option<GuestStream>>>,
+        control_reply: Arc<Mutex<Option<UnixStream>>>,
+        native: Option<NativeWindows>,
+        clipboard: Option<ClipboardProxy>,
+        url_allowlist: Option<Arc<Vec<String>>>,
+        events: Option<Sender<WindowMessage>>,
+        notifications: bool,
+        tls: Option<Arc<tls::Identity>>,
+    ) {
+        outln!("🔌 Guest agent server started for VM: {}", vm_name);
+        
+        loop {
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    outln!("📡 Guest agent connected from: {}!", peer);
                     
                     // Spawn a thread to handle this connection
                     let vm_name_clone = vm_name.clone();
+                    let guest = guest.clone();
+                    let control_reply = control_reply.clone();
+                    let native = native.clone();
+                    let clipboard = clipboard.clone();
+                    let url_allowlist = url_allowlist.clone();
+                    let events = events.clone();
+                    let tls = tls.clone();
                     std::thread::spawn(move || {
-                        if let Err(e) = Self::handle_guest_connection(stream, vm_name_clone) {
-                            eprintln!("Connection error: {}", e);
+                        if let Err(e) = Self::handle_guest_connection(stream, peer, vm_name_clone, guest, control_reply, native, clipboard, url_allowlist, events, notifications, tls) {
+                            eoutln!("Connection error: {}", e);
                         }
                     });
                 }
                 Err(e) => {
-                    eprintln!("Connection failed: {}", e);
+                    eoutln!("Connection failed: {}", e);
                 }
             }
         }
     }
     
+    /// Forward length-prefixed messages from local CLI clients to the guest agent.
+    /// A client that sends `ChangePassword` gets the guest's `PasswordChanged` back.
+    fn run_control_server(listener: UnixListener, guest: Arc<Mutex<Option<GuestStream>>>, control_reply: Arc<Mutex<Option<UnixStream>>>) {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eoutln!("Control connection failed: {}", e);
+                    continue;
+                }
+            };
+            
+            let mut forwarded = 0;
+            while let Ok(data) = read_frame(&mut stream) {
+                let password_change = matches!(bincode::deserialize(&data), Ok(WindowMessage::ChangePassword { .. }));
+                let mut guest = guest.lock().unwrap();
+                let Some(conn) = guest.as_mut() else {
+                    eoutln!("⚠️  No guest agent connected, dropping control message");
+                    if password_change {
+                        let error = Some("no guest agent is connected".to_string());
+                        let _ = send_message(&mut stream, &WindowMessage::PasswordChanged { error });
+                    }
+                    break;
+                };
+                if password_change {
+                    *control_reply.lock().unwrap() = stream.try_clone().ok();
+                }
+                if let Err(e) = write_frame(conn, &data) {
+                    eoutln!("⚠️  Failed to forward message to guest: {}", e);
+                    *guest = None;
+                    break;
+                }
+                forwarded += 1;
+            }
+            
+            if forwarded > 0 {
+                outln!("📤 Forwarded {} control message(s) to guest", forwarded);
+            }
+        }
+    }
+    
+    /// Require the guest's first message to be a `ProtocolHello` with our version
+    fn check_protocol_hello(stream: &mut GuestStream, vm_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let data = read_frame(stream)?;
+        
+        match bincode::deserialize::<WindowMessage>(&data) {
+            Ok(WindowMessage::ProtocolHello { version }) if version == PROTOCOL_VERSION => {
+                outln!("🤝 Guest agent speaks protocol v{}", version);
+                Ok(())
+            }
+            Ok(WindowMessage::ProtocolHello { version }) => Err(format!(
+                "Guest agent protocol v{} does not match host protocol v{}. Regenerate the guest agent by running: vm-provisioner reprovision {}",
+                version, PROTOCOL_VERSION, vm_name
+            ).into()),
+            _ => Err(format!(
+                "Guest agent did not send a protocol handshake (too old?). Regenerate the guest agent by running: vm-provisioner reprovision {}",
+                vm_name
+            ).into()),
+        }
+    }
+    
+    #[allow(clippy::too_many_arguments)]
     fn handle_guest_connection(
-        mut stream: std::net::TcpStream, 
-        vm_name: String
+        stream: GuestStream, 
+        peer: String,
+        vm_name: String,
+        guest: Arc<Mutex<Option<GuestStream>>>,
+        control_reply: Arc<Mutex<Option<UnixStream>>>,
+        native: Option<NativeWindows>,
+        clipboard: Option<ClipboardProxy>,
+        url_allowlist: Option<Arc<Vec<String>>>,
+        events: Option<Sender<WindowMessage>>,
+        notifications: bool,
+        tls: Option<Arc<tls::Identity>>,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        use std::io::Read;
+        outln!("🔄 Handling connection for VM: {}", vm_name);
+        let mut log = ProxyLog::open(&vm_name);
+        
+        // With TLS, only a peer holding the VM's certificate and key gets any further
+        let mut stream = match (tls, stream) {
+            (Some(identity), GuestStream::Tcp(tcp)) => match tls::accept(tcp, &identity) {
+                Ok(stream) => GuestStream::Unix(stream),
+                Err(e) => {
+                    log.write(&format!("Rejected unauthenticated connection from {}: {}", peer, e));
+                    return Err(format!("rejected unauthenticated connection from {}: {}", peer, e).into());
+                }
+            },
+            (_, stream) => stream,
+        };
+        
+        if let Err(e) = Self::check_protocol_hello(&mut stream, &vm_name) {
+            log.write(&format!("Rejected guest agent: {}", e));
+            return Err(e);
+        }
+        log.write("Guest agent connected");
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs();
+        if let Err(e) = VMState::update(&vm_name, |state| state.agent_connected_at = Some(now)) {
+            eoutln!("Failed to record guest agent connection: {}", e);
+        }
+        *guest.lock().unwrap() = Some(stream.try_clone()?);
         
-        println!("🔄 Handling connection for VM: {}", vm_name);
-        let mut buffer = vec![0u8; 4096];
+        // The agent pings every PING_INTERVAL, so a silent connection is a dead one
+        // (e.g. a half-open TCP connection after the VM was paused or lost its network)
+        stream.set_read_timeout(Some(HEARTBEAT_TIMEOUT))?;
+        let mut last_seen = std::time::Instant::now();
+        let mut windows: Vec<u32> = Vec::new();
+        let mut writer = stream.try_clone()?;
         
         loop {
-            // Read message length first
-            let mut len_buf = [0u8; 4];
-            match stream.read_exact(&mut len_buf) {
-                Ok(()) => {
-                    let len = u32::from_le_bytes(len_buf) as usize;
-                    if len > buffer.len() {
-                        buffer.resize(len, 0);
-                    }
-                    
-                    // Read the actual message
-                    stream.read_exact(&mut buffer[..len])?;
-                    
-                    // Deserialize and handle message
-                    if let Ok(msg) = bincode::deserialize::<WindowMessage>(&buffer[..len]) {
-                        println!("📨 Received message: {:?}", msg);
-                        
-                        // Handle the message (for now just print)
-                        match msg {
-                            WindowMessage::WindowCreated { id, title, width, height, x, y, app_name } => {
-                                println!("🪟 VM window created: {} '{}' ({}x{}+{}+{}) [{}]", 
-                                         id, title, width, height, x, y, app_name);
-                                // TODO: Create native Wayland window
-                            }
-                            WindowMessage::WindowDestroyed { id } => {
-                                println!("🗑️  VM window destroyed: {}", id);
-                                // TODO: Destroy native window
-                            }
-                            WindowMessage::ApplicationStarted { app_name, pid } => {
-                                println!("🚀 Application started in VM: {} (PID: {})", app_name, pid);
-                            }
-                            _ => {
-                                println!("📦 Other message: {:?}", msg);
-                            }
-                        }
-                    }
+            let frame = match read_frame(&mut stream) {
+                Ok(frame) => frame,
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {
+                    let silent = last_seen.elapsed();
+                    let missed = silent.as_secs() / PING_INTERVAL.as_secs();
+                    eoutln!("💔 [{}] No heartbeat from the guest agent for {}s ({} pings missed), treating it as disconnected",
+                            vm_name, silent.as_secs(), missed);
+                    log.write(&format!("No heartbeat for {}s ({} pings missed), disconnected", silent.as_secs(), missed));
+                    break;
                 }
                 Err(e) => {
-                    println!("🔌 Guest agent disconnected: {}", e);
+                    outln!("🔌 Guest agent disconnected: {}", e);
+                    log.write(&format!("Guest agent disconnected: {}", e));
                     break;
                 }
+            };
+            last_seen = std::time::Instant::now();
+            
+            // Deserialize and handle message
+            if let Ok(msg) = bincode::deserialize::<WindowMessage>(&frame) {
+                if let WindowMessage::Ping { seq } = msg {
+                    // Under the guest lock, so the pong doesn't interleave with control messages
+                    let _guest = guest.lock().unwrap();
+                    if let Err(e) = send_message(&mut writer, &WindowMessage::Pong { seq }) {
+                        eoutln!("⚠️  Failed to answer guest heartbeat: {}", e);
+                    }
+                    continue;
+                }
+                // Not printed or forwarded: clipboard data can be large and private
+                if let WindowMessage::Clipboard(msg) = msg {
+                    if let Some(clipboard) = &clipboard {
+                        clipboard.handle(msg);
+                    }
+                    continue;
+                }
+                track_windows(&mut windows, &msg);
+                
+                outln!("📨 Received message: {:?}", msg);
+                if let Some(events) = &events {
+                    let _ = events.send(msg.clone());
+                }
+                if let Some(native) = &native {
+                    native.apply(&msg);
+                }
+                
+                match msg {
+                    WindowMessage::WindowCreated { id, title, width, height, x, y, app_name } => {
+                        outln!("🪟 VM window created: {} '{}' ({}x{}+{}+{}) [{}]", 
+                                 id, title, width, height, x, y, app_name);
+                    }
+                    WindowMessage::WindowDestroyed { id } => {
+                        outln!("🗑️  VM window destroyed: {}", id);
+                    }
+                    WindowMessage::ApplicationStarted { app_name, pid } => {
+                        outln!("🚀 Application started in VM: {} (PID: {})", app_name, pid);
+                        log.write(&format!("Application started: {} (PID {})", app_name, pid));
+                    }
+                    WindowMessage::GuestLog { level, message } => {
+                        outln!("📜 [{}] {:?}: {}", vm_name, level, message);
+                        log.write(&format!("{:?}: {}", level, message));
+                    }
+                    WindowMessage::ApplicationList { apps } => {
+                        outln!("📚 [{}] {} applications available", vm_name, apps.len());
+                        if let Err(e) = VMState::update(&vm_name, |state| state.applications = apps) {
+                            eoutln!("Failed to record application list: {}", e);
+                        }
+                    }
+                    WindowMessage::Notification { app, summary, body, icon } if notifications => {
+                        let args = notify_send_args(&vm_name, &app, &summary, &body, icon.as_deref());
+                        if let Err(e) = std::process::Command::new("notify-send").args(&args).spawn() {
+                            eoutln!("🔔 [{}] Failed to show notification: {}", vm_name, e);
+                            log.write(&format!("Failed to show notification: {}", e));
+                        }
+                    }
+                    WindowMessage::OpenUrl { url } => {
+                        if let Err(e) = Self::open_url(&vm_name, &url, url_allowlist.as_deref()) {
+                            eoutln!("🔗 [{}] Not opening {:?}: {}", vm_name, url, e);
+                            log.write(&format!("Not opening {:?}: {}", url, e));
+                        }
+                    }
+                    WindowMessage::PasswordChanged { error } => {
+                        if let Some(mut client) = control_reply.lock().unwrap().take() {
+                            let _ = send_message(&mut client, &WindowMessage::PasswordChanged { error });
+                        }
+                    }
+                    // Already printed above
+                    _ => {}
+                }
+            }
+        }
+        
+        // The guest's windows went with the connection; close their native windows
+        // and let listeners drop theirs
+        if let Some(native) = &native {
+            native.destroy(&windows);
+        }
+        if let Some(events) = &events {
+            for id in windows {
+                let _ = events.send(WindowMessage::WindowDestroyed { id });
             }
         }
         
         Ok(())
     }
+    
+    /// Open a guest-requested link in the host browser, subject to the allowlist
+    fn open_url(vm_name: &str, url: &str, allowlist: Option<&Vec<String>>) -> Result<(), Box<dyn std::error::Error>> {
+        use std::io::IsTerminal;
+        
+        let allowlist = allowlist.ok_or("opening links on the host is disabled for this VM")?;
+        let host = url_host(url)?;
+        
+        if !is_allowlisted(&host, allowlist) {
+            if !std::io::stdin().is_terminal() {
+                return Err(format!("{} is not in open_url_allowlist", host).into());
+            }
+            
+            let confirm = dialoguer::Confirm::new()
+                .with_prompt(format!("VM {} wants to open {} on the host. Allow?", vm_name, url))
+                .default(false)
+                .interact()?;
+            if !confirm {
+                return Err("declined".into());
+            }
+        }
+        
+        outln!("🔗 [{}] Opening {}", vm_name, url);
+        std::process::Command::new("xdg-open").arg(url).spawn()?;
+        Ok(())
+    }
+}
+
+/// Host part of an http(s) URL; anything else is rejected before it reaches xdg-open
+pub fn url_host(url: &str) -> Result<String, String> {
+    if url.chars().any(|c| c.is_control() || c.is_whitespace()) {
+        return Err("URL contains whitespace or control characters".to_string());
+    }
+    
+    let rest = url.strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))
+        .ok_or_else(|| "only http and https URLs can be opened on the host".to_string())?;
+    
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or("");
+    if authority.contains('@') {
+        return Err("URLs with embedded credentials are not opened on the host".to_string());
+    }
+    
+    // IPv6 literals keep their brackets; anything after the host must be a port
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(v6) => match v6.split_once(']') {
+            Some((addr, port)) => (&authority[..addr.len() + 2], port),
+            None => return Err(format!("invalid IPv6 host in {}", url)),
+        },
+        None => authority.split_at(authority.find(':').unwrap_or(authority.len())),
+    };
+    if let Some(port) = port.strip_prefix(':') {
+        port.parse::<u16>().map_err(|_| format!("invalid port in {}", url))?;
+    } else if !port.is_empty() {
+        return Err(format!("invalid host in {}", url));
+    }
+    
+    if host.is_empty() {
+        return Err("URL has no host".to_string());
+    }
+    Ok(host.to_ascii_lowercase())
+}
+
+/// Whether `host` is an allowlisted domain or a subdomain of one
+fn is_allowlisted(host: &str, allowlist: &[String]) -> bool {
+    allowlist.iter().any(|domain| {
+        let domain = domain.to_ascii_lowercase();
+        host == domain || host.ends_with(&format!(".{}", domain))
+    })
+}
+
+/// Arguments for `notify-send`, labelled with the VM so guest notifications can't pose as host ones
+fn notify_send_args(vm_name: &str, app: &str, summary: &str, body: &str, icon: Option<&str>) -> Vec<String> {
+    let app_name = if app.is_empty() { vm_name.to_string() } else { format!("{} ({})", app, vm_name) };
+    let mut args = vec!["--app-name".to_string(), app_name];
+    
+    // Paths refer to the guest filesystem; only themed icon names mean anything here
+    if let Some(icon) = icon.filter(|icon| !icon.contains('/')) {
+        args.push("--icon".to_string());
+        args.push(icon.to_string());
+    }
+    
+    args.push("--".to_string());
+    args.push(format!("[{}] {}", vm_name, summary));
+    if !body.is_empty() {
+        args.push(body.to_string());
+    }
+    args
+}
+
+/// Per-VM control socket through which CLI commands send messages to the guest
+pub fn control_socket_path(vm_name: &str) -> String {
+    let runtime_dir = std::env::var("XDG_RUNTIME_DIR").unwrap_or_else(|_| "/tmp".to_string());
+    format!("{}/vm-provisioner/{}.sock", runtime_dir, vm_name)
+}
+
+/// Keep `windows` in step with the guest's window lifecycle messages
+fn track_windows(windows: &mut Vec<u32>, msg: &WindowMessage) {
+    match msg {
+        WindowMessage::WindowCreated { id, .. } if !windows.contains(id) => windows.push(*id),
+        WindowMessage::WindowDestroyed { id } => windows.retain(|window| window != id),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn guest_windows_are_tracked_for_cleanup() {
+        let created = |id| WindowMessage::WindowCreated {
+            id, title: String::new(), width: 1, height: 1, x: 0, y: 0, app_name: String::new(),
+        };
+        let mut windows = Vec::new();
+        for msg in [created(1), created(2), created(1), WindowMessage::WindowDestroyed { id: 1 }, WindowMessage::Ping { seq: 1 }] {
+            track_windows(&mut windows, &msg);
+        }
+        assert_eq!(windows, vec![2]);
+    }
+    
+    #[test]
+    fn url_host_accepts_only_http_and_https() {
+        assert_eq!(url_host("https://Example.com/path?q=1").unwrap(), "example.com");
+        assert_eq!(url_host("http://example.com:8080").unwrap(), "example.com");
+        assert_eq!(url_host("https://[::1]:8443/").unwrap(), "[::1]");
+        assert!(url_host("file:///etc/passwd").is_err());
+        assert!(url_host("javascript:alert(1)").is_err());
+        assert!(url_host("https://user@evil.example/").is_err());
+        assert!(url_host("https://example.com:notaport/").is_err());
+        assert!(url_host("https://exa mple.com/").is_err());
+        assert!(url_host("https:///path").is_err());
+    }
+    
+    #[test]
+    fn notifications_are_labelled_with_the_vm() {
+        assert_eq!(
+            notify_send_args("work-vm", "Slack", "New message", "hi", Some("com.slack.Slack")),
+            vec!["--app-name", "Slack (work-vm)", "--icon", "com.slack.Slack", "--", "[work-vm] New message", "hi"]
+        );
+        assert_eq!(
+            notify_send_args("work-vm", "", "-rf", "", Some("/usr/share/icons/x.png")),
+            vec!["--app-name", "work-vm", "--", "[work-vm] -rf"]
+        );
+    }
+    
+    #[test]
+    fn allowlist_matches_domains_and_subdomains() {
+        let allowlist = vec!["example.com".to_string()];
+        assert!(is_allowlisted("example.com", &allowlist));
+        assert!(is_allowlisted("docs.example.com", &allowlist));
+        assert!(!is_allowlisted("badexample.com", &allowlist));
+        assert!(!is_allowlisted("example.com.evil.net", &allowlist));
+    }
+    
+    #[test]
+    fn transport_falls_back_to_loopback_tcp() {
+        let loopback: IpAddr = "127.0.0.1".parse().unwrap();
+        let tcp = Transport::Tcp("127.0.0.1:10123".parse().unwrap());
+        
+        let (transport, fallback) = choose_transport("work", ProxyTransport::Vsock, 10123, loopback, true, None);
+        assert_eq!(transport, Transport::Vsock { port: 10123, cid: None });
+        assert!(fallback.is_none());
+        
+        let (transport, fallback) = choose_transport("work", ProxyTransport::Vsock, 10123, loopback, false, Some("/run/user/1000"));
+        assert_eq!(transport, tcp);
+        assert!(fallback.unwrap().contains("vsock"));
+        
+        let (transport, _) = choose_transport("work", ProxyTransport::Unix, 10123, loopback, true, Some("/run/user/1000"));
+        assert_eq!(transport, Transport::Unix(PathBuf::from("/run/user/1000/vm-provisioner/work-guest.sock")));
+        
+        let (transport, fallback) = choose_transport("work", ProxyTransport::Unix, 10123, loopback, true, None);
+        assert_eq!(transport, tcp);
+        assert!(fallback.is_some());
+        
+        let bridge: IpAddr = "192.168.122.1".parse().unwrap();
+        let (transport, fallback) = choose_transport("work", ProxyTransport::Tcp, 10123, bridge, true, None);
+        assert_eq!(transport, Transport::Tcp("192.168.122.1:10123".parse().unwrap()));
+        assert!(fallback.is_none());
+    }
+    
+    #[test]
+    fn axis_motion_scrolls_in_whole_wheel_steps() {
+        let mut pending = 0.0;
+        assert_eq!(scroll_steps(&mut pending, 10.0), 1);
+        assert_eq!(scroll_steps(&mut pending, 4.0), 0);
+        assert_eq!(scroll_steps(&mut pending, 17.0), 2);
+        assert_eq!(scroll_steps(&mut pending, -11.0), -1);
+        assert_eq!(scroll_steps(&mut pending, -30.0), -3);
+    }
+    
+    #[test]
+    fn input_on_a_proxied_window_reaches_the_guest() {
+        let (host_end, mut guest_end) = UnixStream::pair().unwrap();
+        let guest = Mutex::new(Some(GuestStream::Unix(host_end)));
+        let mut state = AppState { keyboard_focus: Some(7), pointer_focus: Some(8), ..AppState::default() };
+        state.key(30, true);
+        state.pointer(PointerAction::Button { button: 0x110, pressed: true });
+        state.close_requests.push(9);
+        forward_input(&mut state, &guest);
+        assert!(state.input.is_empty());
+        
+        let mut received = || bincode::deserialize::<WindowMessage>(&read_frame(&mut guest_end).unwrap()).unwrap();
+        assert!(matches!(received(), WindowMessage::CloseRequested { id: 9 }));
+        assert!(matches!(received(), WindowMessage::KeyEvent { id: 7, key: 30, pressed: true }));
+        assert!(matches!(received(), WindowMessage::PointerEvent { id: 8, action: PointerAction::Button { button: 0x110, pressed: true } }));
+    }
+    
+    #[test]
+    fn input_without_focus_or_guest_is_dropped() {
+        let mut state = AppState::default();
+        state.key(30, true);
+        state.pointer(PointerAction::Motion { x: 1, y: 1 });
+        assert!(state.input.is_empty());
+        
+        let mut state = AppState { keyboard_focus: Some(7), ..AppState::default() };
+        state.key(30, true);
+        forward_input(&mut state, &Mutex::new(None));
+        assert!(state.input.is_empty());
+    }
+    
+    #[test]
+    fn password_change_without_a_guest_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        std::thread::spawn(move || {
+            VMIntegrationHost::run_control_server(listener, Arc::new(Mutex::new(None)), Arc::new(Mutex::new(None)));
+        });
+        
+        let mut client = UnixStream::connect(&path).unwrap();
+        send_message(&mut client, &WindowMessage::ChangePassword { new: "n3w".to_string() }).unwrap();
+        let reply = bincode::deserialize(&read_frame(&mut client).unwrap()).unwrap();
+        assert!(matches!(reply, WindowMessage::PasswordChanged { error: Some(_) }));
+    }
 }